@@ -0,0 +1,130 @@
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+use crate::compress;
+use crate::options::Compression;
+use crate::recording::Recording;
+
+/// Records what the console was shown as an asciicast (v2) file, which asciinema can play back
+/// with the timing it had, throttling and all.
+pub struct Cast {
+    file: Box<dyn Write>,
+    start: Instant,
+    /// An incomplete UTF-8 sequence from the end of the last output, to finish with the next.
+    partial: Vec<u8>,
+}
+
+impl Cast {
+    pub fn create(
+        path: &Path,
+        compression: Option<Compression>,
+        (cols, rows): (u16, u16),
+        command: &str,
+        now: Instant,
+    ) -> Result<Self> {
+        let mut file = compress::create(path, compression)
+            .with_context(|| format!("failed to create recording {path:?}"))?;
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        writeln!(file, "{{\"version\": 2, \"width\": {cols}, \"height\": {rows}, \
+            \"timestamp\": {timestamp}, \"command\": {}}}", json_string(command))
+            .with_context(|| format!("failed to write to recording {path:?}"))?;
+        Ok(Cast {
+            file,
+            start: now,
+            partial: vec![],
+        })
+    }
+
+    fn event(&mut self, now: Instant, code: &str, data: &str) -> io::Result<()> {
+        let time = now.saturating_duration_since(self.start).as_secs_f64();
+        // One write per event, so the file is always up to date.
+        let line = format!("[{time:.6}, \"{code}\", {}]\n", json_string(data));
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+impl Recording for Cast {
+    fn output(&mut self, now: Instant, data: &[u8]) -> io::Result<()> {
+        let text = decode(&mut self.partial, data);
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.event(now, "o", &text)
+    }
+
+    fn resize(&mut self, now: Instant, cols: u16, rows: u16) -> io::Result<()> {
+        self.event(now, "r", &format!("{cols}x{rows}"))
+    }
+}
+
+/// Turn output into text, which is what asciicast events hold. A UTF-8 sequence split across
+/// reads is held back in `partial` until the rest of it arrives; anything that can't be UTF-8
+/// becomes U+FFFD.
+fn decode(partial: &mut Vec<u8>, data: &[u8]) -> String {
+    partial.extend_from_slice(data);
+    let mut text = String::new();
+    let mut rest = &partial[..];
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                text.push_str(s);
+                rest = &[];
+                break;
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap());
+                match e.error_len() {
+                    Some(n) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &after[n ..];
+                    }
+                    None => {
+                        rest = after;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    *partial = rest.to_vec();
+    text
+}
+
+#[test]
+fn test_decode() {
+    let mut partial = vec![];
+    assert_eq!(decode(&mut partial, b"abc"), "abc");
+    assert_eq!(decode(&mut partial, b"x\xe2\x94"), "x");
+    assert_eq!(decode(&mut partial, b"\x80y"), "\u{2500}y");
+    assert_eq!(decode(&mut partial, b"\xff\xe2z"), "\u{fffd}\u{fffd}z");
+    assert!(partial.is_empty());
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0' ..= '\x1f' | '\x7f' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[test]
+fn test_json_string() {
+    assert_eq!(json_string("a \"b\" \\ c"), r#""a \"b\" \\ c""#);
+    assert_eq!(json_string("\x1b[0m\r\n\u{2500}"), r#""\u001b[0m\r\n─""#);
+}
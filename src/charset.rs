@@ -0,0 +1,303 @@
+use crate::filter::Filter;
+use crate::options::{Charset, Direction};
+
+/// The top half of code page 437, as the IBM PC drew it.
+const CP437_HIGH: &str = "\
+    ÇüéâäàåçêëèïîìÄÅ\
+    ÉæÆôöòûùÿÖÜ¢£¥₧ƒ\
+    áíóúñÑªº¿⌐¬½¼¡«»\
+    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐\
+    └┴┬├─┼╞╟╚╔╩╦╠═╬╧\
+    ╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+    αßΓπΣσµτΦΘΩδ∞φε∩\
+    ≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// PETSCII's 0x60 to 0x7F and 0xA0 to 0xBF, in the uppercase and graphics mode a C64 starts in.
+/// Where Unicode only has a graphic in the Symbols for Legacy Computing block, which few fonts
+/// have, it's the nearest line or block drawing character instead.
+const PETSCII_UPPER: &str = "\
+    ─♠│────││╮╰╯└╲╱┌┐●▁♥▏╭╳○♣▕♦┼▒│π◥\
+    \u{a0}▌▄▔▁▏▒▕▒◤▕├▗└┐▂┌┴┬┤▎▍▐▀▀▃┘▖▝┘▘▚";
+
+/// The same, in the lowercase and uppercase mode.
+const PETSCII_LOWER: &str = "\
+    ─ABCDEFGHIJKLMNOPQRSTUVWXYZ┼▒│▒▒\
+    \u{a0}▌▄▔▁▏▒▕▒▒▕├▗└┐▂┌┴┬┤▎▍▐▀▀▃✓▖▝┘▘▚";
+
+/// The PETSCII color codes, and the nearest ANSI colors.
+const PETSCII_COLORS: &[(u8, u8)] = &[
+    (0x05, 97), // white
+    (0x1c, 31), // red
+    (0x1e, 32), // green
+    (0x1f, 34), // blue
+    (0x81, 33), // orange
+    (0x90, 30), // black
+    (0x95, 33), // brown
+    (0x96, 91), // light red
+    (0x97, 90), // dark grey
+    (0x98, 37), // grey
+    (0x99, 92), // light green
+    (0x9a, 94), // light blue
+    (0x9b, 37), // light grey
+    (0x9c, 35), // purple
+    (0x9e, 93), // yellow
+    (0x9f, 96), // cyan
+];
+
+/// ATASCII's 0x00 to 0x1F, which are graphics, except for ESC and the cursor movements, which
+/// show as these only after an ESC.
+const ATASCII_LOW: &str = "♥├▕┘┤┐╱╲◢▗◣▝▘▔▂▖♣┌─┼●▄▎┬┴▌└␛↑↓←→";
+
+/// The characters of a string, as an array.
+fn table<const N: usize>(s: &str) -> [char; N] {
+    let mut chars = s.chars();
+    let table = std::array::from_fn(|_| chars.next().unwrap());
+    assert!(chars.next().is_none());
+    table
+}
+
+/// With `--charset`, turns the output from an 8-bit character set into UTF-8, and its control
+/// codes, where they aren't ASCII's, into the ANSI equivalents.
+pub enum ToUtf8 {
+    /// ASCII in the bottom half, control characters included, so escape sequences come through
+    /// as they were, and what each byte from 0x80 up stands for.
+    Table([char; 128]),
+    /// The Commodore 64's, which switches between two sets of graphics.
+    Petscii {
+        /// The uppercase and lowercase mode's graphics.
+        graphics: [[char; 64]; 2],
+        lowercase: bool,
+        reverse: bool,
+    },
+    /// The Atari 8-bit computers', where the top half is the bottom half in inverse video.
+    Atascii {
+        low: [char; 32],
+        /// After an ESC, which shows the next character instead of doing what it does.
+        literal: bool,
+        /// Whether the last character shown was in inverse video, which is still on.
+        inverse: bool,
+    },
+}
+
+impl ToUtf8 {
+    pub fn new(charset: Charset) -> Self {
+        match charset {
+            Charset::Cp437 => ToUtf8::Table(table(CP437_HIGH)),
+            Charset::Latin1 => ToUtf8::Table(std::array::from_fn(|i| char::from(0x80 + i as u8))),
+            Charset::Petscii => ToUtf8::Petscii {
+                graphics: [table(PETSCII_UPPER), table(PETSCII_LOWER)],
+                lowercase: false,
+                reverse: false,
+            },
+            Charset::Atascii => ToUtf8::Atascii {
+                low: table(ATASCII_LOW),
+                literal: false,
+                inverse: false,
+            },
+        }
+    }
+}
+
+impl Filter for ToUtf8 {
+    fn transform(&mut self, _dir: Direction, input: &[u8], out: &mut Vec<u8>) {
+        for &b in input {
+            match self {
+                ToUtf8::Table(_) if b < 0x80 => out.push(b),
+                ToUtf8::Table(high) => push_char(out, high[usize::from(b - 0x80)]),
+                ToUtf8::Petscii { graphics, lowercase, reverse } => {
+                    petscii(b, graphics, lowercase, reverse, out);
+                }
+                ToUtf8::Atascii { low, literal, inverse } => atascii(b, low, literal, inverse, out),
+            }
+        }
+    }
+}
+
+fn push_char(out: &mut Vec<u8>, c: char) {
+    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+fn petscii(b: u8, graphics: &[[char; 64]; 2], lowercase: &mut bool, reverse: &mut bool,
+    out: &mut Vec<u8>)
+{
+    if let Some(&(_, color)) = PETSCII_COLORS.iter().find(|&&(code, _)| code == b) {
+        out.extend_from_slice(format!("\x1b[{color}m").as_bytes());
+        return;
+    }
+    let control: &[u8] = match b {
+        0x07 => b"\x07",
+        // Return also ends reverse video.
+        0x0d | 0x8d if *reverse => {
+            *reverse = false;
+            b"\x1b[27m\r\n"
+        }
+        0x0d | 0x8d => b"\r\n",
+        0x0e => {
+            *lowercase = true;
+            b""
+        }
+        0x8e => {
+            *lowercase = false;
+            b""
+        }
+        0x11 => b"\x1b[B",
+        0x91 => b"\x1b[A",
+        0x1d => b"\x1b[C",
+        0x9d => b"\x1b[D",
+        0x12 => {
+            *reverse = true;
+            b"\x1b[7m"
+        }
+        0x92 => {
+            *reverse = false;
+            b"\x1b[27m"
+        }
+        0x13 => b"\x1b[H",
+        0x93 => b"\x1b[H\x1b[2J",
+        0x14 => b"\x08\x1b[P",
+        0x94 => b"\x1b[@",
+        // The rest do nothing on a C64; LF included.
+        0x00 ..= 0x1f | 0x80 ..= 0x9f => b"",
+        _ => {
+            push_char(out, petscii_char(b, &graphics[usize::from(*lowercase)], *lowercase));
+            return;
+        }
+    };
+    out.extend_from_slice(control);
+}
+
+fn petscii_char(b: u8, graphics: &[char; 64], lowercase: bool) -> char {
+    match b {
+        0x41 ..= 0x5a if lowercase => char::from(b + 0x20),
+        0x5c => '£',
+        0x5e => '↑',
+        0x5f => '←',
+        0x60 ..= 0x7f => graphics[usize::from(b - 0x60)],
+        0xa0 ..= 0xbf => graphics[usize::from(b - 0xa0 + 32)],
+        // Copies of the above.
+        0xc0 ..= 0xdf => graphics[usize::from(b - 0xc0)],
+        0xe0 ..= 0xfe => graphics[usize::from(b - 0xe0 + 32)],
+        0xff => graphics[0x1e],
+        _ => char::from(b),
+    }
+}
+
+fn atascii(b: u8, low: &[char; 32], literal: &mut bool, inverse: &mut bool, out: &mut Vec<u8>) {
+    if !*literal {
+        let control: Option<&[u8]> = match b {
+            0x1b => {
+                *literal = true;
+                return;
+            }
+            0x1c => Some(b"\x1b[A"),
+            0x1d => Some(b"\x1b[B"),
+            0x1e => Some(b"\x1b[D"),
+            0x1f => Some(b"\x1b[C"),
+            0x7d => Some(b"\x1b[H\x1b[2J"),
+            0x7e => Some(b"\x08 \x08"),
+            0x7f => Some(b"\t"),
+            0x9b => Some(b"\r\n"),
+            0x9c => Some(b"\x1b[M"),
+            0x9d => Some(b"\x1b[L"),
+            // Clearing and setting tab stops.
+            0x9e | 0x9f => Some(b""),
+            0xfd => Some(b"\x07"),
+            0xfe => Some(b"\x1b[P"),
+            0xff => Some(b"\x1b[@"),
+            _ => None,
+        };
+        if let Some(control) = control {
+            if *inverse {
+                *inverse = false;
+                out.extend_from_slice(b"\x1b[27m");
+            }
+            out.extend_from_slice(control);
+            return;
+        }
+    }
+    *literal = false;
+
+    if (b & 0x80 != 0) != *inverse {
+        *inverse = !*inverse;
+        out.extend_from_slice(if *inverse { b"\x1b[7m" } else { b"\x1b[27m" });
+    }
+    let c = match b & 0x7f {
+        c @ 0x00 ..= 0x1f => low[usize::from(c)],
+        0x60 => '◆',
+        0x7b => '♠',
+        0x7d => '↰',
+        0x7e => '◀',
+        0x7f => '▶',
+        c => char::from(c),
+    };
+    push_char(out, c);
+}
+
+/// With `--charset petscii` or `atascii`, sends the keys typed at the console the way the
+/// machine's own keyboard would have: Return, Backspace and the letters, which are what a
+/// terminal sends differently.
+pub struct Keys(Charset);
+
+impl Keys {
+    pub fn new(charset: Charset) -> Option<Self> {
+        matches!(charset, Charset::Petscii | Charset::Atascii).then_some(Keys(charset))
+    }
+}
+
+impl Filter for Keys {
+    fn transform(&mut self, _dir: Direction, input: &[u8], out: &mut Vec<u8>) {
+        out.extend(input.iter().filter_map(|&b| match (self.0, b) {
+            (Charset::Petscii, b'\n') => None,
+            (Charset::Petscii, 0x08 | 0x7f) => Some(0x14),
+            // Unshifted, then shifted.
+            (Charset::Petscii, b'a' ..= b'z') => Some(b - 0x20),
+            (Charset::Petscii, b'A' ..= b'Z') => Some(b + 0x80),
+            (Charset::Atascii, b'\r' | b'\n') => Some(0x9b),
+            (Charset::Atascii, 0x08 | 0x7f) => Some(0x7e),
+            (Charset::Atascii, b'\t') => Some(0x7f),
+            _ => Some(b),
+        }));
+    }
+}
+
+#[test]
+fn test_to_utf8() {
+    let run = |filter: &mut ToUtf8, input: &[u8]| {
+        let mut out = vec![];
+        filter.transform(Direction::Out, input, &mut out);
+        String::from_utf8(out).unwrap()
+    };
+
+    let mut cp437 = ToUtf8::new(Charset::Cp437);
+    assert_eq!(run(&mut cp437, b"\x1b[1m\xc9\xcd\xbb\r\n\xba\x01\xb0\xba\r\n"),
+        "\x1b[1m╔═╗\r\n║\x01░║\r\n");
+    assert_eq!(run(&mut cp437, b"\x80\xe1\xff"), "Çß\u{a0}");
+
+    let mut latin1 = ToUtf8::new(Charset::Latin1);
+    assert_eq!(run(&mut latin1, b"caf\xe9 \xa9 \xff"), "café © ÿ");
+
+    let mut petscii = ToUtf8::new(Charset::Petscii);
+    assert_eq!(run(&mut petscii, b"\x93\x05HELLO \x12\x1cC64\x0d\xd5\xc3\xc9\xff"),
+        "\x1b[H\x1b[2J\x1b[97mHELLO \x1b[7m\x1b[31mC64\x1b[27m\r\n╭─╮π");
+    // Switching to lowercase, which persists.
+    assert_eq!(run(&mut petscii, b"\x0eHELLO \xc3"), "hello C");
+    assert_eq!(run(&mut petscii, b"\x0a\x9dA\x8eA\x5c"), "\x1b[DaA£");
+
+    let mut atascii = ToUtf8::new(Charset::Atascii);
+    assert_eq!(run(&mut atascii, b"\x7dREADY\x9b\x11\x12\x05 \xc1\xc2 ok"),
+        "\x1b[H\x1b[2JREADY\r\n┌─┐ \x1b[7mAB\x1b[27m ok");
+    // ESC shows the cursor movement instead of doing it.
+    assert_eq!(run(&mut atascii, b"\x1b\x1c\x1c"), "↑\x1b[A");
+    assert_eq!(run(&mut atascii, b"\x1b\x1b\xc1\x9b"), "␛\x1b[7mA\x1b[27m\r\n");
+}
+
+#[test]
+fn test_keys() {
+    assert!(Keys::new(Charset::Cp437).is_none());
+    let mut out = vec![];
+    Keys::new(Charset::Petscii).unwrap().transform(Direction::In, b"Hi!\x7f\r\n", &mut out);
+    assert_eq!(out, b"\xc8I!\x14\r");
+    let mut out = vec![];
+    Keys::new(Charset::Atascii).unwrap().transform(Direction::In, b"Hi!\x7f\r", &mut out);
+    assert_eq!(out, b"Hi!\x7e\x9b");
+}
@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::OpenOptions;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use crate::checkerr;
+
+/// The program's exit status when it couldn't be started.
+const EXEC_FAILED: i32 = 101;
+
+/// Where to look for programs when there's no $PATH, as `execvp` does.
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+
+/// The program running on the pty.
+pub struct Child {
+    pid: libc::pid_t,
+    /// Wait status, once it has been reaped.
+    status: Option<libc::c_int>,
+}
+
+impl Child {
+    pub fn new(pid: libc::pid_t) -> Self {
+        Child { pid, status: None }
+    }
+
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// If the child has exited, reap it and return its wait status, without blocking.
+    pub fn try_wait(&mut self) -> Result<Option<libc::c_int>> {
+        if self.status.is_none() {
+            let mut status = 0;
+            let pid = checkerr(unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) },
+                "waitpid")?;
+            if pid == self.pid {
+                self.status = Some(status);
+            }
+        }
+        Ok(self.status)
+    }
+
+    /// Wait for the child to exit, and return its wait status.
+    pub fn wait(&mut self) -> Result<libc::c_int> {
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+        let mut status = 0;
+        checkerr(unsafe { libc::waitpid(self.pid, &mut status, 0) }, "waitpid")
+            .context("error waiting for child process")?;
+        self.status = Some(status);
+        Ok(status)
+    }
+
+    /// Wait up to `timeout` for the child to exit, and return its wait status if it did.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<libc::c_int>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Send a signal to the child, unless it has already been reaped.
+    pub fn signal(&self, sig: libc::c_int) {
+        if self.status.is_none() {
+            unsafe { libc::kill(self.pid, sig) };
+        }
+    }
+
+    /// Send a signal to the child's process group, which it leads, being a session leader.
+    pub fn signal_group(&self, sig: libc::c_int) {
+        if unsafe { libc::kill(-self.pid, sig) } == -1 {
+            debug!("failed to signal process group {}: {}", self.pid, io::Error::last_os_error());
+        }
+    }
+}
+
+/// Everything needed to start the program, got ready before forking. In the child, nothing may
+/// allocate or take a lock, as another thread could have been holding it (the allocator's, or
+/// std's on the environment) when the fork happened, and it would never be let go of.
+pub struct Exec {
+    /// Where the program was found, or None if it wasn't.
+    path: Option<CString>,
+    /// The program's arguments and environment, which `argv` and `envp` point into.
+    _args: Vec<CString>,
+    _env: Vec<CString>,
+    argv: Vec<*const libc::c_char>,
+    envp: Vec<*const libc::c_char>,
+    /// The directory to run it in.
+    dir: Option<OwnedFd>,
+    /// What to say if changing to the directory fails, or the exec does.
+    dir_failed: Vec<u8>,
+    exec_failed: Vec<u8>,
+}
+
+impl Exec {
+    /// Get ready to run the command, with slowpty's environment (or none of it, with
+    /// `env_clear`) plus `env`, in `dir` if given. The program is looked for on the $PATH that
+    /// it's going to get, as `execvp` would.
+    pub fn new(command: &[OsString], env: &[(OsString, OsString)], env_clear: bool,
+        dir: Option<&Path>) -> Result<Self>
+    {
+        let program = command.first().context("no program to run")?;
+        let mut vars = if env_clear { vec![] } else { std::env::vars_os().collect::<Vec<_>>() };
+        for (key, value) in env {
+            vars.retain(|(k, _)| k != key);
+            vars.push((key.clone(), value.clone()));
+        }
+
+        let argv0 = std::env::args_os().next().unwrap_or_else(|| "slowpty".into());
+        let message = |what: &OsStr| [argv0.as_bytes(), b": ", what.as_bytes()].concat();
+        let dir_failed = dir.map(|dir| message(dir.as_os_str())).unwrap_or_default();
+        let dir = dir.map(|dir| {
+            OpenOptions::new().read(true).custom_flags(libc::O_DIRECTORY).open(dir)
+                .map(OwnedFd::from)
+                .with_context(|| format!("can't run the program in {}", dir.display()))
+        }).transpose()?;
+
+        let path = if program.as_bytes().contains(&b'/') {
+            Some(PathBuf::from(program))
+        } else {
+            let search = vars.iter().find(|(key, _)| key == "PATH")
+                .map_or(OsStr::new(DEFAULT_PATH), |(_, value)| value);
+            find_program(program, search, dir.as_ref())
+        };
+        let path = path.map(|path| CString::new(path.as_os_str().as_bytes())).transpose()
+            .context("the program's path has a NUL in it")?;
+
+        let args = command.iter()
+            .map(|arg| CString::new(arg.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("the command has a NUL in it")?;
+        let env = vars.iter()
+            .map(|(key, value)| CString::new([key.as_bytes(), b"=", value.as_bytes()].concat()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("the environment has a NUL in it")?;
+        let argv = args.iter().map(|arg| arg.as_ptr()).chain([ptr::null()]).collect();
+        let envp = env.iter().map(|var| var.as_ptr()).chain([ptr::null()]).collect();
+
+        Ok(Exec {
+            path,
+            _args: args,
+            _env: env,
+            argv,
+            envp,
+            dir,
+            dir_failed,
+            exec_failed: message(program),
+        })
+    }
+
+    /// In the child: change to the directory and exec the program, or if that fails, say why and
+    /// exit.
+    pub fn exec(&self) -> ! {
+        if let Some(ref dir) = self.dir {
+            if unsafe { libc::fchdir(dir.as_raw_fd()) } == -1 {
+                fail(&self.dir_failed, errno());
+            }
+        }
+        let Some(ref path) = self.path else { fail(&self.exec_failed, libc::ENOENT) };
+        unsafe { libc::execve(path.as_ptr(), self.argv.as_ptr(), self.envp.as_ptr()) };
+        fail(&self.exec_failed, errno())
+    }
+}
+
+/// Look for the program in each directory on the search path (relative to `dir`, which the child
+/// will be in): the first place it can be run from, or failing that, somewhere it is but can't
+/// be run, for exec to fail with the right error.
+fn find_program(program: &OsStr, search: &OsStr, dir: Option<&OwnedFd>) -> Option<PathBuf> {
+    let at = dir.map_or(libc::AT_FDCWD, AsRawFd::as_raw_fd);
+    let mut found = None;
+    for entry in search.as_bytes().split(|&b| b == b':') {
+        // An empty entry is the current directory.
+        let entry = if entry.is_empty() { OsStr::new(".") } else { OsStr::from_bytes(entry) };
+        let candidate = Path::new(entry).join(program);
+        let Ok(c_path) = CString::new(candidate.as_os_str().as_bytes()) else { continue };
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        if unsafe { libc::fstatat(at, c_path.as_ptr(), &mut stat, 0) } == -1 {
+            continue;
+        }
+        if stat.st_mode & libc::S_IFMT == libc::S_IFREG
+            && unsafe { libc::faccessat(at, c_path.as_ptr(), libc::X_OK, 0) } == 0
+        {
+            return Some(candidate);
+        }
+        found.get_or_insert(candidate);
+    }
+    found
+}
+
+#[test]
+fn test_find_program() {
+    let find = |program, search, dir| find_program(OsStr::new(program), OsStr::new(search), dir);
+    assert_eq!(find("sh", "/nonexistent:/bin", None), Some(PathBuf::from("/bin/sh")));
+    assert_eq!(find("sh", "/nonexistent", None), None);
+    // There, but it can't be run.
+    assert_eq!(find("passwd", "/etc:/nonexistent", None), Some(PathBuf::from("/etc/passwd")));
+    // Relative to where the program will run.
+    let bin = OwnedFd::from(std::fs::File::open("/bin").unwrap());
+    assert_eq!(find("sh", ":/nonexistent", Some(&bin)), Some(PathBuf::from("./sh")));
+}
+
+pub fn errno() -> libc::c_int {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+/// In the child: print the message and what the error number means, and exit, all without
+/// allocating.
+pub fn fail(message: &[u8], errno: libc::c_int) -> ! {
+    let mut reason = [0u8; 256];
+    unsafe { libc::strerror_r(errno, reason.as_mut_ptr().cast(), reason.len()) };
+    let len = reason.iter().position(|&b| b == 0).unwrap_or(0);
+    for part in [message, b": ", &reason[.. len], b"\n"] {
+        unsafe { libc::write(2, part.as_ptr().cast(), part.len()) };
+    }
+    unsafe { libc::_exit(EXEC_FAILED) }
+}
+
+/// In the child, before it execs the program: arrange for it to get SIGHUP if slowpty dies, even
+/// by SIGKILL or a crash, so it isn't left running on its own. Strictly, it's when the thread
+/// that forked it exits, as that's what `PR_SET_PDEATHSIG` goes by.
+///
+/// Elsewhere than Linux, there's nothing to do this with, but the same thing mostly happens
+/// anyway: when slowpty dies, the pty master is closed, which hangs up the program's terminal.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn hangup_on_parent_death(parent: libc::pid_t) {
+    // Until the exec, slowpty's own handler would catch it, and it'd be lost.
+    unsafe { libc::signal(libc::SIGHUP, libc::SIG_DFL) };
+    // If this fails, there's no telling anyone: logging isn't safe after a fork.
+    unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGHUP) };
+    // The parent could have died before that took effect.
+    if unsafe { libc::getppid() } != parent {
+        unsafe { libc::raise(libc::SIGHUP) };
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn hangup_on_parent_death(_parent: libc::pid_t) {}
@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use std::time::Instant;
+
+use crate::delay::Delay;
+use crate::readable::{PollResult, ReadableSet};
+
+/// The event loop's source of time, so that tests can substitute a fake one.
+///
+/// Waits are until a deadline, worked out from the time the need to wait was found, rather than
+/// for a length of time. Otherwise the time spent in between would be added on to every wait,
+/// and the pacing would always run slow.
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep_until(&mut self, deadline: Instant) -> Result<()>;
+
+    /// Wait until the deadline, picking up events on the readable set along the way: at least
+    /// those that are already there, and if possible, any that arrive while waiting, which end
+    /// the wait early.
+    fn wait_until(&mut self, deadline: Instant, readable_set: &mut ReadableSet)
+        -> Result<PollResult>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&mut self, deadline: Instant) -> Result<()> {
+        let d = deadline.saturating_duration_since(Instant::now());
+        Delay::from_duration(d).sleep().context("delay error")
+    }
+
+    fn wait_until(&mut self, deadline: Instant, readable_set: &mut ReadableSet)
+        -> Result<PollResult>
+    {
+        readable_set.block(Some(deadline.saturating_duration_since(Instant::now())))
+    }
+}
+
+/// A clock that only moves forward when something sleeps on it, so tests don't have to actually
+/// wait. Clones share the same time.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct FakeClock {
+    now: std::rc::Rc<std::cell::Cell<Instant>>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new() -> Self {
+        FakeClock { now: std::rc::Rc::new(std::cell::Cell::new(Instant::now())) }
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+
+    fn sleep_until(&mut self, deadline: Instant) -> Result<()> {
+        self.now.set(self.now.get().max(deadline));
+        Ok(())
+    }
+
+    fn wait_until(&mut self, deadline: Instant, readable_set: &mut ReadableSet)
+        -> Result<PollResult>
+    {
+        // Anything that's already there ends the wait before it starts.
+        let result = readable_set.block(Some(std::time::Duration::ZERO))?;
+        if let PollResult::Nothing = result {
+            self.sleep_until(deadline)?;
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,252 @@
+use crate::filter::Filter;
+use crate::options::{Colors, Direction};
+
+const ESC: u8 = 0x1b;
+
+/// Longest control sequence to look into; anything longer is passed on as it is.
+const MAX_SEQUENCE: usize = 256;
+
+/// The xterm palette's first 16 colors.
+const BASIC: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+    (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+];
+
+/// The levels of each component in the 6x6x6 color cube of the 256-color palette.
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Ground,
+    /// Just after an ESC.
+    Escape,
+    /// After `ESC [`, until the final byte.
+    Csi,
+}
+
+/// With `--colors` or `--monochrome`, rewrites the SGR sequences in the output for a terminal
+/// that can't show as many colors: each color is changed to the nearest one it has, or with
+/// `--monochrome`, the sequences are taken out altogether. Everything else goes through as it is.
+pub struct Downgrade {
+    colors: Colors,
+    state: State,
+    /// The sequence so far, which may have been cut off at the end of the last read.
+    pending: Vec<u8>,
+}
+
+impl Downgrade {
+    pub fn new(colors: Colors) -> Self {
+        Downgrade { colors, state: State::Ground, pending: vec![] }
+    }
+
+    /// Take output as it's read, and return it rewritten, holding back a sequence that isn't
+    /// finished yet.
+    pub fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            match (self.state, b) {
+                (State::Ground, ESC) => {
+                    self.state = State::Escape;
+                    self.pending.push(b);
+                }
+                (State::Ground, _) => out.push(b),
+                (State::Escape, b'[') => {
+                    self.state = State::Csi;
+                    self.pending.push(b);
+                }
+                (State::Csi, 0x20 ..= 0x3f) if self.pending.len() < MAX_SEQUENCE => {
+                    self.pending.push(b);
+                }
+                (State::Csi, b'm') => {
+                    let params = String::from_utf8_lossy(&self.pending[2 ..]).into_owned();
+                    match sgr(&params, self.colors) {
+                        Some(params) => out.extend(format!("\x1b[{params}m").as_bytes()),
+                        None => debug!("dropping SGR {:?}", params),
+                    }
+                    self.pending.clear();
+                    self.state = State::Ground;
+                }
+                _ => {
+                    // Not a sequence to rewrite, or not a proper one, so it's left alone.
+                    out.append(&mut self.pending);
+                    self.state = State::Ground;
+                    if b == ESC {
+                        self.state = State::Escape;
+                        self.pending.push(b);
+                    } else {
+                        out.push(b);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl Filter for Downgrade {
+    fn transform(&mut self, _dir: Direction, input: &[u8], out: &mut Vec<u8>) {
+        out.extend(self.filter(input));
+    }
+}
+
+/// Rewrite the parameters of an SGR sequence for the colors there are, or return None to drop
+/// it.
+fn sgr(params: &str, colors: Colors) -> Option<String> {
+    if colors == Colors::Monochrome {
+        return None;
+    }
+    // Private sequences that happen to end in m, and ones with intermediate bytes, aren't SGR.
+    if params.starts_with(['<', '=', '>', '?']) || params.bytes().any(|b| b < 0x30) {
+        return Some(params.to_owned());
+    }
+    if params.is_empty() {
+        return Some(String::new());
+    }
+
+    let items: Vec<&str> = params.split(';').collect();
+    let mut out: Vec<String> = vec![];
+    let mut i = 0;
+    while i < items.len() {
+        let item = items[i];
+        i += 1;
+        let mut sub = item.split(':').map(|n| n.parse::<u32>().unwrap_or(0));
+        let code = sub.next().unwrap_or(0);
+        let color = match code {
+            30 ..= 37 | 40 ..= 47 => Some((code % 10) as u8),
+            90 ..= 97 | 100 ..= 107 => Some((code % 10) as u8 + 8),
+            38 | 48 | 58 => {
+                // The color is in sub-parameters (38:5:n, or 38:2:r:g:b, maybe with a color
+                // space before r) or in the parameters that follow (38;5;n or 38;2;r;g;b).
+                let rest: Vec<u32> = if item.contains(':') {
+                    sub.collect()
+                } else {
+                    let count = match items.get(i) {
+                        Some(&"5") => 2,
+                        Some(&"2") => 4,
+                        _ => 1,
+                    };
+                    let rest = items[i .. (i + count).min(items.len())].iter()
+                        .map(|n| n.parse().unwrap_or(0))
+                        .collect();
+                    i += count;
+                    rest
+                };
+                let index = match rest[..] {
+                    [5, n, ..] => Some(n.min(255) as u8),
+                    [2, _, r, g, b] | [2, r, g, b, ..] => {
+                        let [r, g, b] = [r, g, b].map(|c| c.min(255) as u8);
+                        Some(if colors == Colors::Palette { nearest_256(r, g, b) }
+                            else { nearest(&BASIC, (r, g, b)) as u8 })
+                    }
+                    _ => None,
+                };
+                // The underline color is newer than any terminal these stand in for.
+                if code == 58 {
+                    continue;
+                }
+                match index {
+                    Some(index) => Some(index),
+                    None => continue,
+                }
+            }
+            // The underline color's reset goes with it.
+            59 => continue,
+            _ => None,
+        };
+        let Some(color) = color else {
+            out.push(item.to_owned());
+            continue;
+        };
+
+        let background = matches!(code, 40 ..= 49 | 100 ..= 107);
+        let color = match colors {
+            Colors::Palette if color >= 16 => {
+                out.push(format!("{};5;{color}", if background { 48 } else { 38 }));
+                continue;
+            }
+            Colors::Palette | Colors::Sixteen => basic_16(color),
+            _ => basic_16(color) % 8,
+        };
+        let base = match (background, color >= 8) {
+            (false, false) => 30,
+            (true, false) => 40,
+            (false, true) => 90 - 8,
+            (true, true) => 100 - 8,
+        };
+        out.push((base + u32::from(color)).to_string());
+    }
+    // With everything in it taken out, it would reset the attributes instead.
+    (!out.is_empty()).then(|| out.join(";"))
+}
+
+/// What a color from the 256-color palette looks like.
+fn palette_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0 ..= 15 => BASIC[usize::from(index)],
+        16 ..= 231 => {
+            let i = usize::from(index - 16);
+            (CUBE[i / 36], CUBE[i / 6 % 6], CUBE[i % 6])
+        }
+        _ => {
+            let level = 8 + 10 * (index - 232);
+            (level, level, level)
+        }
+    }
+}
+
+/// The nearest of the first 16 colors to a color from the 256-color palette.
+fn basic_16(index: u8) -> u8 {
+    if index < 16 {
+        return index;
+    }
+    nearest(&BASIC, palette_rgb(index)) as u8
+}
+
+/// The color in the 256-color palette nearest to a true color, from the cube or the gray ramp.
+fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |c: u8| nearest(&CUBE.map(|l| (l, l, l)), (c, c, c));
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+    let gray = (u32::from(r) + u32::from(g) + u32::from(b)) / 3;
+    let gray = 232 + (gray.saturating_sub(3) / 10).min(23);
+    [cube as u8, gray as u8].into_iter()
+        .min_by_key(|&index| distance(palette_rgb(index), (r, g, b)))
+        .unwrap()
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| u32::from(x.abs_diff(y)).pow(2);
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+fn nearest(colors: &[(u8, u8, u8)], color: (u8, u8, u8)) -> usize {
+    (0 .. colors.len()).min_by_key(|&i| distance(colors[i], color)).unwrap()
+}
+
+#[test]
+fn test_sgr() {
+    // The 256-color and true color forms, with ; and with :.
+    assert_eq!(sgr("1;38;5;196;48;2;0;0;139", Colors::Sixteen).as_deref(), Some("1;91;44"));
+    assert_eq!(sgr("38:2::255:255:255", Colors::Eight).as_deref(), Some("37"));
+    assert_eq!(sgr("38:5:46;97", Colors::Eight).as_deref(), Some("32;37"));
+    assert_eq!(sgr("38;2;255;135;0", Colors::Palette).as_deref(), Some("38;5;208"));
+    assert_eq!(sgr("48;2;128;128;128", Colors::Palette).as_deref(), Some("48;5;244"));
+    assert_eq!(sgr("0;31;104", Colors::Palette).as_deref(), Some("0;31;104"));
+    assert_eq!(sgr("", Colors::Eight).as_deref(), Some(""));
+    assert_eq!(sgr("58;5;1", Colors::Palette), None);
+    assert_eq!(sgr("4;58;5;1", Colors::Palette).as_deref(), Some("4"));
+    assert_eq!(sgr("1", Colors::Monochrome), None);
+    assert_eq!(sgr(">4;2", Colors::Eight).as_deref(), Some(">4;2"));
+}
+
+#[test]
+fn test_downgrade() {
+    let mut downgrade = Downgrade::new(Colors::Eight);
+    assert_eq!(downgrade.filter(b"\x1b[1;9"), b"");
+    assert_eq!(downgrade.filter(b"1mred\x1b[0m \x1b[2J\x1b"), b"\x1b[1;31mred\x1b[0m \x1b[2J");
+    assert_eq!(downgrade.filter(b"]0;title\x07\x1b[1\r"), b"\x1b]0;title\x07\x1b[1\r");
+
+    let mut downgrade = Downgrade::new(Colors::Monochrome);
+    assert_eq!(downgrade.filter(b"\x1b[1;38;5;46mok\x1b[m\x1b[H"), b"ok\x1b[H");
+}
@@ -0,0 +1,55 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::options::Compression;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Create a file to write a recording to, with `--compress`, through a streaming compressor.
+/// The compressed stream is finished when the writer is dropped; until then, the end of it may
+/// still be held back in the compressor.
+pub fn create(path: &Path, compression: Option<Compression>) -> io::Result<Box<dyn Write>> {
+    let file = File::create(path)?;
+    Ok(match compression {
+        None => Box::new(file),
+        Some(Compression::Gzip) => {
+            Box::new(flate2::write::GzEncoder::new(file, flate2::Compression::default()))
+        }
+        Some(Compression::Zstd) => Box::new(zstd::Encoder::new(file, 0)?.auto_finish()),
+    })
+}
+
+/// Read all of a file, decompressing it if it was compressed.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    decompress(std::fs::read(path)?)
+}
+
+fn decompress(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    if data.starts_with(GZIP_MAGIC) {
+        flate2::read::MultiGzDecoder::new(&data[..]).read_to_end(&mut out)?;
+    } else if data.starts_with(ZSTD_MAGIC) {
+        zstd::Decoder::new(&data[..])?.read_to_end(&mut out)?;
+    } else {
+        return Ok(data);
+    }
+    Ok(out)
+}
+
+#[test]
+fn test_compress() {
+    let path = std::env::temp_dir().join(format!("slowpty-compress-{}", std::process::id()));
+    for compression in [None, Some(Compression::Gzip), Some(Compression::Zstd)] {
+        let mut file = create(&path, compression).unwrap();
+        for _ in 0 .. 100 {
+            file.write_all(b"[0.5, \"o\", \"hello\\r\\n\"]\n").unwrap();
+        }
+        drop(file);
+        let size = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(size < 2400, compression.is_some(), "{compression:?}: {size} bytes");
+        assert_eq!(read(&path).unwrap(), b"[0.5, \"o\", \"hello\\r\\n\"]\n".repeat(100));
+    }
+    std::fs::remove_file(path).unwrap();
+}
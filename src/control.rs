@@ -0,0 +1,181 @@
+use anyhow::{Context, Result};
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use crate::options::parse_rate;
+use crate::readable::CONTROL;
+
+/// Something a control client asked for.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Change the rate of both directions.
+    Rate(f64),
+    /// Stop forwarding anything until resumed.
+    Pause,
+    Resume,
+    /// Report how much has been transferred so far.
+    Stats,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let command = match (words.next(), words.next()) {
+        (Some("rate"), Some(rate)) => Command::Rate(parse_rate(rate)?),
+        (Some("rate"), None) => return Err("rate needs a value".to_owned()),
+        (Some("pause"), None) => Command::Pause,
+        (Some("resume"), None) => Command::Resume,
+        (Some("stats"), None) => Command::Stats,
+        _ => return Err(format!("unknown command {:?}", line.trim())),
+    };
+    match words.next() {
+        Some(extra) => Err(format!("unexpected {extra:?}")),
+        None => Ok(command),
+    }
+}
+
+#[test]
+fn test_parse_command() {
+    assert_eq!(parse_command("rate 9600"), Ok(Command::Rate(9600.)));
+    assert_eq!(parse_command("  rate 2k "), Ok(Command::Rate(2000.)));
+    assert_eq!(parse_command("pause"), Ok(Command::Pause));
+    assert_eq!(parse_command("resume"), Ok(Command::Resume));
+    assert_eq!(parse_command("stats"), Ok(Command::Stats));
+    assert!(parse_command("rate").is_err());
+    assert!(parse_command("rate fast").is_err());
+    assert!(parse_command("pause 5").is_err());
+    assert!(parse_command("speed up").is_err());
+}
+
+struct Client {
+    stream: UnixStream,
+    /// What's been read that doesn't make a whole line yet.
+    buf: Vec<u8>,
+}
+
+/// A Unix domain socket that takes commands, one per line, to adjust the session while it's
+/// running. Each command gets a one-line reply.
+///
+/// The listener and all the connections are registered with the event loop's poll under the
+/// `CONTROL` token, so any activity on them sets that bit.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+    registry: Registry,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path, registry: Registry) -> Result<Self> {
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to create control socket {path:?}"))?;
+        let socket = ControlSocket {
+            listener,
+            path: path.to_owned(),
+            clients: vec![],
+            registry,
+        };
+        socket.listener.set_nonblocking(true)
+            .context("failed to set control socket nonblocking")?;
+        socket.registry
+            .register(&mut SourceFd(&socket.listener.as_raw_fd()), Token(CONTROL),
+                Interest::READABLE)
+            .context("mio poll registration for control socket")?;
+        Ok(socket)
+    }
+
+    /// Take any new connections, and run whatever commands have come in, replying with what
+    /// `run` returns.
+    pub fn handle(&mut self, mut run: impl FnMut(Command) -> String) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.add_client(stream) {
+                        warn!("control socket: failed to accept connection: {:#}", e);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    warn!("control socket: accept failed: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let mut i = 0;
+        while i < self.clients.len() {
+            if Self::serve(&mut self.clients[i], &mut run) {
+                i += 1;
+            } else {
+                let client = self.clients.swap_remove(i);
+                let fd = client.stream.as_raw_fd();
+                if let Err(e) = self.registry.deregister(&mut SourceFd(&fd)) {
+                    debug!("control socket: failed to deregister connection: {}", e);
+                }
+            }
+        }
+    }
+
+    fn add_client(&mut self, stream: UnixStream) -> Result<()> {
+        stream.set_nonblocking(true).context("failed to set connection nonblocking")?;
+        self.registry
+            .register(&mut SourceFd(&stream.as_raw_fd()), Token(CONTROL), Interest::READABLE)
+            .context("mio poll registration for control connection")?;
+        debug!("control socket: new connection");
+        self.clients.push(Client { stream, buf: vec![] });
+        Ok(())
+    }
+
+    /// Read and run a client's commands. Returns whether the client is still connected.
+    fn serve(client: &mut Client, run: &mut impl FnMut(Command) -> String) -> bool {
+        let mut buf = [0u8; 256];
+        let open = loop {
+            match client.stream.read(&mut buf) {
+                Ok(0) => break false,
+                Ok(n) => client.buf.extend_from_slice(&buf[.. n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break true,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    debug!("control socket: read failed: {}", e);
+                    return false;
+                }
+            }
+        };
+        if !open && !client.buf.is_empty() {
+            // The last command doesn't need a newline.
+            client.buf.push(b'\n');
+        }
+
+        while let Some(end) = client.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = client.buf.drain(..= end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            debug!("control command: {:?}", line.trim());
+            let reply = match parse_command(&line) {
+                Ok(command) => run(command),
+                Err(e) => format!("error: {e}"),
+            };
+            // Replies are short enough to fit in the socket buffer, unless the client has stopped
+            // reading them, in which case it can't be helped.
+            if let Err(e) = client.stream.write_all(format!("{reply}\n").as_bytes()) {
+                debug!("control socket: write failed: {}", e);
+                return false;
+            }
+        }
+        open
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("failed to remove control socket {:?}: {}", self.path, e);
+        }
+    }
+}
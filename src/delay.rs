@@ -1,28 +1,68 @@
 use anyhow::{Context, Result};
 use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
 
-pub const SEC_NS: i32 = 1_000_000_000;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::unix::io::{FromRawFd, OwnedFd};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::checkerr;
+
+const SEC_NS: i64 = 1_000_000_000;
 
 pub struct Delay {
     ts: libc::timespec,
 }
 
-impl Delay {
-    pub fn from_rate(rate: f64) -> Self {
-        let delay_nanos = (f64::from(SEC_NS) / rate) as i32;
-        Delay::from_nanos(delay_nanos)
+/// Make a timespec that nanosleep will accept: the nanoseconds are carried over into the seconds
+/// until they're less than a second, and the seconds are clamped to what fits. Returns None for
+/// negative times.
+fn normalize(sec: i64, nsec: i64) -> Option<libc::timespec> {
+    let sec = sec.checked_add(nsec.div_euclid(SEC_NS))?;
+    let nsec = nsec.rem_euclid(SEC_NS);
+    if sec < 0 {
+        return None;
     }
+    Some(libc::timespec {
+        tv_sec: libc::time_t::try_from(sec).unwrap_or(libc::time_t::MAX),
+        tv_nsec: nsec as libc::c_long,
+    })
+}
 
-    pub fn from_nanos(nanos: i32) -> Self {
-        Delay {
-            ts: libc::timespec {
-                tv_sec: libc::time_t::from(nanos / SEC_NS),
-                tv_nsec: libc::c_long::from(nanos % SEC_NS),
-            },
-        }
+/// A timespec's seconds and nanoseconds, widened.
+// They're already i64 on 64-bit platforms, but not everywhere.
+#[allow(clippy::useless_conversion)]
+fn parts(ts: &libc::timespec) -> (i64, i64) {
+    (i64::from(ts.tv_sec), i64::from(ts.tv_nsec))
+}
+
+fn is_normalized(ts: &libc::timespec) -> bool {
+    let (sec, nsec) = parts(ts);
+    sec >= 0 && (0 .. SEC_NS).contains(&nsec)
+}
+
+#[test]
+fn test_normalize() {
+    let ts = |sec, nsec| normalize(sec, nsec).map(|ts| parts(&ts));
+    assert_eq!(ts(1, 500), Some((1, 500)));
+    assert_eq!(ts(1, 2_500_000_000), Some((3, 500_000_000)));
+    assert_eq!(ts(2, -500_000_000), Some((1, 500_000_000)));
+    assert_eq!(ts(0, -1), None);
+    assert_eq!(ts(-1, 0), None);
+    assert_eq!(ts(i64::MAX, SEC_NS), None);
+}
+
+impl Delay {
+    pub fn from_duration(d: Duration) -> Self {
+        let sec = i64::try_from(d.as_secs()).unwrap_or(i64::MAX);
+        let ts = normalize(sec, i64::from(d.subsec_nanos()))
+            .expect("durations are never negative");
+        Delay { ts }
     }
 
     pub fn sleep(&self) -> Result<()> {
+        debug_assert!(is_normalized(&self.ts));
         let mut delay = self.ts;
         loop {
             let mut remaining: libc::timespec = unsafe { std::mem::zeroed() };
@@ -31,8 +71,14 @@ impl Delay {
                 _ => {
                     let e = io::Error::last_os_error();
                     if e.kind() == io::ErrorKind::Interrupted {
-                        delay.tv_sec = remaining.tv_sec;
-                        delay.tv_nsec = remaining.tv_nsec;
+                        // The kernel should only ever report a valid remaining time, but don't
+                        // trust it with the session.
+                        debug_assert!(is_normalized(&remaining));
+                        let (sec, nsec) = parts(&remaining);
+                        match normalize(sec, nsec) {
+                            Some(ts) => delay = ts,
+                            None => return Ok(()),
+                        }
                     } else {
                         return Err(e).context("nanosleep");
                     }
@@ -41,3 +87,102 @@ impl Delay {
         }
     }
 }
+
+#[test]
+fn test_delay_from_duration() {
+    let d = Delay::from_duration(Duration::new(3, 999_999_999));
+    assert_eq!(parts(&d.ts), (3, 999_999_999));
+
+    let d = Delay::from_duration(Duration::MAX);
+    assert!(is_normalized(&d.ts));
+
+    Delay::from_duration(Duration::from_millis(1)).sleep().unwrap();
+}
+
+/// A timer that can be polled along with the endpoints, so that waiting for the rate limit can be
+/// cut short by something arriving, without giving up the precision of a sleep (poll's own
+/// timeout only goes to the millisecond).
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub struct Timer {
+    fd: OwnedFd,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Timer {
+    pub fn new() -> Result<Option<Self>> {
+        let fd = checkerr(unsafe {
+            libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_NONBLOCK | libc::TFD_CLOEXEC)
+        }, "timerfd_create")?;
+        Ok(Some(Timer { fd: unsafe { OwnedFd::from_raw_fd(fd) } }))
+    }
+
+    /// Go off once, after the given time; or if it's zero, not at all.
+    pub fn set(&self, d: Duration) -> Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec { tv_sec: 0, tv_nsec: 0 },
+            it_value: Delay::from_duration(d).ts,
+        };
+        checkerr(unsafe {
+            libc::timerfd_settime(self.fd.as_raw_fd(), 0, &spec, std::ptr::null_mut())
+        }, "timerfd_settime")?;
+        Ok(())
+    }
+
+    /// Take note of it having gone off, so it stops being readable.
+    pub fn clear(&self) {
+        let mut expirations = 0u64;
+        unsafe { libc::read(self.fd.as_raw_fd(), &mut expirations as *mut u64 as *mut _, 8) };
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Elsewhere, there's no such timer, and the poll's own timeout has to do.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub enum Timer {}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+impl Timer {
+    pub fn new() -> Result<Option<Self>> {
+        Ok(None)
+    }
+
+    pub fn set(&self, _d: Duration) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn clear(&self) {
+        match *self {}
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {}
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn test_timer() {
+    use std::time::Instant;
+
+    let timer = Timer::new().unwrap().unwrap();
+    let mut pollfd = libc::pollfd { fd: timer.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    let start = Instant::now();
+    timer.set(Duration::from_micros(2500)).unwrap();
+    assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 1000) }, 1);
+    assert!(start.elapsed() >= Duration::from_micros(2500));
+    timer.clear();
+    assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 0) }, 0);
+
+    timer.set(Duration::from_millis(1)).unwrap();
+    timer.set(Duration::ZERO).unwrap();
+    assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 5) }, 0);
+}
@@ -0,0 +1,270 @@
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::fs::{DirBuilder, File};
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process::exit;
+
+use crate::checkerr;
+use crate::options::Options;
+use crate::session::SlowPty;
+use crate::term::{self, TermGuard};
+
+/// How much of the output to keep while no one is attached, to show whoever attaches next.
+const BACKLOG: usize = 64 * 1024;
+
+/// Where the socket for the named session is: in a directory of our own under
+/// `$XDG_RUNTIME_DIR`, or failing that, the temporary directory.
+fn socket_path(name: &str) -> Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("slowpty"),
+        None => std::env::temp_dir().join(format!("slowpty-{}", unsafe { libc::getuid() })),
+    };
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)
+        .with_context(|| format!("failed to create {dir:?}"))?;
+    Ok(dir.join(name))
+}
+
+/// With `--session`, run the session in the background, in a process of its own that keeps it
+/// going with no one attached, and attach to it. This returns when the session ends, or when
+/// it's detached from.
+pub fn start_session(mut options: Options) -> Result<()> {
+    let name = options.session.clone().expect("no session name");
+    let path = socket_path(&name)?;
+    match UnixStream::connect(&path) {
+        Ok(_) => bail!("there's already a session named {name:?}"),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            // Left over from a session that's gone.
+            debug!("removing stale socket {:?}", path);
+            let _ = std::fs::remove_file(&path);
+        }
+        Err(_) => (),
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("failed to create session socket {path:?}"))?;
+
+    // The program gets the size of the terminal it was started from, as there may not be one
+    // to ask later on.
+    if let Ok(ws) = term::WindowSize::from_fd(0) {
+        options.cols.get_or_insert(ws.cols());
+        options.rows.get_or_insert(ws.rows());
+    }
+
+    let prefix_key = options.prefix_key;
+    let pid = checkerr(unsafe { libc::fork() }, "fork")?;
+    if pid == 0 {
+        let result = hold(listener, options);
+        let _ = std::fs::remove_file(&path);
+        if let Err(e) = result {
+            error!("session {:?}: {:#}", name, e);
+            exit(1);
+        }
+        exit(0);
+    }
+    drop(listener);
+    attach(&name, prefix_key)
+}
+
+/// Run the session, with a socket pair for its console, and pass what goes through that to and
+/// from whoever is attached.
+fn hold(listener: UnixListener, options: Options) -> Result<()> {
+    // Out of the way of the terminal it was started from, which may go away.
+    checkerr(unsafe { libc::setsid() }, "setsid")?;
+    let null = File::options().read(true).write(true).open("/dev/null")
+        .context("failed to open /dev/null")?;
+    checkerr(unsafe { libc::dup2(null.as_raw_fd(), 2) }, "dup2 /dev/null -> 2")?;
+
+    let (ours, theirs) = UnixStream::pair().context("failed to create socket pair")?;
+    for fd in [0, 1] {
+        checkerr(unsafe { libc::dup2(ours.as_raw_fd(), fd) }, "dup2")?;
+    }
+    drop(ours);
+    let relay = std::thread::spawn(move || relay(listener, theirs));
+
+    let result = SlowPty::with_options(options).spawn().and_then(|running| running.wait());
+
+    // Closing the console lets the relay pass on the last of the output, and finish.
+    for fd in [0, 1] {
+        checkerr(unsafe { libc::dup2(null.as_raw_fd(), fd) }, "dup2 /dev/null")?;
+    }
+    match relay.join() {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => warn!("relay failed: {}", e),
+        Err(_) => warn!("relay panicked"),
+    }
+    result.map(drop)
+}
+
+fn poll_in(fd: RawFd) -> libc::pollfd {
+    libc::pollfd { fd, events: libc::POLLIN, revents: 0 }
+}
+
+/// Pass what's read from the console socket on to the attached client, or keep the end of it
+/// while there isn't one, and pass what the client sends back to the console. A client that
+/// attaches while another one is attached takes over from it. This returns when the console is
+/// closed.
+fn relay(listener: UnixListener, mut console: UnixStream) -> io::Result<()> {
+    let mut client: Option<UnixStream> = None;
+    let mut backlog = VecDeque::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let mut fds = vec![poll_in(console.as_raw_fd()), poll_in(listener.as_raw_fd())];
+        if let Some(ref client) = client {
+            fds.push(poll_in(client.as_raw_fd()));
+        }
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => continue,
+                e => return Err(e),
+            }
+        }
+
+        if fds[0].revents != 0 {
+            let n = match console.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let sent = client.as_mut().is_some_and(|client| client.write_all(&buf[.. n]).is_ok());
+            if !sent {
+                if client.take().is_some() {
+                    debug!("client went away");
+                }
+                backlog.extend(&buf[.. n]);
+                let excess = backlog.len().saturating_sub(BACKLOG);
+                backlog.drain(.. excess);
+            }
+        }
+
+        if fds.get(2).is_some_and(|fd| fd.revents != 0) {
+            match client.as_mut().map(|client| client.read(&mut buf)) {
+                Some(Ok(0) | Err(_)) => {
+                    debug!("client detached");
+                    client = None;
+                }
+                Some(Ok(n)) => console.write_all(&buf[.. n])?,
+                None => (),
+            }
+        }
+
+        if fds[1].revents != 0 {
+            let (mut new, _) = listener.accept()?;
+            debug!("client attached");
+            if new.write_all(backlog.make_contiguous()).is_ok() {
+                backlog.clear();
+                // Whoever was attached before is cut off.
+                client = Some(new);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_relay() {
+    let path = std::env::temp_dir().join(format!("slowpty-relay-{}", std::process::id()));
+    let listener = UnixListener::bind(&path).unwrap();
+    let (mut ours, theirs) = UnixStream::pair().unwrap();
+    let relay = std::thread::spawn(move || relay(listener, theirs));
+
+    // Output from before anyone attached is kept for the first client.
+    ours.write_all(b"hello ").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let mut client = UnixStream::connect(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let mut buf = [0u8; 6];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello ");
+
+    client.write_all(b"ls\r").unwrap();
+    let mut buf = [0u8; 3];
+    ours.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ls\r");
+
+    // Closing the console ends the relay, and that ends the client's connection.
+    ours.write_all(b"bye").unwrap();
+    drop(ours);
+    relay.join().unwrap().unwrap();
+    let mut rest = vec![];
+    client.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"bye");
+}
+
+/// Attach to the named session, with the terminal in raw mode, until the session ends or the
+/// prefix key is typed and then d, which detaches and leaves it running. Every other key goes to
+/// the session, prefix key and all.
+pub fn attach(name: &str, prefix_key: Option<u8>) -> Result<()> {
+    let path = socket_path(name)?;
+    let mut conn = UnixStream::connect(&path)
+        .with_context(|| format!("there's no session named {name:?}"))?;
+    let term = if term::is_tty(0) {
+        let term = TermGuard::new(0)?;
+        term.set_raw()?;
+        Some(term)
+    } else {
+        None
+    };
+    // Neither of these is ours to close.
+    let mut stdin = ManuallyDrop::new(unsafe { File::from_raw_fd(0) });
+    let mut stdout = ManuallyDrop::new(unsafe { File::from_raw_fd(1) });
+
+    let mut prefixed = false;
+    let mut buf = [0u8; 4096];
+    let detached = loop {
+        let mut fds = [poll_in(conn.as_raw_fd()), poll_in(0)];
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => continue,
+                e => return Err(e).context("poll"),
+            }
+        }
+
+        if fds[0].revents != 0 {
+            match conn.read(&mut buf) {
+                Ok(0) => break false,
+                Ok(n) => stdout.write_all(&buf[.. n]).context("failed to write output")?,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e).context("failed to read from the session"),
+            }
+        }
+
+        if fds[1].revents != 0 {
+            let n = match stdin.read(&mut buf) {
+                // Nothing more can be typed, so there's no use staying attached.
+                Ok(0) => break true,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("failed to read input"),
+            };
+            let mut keys = Vec::with_capacity(n + 1);
+            let mut detach = false;
+            for &b in &buf[.. n] {
+                match prefix_key {
+                    Some(prefix) if prefixed => {
+                        prefixed = false;
+                        if b == b'd' {
+                            detach = true;
+                            break;
+                        }
+                        keys.extend([prefix, b]);
+                    }
+                    Some(prefix) if b == prefix => prefixed = true,
+                    _ => keys.push(b),
+                }
+            }
+            conn.write_all(&keys).context("failed to write to the session")?;
+            if detach {
+                break true;
+            }
+        }
+    };
+    drop(term);
+    if detached {
+        eprintln!("[detached from session {name:?}]");
+    }
+    Ok(())
+}
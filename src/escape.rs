@@ -0,0 +1,198 @@
+use std::time::{Duration, Instant};
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Longest string sequence (like an OSC) to hold together; anything longer is sent as it is.
+const MAX_SEQUENCE: usize = 4096;
+
+/// A piece of input, as split up by [`Coalescer`].
+#[derive(Debug, PartialEq)]
+pub enum Piece {
+    /// Ordinary input, which can be paced a byte at a time.
+    Plain(Vec<u8>),
+    /// An escape sequence (like an arrow key), which has to reach the program all at once or it
+    /// may be taken for an Escape keypress followed by other keys.
+    Sequence(Vec<u8>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// Not in a sequence.
+    Ground,
+    /// Just after the ESC.
+    Escape,
+    /// After `ESC [`, until the final byte.
+    Csi,
+    /// After `ESC O`, which takes one more byte.
+    Ss3,
+    /// After `ESC ]` (or one of the other string introducers), until BEL or `ESC \`.
+    String,
+    /// An ESC inside a string, which is the start of `ESC \`.
+    StringEscape,
+}
+
+/// Picks the escape sequences out of what's typed at the console, so each one can be sent to the
+/// program whole. One that arrives in pieces is held back until the rest of it comes, for up to
+/// the timeout; after that, what there is of it goes as it is.
+pub struct Coalescer {
+    state: State,
+    /// The sequence so far.
+    pending: Vec<u8>,
+    timeout: Duration,
+    /// When the last of `pending` arrived.
+    updated: Option<Instant>,
+}
+
+impl Coalescer {
+    pub fn new(timeout: Duration) -> Self {
+        Coalescer {
+            state: State::Ground,
+            pending: vec![],
+            timeout,
+            updated: None,
+        }
+    }
+
+    /// Split up some input, holding back a sequence that isn't finished yet.
+    pub fn feed(&mut self, now: Instant, data: &[u8]) -> Vec<Piece> {
+        let mut pieces = vec![];
+        let mut plain = vec![];
+        for &b in data {
+            if self.state == State::Ground {
+                if b == ESC {
+                    self.state = State::Escape;
+                    self.pending.push(b);
+                } else {
+                    plain.push(b);
+                }
+                continue;
+            }
+
+            if !plain.is_empty() {
+                pieces.push(Piece::Plain(std::mem::take(&mut plain)));
+            }
+            match self.step(b) {
+                Step::More => self.pending.push(b),
+                Step::Done => {
+                    self.pending.push(b);
+                    pieces.push(self.take());
+                }
+                Step::Abandon => {
+                    // The sequence ended early (or never was one); whatever this byte is, it
+                    // isn't part of it.
+                    pieces.push(self.take());
+                    if b == ESC {
+                        self.state = State::Escape;
+                        self.pending.push(b);
+                    } else {
+                        plain.push(b);
+                    }
+                }
+            }
+            if self.pending.len() >= MAX_SEQUENCE {
+                pieces.push(self.take());
+            }
+        }
+        if !plain.is_empty() {
+            pieces.push(Piece::Plain(plain));
+        }
+        self.updated = (!self.pending.is_empty()).then_some(now);
+        pieces
+    }
+
+    fn step(&mut self, b: u8) -> Step {
+        match (self.state, b) {
+            (State::Escape, b'[') => {
+                self.state = State::Csi;
+                Step::More
+            }
+            (State::Escape, b'O') => {
+                self.state = State::Ss3;
+                Step::More
+            }
+            (State::Escape, b']' | b'P' | b'X' | b'^' | b'_') => {
+                self.state = State::String;
+                Step::More
+            }
+            // Alt with a key, and the like.
+            (State::Escape, 0x20 ..= 0x7e) => Step::Done,
+            (State::Csi, 0x20 ..= 0x3f) => Step::More,
+            (State::Csi, 0x40 ..= 0x7e) | (State::Ss3, 0x20 ..= 0x7e) => Step::Done,
+            (State::String, BEL) => Step::Done,
+            (State::String, ESC) => {
+                self.state = State::StringEscape;
+                Step::More
+            }
+            (State::String, _) => Step::More,
+            (State::StringEscape, b'\\') => Step::Done,
+            _ => Step::Abandon,
+        }
+    }
+
+    fn take(&mut self) -> Piece {
+        self.state = State::Ground;
+        Piece::Sequence(std::mem::take(&mut self.pending))
+    }
+
+    /// When what's being held back has to be sent, finished or not.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.updated.map(|t| t + self.timeout)
+    }
+
+    /// Give up on the rest of a sequence that's being held back, if it's time to, or `force` says
+    /// so, and return what there is of it.
+    pub fn expire(&mut self, now: Instant, force: bool) -> Option<Piece> {
+        if self.pending.is_empty() || !(force || self.deadline().is_some_and(|t| now >= t)) {
+            return None;
+        }
+        self.updated = None;
+        Some(self.take())
+    }
+}
+
+enum Step {
+    /// The byte is part of the sequence, which isn't finished yet.
+    More,
+    /// The byte finishes the sequence.
+    Done,
+    /// The byte isn't part of the sequence.
+    Abandon,
+}
+
+#[test]
+fn test_coalescer() {
+    use Piece::*;
+    let now = Instant::now();
+    let ms = Duration::from_millis;
+    let mut c = Coalescer::new(ms(50));
+
+    assert_eq!(c.feed(now, b"ab\x1b[A\x1bOPc"),
+        vec![Plain(b"ab".to_vec()), Sequence(b"\x1b[A".to_vec()), Sequence(b"\x1bOP".to_vec()),
+            Plain(b"c".to_vec())]);
+    assert_eq!(c.deadline(), None);
+
+    // Arriving in pieces.
+    assert_eq!(c.feed(now, b"x\x1b[1;"), vec![Plain(b"x".to_vec())]);
+    assert_eq!(c.deadline(), Some(now + ms(50)));
+    assert_eq!(c.feed(now + ms(10), b"5C"), vec![Sequence(b"\x1b[1;5C".to_vec())]);
+
+    // A lone Escape keypress goes once the time is up.
+    assert_eq!(c.feed(now, b"\x1b"), vec![]);
+    assert_eq!(c.expire(now + ms(49), false), None);
+    assert_eq!(c.expire(now + ms(50), false), Some(Sequence(b"\x1b".to_vec())));
+    assert_eq!(c.expire(now + ms(50), false), None);
+
+    // Alt-x, and Escape twice.
+    assert_eq!(c.feed(now, b"\x1bx\x1b\x1b"),
+        vec![Sequence(b"\x1bx".to_vec()), Sequence(b"\x1b".to_vec())]);
+    assert_eq!(c.expire(now, true), Some(Sequence(b"\x1b".to_vec())));
+
+    // OSC, ended with BEL or ST.
+    assert_eq!(c.feed(now, b"\x1b]11;rgb:0/0/0\x07\x1b]2;t\x1b\\"),
+        vec![Sequence(b"\x1b]11;rgb:0/0/0\x07".to_vec()), Sequence(b"\x1b]2;t\x1b\\".to_vec())]);
+
+    // Not a proper sequence.
+    assert_eq!(c.feed(now, b"\x1b[1\rz"),
+        vec![Sequence(b"\x1b[1".to_vec()), Plain(b"\rz".to_vec())]);
+}
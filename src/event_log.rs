@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::cast::json_string;
+use crate::event_loop::Exit;
+use crate::signal_name;
+
+/// Something that happened in the event loop, for the event log. Directions are indexed like the
+/// `ReadableSet` endpoints.
+pub enum Event<'a> {
+    /// Read some bytes to send in a direction.
+    Read { idx: usize, bytes: usize },
+    /// Delivered some bytes in a direction, after they'd been in transit for a while.
+    Write { idx: usize, bytes: usize, latency: Duration },
+    /// Reading, or writing, in a direction would have blocked.
+    WouldBlock { idx: usize, write: bool },
+    /// This endpoint closed.
+    Closed(&'static str),
+    Signal(libc::c_int),
+    Exit(&'a Exit),
+}
+
+/// Writes what the event loop does to a file as newline-delimited JSON, for looking at how the
+/// pacing went afterwards.
+pub struct EventLog {
+    file: File,
+    start: Instant,
+}
+
+impl EventLog {
+    pub fn create(path: &Path, now: Instant) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create event log {path:?}"))?;
+        Ok(EventLog { file, start: now })
+    }
+
+    pub fn write(&mut self, now: Instant, event: &Event) -> io::Result<()> {
+        let time = now.saturating_duration_since(self.start).as_secs_f64();
+        // One write per event, so the file is always up to date.
+        let line = format!("{{\"time\": {time:.6}, {}}}\n", fields(event));
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+fn direction(idx: usize) -> &'static str {
+    if idx == 0 { "input" } else { "output" }
+}
+
+/// The JSON object members for an event.
+fn fields(event: &Event) -> String {
+    match *event {
+        Event::Read { idx, bytes } => {
+            format!("\"event\": \"read\", \"direction\": \"{}\", \"bytes\": {bytes}",
+                direction(idx))
+        }
+        Event::Write { idx, bytes, latency } => {
+            format!("\"event\": \"write\", \"direction\": \"{}\", \"bytes\": {bytes}, \
+                \"latency\": {:.6}", direction(idx), latency.as_secs_f64())
+        }
+        Event::WouldBlock { idx, write } => {
+            format!("\"event\": \"wouldblock\", \"direction\": \"{}\", \"op\": \"{}\"",
+                direction(idx), if write { "write" } else { "read" })
+        }
+        Event::Closed(endpoint) => {
+            format!("\"event\": \"closed\", \"endpoint\": {}", json_string(endpoint))
+        }
+        Event::Signal(sig) => {
+            format!("\"event\": \"signal\", \"signal\": {sig}, \"name\": {}",
+                json_string(&signal_name(sig)))
+        }
+        Event::Exit(Exit::Closed) => "\"event\": \"exit\", \"reason\": \"closed\"".to_owned(),
+        Event::Exit(Exit::ProbeFinished(rate)) => {
+            format!("\"event\": \"exit\", \"reason\": \"probe finished\", \"rate\": {rate:.1}")
+        }
+        Event::Exit(Exit::Signaled(sig)) => {
+            format!("\"event\": \"exit\", \"reason\": \"signal\", \"signal\": {sig}")
+        }
+        Event::Exit(Exit::TimedOut) => "\"event\": \"exit\", \"reason\": \"timeout\"".to_owned(),
+    }
+}
+
+#[test]
+fn test_fields() {
+    assert_eq!(fields(&Event::Read { idx: 1, bytes: 12 }),
+        r#""event": "read", "direction": "output", "bytes": 12"#);
+    assert_eq!(fields(&Event::Write { idx: 0, bytes: 1, latency: Duration::from_millis(250) }),
+        r#""event": "write", "direction": "input", "bytes": 1, "latency": 0.250000"#);
+    assert_eq!(fields(&Event::WouldBlock { idx: 1, write: true }),
+        r#""event": "wouldblock", "direction": "output", "op": "write""#);
+    assert_eq!(fields(&Event::Closed("pty")), r#""event": "closed", "endpoint": "pty""#);
+    assert_eq!(fields(&Event::Exit(&Exit::Signaled(15))),
+        r#""event": "exit", "reason": "signal", "signal": 15"#);
+}
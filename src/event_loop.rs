@@ -0,0 +1,2288 @@
+use anyhow::{Context, Result};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cast::Cast;
+use crate::charset::{Keys, ToUtf8};
+use crate::checkerr;
+use crate::child::Child;
+use crate::control::{Command, ControlSocket};
+use crate::clock::{Clock, SystemClock};
+use crate::colors::Downgrade;
+use crate::escape::{Coalescer, Piece};
+use crate::event_log::{Event, EventLog};
+use crate::events::EventStream;
+use crate::filter::Pipeline;
+use crate::hooks::{Action, Hooks};
+use crate::latency::LatencyQueue;
+use crate::limiter::TokenBucket;
+use crate::modem::Online;
+use crate::newlines::Newlines;
+use crate::noise::LineNoise;
+use crate::options::{
+    self, DetachTrigger, Direction, DrainMode, IntrMode, Options, OutputAction, Overflow,
+    RecordFormat, Utf8Charge,
+};
+use crate::parity::SevenBit;
+use crate::rate_log::RateLog;
+use crate::readable::{PollEndpoint, PollResult, ReadableSet, CONTROL, SIGNALS};
+use crate::recording::Recording;
+use crate::signal_name;
+use crate::signals::{SignalPipe, INFO_SIGNALS};
+use crate::snapshot::Snapshot;
+use crate::stats::Stats;
+use crate::status::Status;
+use crate::tee::Tee;
+use crate::telnet::{self, Telnet};
+use crate::teletype::Teletype;
+use crate::term::{self, TermGuard};
+use crate::transcript::Transcript;
+use crate::transfer::{Change, Transfers};
+use crate::triggers::Triggers;
+use crate::ttyrec::Ttyrec;
+use crate::typescript::Typescript;
+use crate::typist::Typist;
+use crate::utf8::Utf8;
+
+/// Pressing this (Ctrl-]) at the console switches to the next rate preset.
+const PRESET_HOTKEY: u8 = 0x1d;
+
+/// With `--xon-xoff`, these (Ctrl-S and Ctrl-Q) stop and start the output.
+const XOFF: u8 = 0x13;
+const XON: u8 = 0x11;
+
+/// The most to read at a time, when the rate allows reading more than one byte.
+const READ_SIZE: usize = 4096;
+
+/// The most data to hold in a latency queue; reading in that direction waits until some of it has
+/// been delivered.
+const MAX_QUEUED: usize = 1 << 20;
+
+/// How long the program has to exit after `--timeout` sends it SIGTERM, before it gets SIGKILL.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(2);
+
+/// How long to give the child to finish exiting once the pty has been closed on its side, so that
+/// how it ended is known.
+const EIO_GRACE: Duration = Duration::from_millis(200);
+
+/// Why the event loop stopped.
+pub enum Exit {
+    /// One of the endpoints closed; the session is over.
+    Closed,
+
+    /// `--probe` finished, with the given natural output rate (bytes per second), and the session
+    /// should be ended.
+    ProbeFinished(f64),
+
+    /// We were sent SIGINT, SIGTERM or SIGHUP, which has been passed on to the child's process
+    /// group.
+    Signaled(libc::c_int),
+
+    /// `--timeout` ran out, and the child was told to exit.
+    TimedOut,
+}
+
+/// The list of rates given with `--rate-presets`, and which one is in effect.
+struct RatePresets {
+    rates: Vec<f64>,
+    current: Option<usize>,
+}
+
+impl RatePresets {
+    fn new(rates: Vec<f64>, initial_rate: Option<f64>) -> Self {
+        let current = rates.iter().position(|&r| Some(r) == initial_rate);
+        RatePresets { rates, current }
+    }
+
+    /// Advance to the next preset, wrapping around at the end, and return its rate.
+    fn cycle(&mut self) -> f64 {
+        let next = match self.current {
+            Some(i) => (i + 1) % self.rates.len(),
+            None => 0,
+        };
+        self.current = Some(next);
+        self.rates[next]
+    }
+}
+
+#[test]
+fn test_rate_presets_cycle() {
+    let mut presets = RatePresets::new(vec![300., 9600., 115200.], Some(9600.));
+    assert_eq!(presets.cycle(), 115200.);
+    assert_eq!(presets.cycle(), 300.);
+    assert_eq!(presets.cycle(), 9600.);
+
+    let mut presets = RatePresets::new(vec![300., 9600.], Some(50.));
+    assert_eq!(presets.cycle(), 300.);
+    assert_eq!(presets.cycle(), 9600.);
+    assert_eq!(presets.cycle(), 300.);
+}
+
+/// With `--percent-adaptive`, how many times longer than a probe to run throttled before
+/// probing again.
+const ADAPTIVE_PERIOD: u32 = 10;
+
+/// An unthrottled measurement window, at the start of the session or (when adaptive) from time to
+/// time after that.
+struct Probe {
+    started: Instant,
+    until: Instant,
+    /// Output byte count when the probe started.
+    start_bytes: u64,
+    /// Output that piled up while throttled is still being read, and the measurement hasn't
+    /// started yet.
+    catching_up: bool,
+    /// Fraction of the measured rate to continue at, or None to end the session.
+    then: Option<f64>,
+    /// Rate to continue at if nothing was measured.
+    fallback: Option<f64>,
+}
+
+/// With `--percent-adaptive`, the probes that keep happening after the first one.
+struct AdaptiveProbe {
+    duration: Duration,
+    /// Fraction of the measured rate to continue at after each one.
+    fraction: f64,
+    /// When the next one starts; None while one is in progress.
+    next: Option<Instant>,
+}
+
+/// With `--wakeup`, the link goes to sleep when idle, and data has to wait for it to wake up.
+struct Wakeup {
+    delay: Duration,
+    idle: Duration,
+    last_activity: Instant,
+    /// When the link will be awake, if it's waking up.
+    until: Option<Instant>,
+}
+
+impl Wakeup {
+    /// Data is waiting to be sent: how long until it can go, if it has to wait for the link to
+    /// wake up. Returns true along with the first wait of each wake-up.
+    fn wait(&mut self, now: Instant) -> Option<(Duration, bool)> {
+        match self.until {
+            Some(until) if now < until => Some((until - now, false)),
+            Some(until) => {
+                // Awake now, whether or not the data turned out to be there.
+                self.last_activity = until;
+                self.until = None;
+                None
+            }
+            None if now.saturating_duration_since(self.last_activity) > self.idle => {
+                self.until = Some(now + self.delay);
+                Some((self.delay, true))
+            }
+            None => None,
+        }
+    }
+
+    fn activity(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+}
+
+#[test]
+fn test_wakeup() {
+    let start = Instant::now();
+    let secs = Duration::from_secs;
+    let mut wakeup = Wakeup {
+        delay: secs(2),
+        idle: secs(1),
+        last_activity: start,
+        until: None,
+    };
+    assert!(wakeup.wait(start).is_none());
+    wakeup.activity(start);
+
+    // Idle for too long: it takes two seconds to wake up.
+    assert_eq!(wakeup.wait(start + secs(5)), Some((secs(2), true)));
+    assert_eq!(wakeup.wait(start + secs(6)), Some((secs(1), false)));
+    assert!(wakeup.wait(start + secs(7)).is_none());
+
+    // It stays awake for a while, even without any activity.
+    assert!(wakeup.wait(start + secs(8)).is_none());
+    assert_eq!(wakeup.wait(start + secs(9)), Some((secs(2), true)));
+}
+
+/// With `--detach-after`, watches for the end of the throttled phase.
+///
+/// Afterwards, the program is still connected to the pty, and everything keeps going through the
+/// event loop, just without any limits. Handing the program's stdin/stdout/stderr over to the
+/// real terminal instead isn't possible: they were set up before it was exec'd, and can't be
+/// changed from the outside now.
+struct Detach {
+    trigger: DetachTrigger,
+    started: Instant,
+    /// The end of the output so far, in case the text to match is split across reads.
+    tail: Vec<u8>,
+}
+
+impl Detach {
+    fn deadline(&self) -> Option<Instant> {
+        match self.trigger {
+            DetachTrigger::Elapsed(d) => Some(self.started + d),
+            _ => None,
+        }
+    }
+
+    /// Look at some output that was just forwarded, and the total so far. Returns whether it's
+    /// time to detach.
+    fn output(&mut self, data: &[u8], total: u64) -> bool {
+        match self.trigger {
+            DetachTrigger::Elapsed(_) => false,
+            DetachTrigger::OutputBytes(n) => total >= n,
+            DetachTrigger::OutputMatch(ref pattern) => {
+                self.tail.extend_from_slice(data);
+                if self.tail.windows(pattern.len()).any(|w| w == &pattern[..]) {
+                    return true;
+                }
+                let keep = self.tail.len().min(pattern.len() - 1);
+                self.tail.drain(.. self.tail.len() - keep);
+                false
+            }
+        }
+    }
+}
+
+#[test]
+fn test_detach_output_match() {
+    let mut detach = Detach {
+        trigger: DetachTrigger::OutputMatch(b"ready".to_vec()),
+        started: Instant::now(),
+        tail: vec![],
+    };
+    assert!(!detach.output(b"starting up... re", 17));
+    assert!(!detach.output(b"a", 18));
+    assert!(detach.output(b"dy\r\n", 22));
+}
+
+/// What the event loop runs on.
+pub struct Session<'a> {
+    pub console: &'a mut File,
+    /// Where to write to the console, if not `console`.
+    pub console_out: Option<&'a mut File>,
+    pub pty_master: &'a mut File,
+    /// The program on the other end of the pty, if there is one.
+    pub child: Option<&'a mut Child>,
+    /// Signals to handle, if any are being caught.
+    pub signals: Option<&'a mut SignalPipe>,
+    /// The console's settings from before it was put in raw mode, if it was.
+    pub term: Option<&'a mut TermGuard>,
+    /// With `--events-fd`, where to say what happens.
+    pub events: Option<&'a mut EventStream>,
+}
+
+/// Run the session: shuttle bytes between the console and the pty until one of them closes.
+///
+/// Nothing here depends on the pty actually being a pty or on there being a child process; any
+/// pair of pollable files works, which is how the tests exercise it.
+pub fn event_loop(options: &Options, session: Session, stats: &mut Stats) -> Result<Exit> {
+    event_loop_with_clock(options, session, stats, Box::new(SystemClock))
+}
+
+fn event_loop_with_clock(
+    options: &Options,
+    session: Session,
+    stats: &mut Stats,
+    clock: Box<dyn Clock>,
+) -> Result<Exit> {
+    let mut ev = EventLoop::new(options, session, stats, clock)?;
+    let result = match options.connect_banner {
+        Some(handshake) => ev.connect_banner(handshake),
+        None => Ok(()),
+    }
+        .and_then(|()| match options.show_command {
+            Some(ref prompt) => {
+                let line = format!("{prompt} {}\r\n", options::display_command(&options.command));
+                ev.type_out(line.as_bytes())
+            }
+            None => Ok(()),
+        })
+        .and_then(|()| ev.run())
+        .map(|exit| match exit {
+            Exit::Closed if ev.timed_out => Exit::TimedOut,
+            exit => exit,
+        })
+        .and_then(|exit| ev.flush_queues().map(|()| exit))
+        .and_then(|exit| {
+            // The other end hung up, or the call was ended some other way.
+            if options.modem && !ev.hung_up {
+                write_fully(ev.readable_set.console(), b"\r\nNO CARRIER\r\n")
+                    .context("write error")?;
+            }
+            Ok(exit)
+        });
+    if let Ok(ref exit) = result {
+        ev.log_event(&Event::Exit(exit));
+    }
+    ev.finish();
+    result
+}
+
+struct EventLoop<'a> {
+    readable_set: ReadableSet<'a>,
+    /// One per direction normally; just one shared by both with `--shared-rate`.
+    limiters: Vec<TokenBucket>,
+    /// Which limiter each direction (indexed like the `ReadableSet` endpoints) uses.
+    limiter_for: [usize; 2],
+    /// With `--direction`, which limiters stay unlimited whatever the rate.
+    unlimited: [bool; 2],
+    /// Data on its way in each direction, with `--in-latency` and `--out-latency`.
+    queues: [LatencyQueue; 2],
+    /// With `--buffer-limit`, the most each of the queues can hold.
+    buffer_limit: Option<usize>,
+    overflow: Overflow,
+    /// Which direction to service first on the next iteration.
+    next_first: usize,
+    /// Finds the escape sequences in the input, to be sent whole.
+    escapes: Coalescer,
+    /// What's read in each direction goes through these before it's queued: with `--databits 7`,
+    /// `--colors`, `--onlcr`, `--noise` and `--telnet`.
+    filters: [Pipeline; 2],
+    /// Whether the other end is a pty, rather than a connection (from `slowpty connect` or
+    /// `slowpty replay`, or with `--cat`) or a serial device.
+    pty: bool,
+    /// Whether the other end is a serial device, from `slowpty tty`.
+    serial: bool,
+    /// With `--telnet`, the protocol spoken on the console.
+    telnet: Option<Telnet>,
+    /// With `--fill-nuls` or `--cr-delay`, what's done after each line of output.
+    teletype: Option<Teletype>,
+    /// With `--utf8`, keeps the characters in the output whole.
+    utf8: Option<Utf8>,
+    /// With `--humanize`, what's done after each keystroke of input.
+    typist: Option<Typist>,
+    /// With `--modem`, watches the input for the escape to command mode during the call.
+    modem: Option<Online>,
+    /// Set once the call has been hung up from the modem's command mode.
+    hung_up: bool,
+    presets: RatePresets,
+    status: Status,
+    stats: &'a mut Stats,
+    probe: Option<Probe>,
+    adaptive: Option<AdaptiveProbe>,
+    wakeup: Option<Wakeup>,
+    detach: Option<Detach>,
+    /// With `--transfer-rate`, watches for file transfers, and the rate to run them at.
+    transfers: Option<(Transfers, f64)>,
+    /// During a file transfer, the rates to go back to after it.
+    rates_before_transfer: Vec<f64>,
+    /// With `--hooks`, the script to tell what happens.
+    hooks: Option<Hooks>,
+    events: Option<&'a mut EventStream>,
+    /// Set while doing what a hook asked for, so that it doesn't set off more hooks.
+    in_hook: bool,
+    /// With `--on-output`, watches the output for the patterns.
+    triggers: Option<Triggers>,
+    /// Set when `--on-output` says to end the session.
+    triggered_exit: bool,
+    /// With `--schedule`, the rate changes still to come, and when.
+    schedule: VecDeque<(Instant, f64)>,
+    /// With `--script`, the input still to be typed, and when.
+    script: VecDeque<(Instant, Vec<u8>)>,
+    rate_log: Option<RateLog>,
+    event_log: Option<EventLog>,
+    transcript: Option<Transcript>,
+    /// With `--record`, `--script-record` and `--snapshot`, the recordings being made, and what
+    /// to call each one in warnings.
+    recordings: Vec<(&'static str, Box<dyn Recording>)>,
+    /// With `--log-input` and `--log`, where to copy what's delivered in each direction.
+    logs: [Option<Tee>; 2],
+    control: Option<ControlSocket>,
+    /// Nothing is forwarded while paused from the control socket.
+    paused: bool,
+    child: Option<&'a mut Child>,
+    signals: Option<&'a mut SignalPipe>,
+    term: Option<&'a mut TermGuard>,
+    /// Set once the child has exited; from then on, the pty is read only until it's empty.
+    draining: bool,
+    /// With `--drain`, what to do with the output that's left then.
+    drain: DrainMode,
+    clock: Box<dyn Clock>,
+    started: Instant,
+    /// With `--kick-winch`, when to send the SIGWINCH.
+    kick_winch: Option<Instant>,
+    /// With `--cols` and `--rows`, the window size the program gets whatever the console's is.
+    size_override: (Option<u16>, Option<u16>),
+    /// With `--timeout`, when to send the child SIGTERM, or once that's been done, SIGKILL.
+    timeout: Option<Instant>,
+    timed_out: bool,
+    /// With `--idle-timeout`, how long nothing can go either way before the session is ended,
+    /// and the signal to end the child with.
+    idle_timeout: Option<(Duration, libc::c_int)>,
+    /// When anything last went either way.
+    last_traffic: Instant,
+    /// With `--intr signal`, the character that interrupts the child.
+    intr_char: Option<u8>,
+    xon_xoff: bool,
+    /// With `--prefix-key`, the key that makes the next one a command to us.
+    prefix_key: Option<u8>,
+    /// Set once the prefix key has been typed, until the command after it.
+    prefix_pending: bool,
+    /// Set by XOFF: the output is held, though still read, until XON.
+    output_stopped: bool,
+    /// With `--no-raw`, the end of the console's input is passed on to the program, rather than
+    /// ending the session.
+    filter: bool,
+    /// Set once the console's input has ended, until the program has been told.
+    eof_pending: bool,
+    /// Whether what's been delivered to the program ends a line (or is nothing yet).
+    at_line_start: bool,
+}
+
+impl<'a> EventLoop<'a> {
+    fn new(
+        options: &Options,
+        session: Session<'a>,
+        stats: &'a mut Stats,
+        clock: Box<dyn Clock>,
+    ) -> Result<Self> {
+        let Session { console, console_out, pty_master, child, signals, term, events } = session;
+        let mut readable_set = ReadableSet::new(console, console_out, pty_master)
+            .context("creating readable set")?;
+        if options.no_raw {
+            readable_set.allow_console_eof();
+        }
+        if let Some(ref signals) = signals {
+            readable_set.register_signals(signals.as_raw_fd())?;
+        }
+
+        let now = clock.now();
+        let probe = options.probe.map(|duration| Probe {
+            started: now,
+            until: now + duration,
+            start_bytes: 0,
+            catching_up: false,
+            then: options.probe_then,
+            fallback: options.rate,
+        });
+
+        let adaptive = match (options.probe_adaptive, options.probe, options.probe_then) {
+            (true, Some(duration), Some(fraction)) => {
+                Some(AdaptiveProbe { duration, fraction, next: None })
+            }
+            _ => None,
+        };
+
+        let wakeup = options.wakeup.map(|delay| Wakeup {
+            delay,
+            idle: options.wakeup_idle,
+            last_activity: now,
+            until: None,
+        });
+
+        let detach = options.detach_after.clone().map(|trigger| Detach {
+            trigger,
+            started: now,
+            tail: vec![],
+        });
+
+        let rate_log = match options.rate_log {
+            Some(ref path) => Some(RateLog::create(path, options.rate_log_interval, now)?),
+            None => None,
+        };
+
+        let event_log = match options.json_log {
+            Some(ref path) => Some(EventLog::create(path, now)?),
+            None => None,
+        };
+
+        let transcript = match options.transcript {
+            Some(ref path) => {
+                Some(Transcript::create(path, options.compress, options.transcript_format, now)?)
+            }
+            None => None,
+        };
+
+        let mut recordings: Vec<(&'static str, Box<dyn Recording>)> = vec![];
+        let command = options::display_command(&options.command);
+        let size = term::WindowSize::from_fd(readable_set.console_input().as_raw_fd())
+            .ok()
+            .filter(|ws| ws.cols() > 0 && ws.rows() > 0)
+            .unwrap_or_default()
+            .overridden(options.cols, options.rows);
+        let size = (size.cols(), size.rows());
+        match (&options.record, options.record_format) {
+            (Some(path), RecordFormat::Cast) => {
+                recordings.push(("recording",
+                    Box::new(Cast::create(path, options.compress, size, &command, now)?)));
+            }
+            (Some(path), RecordFormat::Ttyrec) => {
+                recordings.push(("recording",
+                    Box::new(Ttyrec::create(path, options.compress, now)?)));
+            }
+            (None, _) => (),
+        }
+        if let Some(ref path) = options.script_record {
+            let typescript = Typescript::create(path, options.compress,
+                options.timing.as_deref(), &command, now)?;
+            recordings.push(("typescript", Box::new(typescript)));
+        }
+        if let Some(ref path) = options.snapshot {
+            recordings.push(("snapshot", Box::new(Snapshot::create(path, size)?)));
+        }
+
+        let mut logs = [None, None];
+        for (log, path) in logs.iter_mut().zip([&options.log_input, &options.log_output]) {
+            if let Some(path) = path {
+                *log = Some(Tee::create(path, options.log_timestamps, now)?);
+            }
+        }
+
+        let control = match options.control {
+            Some(ref path) => Some(ControlSocket::bind(path, readable_set.registry()?)?),
+            None => None,
+        };
+
+        let intr_char = match options.intr {
+            IntrMode::Byte => None,
+            IntrMode::Signal => {
+                Some(term.as_ref().and_then(|term| term.control_char(libc::VINTR)).unwrap_or(0x03))
+            }
+        };
+
+        // With --cat, the input only goes round to come back as output, which is what's limited.
+        let unlimited = match options.direction {
+            _ if options.cat => [true, false],
+            Direction::Both => [false, false],
+            Direction::In => [false, true],
+            Direction::Out => [true, false],
+        };
+        // While probing, run unthrottled.
+        let rates = match probe {
+            Some(_) => [f64::INFINITY; 2],
+            None => [0, 1].map(|idx| match [options.in_rate, options.out_rate][idx] {
+                _ if unlimited[idx] => f64::INFINITY,
+                rate => rate.or(options.rate).unwrap_or(f64::INFINITY),
+            }),
+        };
+        let (count, limiter_for) = if options.shared_rate { (1, [0, 0]) } else { (2, [0, 1]) };
+        let seed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let seven_bit = (options.data_bits == 7).then_some(SevenBit(options.parity));
+        let filters = [
+            Pipeline::new(Direction::In)
+                .with(options.charset.and_then(Keys::new))
+                .with(seven_bit),
+            Pipeline::new(Direction::Out)
+                .with(seven_bit)
+                .with(options.charset.map(ToUtf8::new))
+                .with(options.colors.map(Downgrade::new))
+                .with((options.onlcr || options.ocrnl)
+                    .then(|| Newlines::new(options.onlcr, options.ocrnl)))
+                .with((options.noise > 0.).then(|| {
+                    LineNoise::new(options.noise, options.noise_burst, seed.wrapping_add(2))
+                }))
+                .with(options.telnet.map(|_| telnet::Escape)),
+        ];
+
+        let limiters = rates[.. count].iter().enumerate()
+            .map(|(i, &rate)| {
+                let bucket = TokenBucket::new(rate, f64::from(options.burst), now);
+                match options.chunk {
+                    Some(chunk) => bucket.with_chunk(f64::from(chunk)),
+                    None => bucket.with_auto_chunk(),
+                }
+                    .with_jitter(options.jitter, seed.wrapping_add(i as u64))
+            })
+            .collect();
+
+        Ok(EventLoop {
+            readable_set,
+            limiters,
+            limiter_for,
+            unlimited,
+            queues: [options.in_latency, options.out_latency].map(LatencyQueue::new),
+            buffer_limit: options.buffer_limit.map(|limit| limit as usize),
+            overflow: options.overflow,
+            next_first: 0,
+            escapes: Coalescer::new(options.esc_timeout),
+            filters,
+            teletype: (options.fill_nuls > 0 || !options.cr_delay.is_zero())
+                .then(|| Teletype::new(options.fill_nuls as usize, options.cr_delay)),
+            utf8: options.utf8.map(|charge| Utf8::new(charge == Utf8Charge::Chars)),
+            typist: options.humanize.map(|gap| Typist::new(gap, seed.wrapping_add(3))),
+            modem: options.modem.then(Online::new),
+            hung_up: false,
+            pty: options.connect.is_none() && options.replay.is_none() && !options.cat
+                && options.serial.is_none(),
+            serial: options.serial.is_some(),
+            telnet: options.telnet.map(|_| Telnet::new()),
+            presets: RatePresets::new(options.rate_presets.clone(), options.rate),
+            // The title escape sequences would only get in the way of a pipeline.
+            status: Status::new(options.indicate && !options.no_raw),
+            stats,
+            probe,
+            adaptive,
+            wakeup,
+            detach,
+            transfers: options.transfer_rate.map(|rate| (Transfers::new(now), rate)),
+            rates_before_transfer: vec![],
+            hooks: options.hooks.as_deref().map(Hooks::load).transpose()?,
+            events,
+            in_hook: false,
+            triggers: (!options.on_output.is_empty())
+                .then(|| Triggers::new(options.on_output.clone())),
+            triggered_exit: false,
+            schedule: options.schedule.iter().map(|&(offset, rate)| (now + offset, rate))
+                .collect(),
+            script: options.script.iter()
+                .scan(now, |t, (delay, keys)| {
+                    *t += *delay;
+                    Some((*t, keys.clone()))
+                })
+                .collect(),
+            rate_log,
+            event_log,
+            transcript,
+            recordings,
+            logs,
+            control,
+            paused: false,
+            child,
+            signals,
+            term,
+            draining: false,
+            drain: options.drain,
+            clock,
+            started: now,
+            kick_winch: options.kick_winch.map(|delay| now + delay),
+            size_override: (options.cols, options.rows),
+            timeout: options.timeout.map(|timeout| now + timeout),
+            timed_out: false,
+            idle_timeout: options.idle_timeout.map(|idle| (idle, options.idle_signal)),
+            last_traffic: now,
+            intr_char,
+            xon_xoff: options.xon_xoff,
+            prefix_key: options.prefix_key,
+            prefix_pending: false,
+            output_stopped: false,
+            filter: options.no_raw,
+            eof_pending: false,
+            at_line_start: true,
+        })
+    }
+
+    fn finish(&mut self) {
+        self.stats.elapsed = self.clock.now().saturating_duration_since(self.started);
+        if let Some(ref mut rate_log) = self.rate_log {
+            if let Err(e) = rate_log.sample(self.clock.now(), self.stats) {
+                warn!("failed to write to rate log: {}", e);
+            }
+        }
+        if let Some(ref mut transcript) = self.transcript {
+            if let Err(e) = transcript.finish() {
+                warn!("failed to write to transcript: {}", e);
+            }
+        }
+        for (what, recording) in &mut self.recordings {
+            if let Err(e) = recording.finish() {
+                warn!("failed to write to {}: {}", what, e);
+            }
+        }
+        if let Err(e) = self.status.clear(&mut Blocking(self.readable_set.console_input())) {
+            warn!("failed to restore terminal title: {}", e);
+        }
+    }
+
+    /// Write something of our own to the console, at the output rate.
+    fn type_out(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let now = self.clock.now();
+            let limiter = &mut self.limiters[self.limiter_for[1]];
+            let n = limiter.available(now).min(data.len());
+            if n == 0 {
+                let wait = limiter.wait_time(now);
+                self.clock.sleep_until(now + wait)?;
+                continue;
+            }
+            write_fully(self.readable_set.console(), &data[.. n]).context("write error")?;
+            self.limiters[self.limiter_for[1]].consume(n);
+            let now = self.clock.now();
+            self.log(1, now, &data[.. n]);
+            self.record(now, &data[.. n]);
+            data = &data[n ..];
+        }
+        Ok(())
+    }
+
+    /// Wait out the handshake, and print the banner a modem would on connecting, with the speed
+    /// of the line: the output rate in bits per second, with a start and stop bit to each byte.
+    fn connect_banner(&mut self, handshake: Duration) -> Result<()> {
+        let now = self.clock.now();
+        self.clock.sleep_until(now + handshake)?;
+        let bps = self.limiters[self.limiter_for[1]].rate() * 10.;
+        let banner = if bps.is_finite() {
+            format!("\r\nCONNECT {}/ARQ\r\n", bps.round())
+        } else {
+            "\r\nCONNECT\r\n".to_owned()
+        };
+        self.type_out(banner.as_bytes())
+    }
+
+    /// Copy what was delivered in one direction to its log, if it has one.
+    fn log(&mut self, idx: usize, now: Instant, data: &[u8]) {
+        if let Some(ref mut log) = self.logs[idx] {
+            if let Err(e) = log.write(now, data) {
+                let direction = if idx == 0 { "input" } else { "output" };
+                warn!("failed to write to {} log, giving up on it: {}", direction, e);
+                self.logs[idx] = None;
+            }
+        }
+    }
+
+    /// Deal with what the telnet client asked for: send the replies, and pass on a new window
+    /// size.
+    fn handle_telnet(&mut self, now: Instant) {
+        let Some(ref mut telnet) = self.telnet else { return };
+        let replies = telnet.take_replies();
+        let window_size = telnet.take_window_size();
+        if !replies.is_empty() {
+            self.queues[1].push_whole(now, replies);
+        }
+        if let Some((cols, rows)) = window_size {
+            self.resize_to(term::WindowSize::new(cols, rows));
+        }
+    }
+
+    /// Add an event to the event log, if there is one.
+    fn log_event(&mut self, event: &Event) {
+        if let Some(ref mut event_log) = self.event_log {
+            if let Err(e) = event_log.write(self.clock.now(), event) {
+                warn!("failed to write to event log, giving up on it: {}", e);
+                self.event_log = None;
+            }
+        }
+    }
+
+    /// Add output shown on the console to the recordings, if there are any.
+    fn record(&mut self, now: Instant, data: &[u8]) {
+        self.recordings.retain_mut(|(what, recording)| match recording.output(now, data) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("failed to write to {}, giving up on it: {}", what, e);
+                false
+            }
+        });
+    }
+
+    fn set_rate(&mut self, rate: f64, msg: &str) -> Result<()> {
+        let now = self.clock.now();
+        for (limiter, &unlimited) in self.limiters.iter_mut().zip(&self.unlimited) {
+            if !unlimited {
+                limiter.set_rate(rate, now);
+            }
+        }
+        self.status.show(&mut Blocking(self.readable_set.console_input()), msg)
+            .context("failed to show status")?;
+        self.rate_changed()
+    }
+
+    /// A file transfer started or ended: switch to the transfer rate, or back from it.
+    fn transfer_changed(&mut self, change: Change) -> Result<()> {
+        let Some((_, rate)) = self.transfers else { return Ok(()) };
+        let msg = match change {
+            Change::Started(protocol) => {
+                debug!("{} transfer started", protocol);
+                self.rates_before_transfer = self.limiters.iter().map(TokenBucket::rate).collect();
+                let msg = if rate.is_finite() {
+                    format!("{protocol} transfer at {rate} bytes/sec")
+                } else {
+                    format!("{protocol} transfer, unthrottled")
+                };
+                return self.set_rate(rate, &msg);
+            }
+            Change::Ended => {
+                debug!("transfer ended");
+                let now = self.clock.now();
+                let rates = std::mem::take(&mut self.rates_before_transfer);
+                for (limiter, rate) in self.limiters.iter_mut().zip(rates) {
+                    limiter.set_rate(rate, now);
+                }
+                format!("transfer finished; rate {} bytes/sec", self.rate())
+            }
+        };
+        self.status.show(&mut Blocking(self.readable_set.console_input()), &msg)
+            .context("failed to show status")?;
+        self.rate_changed()
+    }
+
+    /// Speed up or slow down each direction by the given factor, for SIGUSR1 and SIGUSR2.
+    fn scale_rate(&mut self, factor: f64) -> Result<()> {
+        let now = self.clock.now();
+        for limiter in &mut self.limiters {
+            let rate = limiter.rate() * factor;
+            limiter.set_rate(rate, now);
+        }
+        let rate = self.rate();
+        let msg = format!("rate {rate} bytes/sec");
+        self.status.show(&mut Blocking(self.readable_set.console_input()), &msg)
+            .context("failed to show status")?;
+        self.rate_changed()
+    }
+
+    fn rate_changed(&mut self) -> Result<()> {
+        let rate = self.rate();
+        if let Some(ref mut events) = self.events {
+            events.rate_change(rate);
+        }
+        self.run_hook(|hooks| hooks.rate_changed(rate))
+    }
+
+    /// Call a hook, if there's a script, and do what it asks for.
+    fn run_hook(&mut self, call: impl FnOnce(&mut Hooks) -> Vec<Action>) -> Result<()> {
+        if self.in_hook {
+            return Ok(());
+        }
+        let Some(ref mut hooks) = self.hooks else { return Ok(()) };
+        let actions = call(hooks);
+        self.in_hook = true;
+        let result = actions.into_iter().try_for_each(|action| {
+            debug!("hook: {:?}", action);
+            match action {
+                Action::SetRate(rate) => self.set_rate(rate, &format!("rate {rate} bytes/sec")),
+                Action::Inject(keys) => {
+                    let now = self.clock.now();
+                    for piece in self.escapes.feed(now, &keys) {
+                        self.queue_input(now, piece);
+                    }
+                    Ok(())
+                }
+                Action::KillChild => {
+                    match self.child {
+                        Some(ref child) => child.signal_group(libc::SIGTERM),
+                        None => warn!("hooks: there's no program to kill"),
+                    }
+                    Ok(())
+                }
+            }
+        });
+        self.in_hook = false;
+        result
+    }
+
+    /// Handle any keys typed at the console that are meant for us rather than the child, and
+    /// return the rest.
+    fn intercept_input<'b>(&mut self, data: &'b [u8]) -> Result<Cow<'b, [u8]>> {
+        let have_presets = !self.presets.rates.is_empty();
+        let xon_xoff = self.xon_xoff;
+        let is_ours = |b: u8| (have_presets && b == PRESET_HOTKEY) || Some(b) == self.intr_char
+            || (xon_xoff && (b == XOFF || b == XON)) || Some(b) == self.prefix_key;
+        if !self.prefix_pending && !data.iter().any(|&b| is_ours(b)) {
+            return Ok(Cow::Borrowed(data));
+        }
+
+        let mut rest = Vec::with_capacity(data.len());
+        for &b in data {
+            if self.prefix_pending {
+                self.prefix_pending = false;
+                if Some(b) == self.prefix_key {
+                    rest.push(b);
+                } else {
+                    self.prefix_command(b)?;
+                }
+            } else if Some(b) == self.prefix_key {
+                self.prefix_pending = true;
+            } else if have_presets && b == PRESET_HOTKEY {
+                let rate = self.presets.cycle();
+                self.set_rate(rate, &format!("rate {rate} bytes/sec"))?;
+            } else if Some(b) == self.intr_char {
+                self.signal_foreground(libc::SIGINT);
+            } else if xon_xoff && (b == XOFF || b == XON) {
+                self.output_stopped = b == XOFF;
+                debug!("output {}", if self.output_stopped { "stopped" } else { "started" });
+            } else {
+                rest.push(b);
+            }
+        }
+        Ok(Cow::Owned(rest))
+    }
+
+    /// Carry out the command typed after the prefix key.
+    fn prefix_command(&mut self, key: u8) -> Result<()> {
+        let msg = match key {
+            b'+' | b'=' => return self.scale_rate(2.),
+            b'-' => return self.scale_rate(0.5),
+            b'p' => {
+                self.output_stopped = !self.output_stopped;
+                if self.output_stopped { "output stopped" } else { "output started" }.to_owned()
+            }
+            b's' => self.report(),
+            b'q' => {
+                self.detach();
+                return Ok(());
+            }
+            b'b' => self.send_break()?,
+            _ => format!("unknown command {:?}", char::from(key)),
+        };
+        self.status.show(&mut Blocking(self.readable_set.console_input()), &msg)
+            .context("failed to show status")
+    }
+
+    /// Send a break to the program's end, which means something to a serial device; a pty takes
+    /// it and does nothing with it, and a connection has no way to send one.
+    fn send_break(&mut self) -> Result<String> {
+        if !self.pty && !self.serial {
+            return Ok("can't send a break over a connection".to_owned());
+        }
+        let fd = self.readable_set.pty_master().as_raw_fd();
+        checkerr(unsafe { libc::tcsendbreak(fd, 0) }, "tcsendbreak")?;
+        Ok("sent a break".to_owned())
+    }
+
+    /// Send a signal to whatever is in the foreground on the pty, or to the child if that can't be
+    /// determined.
+    fn signal_foreground(&self, sig: libc::c_int) {
+        let pgrp = unsafe { libc::tcgetpgrp(self.readable_set.pty_master().as_raw_fd()) };
+        let pgrp = match (pgrp, &self.child) {
+            (pgrp, _) if pgrp > 0 => pgrp,
+            (_, Some(child)) => child.pid(),
+            (_, None) => {
+                debug!("no process to send {} to", signal_name(sig));
+                return;
+            }
+        };
+        debug!("sending {} to process group {}", signal_name(sig), pgrp);
+        if unsafe { libc::kill(-pgrp, sig) } == -1 {
+            warn!("failed to send {} to process group {}: {}", signal_name(sig), pgrp,
+                io::Error::last_os_error());
+        }
+    }
+
+    fn handle_signals(&mut self) -> Result<Option<Exit>> {
+        let Some(ref mut signals) = self.signals else { return Ok(None) };
+        for sig in signals.pending() {
+            self.log_event(&Event::Signal(sig));
+            match sig {
+                libc::SIGCHLD => {
+                    if let Some(exit) = self.check_child()? {
+                        return Ok(Some(exit));
+                    }
+                }
+                libc::SIGWINCH => self.resize(),
+                libc::SIGUSR1 => self.scale_rate(2.)?,
+                libc::SIGUSR2 => self.scale_rate(0.5)?,
+                libc::SIGTSTP => self.suspend()?,
+                sig if INFO_SIGNALS.contains(&sig) => {
+                    // On stderr, like dd. In raw mode, the line ending has to be spelled out.
+                    let report = self.report();
+                    eprint!("{report}{}", if self.filter { "\n" } else { "\r\n" });
+                }
+                libc::SIGINT | libc::SIGTERM | libc::SIGHUP => {
+                    info!("received {}; passing it on and ending the session", signal_name(sig));
+                    if let Some(ref child) = self.child {
+                        child.signal_group(sig);
+                    }
+                    return Ok(Some(Exit::Signaled(sig)));
+                }
+                _ => debug!("ignoring signal {}", sig),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stop for job control: put the terminal back the way it was, stop the program, and stop
+    /// ourselves. Once continued, set things up again from however the terminal is now.
+    fn suspend(&mut self) -> Result<()> {
+        debug!("suspending");
+        if let Some(ref term) = self.term {
+            term.restore()?;
+        }
+        if let Some(ref child) = self.child {
+            child.signal_group(libc::SIGSTOP);
+        }
+        if let Some(ref signals) = self.signals {
+            signals.raise_default(libc::SIGTSTP)?;
+        }
+
+        debug!("continuing");
+        if let Some(ref mut term) = self.term {
+            // The settings may have been changed while we were stopped.
+            term.save()?;
+            term.set_raw()?;
+        }
+        if let Some(ref child) = self.child {
+            child.signal_group(libc::SIGCONT);
+        }
+        // And so may the size.
+        self.resize();
+        Ok(())
+    }
+
+    /// The console was resized; pass the new size on to the pty. The kernel sends SIGWINCH to the
+    /// pty's foreground process group when its size changes, so the program finds out from that.
+    fn resize(&mut self) {
+        match term::WindowSize::from_fd(self.readable_set.console_input().as_raw_fd()) {
+            Ok(ws) => self.resize_to(ws),
+            Err(e) => debug!("not resizing the pty: {:#}", e),
+        }
+    }
+
+    fn resize_to(&mut self, ws: term::WindowSize) {
+        let ws = ws.overridden(self.size_override.0, self.size_override.1);
+        debug!("terminal resized to {}x{}", ws.cols(), ws.rows());
+        let now = self.clock.now();
+        self.recordings.retain_mut(|(what, recording)| {
+            match recording.resize(now, ws.cols(), ws.rows()) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("failed to write to {}, giving up on it: {}", what, e);
+                    false
+                }
+            }
+        });
+        if !self.pty {
+            return;
+        }
+        if let Err(e) = ws.apply_to_fd(self.readable_set.pty_master().as_raw_fd()) {
+            warn!("failed to resize the pty: {:#}", e);
+        }
+    }
+
+    fn handle_control(&mut self) {
+        let Some(mut control) = self.control.take() else { return };
+        control.handle(|command| self.control_command(command));
+        self.control = Some(control);
+    }
+
+    /// Carry out a command from the control socket, and return the reply.
+    fn control_command(&mut self, command: Command) -> String {
+        let result = match command {
+            Command::Rate(rate) => self.set_rate(rate, &format!("rate {rate} bytes/sec")),
+            Command::Pause => {
+                self.paused = true;
+                self.status.show(&mut Blocking(self.readable_set.console_input()), "paused")
+                    .context("failed to show status")
+            }
+            Command::Resume => {
+                self.paused = false;
+                self.status.show(&mut Blocking(self.readable_set.console_input()), "resumed")
+                    .context("failed to show status")
+            }
+            Command::Stats => return self.report(),
+        };
+        match result {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => format!("error: {e:#}"),
+        }
+    }
+
+    /// The rate to show: the output's, unless only the input is limited.
+    fn rate(&self) -> f64 {
+        let idx = if self.unlimited[1] { 0 } else { 1 };
+        self.limiters[self.limiter_for[idx]].rate()
+    }
+
+    /// A line on how the session is going: how long it's been running, how much has gone each way
+    /// and how fast, and the rate.
+    fn report(&mut self) -> String {
+        self.stats.elapsed = self.clock.now().saturating_duration_since(self.started);
+        let [input, output] = [0, 1].map(|idx| format!("{} bytes, {:.1} bytes/sec",
+            self.stats.bytes[idx], self.stats.throughput(idx)));
+        let rate = self.rate();
+        format!("up {:.1}s; input: {input}; output: {output}; rate: {rate}{}",
+            self.stats.elapsed.as_secs_f64(), if self.paused { " (paused)" } else { "" })
+    }
+
+    fn check_child(&mut self) -> Result<Option<Exit>> {
+        let Some(ref mut child) = self.child else { return Ok(None) };
+        if self.draining {
+            return Ok(None);
+        }
+        let Some(status) = child.try_wait()? else { return Ok(None) };
+        self.child_exited(status)?;
+        if self.drain == DrainMode::Discard {
+            return Ok(Some(Exit::Closed));
+        }
+        // Whatever the child wrote before exiting may still be sitting in the pty. Keep reading
+        // until it's empty; there won't be any more after that.
+        debug!("child exited with status {:#x}; draining the pty", status);
+        self.draining = true;
+        self.readable_set.set(1);
+        Ok(None)
+    }
+
+    /// The child exited, and what it wrote is to be shown as `--drain` says.
+    fn child_exited(&mut self, status: libc::c_int) -> Result<()> {
+        match self.drain {
+            DrainMode::Rate => (),
+            DrainMode::Fast => {
+                debug!("showing the rest of the output unthrottled");
+                let now = self.clock.now();
+                self.limiters[self.limiter_for[1]].set_rate(f64::INFINITY, now);
+            }
+            DrainMode::Discard => {
+                debug!("discarding the rest of the output ({} bytes queued)",
+                    self.queues[1].bytes());
+                self.queues[1] = LatencyQueue::new(self.queues[1].latency());
+            }
+        }
+        self.run_hook(|hooks| hooks.child_exited(status))
+    }
+
+    /// When the next call to `run_timers` has something to do, or data in a latency queue
+    /// becomes due. (Data that's already due is waiting on the limiter or the destination.)
+    fn next_timer(&self) -> Option<Instant> {
+        let now = self.clock.now();
+        [
+            self.probe.as_ref().map(|probe| probe.until),
+            self.adaptive.as_ref().and_then(|adaptive| adaptive.next),
+            self.detach.as_ref().and_then(Detach::deadline),
+            self.transfers.as_ref().and_then(|(transfers, _)| transfers.deadline()),
+            self.schedule.front().map(|&(t, _)| t),
+            self.script.front().map(|&(t, _)| t),
+            self.kick_winch,
+            self.timeout,
+            self.idle_timeout.map(|(idle, _)| self.last_traffic + idle),
+            self.escapes.deadline(),
+            self.queues[0].next_due().filter(|&due| due > now),
+            self.queues[1].next_due().filter(|&due| due > now),
+            self.rate_log.as_ref().map(RateLog::next_sample),
+        ].into_iter().flatten().min()
+    }
+
+    /// Handle anything that is due to happen at a particular time.
+    fn run_timers(&mut self) -> Result<Option<Exit>> {
+        let now = self.clock.now();
+
+        if self.probe.as_ref().is_some_and(|probe| now >= probe.until) {
+            if let Some(exit) = self.finish_probe(now) {
+                return Ok(Some(exit));
+            }
+        }
+
+        if self.detach.as_ref().and_then(Detach::deadline).is_some_and(|t| now >= t) {
+            self.detach();
+        }
+
+        if self.transfers.as_mut().is_some_and(|(transfers, _)| transfers.expire(now)) {
+            self.transfer_changed(Change::Ended)?;
+        }
+
+        if self.kick_winch.is_some_and(|t| now >= t) {
+            self.kick_winch = None;
+            self.signal_foreground(libc::SIGWINCH);
+        }
+
+        if let Some((idle, sig)) = self.idle_timeout {
+            if now >= self.last_traffic + idle && !self.timed_out {
+                let Some(ref child) = self.child else { return Ok(Some(Exit::TimedOut)) };
+                info!("idle for {:?}; sending the program {}", idle, signal_name(sig));
+                child.signal_group(sig);
+                self.idle_timeout = None;
+                self.timed_out = true;
+                self.timeout = Some(now + TIMEOUT_GRACE);
+            }
+        }
+
+        if self.timeout.is_some_and(|t| now >= t) {
+            let Some(ref child) = self.child else { return Ok(Some(Exit::TimedOut)) };
+            if self.timed_out {
+                info!("the program is still running; killing it");
+                child.signal_group(libc::SIGKILL);
+                self.timeout = None;
+            } else {
+                // Keep going until it exits, so what it had to say on the way out isn't lost.
+                info!("timed out; terminating the program");
+                child.signal_group(libc::SIGTERM);
+                self.timed_out = true;
+                self.timeout = Some(now + TIMEOUT_GRACE);
+            }
+        }
+
+        if let Some(piece) = self.escapes.expire(now, false) {
+            self.queue_input(now, piece);
+        }
+
+        let mut scheduled = None;
+        while let Some(&(t, rate)) = self.schedule.front() {
+            if now < t {
+                break;
+            }
+            self.schedule.pop_front();
+            scheduled = Some(rate);
+        }
+        if let Some(rate) = scheduled {
+            self.set_rate(rate, &format!("rate {rate} bytes/sec"))?;
+        }
+
+        while self.script.front().is_some_and(|&(t, _)| now >= t) {
+            let (_, keys) = self.script.pop_front().unwrap();
+            debug!("script: typing {:?}", String::from_utf8_lossy(&keys));
+            for piece in self.escapes.feed(now, &keys) {
+                self.queue_input(now, piece);
+            }
+        }
+
+        if self.adaptive.as_ref().and_then(|adaptive| adaptive.next).is_some_and(|t| now >= t) {
+            self.start_probe(now);
+        }
+
+        if let Some(ref mut rate_log) = self.rate_log {
+            if now >= rate_log.next_sample() {
+                if let Err(e) = rate_log.sample(now, self.stats) {
+                    warn!("failed to write to rate log, giving up on it: {}", e);
+                    self.rate_log = None;
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Write out as much of what's due in one direction as the limiter and the destination
+    /// allow. Returns how long until the limiter allows more, if that's what stopped it.
+    fn write_due(&mut self, idx: usize, now: Instant) -> Result<Option<Duration>> {
+        let dst_idx = 1 - idx;
+        while self.readable_set.is_writable(dst_idx) {
+            let wait = match idx {
+                0 => self.typist.as_ref().and_then(|typist| typist.wait(now)),
+                _ => self.teletype.as_ref().and_then(|teletype| teletype.wait(now)),
+            };
+            if let Some(wait) = wait {
+                return Ok(Some(wait));
+            }
+            let PollEndpoint { name, ref mut dst, .. } = self.readable_set.endpoint(idx).unwrap();
+            let limiter = &mut self.limiters[self.limiter_for[idx]];
+            let utf8 = self.utf8.as_ref().filter(|_| idx == 1);
+            let (sent, data) = match write_paced(dst, &mut self.queues[idx], limiter, utf8, now) {
+                Ok(Written::Bytes(sent, data)) => (sent, data),
+                Ok(Written::NothingDue) => break,
+                Ok(Written::NoTokens(wait)) => return Ok(Some(wait)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // The rest waits in the queue until the destination has room for it.
+                    debug!("{}: write would block", name);
+                    self.log_event(&Event::WouldBlock { idx, write: true });
+                    self.readable_set.wait_writable(dst_idx)?;
+                    break;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if idx == 0 && !self.pty && e.kind() == io::ErrorKind::BrokenPipe => {
+                    // The connection or recording has finished with its input; what's left of
+                    // it can go, and reading will find it closed soon enough.
+                    debug!("{}: closed for input", name);
+                    self.queues[idx] = LatencyQueue::new(self.queues[idx].latency());
+                    break;
+                }
+                Err(e) => return Err(e).context("write error"),
+            };
+
+            let delivered = self.clock.now();
+            self.last_traffic = delivered;
+            let latency = delivered.saturating_duration_since(sent);
+            self.stats.delivered(idx, data.len(), latency);
+            self.log_event(&Event::Write { idx, bytes: data.len(), latency });
+            self.log(idx, delivered, &data);
+            if let Some(ref mut transcript) = self.transcript {
+                if let Err(e) = transcript.record(delivered, idx, &data) {
+                    warn!("failed to write to transcript, giving up on it: {}", e);
+                    self.transcript = None;
+                }
+            }
+            if idx == 0 {
+                self.at_line_start = data.last() == Some(&b'\n');
+                if let Some(ref mut typist) = self.typist {
+                    typist.typed(delivered, &data);
+                }
+            }
+            if idx == 1 {
+                if let Some(ref mut teletype) = self.teletype {
+                    teletype.written(delivered, &data);
+                }
+                self.record(delivered, &data);
+                let total = self.stats.output_bytes();
+                if self.detach.as_mut().is_some_and(|detach| detach.output(&data, total)) {
+                    self.detach();
+                }
+                if let Some(ref mut triggers) = self.triggers {
+                    for action in triggers.output(&data) {
+                        self.output_matched(action)?;
+                    }
+                    if self.output_stopped || self.triggered_exit {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Do what `--on-output` says to when the output matches.
+    fn output_matched(&mut self, action: OutputAction) -> Result<()> {
+        debug!("output matched: {:?}", action);
+        match action {
+            OutputAction::Pause => {
+                self.output_stopped = true;
+                self.status.show(&mut Blocking(self.readable_set.console_input()),
+                    "output stopped")
+                    .context("failed to show status")
+            }
+            OutputAction::Rate(rate) => self.set_rate(rate, &format!("rate {rate} bytes/sec")),
+            OutputAction::Exit => {
+                // The rest of the output isn't wanted.
+                self.queues[1] = LatencyQueue::new(self.queues[1].latency());
+                self.triggered_exit = true;
+                Ok(())
+            }
+            OutputAction::Send(text) => {
+                let now = self.clock.now();
+                for piece in self.escapes.feed(now, &text) {
+                    self.queue_input(now, piece);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn queue_input(&mut self, now: Instant, piece: Piece) {
+        match piece {
+            // Each keystroke on its own, to be typed after a gap of its own.
+            Piece::Plain(data) if self.typist.is_some() => {
+                for b in data {
+                    self.queues[0].push(now, vec![b]);
+                }
+            }
+            Piece::Plain(data) => self.queues[0].push(now, data),
+            Piece::Sequence(data) => self.queues[0].push_whole(now, data),
+        }
+    }
+
+    /// Tell the program its input has ended, by typing the pty's EOF character: one ends the
+    /// input at the start of a line, but elsewhere it only ends the line, so it takes two. A
+    /// connection is shut down for writing instead. A serial line has no way to say so, so the
+    /// session carries on until the device goes away or slowpty is signaled.
+    fn send_eof(&mut self) -> Result<()> {
+        self.eof_pending = false;
+        if self.serial {
+            debug!("end of input; nothing to tell the serial device");
+            return Ok(());
+        }
+        if !self.pty {
+            debug!("shutting down the connection for writing");
+            let fd = self.readable_set.pty_master().as_raw_fd();
+            checkerr(unsafe { libc::shutdown(fd, libc::SHUT_WR) }, "shutdown")?;
+            return Ok(());
+        }
+        let mut t: libc::termios = unsafe { std::mem::zeroed() };
+        let eof = match unsafe { libc::tcgetattr(self.readable_set.pty_master().as_raw_fd(),
+            &mut t) }
+        {
+            0 => t.c_cc[libc::VEOF],
+            _ => 0x04, // ^D
+        };
+        let data = if self.at_line_start { vec![eof] } else { vec![eof, eof] };
+        debug!("sending EOF to the program");
+        let PollEndpoint { dst, .. } = self.readable_set.endpoint(0).unwrap();
+        write_fully(dst, &data).context("write error")
+    }
+
+    /// How much more may be read in one direction. Only as much is taken as will keep the link
+    /// busy until the latency has passed, plus a burst (one byte, unless `--burst` says
+    /// otherwise), so that the program doesn't get to run ahead of the throttle. With
+    /// `--buffer-limit`, it's up to the buffer's size instead, or as much as there is when the
+    /// overflow is thrown away.
+    fn read_room(&self, idx: usize) -> usize {
+        let limiter = &self.limiters[self.limiter_for[idx]];
+        let limit = match self.buffer_limit {
+            Some(_) if self.overflow != Overflow::Block => return READ_SIZE,
+            Some(limit) => limit,
+            None if limiter.is_unlimited() => MAX_QUEUED,
+            None => {
+                let in_flight = limiter.rate() * self.queues[idx].latency().as_secs_f64();
+                (in_flight as usize).saturating_add(limiter.capacity() as usize).min(MAX_QUEUED)
+            }
+        };
+        limit.saturating_sub(self.queues[idx].bytes()).min(READ_SIZE)
+    }
+
+    /// With `--overflow drop-oldest` or `drop-newest`, throw away what doesn't fit in the buffer,
+    /// as a terminal that couldn't keep up lost characters.
+    fn handle_overflow(&mut self, idx: usize) {
+        let Some(limit) = self.buffer_limit else { return };
+        let excess = self.queues[idx].bytes().saturating_sub(limit);
+        let dropped = match self.overflow {
+            _ if excess == 0 => return,
+            Overflow::Block => return,
+            Overflow::DropOldest => self.queues[idx].drop_front(excess),
+            Overflow::DropNewest => self.queues[idx].drop_back(excess),
+        };
+        debug!("overrun: dropped {} bytes", dropped);
+        self.stats.dropped[idx] += dropped as u64;
+    }
+
+    /// At the end of the session, wait for everything still in transit to be delivered.
+    fn flush_queues(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        if let Some(piece) = self.escapes.expire(now, true) {
+            self.queue_input(now, piece);
+        }
+        loop {
+            let now = self.clock.now();
+            let mut wait: Option<Duration> = None;
+            for idx in [0, 1] {
+                if self.queues[idx].bytes() == 0 {
+                    continue;
+                }
+                if !self.readable_set.is_writable(1 - idx) {
+                    self.readable_set.poll_writable(1 - idx)?;
+                }
+                let t = match self.write_due(idx, now)? {
+                    Some(t) => t,
+                    None => match self.queues[idx].next_due() {
+                        Some(due) => due.saturating_duration_since(now),
+                        None => continue,
+                    },
+                };
+                wait = Some(wait.map_or(t, |w| w.min(t)));
+            }
+            match wait {
+                Some(wait) => self.clock.sleep_until(now + wait)?,
+                None if self.queues.iter().all(|queue| queue.bytes() == 0) => return Ok(()),
+                None => (),
+            }
+        }
+    }
+
+    /// End the throttled phase: no more limits, wake-ups, or rate changes from probing.
+    fn detach(&mut self) {
+        debug!("detaching");
+        self.detach = None;
+        self.transfers = None;
+        self.rates_before_transfer.clear();
+        self.schedule.clear();
+        self.adaptive = None;
+        self.wakeup = None;
+        if self.probe.as_ref().is_some_and(|probe| probe.then.is_some()) {
+            self.probe = None;
+        }
+        if let Err(e) = self.set_rate(f64::INFINITY, "no longer throttling") {
+            warn!("{:#}", e);
+        }
+    }
+
+    /// Go unthrottled again to measure the output rate, keeping the current rate to fall back to.
+    fn start_probe(&mut self, now: Instant) {
+        let Some(ref mut adaptive) = self.adaptive else { return };
+        adaptive.next = None;
+        self.probe = Some(Probe {
+            started: now,
+            until: now + adaptive.duration,
+            start_bytes: self.stats.output_bytes(),
+            catching_up: true,
+            then: Some(adaptive.fraction),
+            fallback: Some(self.limiters[self.limiter_for[1]].rate()).filter(|r| r.is_finite()),
+        });
+        if let Err(e) = self.set_rate(f64::INFINITY, "measuring output rate") {
+            warn!("{:#}", e);
+        }
+    }
+
+    /// The pty has been emptied, so start measuring if a probe was waiting for that.
+    fn caught_up(&mut self, now: Instant) {
+        let Some(ref mut probe) = self.probe else { return };
+        if probe.catching_up {
+            let backlog = self.stats.output_bytes() - probe.start_bytes;
+            debug!("probe: caught up after {} bytes", backlog);
+            probe.until = now + (probe.until - probe.started);
+            probe.started = now;
+            probe.start_bytes = self.stats.output_bytes();
+            probe.catching_up = false;
+        }
+    }
+
+    fn finish_probe(&mut self, now: Instant) -> Option<Exit> {
+        let probe = self.probe.take()?;
+        let elapsed = now.duration_since(probe.started).as_secs_f64();
+        let bytes = self.stats.output_bytes() - probe.start_bytes;
+        let natural_rate = bytes as f64 / elapsed;
+        debug!("probe: {} bytes in {:.3}s", bytes, elapsed);
+
+        let Some(fraction) = probe.then else {
+            return Some(Exit::ProbeFinished(natural_rate));
+        };
+
+        if let Some(ref mut adaptive) = self.adaptive {
+            adaptive.next = Some(now + adaptive.duration * ADAPTIVE_PERIOD);
+        }
+
+        let result = if natural_rate > 0. {
+            let rate = natural_rate * fraction;
+            self.set_rate(rate, &format!(
+                "natural output rate {natural_rate:.1} bytes/sec; now {rate:.1} bytes/sec"))
+        } else if let Some(rate) = probe.fallback {
+            self.set_rate(rate, &format!(
+                "no output during probe; now {rate} bytes/sec"))
+        } else {
+            warn!("no output during probe; continuing unthrottled");
+            Ok(())
+        };
+        if let Err(e) = result {
+            warn!("{:#}", e);
+        }
+        None
+    }
+
+    fn run(&mut self) -> Result<Exit> {
+        loop {
+            if let Some(exit) = self.run_timers()? {
+                return Ok(exit);
+            }
+
+            if self.triggered_exit {
+                debug!("ending the session on a match in the output");
+                return Ok(Exit::Closed);
+            }
+
+            if self.readable_set.is_set(SIGNALS) {
+                self.readable_set.unset(SIGNALS);
+                if let Some(exit) = self.handle_signals()? {
+                    return Ok(exit);
+                }
+            }
+
+            if self.readable_set.is_set(CONTROL) {
+                self.readable_set.unset(CONTROL);
+                self.handle_control();
+            }
+
+            let now = self.clock.now();
+
+            if !self.readable_set.is_empty() {
+                if let Some((wait, starting)) = self.wakeup.as_mut().and_then(|w| w.wait(now)) {
+                    if starting {
+                        debug!("link waking up");
+                        self.stats.wakeups += 1;
+                    }
+                    let wait = match self.next_timer() {
+                        Some(t) => wait.min(t.saturating_duration_since(now)),
+                        None => wait,
+                    };
+                    if self.wait_until(now + wait)? {
+                        return Ok(Exit::Closed);
+                    }
+                    continue;
+                }
+            }
+
+            // For fairness, always try both directions on each iteration, so that an
+            // intermittently-readable endpoint doesn't get blocked by an always-readable one.
+            // When they share a limiter, also alternate which one goes first, so that neither can
+            // take all the tokens. In each direction, first write out what's due, which makes
+            // room to read more.
+
+            let mut progress = false;
+            let mut wait: Option<Duration> = None;
+            let first = self.next_first;
+            let directions = if self.paused { &[][..] } else { &[first, 1 - first][..] };
+            for &idx in directions {
+                let queued = self.queues[idx].bytes();
+                // Output held by XOFF is still read, as long as there's room for it.
+                let due = if idx == 1 && self.output_stopped {
+                    None
+                } else {
+                    self.write_due(idx, now)?
+                };
+                if let Some(t) = due {
+                    wait = Some(wait.map_or(t, |w| w.min(t)));
+                }
+                if self.queues[idx].bytes() < queued {
+                    progress = true;
+                    self.next_first = 1 - idx;
+                }
+
+                let read_size = self.read_room(idx);
+                if !self.readable_set.is_set(idx) || read_size == 0 {
+                    continue;
+                }
+
+                let mut buf = [0u8; READ_SIZE];
+                let PollEndpoint { name, ref mut src, .. } = self.readable_set.endpoint(idx)
+                    .unwrap();
+                let n = match src.read(&mut buf[.. read_size]) {
+                    Ok(0) if idx == 0 && self.filter => {
+                        debug!("{}: end of input", name);
+                        if let Some(piece) = self.escapes.expire(now, true) {
+                            self.queue_input(now, piece);
+                        }
+                        self.readable_set.close_console()?;
+                        self.eof_pending = true;
+                        continue;
+                    }
+                    Ok(0) => {
+                        debug!("{}: read zero bytes", name);
+                        self.log_event(&Event::Closed(name));
+                        return Ok(Exit::Closed);
+                    }
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if idx == 1 && self.draining {
+                            debug!("{}: drained", name);
+                            self.log_event(&Event::Closed(name));
+                            return Ok(Exit::Closed);
+                        }
+                        // Done reading from this source.
+                        debug!("{}: would block", name);
+                        self.log_event(&Event::WouldBlock { idx, write: false });
+                        if idx == 1 {
+                            self.caught_up(now);
+                        }
+                        self.readable_set.unset(idx);
+                        continue;
+                    }
+                    Err(ref e) if e.raw_os_error() == Some(libc::EIO) => {
+                        // Reading the pty master fails this way when nothing has the slave side
+                        // open anymore, which is how its end of file looks. There's nothing
+                        // left to read by then, but the child may not quite have finished
+                        // exiting, and its SIGCHLD may not have been handled yet.
+                        let status = match self.child {
+                            Some(ref mut child) if idx == 1 => child.wait_timeout(EIO_GRACE)?,
+                            _ => None,
+                        };
+                        match status {
+                            Some(status) => {
+                                debug!("{}: EIO after child exited with status {:#x}", name,
+                                    status);
+                                self.child_exited(status)?;
+                            }
+                            None if idx == 1 && self.child.is_some() => {
+                                warn!("{}: EIO, but the child is still running", name);
+                            }
+                            None => warn!("{}: EIO", name),
+                        }
+                        self.log_event(&Event::Closed(name));
+                        return Ok(Exit::Closed);
+                    }
+                    Err(ref e) => {
+                        panic!("{name}: read error: {e}");
+                    }
+                };
+                debug!("{}: got {:?}", name, String::from_utf8_lossy(&buf[.. n]));
+                self.last_traffic = now;
+                self.log_event(&Event::Read { idx, bytes: n });
+
+                let mut data = &buf[.. n];
+                let from_client;
+                let from_modem;
+                if idx == 0 {
+                    if let Some(ref mut telnet) = self.telnet {
+                        from_client = telnet.input(data);
+                        data = &from_client;
+                    }
+                    self.handle_telnet(now);
+                    if let Some(ref mut modem) = self.modem {
+                        let (send, reply, hang_up) = modem.input(now, data);
+                        // The modem's own replies aren't sent over the line, so aren't limited.
+                        write_fully(self.readable_set.console(), &reply).context("write error")?;
+                        if hang_up {
+                            debug!("hanging up");
+                            self.hung_up = true;
+                            return Ok(Exit::Closed);
+                        }
+                        from_modem = send;
+                        data = &from_modem;
+                    }
+                }
+                let change = self.transfers.as_mut()
+                    .and_then(|(transfers, _)| transfers.data(idx, now, data));
+                if let Some(change) = change {
+                    self.transfer_changed(change)?;
+                }
+                let filtered;
+                if !self.filters[idx].is_empty() {
+                    filtered = self.filters[idx].run(data);
+                    data = &filtered;
+                }
+                let data = if idx == 0 {
+                    self.intercept_input(data)?
+                } else {
+                    Cow::Borrowed(data)
+                };
+
+                if self.hooks.is_some() {
+                    self.run_hook(|hooks| if idx == 0 { hooks.input(&data) }
+                        else { hooks.output(&data) })?;
+                }
+                if let Some(ref mut wakeup) = self.wakeup {
+                    wakeup.activity(now);
+                }
+                if idx == 0 {
+                    for piece in self.escapes.feed(now, &data) {
+                        self.queue_input(now, piece);
+                    }
+                } else {
+                    let data = match self.utf8 {
+                        Some(ref mut utf8) => Cow::Owned(utf8.join(&data)),
+                        None => data,
+                    };
+                    let pieces = match self.teletype {
+                        Some(ref mut teletype) => teletype.split(&data),
+                        None => vec![data.into_owned()],
+                    };
+                    for data in pieces {
+                        self.queues[idx].push(now, data);
+                    }
+                }
+                self.handle_overflow(idx);
+                progress = true;
+            }
+
+            // The end of the console's input waits for the script too.
+            if self.eof_pending && self.queues[0].bytes() == 0 && self.script.is_empty() {
+                self.send_eof()?;
+                progress = true;
+            }
+
+            if progress {
+                continue;
+            }
+
+            if let Some(wait) = wait {
+                // Something is ready to be written, but we're not allowed to yet. Pick up any
+                // events while waiting, so that a constant stream in one direction doesn't shut
+                // out the other one (or signals) for as long as it lasts.
+                let wait = match self.next_timer() {
+                    Some(t) => wait.min(t.saturating_duration_since(now)),
+                    None => wait,
+                };
+                if self.wait_until(now + wait)? {
+                    return Ok(Exit::Closed);
+                }
+                continue;
+            }
+
+            // Nothing can be done right now. Stop the busy-polling and block until an endpoint
+            // becomes readable or writable, or until the next timer is due (which includes data
+            // in the latency queues becoming due). The timer is waited for on the clock, so a
+            // fake one can skip ahead to it.
+            let closed = match self.next_timer() {
+                Some(deadline) => self.wait_until(deadline)?,
+                None => self.poll_events()?,
+            };
+            if closed {
+                return Ok(Exit::Closed);
+            }
+        }
+    }
+
+    /// Wait for events, for as long as it takes. Returns whether one of the endpoints closed, in
+    /// which case there's no point in continuing.
+    fn poll_events(&mut self) -> Result<bool> {
+        let result = self.readable_set.block(None).context("blocking for events")?;
+        Ok(self.closed(result))
+    }
+
+    /// Wait until the deadline, picking up events as well as the clock allows. Returns whether
+    /// one of the endpoints closed, as `poll_events` does.
+    fn wait_until(&mut self, deadline: Instant) -> Result<bool> {
+        let result = self.clock.wait_until(deadline, &mut self.readable_set)
+            .context("waiting")?;
+        Ok(self.closed(result))
+    }
+
+    fn closed(&mut self, result: PollResult) -> bool {
+        match result {
+            PollResult::Ok | PollResult::Nothing => false,
+            PollResult::Closed(endpoint) => {
+                debug!("bailing out");
+                self.log_event(&Event::Closed(endpoint));
+                true
+            }
+        }
+    }
+}
+
+/// What `write_paced` did.
+enum Written {
+    /// Wrote these bytes, which were sent at the given time.
+    Bytes(Instant, Vec<u8>),
+
+    /// Nothing in the queue is due yet.
+    NothingDue,
+
+    /// Something is due, but the limiter won't allow any of it for this long.
+    NoTokens(Duration),
+}
+
+/// Make one attempt at writing what's due from the queue, as much as the limiter allows. The
+/// limiter is charged for exactly what was written, so if the write is partial (or would block),
+/// the rest stays at the front of the queue for next time, and isn't paid for until it goes out.
+fn write_paced(
+    dst: &mut impl Write,
+    queue: &mut LatencyQueue,
+    limiter: &mut TokenBucket,
+    utf8: Option<&Utf8>,
+    now: Instant,
+) -> io::Result<Written> {
+    let Some(data) = queue.due_front(now) else { return Ok(Written::NothingDue) };
+    let tokens = limiter.available(now);
+    if tokens == 0 {
+        return Ok(Written::NoTokens(limiter.wait_time(now)));
+    }
+    // An escape sequence goes all at once, on credit: what comes after it waits longer. So does
+    // a UTF-8 character.
+    let allowed = match utf8 {
+        _ if queue.front_is_whole() => data.len(),
+        Some(utf8) => utf8.fit(data, tokens),
+        None => tokens.min(data.len()),
+    };
+    let n = dst.write(&data[.. allowed])?;
+    limiter.consume(utf8.map_or(n, |utf8| utf8.cost(&data[.. n])));
+    let (sent, data) = queue.consume_front(n);
+    Ok(Written::Bytes(sent, data))
+}
+
+#[test]
+fn test_partial_writes_are_paced_by_what_was_written() {
+    /// Takes at most three bytes per write, and then refuses the next write.
+    struct Trickle {
+        written: Vec<u8>,
+        refuse: bool,
+    }
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.refuse = !self.refuse;
+            if !self.refuse {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(3);
+            self.written.extend_from_slice(&buf[.. n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let start = Instant::now();
+    let mut queue = LatencyQueue::new(Duration::ZERO);
+    queue.push(start, b"0123456789".to_vec());
+    let mut limiter = TokenBucket::new(10., 20., start);
+    let mut dst = Trickle { written: vec![], refuse: false };
+
+    let mut writes = vec![];
+    while queue.bytes() > 0 {
+        match write_paced(&mut dst, &mut queue, &mut limiter, None, start) {
+            Ok(Written::Bytes(_, data)) => writes.push(data.len()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            _ => panic!(),
+        }
+    }
+    assert_eq!(writes, [3, 3, 3, 1]);
+    assert_eq!(dst.written, b"0123456789");
+
+    // Exactly ten bytes were charged, with nothing for the writes that would have blocked.
+    assert_eq!(limiter.available(start), 10);
+    assert_eq!(limiter.available(start + Duration::from_millis(500)), 15);
+}
+
+#[test]
+fn test_escape_sequences_are_written_whole() {
+    let start = Instant::now();
+    let mut queue = LatencyQueue::new(Duration::ZERO);
+    queue.push_whole(start, b"\x1b[A".to_vec());
+    queue.push(start, b"xy".to_vec());
+    let mut limiter = TokenBucket::new(10., 1., start);
+    let mut dst = vec![];
+
+    assert!(matches!(write_paced(&mut dst, &mut queue, &mut limiter, None, start),
+        Ok(Written::Bytes(_, ref data)) if data == b"\x1b[A"));
+
+    // The sequence was sent on credit, so the next byte waits for all three to be paid off.
+    match write_paced(&mut dst, &mut queue, &mut limiter, None, start) {
+        Ok(Written::NoTokens(wait)) => assert_eq!(wait, Duration::from_millis(300)),
+        _ => panic!(),
+    }
+}
+
+/// Like `write_all`, but if the (non-blocking) destination is full, wait for it to drain instead
+/// of failing.
+fn write_fully(mut dst: &File, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match dst.write(data) {
+            Ok(n) => data = &data[n ..],
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let mut pollfd = libc::pollfd {
+                    fd: dst.as_raw_fd(),
+                    events: libc::POLLOUT,
+                    revents: 0,
+                };
+                if unsafe { libc::poll(&mut pollfd, 1, -1) } == -1 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Writes with `write_fully`, for what takes a `Write`, like the status.
+struct Blocking<'a>(&'a File);
+
+impl Write for Blocking<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_fully(self.0, buf).map(|()| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_status_waits_for_a_full_console() {
+    let (console, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+    console.set_nonblocking(true).unwrap();
+    let console = File::from(std::os::fd::OwnedFd::from(console));
+    let mut filled = 0;
+    loop {
+        match (&console).write(&[b'x'; 4096]) {
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => panic!("{e}"),
+        }
+    }
+    let reader = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        let mut buf = vec![0u8; filled];
+        peer.read_exact(&mut buf).unwrap();
+        let mut title = [0u8; 9];
+        peer.read_exact(&mut title).unwrap();
+        title
+    });
+    let mut status = Status::new(true);
+    status.show(&mut Blocking(&console), "rate 30 bytes/sec").unwrap();
+    assert_eq!(&reader.join().unwrap(), b"\x1b[22;0t\x1b]");
+}
+
+#[cfg(test)]
+fn socket_pair() -> (File, File) {
+    let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+    (File::from(std::os::fd::OwnedFd::from(a)), File::from(std::os::fd::OwnedFd::from(b)))
+}
+
+/// Run a session on a fake clock, over sockets standing in for the console and the pty. What's
+/// typed at the console and what the program shows are there from the start, followed by the
+/// end of them if `end` is set; without one, that side stays open and quiet. Returns how the
+/// session ended, what reached the program and the console, and the stats.
+#[cfg(test)]
+fn run_session(options: &Options, typed: Option<&[u8]>, shown: Option<&[u8]>, end: bool)
+    -> (Exit, [Vec<u8>; 2], Stats)
+{
+    use crate::clock::FakeClock;
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let (console, mut console_peer) = UnixStream::pair().unwrap();
+    let (pty, mut pty_peer) = UnixStream::pair().unwrap();
+    for (peer, data) in [(&mut console_peer, typed), (&mut pty_peer, shown)] {
+        if let Some(data) = data {
+            peer.write_all(data).unwrap();
+            if end {
+                peer.shutdown(Shutdown::Write).unwrap();
+            }
+        }
+    }
+
+    let [mut console, mut pty] = [console, pty].map(|s| File::from(std::os::fd::OwnedFd::from(s)));
+    let mut stats = Stats::default();
+    let session = Session { console: &mut console, console_out: None, pty_master: &mut pty,
+        child: None, signals: None, term: None, events: None };
+    let exit = event_loop_with_clock(options, session, &mut stats, Box::new(FakeClock::new()))
+        .unwrap();
+
+    // Everything the loop wrote is already there. Read it while this end is still open: closing
+    // it with something left unread would reset the connection.
+    let mut received = [vec![], vec![]];
+    for (peer, buf) in [&mut pty_peer, &mut console_peer].into_iter().zip(&mut received) {
+        peer.set_nonblocking(true).unwrap();
+        match peer.read_to_end(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            result => {
+                result.unwrap();
+            }
+        }
+    }
+    (exit, received, stats)
+}
+
+#[test]
+fn test_output_is_forwarded_and_paced() {
+    let options = Options { rate: Some(10.), ..Options::default() };
+    let (exit, [_, out], stats) = run_session(&options, None, Some(b"hello world"), true);
+    assert!(matches!(exit, Exit::Closed));
+    assert_eq!(out, b"hello world");
+    assert_eq!(stats.bytes, [0, 11]);
+
+    // The first byte goes immediately, and the other ten take a tenth of a second each. The end of
+    // the stream is noticed as soon as the last byte is written.
+    let elapsed = stats.elapsed;
+    assert!(elapsed > Duration::from_millis(999) && elapsed < Duration::from_millis(1001),
+        "{elapsed:?}");
+}
+
+#[test]
+fn test_overflow_is_dropped() {
+    for (overflow, expected) in [(Overflow::DropNewest, b"hell"), (Overflow::DropOldest, b"orld")] {
+        let options = Options {
+            rate: Some(10.),
+            buffer_limit: Some(4),
+            overflow,
+            ..Options::default()
+        };
+        let (_, [_, out], stats) = run_session(&options, None, Some(b"hello world"), true);
+        assert_eq!(out, expected);
+        assert_eq!(stats.dropped, [0, 7]);
+    }
+}
+
+#[test]
+fn test_on_output() {
+    use regex::bytes::Regex;
+
+    let options = Options {
+        rate: Some(10.),
+        on_output: vec![
+            (Regex::new("Password: ").unwrap(), OutputAction::Send(b"secret\r".to_vec())),
+            (Regex::new("bye").unwrap(), OutputAction::Exit),
+        ],
+        ..Options::default()
+    };
+    let (exit, [typed, out], _) =
+        run_session(&options, None, Some(b"Password: ok\r\nbye\r\nnot shown"), true);
+    assert!(matches!(exit, Exit::Closed));
+    // The rest of the write with the match in it goes out, but no more.
+    assert!(out.starts_with(b"Password: ok\r\nbye"));
+    assert!(out.len() < b"Password: ok\r\nbye\r\nnot".len());
+    assert_eq!(typed, b"secret\r");
+}
+
+#[test]
+fn test_input_hotkey_is_intercepted() {
+    let options = Options {
+        rate: Some(1.),
+        rate_presets: vec![1., 1000.],
+        ..Options::default()
+    };
+    let (_, [typed, _], stats) = run_session(&options, Some(b"ab\x1dcd"), None, true);
+    assert_eq!(typed, b"abcd");
+
+    // Two bytes at 1/sec, then the rest at 1000/sec.
+    assert!(stats.elapsed < Duration::from_millis(2100), "{:?}", stats.elapsed);
+}
+
+#[test]
+fn test_connect_banner() {
+    let options = Options {
+        rate: Some(240.),
+        connect_banner: Some(Duration::from_secs(2)),
+        ..Options::default()
+    };
+    let (_, [_, out], stats) = run_session(&options, None, Some(b"login: "), true);
+    assert_eq!(out, b"\r\nCONNECT 2400/ARQ\r\nlogin: ");
+
+    // The handshake, then 27 bytes at 240/sec.
+    let elapsed = stats.elapsed;
+    assert!(elapsed > Duration::from_millis(2100) && elapsed < Duration::from_millis(2120),
+        "{elapsed:?}");
+}
+
+#[test]
+fn test_prefix_key_commands() {
+    // Ctrl-A twice is a Ctrl-A for the program, z isn't a command, and q stops throttling.
+    let options = Options { rate: Some(1.), prefix_key: Some(0x01), ..Options::default() };
+    let (_, [typed, _], stats) =
+        run_session(&options, Some(b"a\x01\x01b\x01zc\x01qdefgh"), None, true);
+    assert_eq!(typed, b"a\x01bcdefgh");
+
+    // Only the first few bytes are throttled to 1/sec.
+    assert!(stats.elapsed < Duration::from_secs(5), "{:?}", stats.elapsed);
+}
+
+#[test]
+fn test_xon_xoff_is_intercepted() {
+    let options = Options { xon_xoff: true, ..Options::default() };
+    let (_, [typed, _], _) = run_session(&options, Some(b"a\x13b\x11c"), None, true);
+    assert_eq!(typed, b"abc");
+}
+
+#[test]
+fn test_output_latency() {
+    let options = Options {
+        in_rate: Some(1.),
+        out_rate: Some(10.),
+        out_latency: Duration::from_millis(500),
+        ..Options::default()
+    };
+    let (_, [_, out], stats) = run_session(&options, None, Some(b"ab"), true);
+
+    // Everything still in transit when the pty closed is delivered before the loop returns.
+    assert_eq!(out, b"ab");
+    assert_eq!(stats.mean_latency(1), Duration::from_millis(550));
+
+    // Both bytes arrive at the other end of the link after half a second, and then the second one
+    // has to wait a tenth of a second for its turn.
+    let elapsed = stats.elapsed;
+    assert!(elapsed > Duration::from_millis(599) && elapsed < Duration::from_millis(601),
+        "{elapsed:?}");
+}
+
+#[test]
+fn test_schedule() {
+    let options = Options {
+        schedule: vec![(Duration::ZERO, 10.), (Duration::from_millis(500), 100.)],
+        ..Options::default()
+    };
+    let (_, [_, out], stats) = run_session(&options, None, Some(b"hello world"), true);
+    assert_eq!(out, b"hello world");
+
+    // Six bytes in the first half second, then the other five at a hundredth of a second each.
+    let elapsed = stats.elapsed;
+    assert!(elapsed > Duration::from_millis(545) && elapsed < Duration::from_millis(555),
+        "{elapsed:?}");
+}
+
+#[test]
+fn test_filter_passes_on_end_of_input() {
+    let (mut console, console_peer) = socket_pair();
+    let (mut console_out, mut console_out_peer) = socket_pair();
+    let (mut pty, mut pty_peer) = socket_pair();
+
+    let program = std::thread::spawn(move || {
+        // Stands in for the program: it answers once its input has ended.
+        let mut input = [0u8; 4];
+        pty_peer.read_exact(&mut input).unwrap();
+        pty_peer.write_all(b"done").unwrap();
+        input
+    });
+
+    (&console_peer).write_all(b"ab").unwrap();
+    drop(console_peer);
+
+    let options = Options { no_raw: true, ..Options::default() };
+    let mut stats = Stats::default();
+    let session = Session { console: &mut console, console_out: Some(&mut console_out),
+        pty_master: &mut pty, child: None, signals: None, term: None, events: None };
+    let exit = event_loop(&options, session, &mut stats).unwrap();
+    assert!(matches!(exit, Exit::Closed));
+    drop(console_out);
+
+    // Not at the start of a line, so it takes two EOF characters (without a pty to ask, ^D).
+    assert_eq!(&program.join().unwrap(), b"ab\x04\x04");
+    let mut out = vec![];
+    console_out_peer.read_to_end(&mut out).unwrap();
+    assert_eq!(out, b"done");
+}
+
+#[test]
+fn test_script_is_typed_before_end_of_input() {
+    let (mut console, console_peer) = socket_pair();
+    let (mut console_out, _console_out_peer) = socket_pair();
+    let (mut pty, mut pty_peer) = socket_pair();
+
+    let program = std::thread::spawn(move || {
+        let mut input = [0u8; 9];
+        pty_peer.read_exact(&mut input).unwrap();
+        input
+    });
+    drop(console_peer);
+
+    let ms = Duration::from_millis;
+    let options = Options {
+        no_raw: true,
+        script: vec![(ms(20), b"ls\r".to_vec()), (ms(20), b"\x1b[Ax".to_vec())],
+        ..Options::default()
+    };
+    let mut stats = Stats::default();
+    let session = Session { console: &mut console, console_out: Some(&mut console_out),
+        pty_master: &mut pty, child: None, signals: None, term: None, events: None };
+    let started = Instant::now();
+    event_loop(&options, session, &mut stats).unwrap();
+
+    assert_eq!(&program.join().unwrap(), b"ls\r\x1b[Ax\x04\x04");
+    assert!(started.elapsed() >= ms(40));
+}
+
+#[test]
+fn test_idle_timeout() {
+    let options = Options {
+        rate: Some(100.),
+        idle_timeout: Some(Duration::from_millis(50)),
+        ..Options::default()
+    };
+    let (exit, [_, out], stats) = run_session(&options, None, Some(b"hello"), false);
+    assert!(matches!(exit, Exit::TimedOut));
+    assert_eq!(out, b"hello");
+    // It's idle once the output has all been shown (the last byte at 40ms), not once it's been
+    // read.
+    assert_eq!(stats.elapsed, Duration::from_millis(90));
+}
+
+#[test]
+fn test_timeout() {
+    let options = Options { timeout: Some(Duration::from_millis(50)), ..Options::default() };
+    let (exit, _, stats) = run_session(&options, None, None, false);
+    assert!(matches!(exit, Exit::TimedOut));
+    assert_eq!(stats.elapsed, Duration::from_millis(50));
+}
+
+#[test]
+fn test_child_exit() {
+    let data = b"goodbye ".repeat(25);
+    // Each mode in turn, not in tests of their own, as the signal pipe is process-wide.
+    for drain in [DrainMode::Rate, DrainMode::Fast, DrainMode::Discard] {
+        let (mut console, mut console_peer) = socket_pair();
+        let (mut pty, pty_peer) = socket_pair();
+        (&pty_peer).write_all(&data).unwrap();
+
+        // Before forking, so the exit can't be missed.
+        let mut signals = SignalPipe::install(&[libc::SIGCHLD]).unwrap();
+        let pid = checkerr(unsafe { libc::fork() }, "fork").unwrap();
+        if pid == 0 {
+            unsafe { libc::_exit(3) };
+        }
+        let mut child = Child::new(pid);
+        // The child has exited by the time the session starts, so on the fake clock, it's as if
+        // it exited at once.
+        // (The signal itself can interrupt the poll.)
+        let mut pollfd = libc::pollfd { fd: signals.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        while pollfd.revents & libc::POLLIN == 0 {
+            unsafe { libc::poll(&mut pollfd, 1, -1) };
+        }
+
+        // The pty stays open, like it does when the program leaves something running in the
+        // background, so the session ends because the child exited, not because the pty closed.
+        let options = Options { rate: Some(1000.), drain, ..Options::default() };
+        let mut stats = Stats::default();
+        let session = Session { console: &mut console, console_out: None, pty_master: &mut pty,
+            child: Some(&mut child), signals: Some(&mut signals), term: None, events: None };
+        let exit = event_loop_with_clock(&options, session, &mut stats,
+            Box::new(crate::clock::FakeClock::new())).unwrap();
+        assert!(matches!(exit, Exit::Closed));
+        assert_eq!(child.try_wait().unwrap(), Some(3 << 8));
+        drop(console);
+
+        let mut out = vec![];
+        console_peer.read_to_end(&mut out).unwrap();
+        match drain {
+            DrainMode::Rate => {
+                assert_eq!(out, data);
+                // The first byte goes at once, and the other 199 take a millisecond each.
+                assert_eq!(stats.elapsed, Duration::from_millis(199));
+            }
+            DrainMode::Fast => {
+                assert_eq!(out, data);
+                assert_eq!(stats.elapsed, Duration::ZERO);
+            }
+            DrainMode::Discard => assert_eq!(out, b""),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use crate::cast::json_string;
+use crate::checkerr;
+use crate::signal_name;
+use crate::stats::Stats;
+
+/// With `--events-fd`, tells whatever's running slowpty what happens to the session, as it
+/// happens, in newline-delimited JSON.
+pub struct EventStream {
+    /// None once writing to it has failed.
+    file: Option<File>,
+}
+
+impl EventStream {
+    /// Take over the fd, which the program mustn't get a copy of.
+    pub fn from_fd(fd: RawFd) -> Result<Self> {
+        checkerr(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) }, "fcntl")
+            .with_context(|| format!("can't use fd {fd} for events"))?;
+        Ok(EventStream { file: Some(unsafe { File::from_raw_fd(fd) }) })
+    }
+
+    /// The program was started on a pty (or the connection, recording or device was opened,
+    /// without either), with a window of the given size.
+    pub fn session_start(&mut self, pid: Option<libc::pid_t>, pty: Option<&str>,
+        size: Option<(u16, u16)>)
+    {
+        let pid = pid.map_or("null".to_owned(), |pid| pid.to_string());
+        let pty = pty.map_or("null".to_owned(), json_string);
+        let (cols, rows) = match size {
+            Some((cols, rows)) => (cols.to_string(), rows.to_string()),
+            None => ("null".to_owned(), "null".to_owned()),
+        };
+        self.send("session-start",
+            &format!("\"pid\": {pid}, \"pty\": {pty}, \"cols\": {cols}, \"rows\": {rows}"));
+    }
+
+    /// The rate changed, to this many bytes per second.
+    pub fn rate_change(&mut self, rate: f64) {
+        // JSON has no infinity.
+        let rate = if rate.is_finite() { rate.to_string() } else { "null".to_owned() };
+        self.send("rate-change", &format!("\"rate\": {rate}"));
+    }
+
+    /// The program exited, with the given wait status.
+    pub fn child_exit(&mut self, status: libc::c_int) {
+        let fields = if libc::WIFSIGNALED(status) {
+            let sig = libc::WTERMSIG(status);
+            format!("\"signal\": {sig}, \"name\": {}", json_string(&signal_name(sig)))
+        } else {
+            format!("\"code\": {}", libc::WEXITSTATUS(status))
+        };
+        self.send("child-exit", &fields);
+    }
+
+    /// The session is over, having delivered this much each way.
+    pub fn bytes_transferred(&mut self, stats: &Stats) {
+        self.send("bytes-transferred",
+            &format!("\"input\": {}, \"output\": {}", stats.bytes[0], stats.bytes[1]));
+    }
+
+    fn send(&mut self, event: &str, fields: &str) {
+        let Some(ref mut file) = self.file else { return };
+        // One write per event, so a reader never sees half of one.
+        let line = format!("{{\"event\": \"{event}\", {fields}}}\n");
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("failed to write event, giving up on them: {}", e);
+            self.file = None;
+        }
+    }
+}
+
+#[test]
+fn test_event_stream() {
+    use std::io::Read;
+    use std::os::unix::io::IntoRawFd;
+
+    let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+    let mut events = EventStream::from_fd(theirs.into_raw_fd()).unwrap();
+    events.session_start(Some(1234), Some("/dev/pts/7"), Some((80, 24)));
+    events.session_start(None, None, None);
+    events.rate_change(9600.);
+    events.rate_change(f64::INFINITY);
+    events.child_exit(3 << 8);
+    events.child_exit(libc::SIGKILL);
+    let stats = Stats { bytes: [5, 1200], ..Stats::default() };
+    events.bytes_transferred(&stats);
+    drop(events);
+
+    let mut text = String::new();
+    (&ours).read_to_string(&mut text).unwrap();
+    let kill = json_string(&signal_name(libc::SIGKILL));
+    assert_eq!(text, format!("\
+        {{\"event\": \"session-start\", \"pid\": 1234, \"pty\": \"/dev/pts/7\", \"cols\": 80, \
+            \"rows\": 24}}\n\
+        {{\"event\": \"session-start\", \"pid\": null, \"pty\": null, \"cols\": null, \
+            \"rows\": null}}\n\
+        {{\"event\": \"rate-change\", \"rate\": 9600}}\n\
+        {{\"event\": \"rate-change\", \"rate\": null}}\n\
+        {{\"event\": \"child-exit\", \"code\": 3}}\n\
+        {{\"event\": \"child-exit\", \"signal\": 9, \"name\": {kill}}}\n\
+        {{\"event\": \"bytes-transferred\", \"input\": 5, \"output\": 1200}}\n"));
+
+    assert!(EventStream::from_fd(9999).is_err());
+}
@@ -0,0 +1,81 @@
+use crate::options::Direction;
+
+/// A stage that what's read goes through on its way to being queued, like the ones for
+/// `--databits 7`, `--colors` and `--noise`.
+pub trait Filter {
+    /// Take some data going the given way, and add what it becomes to `out`. Something cut off
+    /// at the end may be held back, to go with the next.
+    fn transform(&mut self, dir: Direction, input: &[u8], out: &mut Vec<u8>);
+}
+
+/// The filters for one direction, each taking what the one before it made.
+pub struct Pipeline {
+    dir: Direction,
+    stages: Vec<Box<dyn Filter>>,
+}
+
+impl Pipeline {
+    pub fn new(dir: Direction) -> Self {
+        Pipeline { dir, stages: vec![] }
+    }
+
+    /// Add a stage, if there is one, to the end.
+    pub fn with(mut self, stage: Option<impl Filter + 'static>) -> Self {
+        if let Some(stage) = stage {
+            self.stages.push(Box::new(stage));
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn run(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        let mut out = Vec::with_capacity(data.len());
+        for stage in &mut self.stages {
+            out.clear();
+            stage.transform(self.dir, &data, &mut out);
+            std::mem::swap(&mut data, &mut out);
+        }
+        data
+    }
+}
+
+#[test]
+fn test_pipeline() {
+    struct Upper;
+    impl Filter for Upper {
+        fn transform(&mut self, _dir: Direction, input: &[u8], out: &mut Vec<u8>) {
+            out.extend(input.to_ascii_uppercase());
+        }
+    }
+    /// Holds back a trailing backslash, for the next read to finish.
+    struct Unescape(bool);
+    impl Filter for Unescape {
+        fn transform(&mut self, _dir: Direction, input: &[u8], out: &mut Vec<u8>) {
+            for &b in input {
+                match (self.0, b) {
+                    (false, b'\\') => self.0 = true,
+                    (true, b'n') => {
+                        self.0 = false;
+                        out.push(b'\n');
+                    }
+                    _ => {
+                        self.0 = false;
+                        out.push(b);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut pipeline = Pipeline::new(Direction::Out);
+    assert!(pipeline.is_empty());
+    assert_eq!(pipeline.run(b"as is"), b"as is");
+    let mut pipeline = pipeline.with(Some(Unescape(false))).with(None::<Upper>)
+        .with(Some(Upper));
+    assert_eq!(pipeline.run(b"one\\"), b"ONE");
+    assert_eq!(pipeline.run(b"ntwo"), b"\nTWO");
+}
@@ -0,0 +1,182 @@
+/// What a hook asked for.
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    SetRate(f64),
+    Inject(Vec<u8>),
+    KillChild,
+}
+
+#[cfg(feature = "scripting")]
+pub use engine::Hooks;
+
+#[cfg(feature = "scripting")]
+mod engine {
+    use super::Action;
+    use anyhow::Result;
+    use rhai::{CallFnOptions, Dynamic, Engine, FuncArgs, Map, Scope, AST};
+    use std::cell::RefCell;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    /// The most a hook can do in one call, so a runaway one can't hang the session.
+    const MAX_OPERATIONS: u64 = 1_000_000;
+
+    /// With `--hooks`, a Rhai script that's told what goes through, and can act on it: it
+    /// defines whichever of `on_input(text)`, `on_output(text)`, `on_rate_change(rate)` and
+    /// `on_child_exit(status)` it wants called, and they can call `set_rate(rate)`,
+    /// `inject(text)` and `kill_child()`. Text is the bytes read, with anything that isn't UTF-8
+    /// replaced, and `this` is a map that's kept from one call to the next.
+    pub struct Hooks {
+        engine: Engine,
+        ast: AST,
+        scope: Scope<'static>,
+        /// `this` in the hooks.
+        state: Dynamic,
+        /// What the hook being called has asked for so far.
+        actions: Rc<RefCell<Vec<Action>>>,
+    }
+
+    impl Hooks {
+        pub fn load(path: &Path) -> Result<Self> {
+            let mut engine = Engine::new();
+            engine.set_max_operations(MAX_OPERATIONS);
+            // Anything the script prints would get in the way of the session on the console.
+            engine.on_print(|s| info!("hooks: {}", s));
+            engine.on_debug(|s, _, pos| debug!("hooks: {}: {}", pos, s));
+
+            let actions = Rc::new(RefCell::new(vec![]));
+            let queue = Rc::clone(&actions);
+            engine.register_fn("set_rate", move |rate: f64| {
+                queue.borrow_mut().push(Action::SetRate(rate));
+            });
+            let queue = Rc::clone(&actions);
+            engine.register_fn("set_rate", move |rate: i64| {
+                queue.borrow_mut().push(Action::SetRate(rate as f64));
+            });
+            let queue = Rc::clone(&actions);
+            engine.register_fn("inject", move |text: &str| {
+                queue.borrow_mut().push(Action::Inject(text.as_bytes().to_vec()));
+            });
+            let queue = Rc::clone(&actions);
+            engine.register_fn("kill_child", move || {
+                queue.borrow_mut().push(Action::KillChild);
+            });
+
+            let ast = engine.compile_file(path.to_owned())
+                .map_err(|e| anyhow!("failed to load hooks from {path:?}: {e}"))?;
+            let mut hooks = Hooks {
+                engine,
+                ast,
+                scope: Scope::new(),
+                state: Dynamic::from_map(Map::new()),
+                actions,
+            };
+            // The top level runs once, to start with.
+            hooks.engine.run_ast_with_scope(&mut hooks.scope, &hooks.ast)
+                .map_err(|e| anyhow!("hooks: {e}"))?;
+            Ok(hooks)
+        }
+
+        pub fn input(&mut self, data: &[u8]) -> Vec<Action> {
+            self.call("on_input", (String::from_utf8_lossy(data).into_owned(),))
+        }
+
+        pub fn output(&mut self, data: &[u8]) -> Vec<Action> {
+            self.call("on_output", (String::from_utf8_lossy(data).into_owned(),))
+        }
+
+        pub fn rate_changed(&mut self, rate: f64) -> Vec<Action> {
+            self.call("on_rate_change", (rate,))
+        }
+
+        pub fn child_exited(&mut self, status: i32) -> Vec<Action> {
+            self.call("on_child_exit", (i64::from(status),))
+        }
+
+        /// Call a hook, if the script has it, and return what it asked for. A hook that fails
+        /// is logged, and what it asked for before then still happens.
+        fn call(&mut self, name: &str, args: impl FuncArgs) -> Vec<Action> {
+            if !self.ast.iter_functions().any(|f| f.name == name) {
+                return vec![];
+            }
+            let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut self.state);
+            let result = self.engine.call_fn_with_options::<Dynamic>(options, &mut self.scope,
+                &self.ast, name, args);
+            if let Err(e) = result {
+                warn!("hooks: {}: {}", name, e);
+            }
+            self.actions.take()
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+pub use stub::Hooks;
+
+#[cfg(not(feature = "scripting"))]
+mod stub {
+    use super::Action;
+    use anyhow::Result;
+    use std::path::Path;
+
+    /// Without the `scripting` feature there's nothing to run hooks with, so there can't be any.
+    pub struct Hooks(std::convert::Infallible);
+
+    impl Hooks {
+        pub fn load(_path: &Path) -> Result<Self> {
+            bail!("--hooks needs slowpty to be built with the `scripting` feature")
+        }
+
+        pub fn input(&mut self, _data: &[u8]) -> Vec<Action> {
+            match self.0 {}
+        }
+
+        pub fn output(&mut self, _data: &[u8]) -> Vec<Action> {
+            match self.0 {}
+        }
+
+        pub fn rate_changed(&mut self, _rate: f64) -> Vec<Action> {
+            match self.0 {}
+        }
+
+        pub fn child_exited(&mut self, _status: i32) -> Vec<Action> {
+            match self.0 {}
+        }
+    }
+}
+
+#[cfg(feature = "scripting")]
+#[test]
+fn test_hooks() {
+    let path = std::env::temp_dir().join(format!("slowpty-hooks-{}.rhai", std::process::id()));
+    std::fs::write(&path, r#"
+        fn on_output(text) {
+            if text.contains("login: ") {
+                inject("guest\r");
+            }
+            this.lines = (this.lines ?? 0) + text.split("\n").len() - 1;
+            if this.lines >= 3 {
+                set_rate(9600);
+                kill_child();
+            }
+        }
+
+        fn on_rate_change(rate) {
+            if rate > 1000.0 {
+                set_rate(rate / 2.0);
+            }
+        }
+    "#).unwrap();
+    let hooks = Hooks::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    let mut hooks = hooks.unwrap();
+
+    assert_eq!(hooks.output(b"welcome\nlogin: "), [Action::Inject(b"guest\r".to_vec())]);
+    assert_eq!(hooks.input(b"guest\r"), []);
+    assert_eq!(hooks.output(b"\n\n"), [Action::SetRate(9600.), Action::KillChild]);
+    assert_eq!(hooks.rate_changed(2400.), [Action::SetRate(1200.)]);
+    assert_eq!(hooks.child_exited(0), []);
+
+    assert!(Hooks::load(std::path::Path::new("/nonexistent.rhai")).is_err());
+}
@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Holds data for a fixed time before it can be delivered, like the propagation delay of a link.
+pub struct LatencyQueue {
+    latency: Duration,
+    /// When each chunk was sent, the chunk, and whether it has to be delivered whole.
+    chunks: VecDeque<(Instant, Vec<u8>, bool)>,
+    bytes: usize,
+}
+
+impl LatencyQueue {
+    pub fn new(latency: Duration) -> Self {
+        LatencyQueue {
+            latency,
+            chunks: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// How many bytes are waiting.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn push(&mut self, now: Instant, data: Vec<u8>) {
+        self.push_chunk(now, data, false);
+    }
+
+    /// Add a chunk that's to be delivered all at once, not paced a byte at a time.
+    pub fn push_whole(&mut self, now: Instant, data: Vec<u8>) {
+        self.push_chunk(now, data, true);
+    }
+
+    fn push_chunk(&mut self, now: Instant, data: Vec<u8>, whole: bool) {
+        if !data.is_empty() {
+            self.bytes += data.len();
+            self.chunks.push_back((now, data, whole));
+        }
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// When the next chunk can be delivered.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.chunks.front().map(|(sent, _, _)| *sent + self.latency)
+    }
+
+    /// The next chunk (or what's left of it), if it's due.
+    pub fn due_front(&self, now: Instant) -> Option<&[u8]> {
+        match self.chunks.front() {
+            Some((sent, data, _)) if *sent + self.latency <= now => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Whether the next chunk is to be delivered whole.
+    pub fn front_is_whole(&self) -> bool {
+        self.chunks.front().is_some_and(|&(_, _, whole)| whole)
+    }
+
+    /// Throw away up to `n` bytes, starting with the oldest, and return how many there were.
+    pub fn drop_front(&mut self, n: usize) -> usize {
+        let mut dropped = 0;
+        while dropped < n {
+            let Some((_, data, _)) = self.chunks.front_mut() else { break };
+            let take = data.len().min(n - dropped);
+            data.drain(.. take);
+            if data.is_empty() {
+                self.chunks.pop_front();
+            }
+            dropped += take;
+        }
+        self.bytes -= dropped;
+        dropped
+    }
+
+    /// Throw away up to `n` bytes, starting with the newest, and return how many there were.
+    pub fn drop_back(&mut self, n: usize) -> usize {
+        let mut dropped = 0;
+        while dropped < n {
+            let Some((_, data, _)) = self.chunks.back_mut() else { break };
+            let take = data.len().min(n - dropped);
+            data.truncate(data.len() - take);
+            if data.is_empty() {
+                self.chunks.pop_back();
+            }
+            dropped += take;
+        }
+        self.bytes -= dropped;
+        dropped
+    }
+
+    /// Remove bytes from the front of the next chunk, once they've been delivered, and return
+    /// them along with when they were sent.
+    pub fn consume_front(&mut self, n: usize) -> (Instant, Vec<u8>) {
+        let (sent, data, _) = self.chunks.front_mut().expect("nothing queued");
+        let sent = *sent;
+        let taken = data.drain(.. n).collect();
+        if data.is_empty() {
+            self.chunks.pop_front();
+        }
+        self.bytes -= n;
+        (sent, taken)
+    }
+}
+
+#[test]
+fn test_latency_queue() {
+    let start = Instant::now();
+    let ms = Duration::from_millis;
+    let mut queue = LatencyQueue::new(ms(40));
+    queue.push(start, b"abc".to_vec());
+    queue.push(start + ms(10), b"d".to_vec());
+    queue.push(start + ms(10), vec![]);
+    queue.push_whole(start + ms(20), b"\x1b[A".to_vec());
+    assert_eq!(queue.bytes(), 7);
+    assert_eq!(queue.next_due(), Some(start + ms(40)));
+
+    assert_eq!(queue.due_front(start + ms(39)), None);
+    assert_eq!(queue.due_front(start + ms(45)), Some(&b"abc"[..]));
+    assert_eq!(queue.consume_front(2), (start, b"ab".to_vec()));
+    assert_eq!(queue.due_front(start + ms(45)), Some(&b"c"[..]));
+    assert_eq!(queue.consume_front(1), (start, b"c".to_vec()));
+    assert_eq!(queue.due_front(start + ms(45)), None);
+    assert_eq!(queue.due_front(start + ms(50)), Some(&b"d"[..]));
+    assert!(!queue.front_is_whole());
+    queue.consume_front(1);
+    assert!(queue.front_is_whole());
+    assert_eq!(queue.consume_front(3), (start + ms(20), b"\x1b[A".to_vec()));
+    assert_eq!(queue.bytes(), 0);
+    assert_eq!(queue.next_due(), None);
+}
+
+#[test]
+fn test_latency_queue_drop() {
+    let now = Instant::now();
+    let mut queue = LatencyQueue::new(Duration::ZERO);
+    for chunk in [&b"abc"[..], b"de", b"fgh"] {
+        queue.push(now, chunk.to_vec());
+    }
+    assert_eq!(queue.drop_front(4), 4);
+    assert_eq!(queue.drop_back(2), 2);
+    assert_eq!(queue.bytes(), 2);
+    assert_eq!(queue.consume_front(1), (now, b"e".to_vec()));
+    assert_eq!(queue.consume_front(1), (now, b"f".to_vec()));
+    assert_eq!(queue.drop_front(5), 0);
+}
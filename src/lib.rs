@@ -0,0 +1,188 @@
+//! Runs a program on a pty, with the I/O between it and the terminal slowed down to a given rate.
+//!
+//! The `slowpty` binary is a command line front end to [`SlowPty`], which other programs can use
+//! to run rate-limited sessions of their own.
+
+#[macro_use] extern crate anyhow;
+#[macro_use] extern crate log;
+
+use anyhow::{Context, Result};
+use std::borrow::Cow;
+use std::io;
+
+mod cast;
+mod charset;
+mod clock;
+mod colors;
+mod compress;
+mod child;
+mod control;
+mod delay;
+mod detached;
+mod escape;
+mod event_log;
+mod event_loop;
+mod events;
+mod filter;
+mod hooks;
+mod latency;
+mod limiter;
+mod modem;
+mod newlines;
+mod noise;
+pub mod options;
+mod parity;
+mod pty;
+mod rate_log;
+mod readable;
+mod recording;
+mod replay;
+mod rng;
+mod server;
+mod session;
+mod signals;
+mod snapshot;
+mod stats;
+mod status;
+mod tee;
+mod telnet;
+mod teletype;
+mod term;
+mod transcript;
+mod transfer;
+mod triggers;
+mod ttyrec;
+mod typescript;
+mod typist;
+mod utf8;
+
+pub use detached::{attach, start_session};
+pub use event_loop::Exit;
+pub use options::Options;
+pub use session::{loopback, Outcome, Running, SlowPty};
+pub use stats::Stats;
+pub use server::serve;
+pub use term::TermGuard;
+
+pub fn checkerr(result: i32, msg: &'static str) -> Result<i32> {
+    if result == -1 {
+        let e = io::Error::last_os_error();
+        Err(e).context(msg)
+    } else {
+        Ok(result)
+    }
+}
+
+/// Signals by name, for `signal_name`.
+const SIGNALS: &[(libc::c_int, &str)] = &[
+    (libc::SIGHUP, "SIGHUP"),
+    (libc::SIGINT, "SIGINT"),
+    (libc::SIGQUIT, "SIGQUIT"),
+    (libc::SIGILL, "SIGILL"),
+    (libc::SIGTRAP, "SIGTRAP"),
+    (libc::SIGABRT, "SIGABRT"),
+    (libc::SIGBUS, "SIGBUS"),
+    (libc::SIGFPE, "SIGFPE"),
+    (libc::SIGKILL, "SIGKILL"),
+    (libc::SIGUSR1, "SIGUSR1"),
+    (libc::SIGSEGV, "SIGSEGV"),
+    (libc::SIGUSR2, "SIGUSR2"),
+    (libc::SIGPIPE, "SIGPIPE"),
+    (libc::SIGALRM, "SIGALRM"),
+    (libc::SIGTERM, "SIGTERM"),
+    (libc::SIGCHLD, "SIGCHLD"),
+    (libc::SIGCONT, "SIGCONT"),
+    (libc::SIGSTOP, "SIGSTOP"),
+    (libc::SIGTSTP, "SIGTSTP"),
+    (libc::SIGTTIN, "SIGTTIN"),
+    (libc::SIGTTOU, "SIGTTOU"),
+    (libc::SIGURG, "SIGURG"),
+    (libc::SIGXCPU, "SIGXCPU"),
+    (libc::SIGXFSZ, "SIGXFSZ"),
+    (libc::SIGVTALRM, "SIGVTALRM"),
+    (libc::SIGPROF, "SIGPROF"),
+    (libc::SIGWINCH, "SIGWINCH"),
+    (libc::SIGIO, "SIGIO"),
+    (libc::SIGSYS, "SIGSYS"),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    (libc::SIGPWR, "SIGPWR"),
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+        target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+    (libc::SIGINFO, "SIGINFO"),
+];
+
+/// The real-time signals, by how far past SIGRTMIN they are, except for SIGRTMAX itself.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const REALTIME_SIGNALS: &[&str] = &[
+    "SIGRTMIN", "SIGRTMIN+1", "SIGRTMIN+2", "SIGRTMIN+3", "SIGRTMIN+4", "SIGRTMIN+5",
+    "SIGRTMIN+6", "SIGRTMIN+7", "SIGRTMIN+8", "SIGRTMIN+9", "SIGRTMIN+10", "SIGRTMIN+11",
+    "SIGRTMIN+12", "SIGRTMIN+13", "SIGRTMIN+14", "SIGRTMIN+15", "SIGRTMIN+16", "SIGRTMIN+17",
+    "SIGRTMIN+18", "SIGRTMIN+19", "SIGRTMIN+20", "SIGRTMIN+21", "SIGRTMIN+22", "SIGRTMIN+23",
+    "SIGRTMIN+24", "SIGRTMIN+25", "SIGRTMIN+26", "SIGRTMIN+27", "SIGRTMIN+28", "SIGRTMIN+29",
+    "SIGRTMIN+30", "SIGRTMIN+31",
+];
+
+/// A signal's name, like "SIGTERM", if it has one. It's looked up in a table, so unlike with
+/// `strsignal`, it doesn't depend on the locale, and it's safe from any thread or a signal
+/// handler.
+pub fn known_signal_name(n: i32) -> Option<&'static str> {
+    if let Some(&(_, name)) = SIGNALS.iter().find(|&&(sig, _)| sig == n) {
+        return Some(name);
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if n == libc::SIGRTMAX() {
+        return Some("SIGRTMAX");
+    } else if n >= libc::SIGRTMIN() {
+        return REALTIME_SIGNALS.get((n - libc::SIGRTMIN()) as usize).copied();
+    }
+    None
+}
+
+/// The signal with this name, as in "SIGTERM", "term" or "RTMIN+3": in any case, and with or
+/// without the SIG.
+pub fn signal_by_name(name: &str) -> Option<libc::c_int> {
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    if let Some(&(sig, _)) = SIGNALS.iter().find(|&&(_, known)| known[3 ..] == *name) {
+        return Some(sig);
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if name == "RTMAX" {
+        return Some(libc::SIGRTMAX());
+    } else if let Some(i) = REALTIME_SIGNALS.iter().position(|known| known[3 ..] == *name) {
+        return Some(libc::SIGRTMIN() + i as libc::c_int);
+    }
+    None
+}
+
+/// A signal's name, for messages: like "SIGTERM", or for a number without one, "signal 99".
+pub fn signal_name(n: i32) -> Cow<'static, str> {
+    match known_signal_name(n) {
+        Some(name) => Cow::Borrowed(name),
+        None => Cow::Owned(format!("signal {n}")),
+    }
+}
+
+#[test]
+fn test_signal_name() {
+    assert!(signal_name(libc::SIGKILL).to_lowercase().contains("kill"));
+    assert!(!signal_name(9).contains('9'));
+    assert!(signal_name(-1).contains("-1"));
+    assert!(signal_name(0).contains('0'));
+    assert!(signal_name(999).contains("999"));
+    assert_eq!(signal_name(libc::SIGTERM), "SIGTERM");
+    assert_eq!(known_signal_name(999), None);
+    assert_eq!(signal_by_name("SIGWINCH"), Some(libc::SIGWINCH));
+    assert_eq!(signal_by_name("sigpipe"), Some(libc::SIGPIPE));
+    assert_eq!(signal_by_name("Term"), Some(libc::SIGTERM));
+    assert_eq!(signal_by_name("SIG"), None);
+    assert_eq!(signal_by_name("WHATEVER"), None);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        assert_eq!(signal_name(libc::SIGRTMIN()), "SIGRTMIN");
+        assert_eq!(signal_name(libc::SIGRTMIN() + 3), "SIGRTMIN+3");
+        assert_eq!(signal_name(libc::SIGRTMAX()), "SIGRTMAX");
+        assert_eq!(signal_by_name("rtmin+3"), Some(libc::SIGRTMIN() + 3));
+        assert_eq!(signal_by_name("SIGRTMAX"), Some(libc::SIGRTMAX()));
+    }
+}
@@ -0,0 +1,284 @@
+use std::time::{Duration, Instant};
+
+use crate::rng::Rng;
+
+/// Above this rate, a bucket with automatic chunks sends more than a byte at a time: any faster,
+/// and a write per byte takes more time than the bytes are allowed.
+const AUTO_CHUNK_RATE: f64 = 10_000.;
+
+/// Automatic chunks are what the rate allows in this long.
+const AUTO_CHUNK_TICK: Duration = Duration::from_millis(1);
+
+/// A token bucket rate limiter. Tokens (bytes) accumulate at `rate` per second, up to
+/// `capacity`, and sending a byte uses up one token.
+///
+/// With a capacity of one byte this is strict pacing: one byte every 1/rate seconds, with no
+/// bursts.
+pub struct TokenBucket {
+    /// Bytes per second; infinite for no limit.
+    rate: f64,
+    /// Most bytes that can be sent in a burst.
+    capacity: f64,
+    /// The capacity that was asked for, before it was raised to fit a chunk.
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+    /// How much each send's cost in tokens varies, as a fraction either way, and where the
+    /// variation comes from.
+    jitter: Option<(f64, Rng)>,
+    /// Nothing may be sent until there are tokens for this many bytes.
+    chunk: f64,
+    /// Pick the chunk size to suit the rate, whenever it changes.
+    auto_chunk: bool,
+}
+
+impl TokenBucket {
+    /// A new bucket, which starts out full. The rate may be infinite, for no limit.
+    pub fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            capacity,
+            burst: capacity,
+            tokens: capacity,
+            updated: now,
+            jitter: None,
+            chunk: 1.,
+            auto_chunk: false,
+        }
+    }
+
+    /// Send in chunks of up to this many bytes: nothing may be sent until a whole chunk may, so
+    /// there are fewer, bigger writes for the same rate. The capacity is raised to a chunk if it's
+    /// less.
+    pub fn with_chunk(mut self, chunk: f64) -> Self {
+        self.chunk = chunk;
+        self.capacity = self.capacity.max(chunk);
+        self.tokens = self.capacity;
+        self
+    }
+
+    /// Send a byte at a time at ordinary rates, but at high ones, in chunks of what the rate
+    /// allows every millisecond, which is as smooth as the timing can be anyway.
+    pub fn with_auto_chunk(mut self) -> Self {
+        self.auto_chunk = true;
+        self.fit_chunk();
+        self.tokens = self.capacity;
+        self
+    }
+
+    fn fit_chunk(&mut self) {
+        if !self.auto_chunk {
+            return;
+        }
+        self.chunk = if self.rate > AUTO_CHUNK_RATE && !self.is_unlimited() {
+            (self.rate * AUTO_CHUNK_TICK.as_secs_f64()).floor()
+        } else {
+            1.
+        };
+        self.capacity = self.burst.max(self.chunk);
+    }
+
+    /// Vary the time each send takes by up to this fraction either way (uniformly distributed),
+    /// so the pacing isn't perfectly regular. The average rate stays the same.
+    pub fn with_jitter(mut self, fraction: f64, seed: u64) -> Self {
+        self.jitter = (fraction > 0.).then(|| (fraction, Rng::new(seed)));
+        self
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.rate.is_infinite()
+    }
+
+    pub fn set_rate(&mut self, rate: f64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.fit_chunk();
+        self.tokens = self.tokens.min(self.capacity);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if self.is_unlimited() {
+            self.tokens = self.capacity;
+        } else {
+            let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+            let tokens = self.tokens + elapsed * self.rate;
+            // Coming back for the next byte a little late mustn't cost the link its rate, so
+            // when there was a wait (or this is still what was over from one), what's over the
+            // capacity is kept, as long as it's less than a byte. Any more than that, and the
+            // link was idle.
+            let waited = self.tokens < self.chunk || self.tokens > self.capacity;
+            let late = waited && tokens < self.capacity + 1.;
+            self.tokens = if late { tokens } else { tokens.min(self.capacity) };
+        }
+        self.updated = now;
+    }
+
+    /// How many bytes may be sent right now.
+    pub fn available(&mut self, now: Instant) -> usize {
+        if self.is_unlimited() {
+            return usize::MAX;
+        }
+        self.refill(now);
+        if self.tokens < self.chunk {
+            return 0;
+        }
+        self.tokens as usize
+    }
+
+    /// Account for having sent some bytes.
+    pub fn consume(&mut self, n: usize) {
+        if !self.is_unlimited() {
+            let scale = match self.jitter {
+                Some((fraction, ref mut rng)) => 1. + fraction * (2. * rng.next_f64() - 1.),
+                None => 1.,
+            };
+            self.tokens -= n as f64 * scale;
+        }
+    }
+
+    /// How long until at least one byte (or chunk) may be sent.
+    pub fn wait_time(&mut self, now: Instant) -> Duration {
+        if self.is_unlimited() {
+            return Duration::ZERO;
+        }
+        self.refill(now);
+        if self.tokens >= self.chunk {
+            Duration::ZERO
+        } else {
+            // Rounded up, or a wait for the last fraction of a nanosecond's worth would come to
+            // nothing, and there'd still not be enough after it.
+            let nanos = (self.chunk - self.tokens) / self.rate * 1e9;
+            Duration::from_nanos(nanos.ceil() as u64)
+        }
+    }
+}
+
+#[test]
+fn test_token_bucket_pacing() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(10., 1., start);
+    assert_eq!(bucket.available(start), 1);
+    bucket.consume(1);
+    assert_eq!(bucket.available(start), 0);
+    let wait = bucket.wait_time(start);
+    assert!(wait > Duration::from_millis(99) && wait <= Duration::from_millis(100), "{wait:?}");
+
+    // Tokens don't pile up beyond the capacity.
+    let later = start + Duration::from_secs(10);
+    assert_eq!(bucket.available(later), 1);
+    assert_eq!(bucket.wait_time(later), Duration::ZERO);
+}
+
+#[test]
+fn test_token_bucket_lateness_carries_over() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(1000., 1., start);
+    let mut now = start;
+    let mut sent = 0;
+    while now < start + Duration::from_secs(1) {
+        let n = bucket.available(now);
+        bucket.consume(n);
+        sent += n;
+        // Always waking up a third of a byte late.
+        now += bucket.wait_time(now) + Duration::from_micros(333);
+        assert!(bucket.available(now) <= 1);
+    }
+    assert!((999 ..= 1001).contains(&sent), "{sent}");
+}
+
+#[test]
+fn test_token_bucket_burst() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(10., 5., start);
+    assert_eq!(bucket.available(start), 5);
+    bucket.consume(5);
+    assert_eq!(bucket.wait_time(start), Duration::from_millis(100));
+
+    // Sustained, it's still 10 bytes per second.
+    let later = start + Duration::from_millis(300);
+    assert_eq!(bucket.available(later), 3);
+    bucket.consume(3);
+
+    // And after a pause, there's another burst.
+    assert_eq!(bucket.available(later + Duration::from_secs(10)), 5);
+}
+
+#[test]
+fn test_token_bucket_chunk() {
+    let start = Instant::now();
+    let ms = Duration::from_millis;
+    let mut bucket = TokenBucket::new(100., 1., start).with_chunk(10.);
+    assert_eq!(bucket.capacity(), 10.);
+    assert_eq!(bucket.available(start), 10);
+    bucket.consume(10);
+
+    // Nothing until there's a whole chunk.
+    assert_eq!(bucket.available(start + ms(50)), 0);
+    assert_eq!(bucket.wait_time(start + ms(50)), ms(50));
+    assert_eq!(bucket.available(start + ms(100)), 10);
+
+    // A short chunk only costs what was sent, but the next still waits for a whole one.
+    bucket.consume(4);
+    assert_eq!(bucket.available(start + ms(100)), 0);
+    assert_eq!(bucket.wait_time(start + ms(100)), ms(40));
+}
+
+#[test]
+fn test_token_bucket_auto_chunk() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(9600., 1., start).with_auto_chunk();
+    assert_eq!(bucket.available(start), 1);
+
+    bucket.set_rate(1_000_000., start);
+    assert_eq!(bucket.capacity(), 1000.);
+    bucket.consume(1);
+    assert_eq!(bucket.available(start), 0);
+    assert_eq!(bucket.available(start + Duration::from_millis(1)), 1000);
+
+    // A bigger burst stays as it is, and slowing down goes back to a byte at a time.
+    let mut bucket = TokenBucket::new(20_000., 50., start).with_auto_chunk();
+    assert_eq!(bucket.capacity(), 50.);
+    bucket.set_rate(100., start);
+    bucket.consume(50);
+    assert_eq!(bucket.available(start + Duration::from_millis(10)), 1);
+}
+
+#[test]
+fn test_token_bucket_unlimited() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(f64::INFINITY, 1., now);
+    bucket.consume(1_000_000);
+    assert_eq!(bucket.available(now), usize::MAX);
+    assert_eq!(bucket.wait_time(now), Duration::ZERO);
+
+    // Switching to a limited rate starts with a full bucket.
+    bucket.set_rate(10., now);
+    assert_eq!(bucket.available(now), 1);
+}
+
+#[test]
+fn test_token_bucket_jitter() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(10., 1., start).with_jitter(0.5, 1);
+    let mut now = start;
+    let mut waits = vec![];
+    for _ in 0 .. 1000 {
+        bucket.consume(1);
+        let wait = bucket.wait_time(now);
+        waits.push(wait.as_secs_f64());
+        now += wait;
+    }
+    // Each byte takes 50 to 150ms, and 100ms on average.
+    assert!(waits.iter().all(|&w| (0.05 ..= 0.15).contains(&w)), "{waits:?}");
+    assert!(waits.iter().any(|&w| w < 0.07) && waits.iter().any(|&w| w > 0.13));
+    let mean = waits.iter().sum::<f64>() / waits.len() as f64;
+    assert!((mean - 0.1).abs() < 0.005, "{mean}");
+}
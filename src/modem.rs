@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// How long the input has to have been idle before `+++` for it to be taken as the escape to
+/// command mode, rather than something typed.
+const GUARD_TIME: Duration = Duration::from_secs(1);
+
+const BACKSPACE: u8 = 0x08;
+const DEL: u8 = 0x7f;
+
+/// What a command line asks for, beyond what the modem deals with by itself.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// Dial this number (or with no program to run, connect to this address).
+    Dial(String),
+    /// Hang up the call.
+    HangUp,
+    /// Go back online, from command mode during a call.
+    Online,
+}
+
+/// A Hayes-style command interpreter: takes what's typed a line at a time, and carries out the
+/// commands on the lines that start with AT. Only a few mean anything (D, E, H, I, O and Z);
+/// the rest, like the S registers and the & settings in init strings, are taken and ignored, as
+/// there's nothing for them to set.
+pub struct CommandMode {
+    echo: bool,
+    line: Vec<u8>,
+}
+
+impl CommandMode {
+    pub fn new() -> Self {
+        CommandMode { echo: true, line: vec![] }
+    }
+
+    /// Take what's typed, and return what the modem says back (echo included), and what has to
+    /// be done once the line is finished, if anything. What's after that line is left alone.
+    pub fn feed(&mut self, data: &[u8]) -> (Vec<u8>, Option<Action>) {
+        let mut reply = vec![];
+        for &b in data {
+            match b {
+                b'\r' => {
+                    if self.echo {
+                        reply.push(b'\r');
+                    }
+                    let line = String::from_utf8_lossy(&std::mem::take(&mut self.line))
+                        .into_owned();
+                    let (text, action) = self.command(&line);
+                    reply.extend(text);
+                    if action.is_some() {
+                        return (reply, action);
+                    }
+                }
+                BACKSPACE | DEL => {
+                    if self.line.pop().is_some() && self.echo {
+                        reply.extend(b"\x08 \x08");
+                    }
+                }
+                b'\n' => (),
+                _ => {
+                    self.line.push(b);
+                    if self.echo {
+                        reply.push(b);
+                    }
+                }
+            }
+        }
+        (reply, None)
+    }
+
+    /// Carry out a command line, and return the result code to show and what else is to be done.
+    fn command(&mut self, line: &str) -> (Vec<u8>, Option<Action>) {
+        let line = line.trim();
+        if line.is_empty() {
+            return (vec![], None);
+        }
+        let Some(commands) = line.get(.. 2).filter(|at| at.eq_ignore_ascii_case("AT"))
+            .map(|_| &line[2 ..])
+        else {
+            return (result("ERROR"), None);
+        };
+
+        let mut info = false;
+        let mut chars = commands.char_indices().filter(|(_, c)| !c.is_ascii_whitespace())
+            .map(|(i, c)| (i, c.to_ascii_uppercase()))
+            .peekable();
+        while let Some((i, c)) = chars.next() {
+            let mut number = String::new();
+            if c == 'D' {
+                // The rest of the line is the number, after T or P for tone or pulse dialing.
+                let number = &commands[i + 1 ..];
+                let number = number.strip_prefix(['T', 't', 'P', 'p']).unwrap_or(number);
+                return (vec![], Some(Action::Dial(number.trim().to_owned())));
+            }
+            if c == '&' {
+                chars.next();
+            }
+            if c == 'S' {
+                // A register, to set or ask about.
+                while chars.next_if(|(_, c)| c.is_ascii_digit()).is_some() {}
+                if chars.next_if(|&(_, c)| c == '=').is_some() {
+                    while chars.next_if(|(_, c)| c.is_ascii_digit()).is_some() {}
+                } else if chars.next_if(|&(_, c)| c == '?').is_none() {
+                    return (result("ERROR"), None);
+                }
+                continue;
+            }
+            while let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                number.push(digit);
+            }
+            match (c, number.as_str()) {
+                ('E', "" | "1") => self.echo = true,
+                ('E', "0") => self.echo = false,
+                ('Z', _) => self.echo = true,
+                ('H', "" | "0") => return (result("OK"), Some(Action::HangUp)),
+                ('O', "" | "0") => return (vec![], Some(Action::Online)),
+                ('I', _) => info = true,
+                (c, _) if c.is_ascii_alphabetic() || c == '&' => (),
+                _ => return (result("ERROR"), None),
+            }
+        }
+        let mut reply = vec![];
+        if info {
+            reply.extend(result(concat!("slowpty ", env!("CARGO_PKG_VERSION"))));
+        }
+        reply.extend(result("OK"));
+        (reply, None)
+    }
+}
+
+/// A result code, on a line of its own.
+pub fn result(text: &str) -> Vec<u8> {
+    format!("\r\n{text}\r\n").into_bytes()
+}
+
+#[test]
+fn test_command_mode() {
+    let mut modem = CommandMode::new();
+    assert_eq!(modem.feed(b"ATZ\r"), (b"ATZ\r\r\nOK\r\n".to_vec(), None));
+    assert_eq!(modem.feed(b"ATE0 S0=1 &C1 V1\r"), (b"ATE0 S0=1 &C1 V1\r\r\nOK\r\n".to_vec(),
+        None));
+    assert_eq!(modem.feed(b"hello\r"), (b"\r\nERROR\r\n".to_vec(), None));
+    assert_eq!(modem.feed(b"AT?\r"), (b"\r\nERROR\r\n".to_vec(), None));
+    assert_eq!(modem.feed(b"atx\x7f\r"), (b"\r\nOK\r\n".to_vec(), None));
+    assert_eq!(modem.feed(b"ATH0\r"), (b"\r\nOK\r\n".to_vec(), Some(Action::HangUp)));
+    assert_eq!(modem.feed(b"ate1o\r"), (vec![], Some(Action::Online)));
+    assert_eq!(modem.feed(b"AT&D2 DT bbs.example.com:23\r"),
+        (b"AT&D2 DT bbs.example.com:23\r".to_vec(),
+            Some(Action::Dial("bbs.example.com:23".to_owned()))));
+}
+
+/// Until something is dialed, act as a modem in command mode on the console, and return the
+/// number.
+pub fn dial(input: &mut impl Read, output: &mut impl Write) -> Result<String> {
+    let mut modem = CommandMode::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => bail!("the console closed before anything was dialed"),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("failed to read from the console"),
+        };
+        let (reply, action) = modem.feed(&buf[.. n]);
+        output.write_all(&reply).and_then(|()| output.flush())
+            .context("failed to write to the console")?;
+        match action {
+            Some(Action::Dial(number)) => {
+                debug!("dialing {:?}", number);
+                return Ok(number);
+            }
+            // Not on a call, so there's nothing to hang up or go back to.
+            Some(Action::HangUp) => (),
+            Some(Action::Online) => {
+                output.write_all(&result("NO CARRIER")).context("failed to write to the console")?;
+            }
+            None => (),
+        }
+    }
+}
+
+/// What the modem does with what's typed during a call.
+pub enum Online {
+    /// Sending it on, after this many `+` in a row that could be the escape.
+    Data { plus: usize, last_input: Option<Instant> },
+    /// Back in command mode after `+++`, with the call still up.
+    Escaped(CommandMode),
+}
+
+impl Online {
+    pub fn new() -> Self {
+        Online::Data { plus: 0, last_input: None }
+    }
+
+    /// Take what's typed during the call, and return what's to be sent on, what the modem says
+    /// back, and whether to hang up.
+    pub fn input(&mut self, now: Instant, data: &[u8]) -> (Vec<u8>, Vec<u8>, bool) {
+        let mut send = vec![];
+        let mut reply = vec![];
+        for &b in data {
+            match self {
+                Online::Data { plus, last_input } => {
+                    let idle = last_input.is_none_or(|t| now.duration_since(t) >= GUARD_TIME);
+                    *last_input = Some(now);
+                    send.push(b);
+                    *plus = match (b, *plus) {
+                        (b'+', 0) if idle => 1,
+                        (b'+', 1 | 2) => *plus + 1,
+                        _ => 0,
+                    };
+                    if *plus == 3 {
+                        debug!("escaped to command mode");
+                        reply.extend(result("OK"));
+                        *self = Online::Escaped(CommandMode::new());
+                    }
+                }
+                Online::Escaped(modem) => {
+                    let (text, action) = modem.feed(&[b]);
+                    reply.extend(text);
+                    match action {
+                        Some(Action::HangUp) => return (send, reply, true),
+                        Some(Action::Online) => {
+                            debug!("back online");
+                            *self = Online::new();
+                        }
+                        Some(Action::Dial(_)) => reply.extend(result("ERROR")),
+                        None => (),
+                    }
+                }
+            }
+        }
+        (send, reply, false)
+    }
+}
+
+#[test]
+fn test_online() {
+    let start = Instant::now();
+    let mut online = Online::new();
+    // Not after a pause, so it's just typing.
+    assert_eq!(online.input(start, b"a+++"), (b"a+++".to_vec(), vec![], false));
+    let later = start + GUARD_TIME;
+    assert_eq!(online.input(later, b"+++"), (b"+++".to_vec(), b"\r\nOK\r\n".to_vec(), false));
+    assert_eq!(online.input(later, b"ATO\r"), (vec![], b"ATO\r".to_vec(), false));
+    assert_eq!(online.input(later, b"ls\r"), (b"ls\r".to_vec(), vec![], false));
+    let later = later + GUARD_TIME;
+    assert_eq!(online.input(later, b"+"), (b"+".to_vec(), vec![], false));
+    assert_eq!(online.input(later, b"++"), (b"++".to_vec(), b"\r\nOK\r\n".to_vec(), false));
+    assert_eq!(online.input(later, b"ATH\rx"), (vec![], b"ATH\r\r\nOK\r\n".to_vec(), true));
+}
@@ -0,0 +1,53 @@
+use crate::filter::Filter;
+use crate::options::Direction;
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+
+/// With `--onlcr` and `--ocrnl`, translates the line ends in the output as the termios output
+/// flags of the same names do, since the console is in raw mode and does no translating of its
+/// own. Unlike ONLCR, a LF that already has a CR before it is left alone.
+pub struct Newlines {
+    onlcr: bool,
+    ocrnl: bool,
+    /// The last byte written, which may have been in the last read.
+    prev: u8,
+}
+
+impl Newlines {
+    pub fn new(onlcr: bool, ocrnl: bool) -> Self {
+        Newlines { onlcr, ocrnl, prev: 0 }
+    }
+
+    pub fn translate(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + data.len() / 16);
+        for &b in data {
+            match b {
+                CR if self.ocrnl => out.push(LF),
+                LF if self.onlcr && self.prev != CR => out.extend([CR, LF]),
+                _ => out.push(b),
+            }
+            self.prev = *out.last().unwrap();
+        }
+        out
+    }
+}
+
+impl Filter for Newlines {
+    fn transform(&mut self, _dir: Direction, input: &[u8], out: &mut Vec<u8>) {
+        out.extend(self.translate(input));
+    }
+}
+
+#[test]
+fn test_newlines() {
+    let mut newlines = Newlines::new(true, false);
+    assert_eq!(newlines.translate(b"a\nb\r\nc\r"), b"a\r\nb\r\nc\r");
+    assert_eq!(newlines.translate(b"\n\n"), b"\n\r\n");
+
+    let mut newlines = Newlines::new(false, true);
+    assert_eq!(newlines.translate(b"a\r\nb\r"), b"a\n\nb\n");
+
+    let mut newlines = Newlines::new(true, true);
+    assert_eq!(newlines.translate(b"a\rb\n"), b"a\nb\r\n");
+}
@@ -0,0 +1,73 @@
+use crate::filter::Filter;
+use crate::options::Direction;
+use crate::rng::Rng;
+
+/// Garbles bytes now and then, like line noise on a modem connection.
+pub struct LineNoise {
+    /// Chance of each byte starting a burst of noise.
+    probability: f64,
+    /// The longest a burst can be, in bytes.
+    burst: u32,
+    /// How many more bytes the current burst garbles.
+    remaining: u32,
+    rng: Rng,
+}
+
+impl LineNoise {
+    pub fn new(probability: f64, burst: u32, seed: u64) -> Self {
+        LineNoise {
+            probability,
+            burst: burst.max(1),
+            remaining: 0,
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// Add noise to some data on its way through.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            if self.remaining == 0 {
+                if self.rng.next_f64() >= self.probability {
+                    continue;
+                }
+                self.remaining = 1 + self.rng.below(u64::from(self.burst)) as u32;
+            }
+            self.remaining -= 1;
+            *b = if self.rng.next_f64() < 0.5 {
+                // A flipped bit.
+                *b ^ (1 << self.rng.below(8))
+            } else {
+                // Garbage.
+                self.rng.next_u64() as u8
+            };
+        }
+    }
+}
+
+impl Filter for LineNoise {
+    fn transform(&mut self, _dir: Direction, input: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(input);
+        self.apply(&mut out[start ..]);
+    }
+}
+
+#[test]
+fn test_line_noise() {
+    let clean = vec![b'.'; 10_000];
+
+    let mut data = clean.clone();
+    LineNoise::new(0., 1, 1).apply(&mut data);
+    assert_eq!(data, clean);
+
+    let mut data = clean.clone();
+    LineNoise::new(0.01, 1, 1).apply(&mut data);
+    let garbled = data.iter().filter(|&&b| b != b'.').count();
+    assert!((50 ..= 150).contains(&garbled), "{garbled}");
+
+    // In bursts of up to 9, so 5 on average.
+    let mut data = clean.clone();
+    LineNoise::new(0.01, 9, 1).apply(&mut data);
+    let garbled = data.iter().filter(|&&b| b != b'.').count();
+    assert!((300 ..= 700).contains(&garbled), "{garbled}");
+}
@@ -0,0 +1,2318 @@
+use clap::error::ErrorKind;
+use clap::{ArgAction, ArgGroup, CommandFactory, Parser, Subcommand, ValueEnum};
+use regex::bytes::Regex;
+use std::ffi::OsString;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::term;
+
+/// Everything that can be configured from the command line.
+pub struct Options {
+    /// Bytes per second, in each direction (or in total, with `shared_rate`). Only optional when
+    /// probing, or when either direction has its own rate (and then the other is unlimited).
+    pub rate: Option<f64>,
+
+    /// Bytes per second for input (console to program), instead of `rate`.
+    pub in_rate: Option<f64>,
+
+    /// Bytes per second for output (program to console), instead of `rate`.
+    pub out_rate: Option<f64>,
+
+    /// Which directions are limited; the other is left unlimited, whatever the rate.
+    pub direction: Direction,
+
+    /// How long input takes to arrive, on top of the time it takes to send at the rate.
+    pub in_latency: Duration,
+
+    /// How long output takes to arrive, on top of the time it takes to send at the rate.
+    pub out_latency: Duration,
+
+    /// Both directions draw from one limiter, instead of each having their own.
+    pub shared_rate: bool,
+
+    /// How many bytes can be sent at once, after a pause, before the rate applies.
+    pub burst: u32,
+
+    /// Write at least this many bytes at a time (or all there is), waiting until the rate allows
+    /// it. If not given, it's worked out from the rate.
+    pub chunk: Option<u32>,
+
+    /// The most to hold in each direction between reading and writing it, if not just what will
+    /// keep the link busy.
+    pub buffer_limit: Option<u32>,
+
+    /// What to do with what's read once the buffer is full.
+    pub overflow: Overflow,
+
+    /// How much the time each byte takes varies, as a fraction either way.
+    pub jitter: f64,
+
+    /// Chance of each output byte being garbled (or starting a burst of garbled bytes).
+    pub noise: f64,
+
+    /// The longest a burst of noise can be, in bytes.
+    pub noise_burst: u32,
+
+    /// Bits in each byte on the link: 7 to lose the high bit, or 8.
+    pub data_bits: u8,
+
+    /// With 7 data bits, the parity that bytes arriving with the high bit set are checked
+    /// against.
+    pub parity: Parity,
+
+    /// NULs to send after each line of output, for the carriage to return in.
+    pub fill_nuls: u32,
+
+    /// How long to pause after each line of output, for the carriage to return in.
+    pub cr_delay: Duration,
+
+    /// Keep UTF-8 characters in the output whole, charging for them as this says.
+    pub utf8: Option<Utf8Charge>,
+
+    /// Rewrite the colors in the output for a terminal that has only these.
+    pub colors: Option<Colors>,
+
+    /// Translate the output from this character set into UTF-8.
+    pub charset: Option<Charset>,
+
+    /// Turn each LF in the output that doesn't have a CR before it into CR LF.
+    pub onlcr: bool,
+
+    /// Turn each CR in the output into a LF.
+    pub ocrnl: bool,
+
+    /// Pass the program's line ends on as it writes them, without the pty translating them.
+    pub raw_nl: bool,
+
+    /// Rates to change to, and how long after starting to change to each one, in order.
+    pub schedule: Vec<(Duration, f64)>,
+
+    /// Input to type into the program, each piece after a delay from the one before.
+    pub script: Vec<(Duration, Vec<u8>)>,
+
+    /// Type input a keystroke at a time, with gaps that vary around this one, like a person.
+    pub humanize: Option<Duration>,
+
+    /// Rates that can be cycled through at runtime with the preset hotkey.
+    pub rate_presets: Vec<f64>,
+
+    /// Show status changes in the terminal title.
+    pub indicate: bool,
+
+    /// Run unthrottled for this long at the start, measuring the output rate.
+    pub probe: Option<Duration>,
+
+    /// After probing, continue at this fraction of the measured rate instead of exiting.
+    pub probe_then: Option<f64>,
+
+    /// Probe again periodically, and adjust the rate to the new measurement each time.
+    pub probe_adaptive: bool,
+
+    /// Extra delay before the first byte after the link has been idle.
+    pub wakeup: Option<Duration>,
+
+    /// How long the link has to be idle before it needs to wake up.
+    pub wakeup_idle: Duration,
+
+    /// Before starting, show this prompt followed by the command line, as if it had been typed.
+    pub show_command: Option<String>,
+
+    /// Before starting, wait this long, as a modem would to make its handshake, then print its
+    /// CONNECT banner.
+    pub connect_banner: Option<Duration>,
+
+    /// Stop throttling when this happens, and pass everything through from then on.
+    pub detach_after: Option<DetachTrigger>,
+
+    /// What to do when the output matches each of these patterns.
+    pub on_output: Vec<(Regex, OutputAction)>,
+
+    /// During a ZMODEM, XMODEM or YMODEM file transfer, this rate (which may be infinite)
+    /// instead of the usual one.
+    pub transfer_rate: Option<f64>,
+
+    /// Send the program a SIGWINCH this long after starting, to make it redraw.
+    pub kick_winch: Option<Duration>,
+
+    /// The program's window width, whatever the terminal's is.
+    pub cols: Option<u16>,
+
+    /// The program's window height, whatever the terminal's is.
+    pub rows: Option<u16>,
+
+    /// End the session this long after starting, terminating the program if it's still running.
+    pub timeout: Option<Duration>,
+
+    /// End the session once nothing has gone either way for this long.
+    pub idle_timeout: Option<Duration>,
+
+    /// The signal to send the program when the session has been idle too long.
+    pub idle_signal: libc::c_int,
+
+    /// What slowpty's own exit status says about how the program ended.
+    pub exit_status: ExitMode,
+
+    /// What to do with the output the program leaves behind when it exits.
+    pub drain: DrainMode,
+
+    /// How to handle the interrupt character.
+    pub intr: IntrMode,
+
+    /// Take Ctrl-S and Ctrl-Q typed at the console to stop and start the output, instead of
+    /// passing them to the program.
+    pub xon_xoff: bool,
+
+    /// A key that, typed at the console, takes the next key as a command to slowpty rather than
+    /// input for the program.
+    pub prefix_key: Option<u8>,
+
+    /// How long to wait for the rest of an escape sequence typed at the console, which is sent to
+    /// the program whole.
+    pub esc_timeout: Duration,
+
+    /// Write a CSV time series of the transfer rates here.
+    pub rate_log: Option<PathBuf>,
+
+    /// How often to write a row to the rate log.
+    pub rate_log_interval: Duration,
+
+    /// Write what the event loop does here, as newline-delimited JSON.
+    pub json_log: Option<PathBuf>,
+
+    /// Write both directions of the session, interleaved, to this file.
+    pub transcript: Option<PathBuf>,
+
+    /// How to lay out the transcript.
+    pub transcript_format: TranscriptFormat,
+
+    /// Record what the console was shown to this file.
+    pub record: Option<PathBuf>,
+
+    /// What kind of recording `record` is.
+    pub record_format: RecordFormat,
+
+    /// Compress the recordings and the transcript with this.
+    pub compress: Option<Compression>,
+
+    /// At the end, write what was on the screen to this file, as plain text.
+    pub snapshot: Option<PathBuf>,
+
+    /// Record what the console was shown to this file, as a script(1) typescript.
+    pub script_record: Option<PathBuf>,
+
+    /// Write timing for the typescript here, for scriptreplay(1); or with `replay`, read the
+    /// timing of a typescript from here.
+    pub timing: Option<PathBuf>,
+
+    /// Copy everything written to the console to this file.
+    pub log_output: Option<PathBuf>,
+
+    /// Copy everything written to the program to this file.
+    pub log_input: Option<PathBuf>,
+
+    /// Write the logs one timestamped, escaped chunk per line, instead of byte for byte.
+    pub log_timestamps: bool,
+
+    /// Listen for commands to adjust the session on a Unix domain socket at this path.
+    pub control: Option<PathBuf>,
+
+    /// Run the hooks in this Rhai script on the traffic, the rate and the program's exit.
+    pub hooks: Option<PathBuf>,
+
+    /// Write what happens to the session to this fd, as a line of JSON per event.
+    pub events_fd: Option<RawFd>,
+
+    /// At the end, print how much went each way, and how fast.
+    pub stats: bool,
+
+    /// Run even if the console looks like it would loop back on itself.
+    pub force: bool,
+
+    /// How much to log: 1 for what's happening, 2 or more for the details.
+    pub verbose: u8,
+
+    /// Log nothing at all.
+    pub quiet: bool,
+
+    /// On exit, set the terminal to sane settings instead of restoring the original ones.
+    pub reset_sane: bool,
+
+    /// Throttle a pipeline instead of a terminal: leave the terminal's settings alone, read from
+    /// stdin and write to stdout.
+    pub no_raw: bool,
+
+    /// Connect to this address (host:port) and throttle the connection, instead of running a
+    /// program.
+    pub connect: Option<String>,
+
+    /// Act as a modem on the console until something is dialed: then start the program, or
+    /// without one, connect to the address dialed.
+    pub modem: bool,
+
+    /// Play back this recording, instead of running a program.
+    pub replay: Option<PathBuf>,
+
+    /// What kind of recording `replay` is.
+    pub replay_format: ReplayFormat,
+
+    /// How many times as fast as it was recorded to play back `replay`.
+    pub replay_speed: f64,
+
+    /// Copy stdin to stdout at the output rate, instead of running a program.
+    pub cat: bool,
+
+    /// Talk to this serial device, instead of running a program.
+    pub serial: Option<PathBuf>,
+
+    /// The speed to set the serial device to, rather than leaving it as it is.
+    pub serial_speed: Option<u32>,
+
+    /// Serve sessions to telnet clients on this TCP port, instead of running one on the terminal.
+    /// Within a session, this means the console is a telnet connection.
+    pub telnet: Option<u16>,
+
+    /// Serve sessions to whatever connects to a Unix domain socket at this path. Within a
+    /// session, this means the console is a connection to it.
+    pub listen: Option<PathBuf>,
+
+    /// Run the session in the background under this name, so it can be detached from and
+    /// attached to again.
+    pub session: Option<String>,
+
+    /// Attach to the session of this name, instead of starting one.
+    pub attach: Option<String>,
+
+    /// Environment variables to set for the program.
+    pub env: Vec<(OsString, OsString)>,
+
+    /// Start the program with only the variables from `env`, rather than adding them to ours.
+    pub env_clear: bool,
+
+    /// Directory to run the program in, if not the current one.
+    pub chdir: Option<PathBuf>,
+
+    /// Leave the program's stderr as ours, rather than on the pty.
+    pub separate_stderr: bool,
+
+    /// The program to run, followed by its arguments.
+    pub command: Vec<OsString>,
+}
+
+/// What happens when more comes than `--buffer-limit` holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Overflow {
+    /// Stop reading until there's room, so the writer has to wait.
+    Block,
+
+    /// Make room by throwing away the oldest of what's buffered.
+    DropOldest,
+
+    /// Throw away what doesn't fit.
+    DropNewest,
+}
+
+/// What to do when the interrupt character (usually Ctrl-C) is typed at the console.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IntrMode {
+    /// Pass it through to the pty like any other byte.
+    Byte,
+
+    /// Send SIGINT to the child's foreground process group.
+    Signal,
+}
+
+/// Parity checking on a 7-bit link, for `--parity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Which way the rate limits apply, for `--direction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Direction {
+    Both,
+    /// Only what's typed.
+    In,
+    /// Only what the program prints.
+    Out,
+}
+
+/// How slowpty's exit status reflects the program's, for `--exit-status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExitMode {
+    /// Its exit status, or if it was killed by a signal, die of the same signal, so that a
+    /// wrapper sees just what it would have with the program itself.
+    Child,
+
+    /// 0, however the program ended.
+    AlwaysZero,
+
+    /// Its exit status, or 128 plus the signal's number if it was killed by one, as a shell
+    /// gives.
+    #[value(name = "128+sig")]
+    Shell,
+}
+
+/// What happens to the rest of the output once the program exits, for `--drain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DrainMode {
+    /// Show it all, at the rate it would have been shown at anyway.
+    Rate,
+
+    /// Show it all, as fast as it'll go.
+    Fast,
+
+    /// Throw it away, and end the session right away.
+    Discard,
+}
+
+/// What colors the terminal has, for `--colors`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Colors {
+    /// None at all, with `--monochrome`.
+    #[value(skip)]
+    Monochrome,
+    #[value(name = "8")]
+    Eight,
+    #[value(name = "16")]
+    Sixteen,
+    #[value(name = "256")]
+    Palette,
+}
+
+/// An 8-bit character set the output is in, for `--charset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Charset {
+    /// The IBM PC's code page 437, with its box drawing and block characters.
+    Cp437,
+    /// ISO 8859-1.
+    Latin1,
+    /// The Commodore 64's PETSCII.
+    Petscii,
+    /// The Atari 8-bit computers' ATASCII.
+    Atascii,
+}
+
+/// What a character costs, for `--utf8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Utf8Charge {
+    /// Each of its bytes, as usual.
+    Bytes,
+    /// One byte's worth, however many it takes.
+    Chars,
+}
+
+/// How the transcript is laid out.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TranscriptFormat {
+    /// Readable dialogue: input lines prefixed with `>`, output as plain text with escape
+    /// sequences removed, and each line timestamped.
+    Text,
+
+    /// One line per chunk forwarded, with the direction and the exact bytes, escaped.
+    Chunks,
+}
+
+/// What kind of recording `slowpty replay` plays back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReplayFormat {
+    /// Work it out from what's in the file.
+    Auto,
+
+    /// An asciicast, from --record or asciinema.
+    Cast,
+
+    /// A transcript from --transcript.
+    Transcript,
+
+    /// A ttyrec, from ttyrec(1) or --record-format ttyrec.
+    Ttyrec,
+
+    /// A typescript from script(1) or --script-record, with the timing file given by --timing.
+    Script,
+}
+
+/// What kind of recording `--record` makes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RecordFormat {
+    /// An asciicast (v2), for asciinema.
+    Cast,
+
+    /// A ttyrec, for ttyplay and IPBT.
+    Ttyrec,
+}
+
+/// How `--compress` compresses the recordings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// What ends the throttled phase, for `--detach-after`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DetachTrigger {
+    /// This long after starting.
+    Elapsed(Duration),
+
+    /// Once the program has output this many bytes.
+    OutputBytes(u64),
+
+    /// Once the program outputs this text.
+    OutputMatch(Vec<u8>),
+}
+
+/// What to do when the output matches, for `--on-output`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputAction {
+    /// Stop the output, as XOFF does.
+    Pause,
+
+    /// Change the rate.
+    Rate(f64),
+
+    /// End the session.
+    Exit,
+
+    /// Type this for the program.
+    Send(Vec<u8>),
+}
+
+/// How each byte is sent on a serial line, for `--baud`: a start bit, the data bits, an optional
+/// parity bit, and one or more stop bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Framing {
+    data: u8,
+    parity: bool,
+    /// Can be 1.5, for the old teleprinters that needed a little longer to get ready for the next
+    /// character.
+    stop: f64,
+}
+
+impl Framing {
+    /// Bits on the line for each byte.
+    fn bits(&self) -> f64 {
+        1. + f64::from(self.data) + f64::from(u8::from(self.parity)) + self.stop
+    }
+}
+
+/// The baud rates `--baud` accepts.
+const BAUD_RATES: [u32; 12] =
+    [110, 300, 1200, 2400, 4800, 9600, 14400, 19200, 28800, 38400, 57600, 115200];
+
+/// How long after starting `--kick-winch` sends its SIGWINCH, unless told otherwise.
+const DEFAULT_KICK_WINCH_DELAY: Duration = Duration::from_millis(200);
+
+/// How long `--percent` probes for, unless `--probe` says otherwise.
+const DEFAULT_PERCENT_PROBE: Duration = Duration::from_secs(2);
+
+/// How often `--ramp` changes the rate.
+const RAMP_STEP: Duration = Duration::from_millis(100);
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            rate: None,
+            in_rate: None,
+            out_rate: None,
+            direction: Direction::Both,
+            in_latency: Duration::ZERO,
+            out_latency: Duration::ZERO,
+            shared_rate: false,
+            burst: 1,
+            chunk: None,
+            buffer_limit: None,
+            overflow: Overflow::Block,
+            jitter: 0.,
+            noise: 0.,
+            noise_burst: 1,
+            data_bits: 8,
+            parity: Parity::None,
+            fill_nuls: 0,
+            cr_delay: Duration::ZERO,
+            utf8: None,
+            colors: None,
+            charset: None,
+            onlcr: false,
+            ocrnl: false,
+            raw_nl: false,
+            schedule: vec![],
+            script: vec![],
+            humanize: None,
+            rate_presets: vec![],
+            indicate: false,
+            probe: None,
+            probe_then: None,
+            probe_adaptive: false,
+            wakeup: None,
+            wakeup_idle: Duration::from_secs(1),
+            show_command: None,
+            connect_banner: None,
+            detach_after: None,
+            on_output: vec![],
+            transfer_rate: None,
+            kick_winch: None,
+            cols: None,
+            rows: None,
+            timeout: None,
+            idle_timeout: None,
+            idle_signal: libc::SIGTERM,
+            exit_status: ExitMode::Shell,
+            drain: DrainMode::Rate,
+            esc_timeout: Duration::from_millis(50),
+            intr: IntrMode::Byte,
+            xon_xoff: false,
+            prefix_key: None,
+            rate_log: None,
+            rate_log_interval: Duration::from_secs(1),
+            json_log: None,
+            transcript: None,
+            transcript_format: TranscriptFormat::Text,
+            record: None,
+            record_format: RecordFormat::Cast,
+            compress: None,
+            snapshot: None,
+            script_record: None,
+            timing: None,
+            log_output: None,
+            log_input: None,
+            log_timestamps: false,
+            control: None,
+            hooks: None,
+            events_fd: None,
+            stats: false,
+            force: false,
+            verbose: 0,
+            quiet: false,
+            reset_sane: false,
+            no_raw: false,
+            connect: None,
+            modem: false,
+            replay: None,
+            replay_format: ReplayFormat::Auto,
+            replay_speed: 1.,
+            cat: false,
+            serial: None,
+            serial_speed: None,
+            telnet: None,
+            listen: None,
+            session: None,
+            attach: None,
+            env: vec![],
+            env_clear: false,
+            chdir: None,
+            separate_stderr: false,
+            command: vec![],
+        }
+    }
+}
+
+/// Run the given program, limiting I/O to the specified number of bytes per second.
+///
+/// By default, each direction is limited to <RATE> independently, so typing and output don't slow
+/// each other down. With --shared-rate, their combined throughput is limited to <RATE> instead,
+/// like a link whose bandwidth is shared by both directions.
+///
+/// Rates are in bytes per second, and can have a k, M, or G suffix (for thousands, millions, or
+/// billions). They can also be given in bits per second on a serial line, as in 9600bps or
+/// 2400baud, taking each byte as 10 bits (8N1), or with cps (characters per second), which is the
+/// same as none.
+///
+/// While it's running, sending slowpty SIGUSR1 doubles the rate, and SIGUSR2 halves it.
+/// SIGTSTP suspends slowpty and the program together, restoring the terminal until they're
+/// continued. SIGINT, SIGTERM, or SIGHUP is passed on to the program, which gets a second to exit
+/// before slowpty does. SIGQUIT (or SIGINFO, from Ctrl-T, where there is one) prints a line on
+/// stderr with the rate and how much has been transferred so far.
+#[derive(Parser)]
+#[command(name = "slowpty", version, override_usage = USAGE, args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true, disable_help_subcommand = true)]
+struct Args {
+    #[command(flatten)]
+    shared: Shared,
+
+    /// Act as a Hayes modem on the console, for terminal programs that expect to dial out, as in
+    /// an emulator: take AT commands until ATDT <NUMBER>, then start the program, or without a
+    /// program, connect to the number as a HOST:PORT (port 23 if none is given)
+    ///
+    /// The call starts with a --connect-banner. During it, +++ after a second without typing
+    /// goes back to command mode, where ATH hangs up and ATO goes back online. The end of the
+    /// call shows NO CARRIER.
+    #[arg(long, conflicts_with_all = ["cat", "attach"])]
+    modem: bool,
+
+    /// Instead of running a program, just copy stdin to stdout at <RATE>, as in
+    /// `slowpty --cat 30 < art.ans`
+    ///
+    /// There's no pty, and the terminal isn't put in raw mode.
+    #[arg(long, conflicts_with_all = ["shared_rate", "direction", "in_rate", "out_rate", "env",
+        "env_clear", "chdir", "separate_stderr"])]
+    cat: bool,
+
+    /// Run the session in the background, where it carries on when detached from, as it is when
+    /// the terminal goes away; and attach to it
+    ///
+    /// With --prefix-key, typing it and then d detaches. While no one is attached, the last 64
+    /// KiB of output is kept to be shown on attaching again with --attach. The program's window
+    /// size stays as it was started, and slowpty's exit status doesn't say how it ended.
+    #[arg(long, value_name = "NAME", value_parser = parse_session_name,
+        conflicts_with = "cat")]
+    session: Option<String>,
+
+    /// Attach to a session started with --session, instead of running a program
+    #[arg(long, value_name = "NAME", value_parser = parse_session_name,
+        conflicts_with_all = ["session", "cat", "command"])]
+    attach: Option<String>,
+
+    #[command(flatten)]
+    program: Program,
+
+    /// The rate (unless it's optional), then the program to run and its arguments
+    #[arg(value_name = "ARGS",
+        required_unless_present_any = ["cat", "attach", "modem"],
+        trailing_var_arg = true)]
+    command: Vec<OsString>,
+
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+/// The options that go with any way of running slowpty.
+#[derive(clap::Args)]
+struct Shared {
+    /// Run at the speed of a serial line at this baud rate, instead of giving <RATE>
+    ///
+    /// The rate in bytes per second accounts for the start, parity and stop bits sent along with
+    /// each byte, as given by --framing. Standard rates from 110 to 115200 are accepted.
+    #[arg(short, long, value_parser = parse_baud)]
+    baud: Option<u32>,
+
+    /// How each byte is framed on the line for --baud: data bits, parity (N, E, O, M or S) and
+    /// stop bits
+    #[arg(long, value_parser = parse_framing, default_value = "8N1", requires = "baud")]
+    framing: Framing,
+
+    /// Limit the total of both directions to <RATE>, instead of each one
+    #[arg(short, long, conflicts_with_all = ["in_rate", "out_rate"])]
+    shared_rate: bool,
+
+    /// Which way to limit to <RATE>: input (what's typed), output (what the program prints), or
+    /// both
+    ///
+    /// The other direction goes at full speed, whatever the rate is changed to later on.
+    #[arg(long, value_enum, default_value = "both",
+        conflicts_with_all = ["shared_rate", "in_rate", "out_rate"])]
+    direction: Direction,
+
+    /// Limit input (what's typed) to this rate, instead of <RATE>
+    ///
+    /// Without <RATE>, a direction that isn't given its own rate is unlimited.
+    #[arg(long, visible_alias = "rate-in", value_name = "RATE", value_parser = parse_rate)]
+    in_rate: Option<f64>,
+
+    /// Limit output (what the program prints) to this rate, instead of <RATE>
+    ///
+    /// Without <RATE>, a direction that isn't given its own rate is unlimited, e.g. --out-rate 240
+    /// to page through output at 2400 baud while typing at full speed.
+    #[arg(long, visible_alias = "rate-out", value_name = "RATE", value_parser = parse_rate)]
+    out_rate: Option<f64>,
+
+    /// Delay everything by this much (e.g. 300ms) on its way through, in both directions
+    ///
+    /// This is a fixed delay in addition to the time it takes to send the data at the rate, so a
+    /// link with high latency but plenty of bandwidth, like a satellite link, can be simulated
+    /// with a high rate and a high latency. --in-latency and --out-latency override it for one
+    /// direction.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    latency: Option<Duration>,
+
+    /// Delay input by this much (e.g. 40ms) on its way through
+    ///
+    /// Like the round-trip time of a network link, this is in addition to the time it takes to
+    /// send the data at the rate.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    in_latency: Option<Duration>,
+
+    /// Delay output by this much (e.g. 40ms) on its way through
+    ///
+    /// Like the round-trip time of a network link, this is in addition to the time it takes to
+    /// send the data at the rate.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    out_latency: Option<Duration>,
+
+    /// Let up to this many bytes through at once after a pause, instead of strictly pacing every
+    /// byte
+    ///
+    /// Sustained throughput is still limited to the rate, but short bursts (like the redraw after
+    /// a keystroke in an editor) go through without delay. The bytes that could have been sent
+    /// while idle are saved up, to a maximum of <N>.
+    #[arg(long, value_name = "N", default_value = "1",
+        value_parser = clap::value_parser!(u32).range(1 ..))]
+    burst: u32,
+
+    /// Send <N> bytes at a time, less often, instead of one byte at a time
+    ///
+    /// The rate is the same, but each write waits until a whole chunk is allowed. At high rates,
+    /// this takes far fewer writes (and wake-ups), at the cost of smoothness. By default, it's one
+    /// byte, except at rates over 10k, where it's what the rate allows every millisecond, which
+    /// is as fine as the timing gets anyway.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1 ..))]
+    chunk: Option<u32>,
+
+    /// Hold up to <BYTES> in each direction between reading and writing it
+    ///
+    /// By default, only as much is read as will keep the link busy, so the writer has to wait
+    /// for it, as it would for a real link. With a buffer, it can get that far ahead before
+    /// --overflow applies.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1 ..))]
+    buffer_limit: Option<u32>,
+
+    /// What to do once the buffer is full: wait for room, or lose characters as a real terminal
+    /// did on overrun
+    #[arg(long, value_enum, value_name = "POLICY", default_value = "block",
+        requires = "buffer_limit")]
+    overflow: Overflow,
+
+    /// Vary the time each byte takes by up to this many percent either way, so the pacing isn't
+    /// perfectly regular, like an old serial link
+    ///
+    /// The variation is uniformly distributed, so the average rate is unchanged.
+    #[arg(long, value_name = "PCT", value_parser = parse_percentage)]
+    jitter: Option<f64>,
+
+    /// Garble the program's output now and then, like line noise on a modem: each byte has this
+    /// chance (from 0 to 1) of having a bit flipped or being replaced with garbage
+    #[arg(long, value_name = "P", value_parser = parse_probability)]
+    noise: Option<f64>,
+
+    /// Make noise come in bursts of up to <N> bytes, instead of one byte at a time
+    #[arg(long, value_name = "N", requires = "noise",
+        value_parser = clap::value_parser!(u32).range(1 ..))]
+    noise_burst: Option<u32>,
+
+    /// Pass everything through a link with 7 data bits, which loses the high bit of each byte
+    #[arg(long, value_name = "BITS", default_value = "8",
+        value_parser = clap::value_parser!(u8).range(7 ..= 8))]
+    databits: u8,
+
+    /// With --databits 7, take the high bit of bytes that have it set as a parity bit, and replace
+    /// the ones that fail the check with SUB (^Z)
+    ///
+    /// This is what happens when 8-bit data (like UTF-8) meets a 7E1 or 7O1 serial link.
+    #[arg(long, value_enum, default_value = "none")]
+    parity: Parity,
+
+    /// After each line of output, send <N> NULs, which take time to send but print nothing, as
+    /// a teletype needed to give its carriage time to return
+    ///
+    /// A line ends at a CR, or a LF without a CR before it. At 110 baud, --fill-nuls 2 is about
+    /// right for an ASR-33.
+    #[arg(long, value_name = "N", default_value = "0")]
+    fill_nuls: u32,
+
+    /// After each line of output, pause for this long (e.g. 200ms) before the next, as a
+    /// teletype's carriage takes time to return
+    ///
+    /// Lines end as for --fill-nuls.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    cr_delay: Option<Duration>,
+
+    /// Never split a UTF-8 character in the output, which some terminals show as a replacement
+    /// character until the rest of it comes; each one is written whole
+    ///
+    /// By default, each character is charged for by its bytes; with --utf8=chars, <RATE> is in
+    /// characters instead.
+    #[arg(long, value_enum, value_name = "CHARGE", num_args = 0 ..= 1, require_equals = true,
+        default_missing_value = "bytes")]
+    utf8: Option<Utf8Charge>,
+
+    /// Change the colors in the output to the nearest of the ones a terminal with 8, 16 or 256
+    /// colors has
+    ///
+    /// 256-color and true color codes are changed to the basic ones, or to the 256-color palette.
+    #[arg(long, value_enum, value_name = "N")]
+    colors: Option<Colors>,
+
+    /// Take the colors and all the other attributes (SGR sequences) out of the output, as for a
+    /// VT100 without them
+    #[arg(long, conflicts_with = "colors")]
+    monochrome: bool,
+
+    /// Translate the output from an 8-bit character set into UTF-8, for a modern terminal to
+    /// show DOS ANSI art and box drawing (cp437), old Unix text (latin1), or a Commodore 64
+    /// (petscii) or Atari (atascii) BBS as it was meant to look
+    ///
+    /// With petscii and atascii, their color and cursor codes become the ANSI ones, and Return
+    /// and Backspace typed at the console are sent as the machine's own keyboard would send them.
+    #[arg(long, value_enum, value_name = "CHARSET")]
+    charset: Option<Charset>,
+
+    /// Turn each LF in the output into CR LF, unless it already has a CR before it
+    ///
+    /// For output that comes with bare LFs, which stair-step on a console in raw mode: from a
+    /// connection, a recording or a serial device, or a program that has turned off the pty's
+    /// own translation.
+    #[arg(long)]
+    onlcr: bool,
+
+    /// Turn each CR in the output into a LF
+    #[arg(long)]
+    ocrnl: bool,
+
+    /// Have the pty pass the program's line ends on as they are, rather than turning LF into CR LF
+    #[arg(long, conflicts_with_all = ["onlcr", "ocrnl"])]
+    raw_nl: bool,
+
+    /// Change the rate at set times, as listed in a file
+    ///
+    /// Each line of the file has the time since starting (e.g. 30 or 1m30s) and the rate to
+    /// change to then. Blank lines, and anything after a #, are ignored. Until the first change,
+    /// the rate is <RATE>, which is optional with a schedule.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["probe", "then"])]
+    schedule: Option<PathBuf>,
+
+    /// Start at one rate and speed up (or slow down) smoothly to another over a time, as in
+    /// --ramp 110baud..1M/20s
+    ///
+    /// The rate changes by the same factor every tenth of a second, so it seems to speed up
+    /// evenly however far apart the rates are. <RATE> is optional with a ramp.
+    #[arg(long, value_name = "FROM..TO/TIME", value_parser = parse_ramp,
+        conflicts_with_all = ["schedule", "probe", "then"])]
+    ramp: Option<(f64, f64, Duration)>,
+
+    /// Type input into the program from a file, as well as from the keyboard
+    ///
+    /// Each line of the file has a delay in milliseconds, a tab, and the text to type once the
+    /// delay since the line before has passed. In the text, \r, \n, \t, \e (Escape), \xHH and
+    /// \\ stand for those characters; end it with \r to press Enter. Blank lines, and lines
+    /// starting with #, are ignored.
+    #[arg(long, value_name = "FILE")]
+    script: Option<PathBuf>,
+
+    /// Type input (from the keyboard or --script) like a person would: a keystroke at a time,
+    /// with gaps of around 120ms (or the given time) between them, varying, and longer after
+    /// spaces, punctuation and the ends of lines
+    ///
+    /// This is on top of the input rate, if there is one.
+    #[arg(long, value_name = "GAP", num_args = 0 ..= 1, require_equals = true,
+        default_missing_value = "120ms", value_parser = parse_duration)]
+    humanize: Option<Duration>,
+
+    /// Rates to cycle through by pressing Ctrl-] during the session
+    #[arg(long, value_name = "R1,R2,...", action = ArgAction::Set, value_delimiter = ',',
+        value_parser = parse_preset)]
+    rate_presets: Option<Vec<f64>>,
+
+    /// Show status changes (such as the current rate) in the terminal title
+    #[arg(long)]
+    indicate: bool,
+
+    /// Run unthrottled for the given time (e.g. 5s, 500ms), then report the program's natural
+    /// output rate and exit
+    #[arg(short, long, value_name = "DURATION", value_parser = parse_duration)]
+    probe: Option<Duration>,
+
+    /// After probing, continue throttled to this fraction of the measured rate instead of exiting
+    ///
+    /// If there was no output while probing, <RATE> is used instead.
+    #[arg(long, value_name = "FRACTION", value_parser = parse_fraction, group = "then",
+        requires = "probe")]
+    probe_then: Option<f64>,
+
+    /// Run at <P> percent of the program's natural output rate, as measured by a short probe
+    ///
+    /// The probe takes 2s, or as long as given by --probe. The percentage is relative to the
+    /// throughput observed while probing, not to any theoretical maximum; this is the same as
+    /// --probe-then <P/100>.
+    #[arg(long, value_name = "P", value_parser = parse_fraction, group = "then")]
+    percent: Option<f64>,
+
+    /// With --percent or --probe-then, periodically probe again and adjust the rate to match
+    ///
+    /// Each probe lasts as long as the first, and comes after ten times as long throttled.
+    #[arg(long, requires = "then")]
+    percent_adaptive: bool,
+
+    /// When data comes along after the link has been idle, delay it by this much before carrying
+    /// on as normal
+    ///
+    /// This is like a radio that powers down when it's not in use.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    wakeup: Option<Duration>,
+
+    /// How long the link has to be idle for --wakeup to apply
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1s")]
+    wakeup_idle: Duration,
+
+    /// Before the program's output, print a prompt (default "$") and the command line
+    ///
+    /// These are printed at the output rate, as if they had been typed; for recording demos.
+    #[arg(long, value_name = "PROMPT", num_args = 0 ..= 1, require_equals = true)]
+    show_command: Option<Option<String>>,
+
+    /// Before the program's output, wait for 3 seconds (or the given time) as a modem does to
+    /// make its handshake, then print a banner like "CONNECT 2400/ARQ" for the output rate
+    ///
+    /// The banner is printed at the output rate, and the program's output waits until it's done.
+    #[arg(long, value_name = "HANDSHAKE", num_args = 0 ..= 1, require_equals = true,
+        default_missing_value = "3s", value_parser = parse_duration)]
+    connect_banner: Option<Duration>,
+
+    /// Stop throttling after a time (e.g. 30s), after the program has output <N> bytes (e.g.
+    /// 4096B), or once it outputs the given text
+    ///
+    /// Everything is passed through unthrottled from then on. The program stays connected to the
+    /// pty throughout.
+    #[arg(long, value_name = "DURATION|<N>B|match:<TEXT>", value_parser = parse_detach_trigger)]
+    detach_after: Option<DetachTrigger>,
+
+    /// When the output shown matches the regular expression, do something: pause the output,
+    /// change the rate (rate=<RATE>), end the session (exit), or type some text (send:<TEXT>)
+    ///
+    /// This can be given more than once. Each match only counts once, and the text can have
+    /// escapes like \r in it. For example: --on-output '[Pp]assword: ' 'send:hunter2\r'
+    #[arg(long, num_args = 2, value_names = ["REGEX", "ACTION"])]
+    on_output: Vec<String>,
+
+    /// Watch for ZMODEM, XMODEM and YMODEM file transfers, and run them unthrottled (or at the
+    /// given rate)
+    ///
+    /// The usual rate comes back once the transfer finishes, is cancelled, or has been idle for
+    /// a few seconds.
+    #[arg(long, value_name = "RATE", num_args = 0 ..= 1, require_equals = true,
+        value_parser = parse_rate)]
+    transfer_rate: Option<Option<f64>>,
+
+    /// Shortly after starting (200ms, or the given delay), send the program a SIGWINCH
+    ///
+    /// This is for programs that only get the window size right after being resized. If the
+    /// program hasn't set up its handler by then, the signal is ignored and does nothing, so make
+    /// the delay longer for programs that are slow to start.
+    #[arg(long, value_name = "DELAY", num_args = 0 ..= 1, require_equals = true,
+        value_parser = parse_duration)]
+    kick_winch: Option<Option<Duration>>,
+
+    /// Give the program a window this many columns wide, whatever the terminal's size
+    ///
+    /// Without a terminal (or a size from $COLUMNS and $LINES), the window is 80x24.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1 ..))]
+    cols: Option<u16>,
+
+    /// Give the program a window this many rows high, whatever the terminal's size
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1 ..))]
+    rows: Option<u16>,
+
+    /// Give up on the program after this long (e.g. 300 or 5m): send its process group SIGTERM,
+    /// then SIGKILL if it's still there a couple of seconds later
+    ///
+    /// Its output up to then is still passed on, and slowpty exits with status 124, like
+    /// timeout(1).
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+
+    /// End the session once nothing has gone either way for this long (e.g. 300 or 5m), sending
+    /// the program's process group the --idle-signal, then SIGKILL as --timeout does
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    idle_timeout: Option<Duration>,
+
+    /// The signal --idle-timeout sends the program, by name (e.g. HUP) or number
+    #[arg(long, value_name = "SIGNAL", default_value = "TERM", value_parser = parse_signal,
+        requires = "idle_timeout")]
+    idle_signal: libc::c_int,
+
+    /// How to pass on the way the program ended in slowpty's own exit status
+    ///
+    /// Except with always-zero, it's 101 if the program couldn't be started, 124 after
+    /// --timeout, and 255 if how the program ended can't be made out. A signal that ends the
+    /// session (like SIGINT) counts as though it had killed the program.
+    #[arg(long, value_enum, value_name = "MODE", default_value = "128+sig")]
+    exit_status: ExitMode,
+
+    /// What to do with output the program wrote but that hasn't been shown yet, once it exits
+    #[arg(long, value_enum, value_name = "HOW", default_value = "rate")]
+    drain: DrainMode,
+
+    /// What to do when the interrupt character (e.g. Ctrl-C) is typed
+    ///
+    /// Either pass it to the program like any other byte, or send SIGINT to the program directly,
+    /// regardless of the pty's settings.
+    #[arg(long, value_enum, default_value = "byte")]
+    intr: IntrMode,
+
+    /// Stop the output when Ctrl-S (XOFF) is typed, and start it again on Ctrl-Q (XON), like a
+    /// terminal on a serial line would
+    ///
+    /// What the program writes in the meantime is held until the output starts again. Neither
+    /// key is passed on to the program.
+    #[arg(long)]
+    xon_xoff: bool,
+
+    /// Take the key after this one (Ctrl-A, or another given like ^B) as a command to slowpty,
+    /// instead of passing both to the program, as screen and tmux do
+    ///
+    /// The commands are + and - to double and halve the rate, p to stop and start the output, s
+    /// to show how the session is going, q to stop throttling for the rest of the session, b to
+    /// send a break, and with --session or --attach, d to detach. Typing the prefix key twice
+    /// sends it to the program once.
+    #[arg(long, value_name = "KEY", num_args = 0 ..= 1, require_equals = true,
+        default_missing_value = "^A", value_parser = parse_control_key)]
+    prefix_key: Option<u8>,
+
+    /// How long to wait for the rest of an escape sequence (like an arrow key) typed at the
+    /// console
+    ///
+    /// Escape sequences are sent to the program all at once rather than paced a byte at a time,
+    /// so it doesn't mistake them for an Escape keypress followed by other keys. A lone Escape
+    /// keypress is held back this long, in case it's the start of one.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "50ms")]
+    esc_timeout: Duration,
+
+    /// Periodically write the bytes transferred and the achieved rates in each direction to a CSV
+    /// file
+    #[arg(long, value_name = "FILE.CSV")]
+    rate_log: Option<PathBuf>,
+
+    /// How often to write to the rate log
+    #[arg(long, value_name = "DURATION", value_parser = parse_interval, default_value = "1s")]
+    rate_log_interval: Duration,
+
+    /// Write an event to a file for each read, write, would-block, closed endpoint and signal in
+    /// the event loop, and for the end of the session, as newline-delimited JSON with timestamps
+    ///
+    /// This is for analyzing how accurate the pacing was afterwards.
+    #[arg(long, value_name = "FILE")]
+    json_log: Option<PathBuf>,
+
+    /// Write what was typed and what the program printed to a file, interleaved in the order it
+    /// happened, with timestamps
+    #[arg(short, long, value_name = "FILE")]
+    transcript: Option<PathBuf>,
+
+    /// How to lay out the transcript
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "text")]
+    transcript_format: TranscriptFormat,
+
+    /// Record the session to a file that asciinema (or with --record-format ttyrec, ttyplay) can
+    /// play back, at the speed it ran at
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// What kind of recording --record makes
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "cast", requires = "record")]
+    record_format: RecordFormat,
+
+    /// Compress the --record, --script-record and --transcript files as they're written
+    ///
+    /// slowpty replay can play back compressed recordings as they are. A compressed file is only
+    /// complete once the session has ended.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    compress: Option<Compression>,
+
+    /// When the session ends, write what the screen would show to a file, as plain text
+    ///
+    /// The output is run through a terminal emulator the size of the program's window. The file
+    /// has a line for each row, without trailing blanks, and without the empty rows at the
+    /// bottom. This is for checking what a run ended up showing, as in tests.
+    #[arg(long, value_name = "FILE")]
+    snapshot: Option<PathBuf>,
+
+    /// Record the session to a typescript file, like script(1) does
+    #[arg(long, value_name = "TYPESCRIPT")]
+    script_record: Option<PathBuf>,
+
+    /// Write the timing of the --script-record typescript to a file, so scriptreplay(1) can play
+    /// it back at the speed it ran at
+    ///
+    /// With slowpty replay, read the timing of the typescript being played back from this file
+    /// instead.
+    #[arg(long, value_name = "FILE")]
+    timing: Option<PathBuf>,
+
+    /// Copy everything written to the console (what the program printed, as it was shown) to a
+    /// file
+    #[arg(long, value_name = "FILE")]
+    log: Option<PathBuf>,
+
+    /// Copy everything written to the program (what was typed, as it arrived) to a file
+    #[arg(long, value_name = "FILE")]
+    log_input: Option<PathBuf>,
+
+    /// Write --log and --log-input one chunk per line, with the time it was written and the bytes
+    /// escaped, instead of exactly as they were written
+    #[arg(long)]
+    log_timestamps: bool,
+
+    /// Listen on a Unix domain socket at this path for commands to adjust the session while it
+    /// runs
+    ///
+    /// Commands are sent one per line, and each gets a one-line reply: "rate <RATE>" changes the
+    /// rate, "pause" stops everything until "resume", and "stats" shows how much has been
+    /// transferred. For example: echo "rate 9600" | nc -UN <PATH>
+    #[arg(long, value_name = "PATH")]
+    control: Option<PathBuf>,
+
+    /// Run a Rhai script that can act on what goes through, as it does
+    ///
+    /// The script defines whichever of on_input(text), on_output(text), on_rate_change(rate) and
+    /// on_child_exit(status) it wants, and they're called as those happen. They can call
+    /// set_rate(rate), inject(text) to type something into the program, and kill_child() to send
+    /// it SIGTERM, and `this` in them is a map that's kept between calls. Needs slowpty to be
+    /// built with the `scripting` feature.
+    #[arg(long, value_name = "FILE")]
+    hooks: Option<PathBuf>,
+
+    /// Write a line of JSON to this fd for each thing that happens to the session: when it
+    /// starts, when the rate changes, when the program exits, and how much went each way
+    ///
+    /// Each line is an object with an "event" of "session-start" (with "pid", "pty", "cols" and
+    /// "rows"), "rate-change" (with "rate"), "child-exit" (with "code", or "signal" and "name")
+    /// or "bytes-transferred" (with "input" and "output").
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(i32).range(3 ..))]
+    events_fd: Option<i32>,
+
+    /// When the session ends, print how many bytes went each way, how long it took, and the
+    /// effective rate, to stderr
+    #[arg(long)]
+    stats: bool,
+
+    /// Run even if stdin and stdout look like they're connected to each other
+    #[arg(short, long)]
+    force: bool,
+
+    /// Log what's happening to stderr; given twice (-vv), log the details with timestamps too
+    ///
+    /// Only slowpty's own messages are logged. Without this or --quiet, logging is set by
+    /// $RUST_LOG, and is only of errors if that isn't set.
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Don't log anything, not even errors, such as how the program exited
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// On exit, reset the terminal to sane settings (like `stty sane`) instead of restoring the
+    /// ones it had at startup
+    #[arg(long)]
+    reset_sane: bool,
+
+    /// Don't put the terminal in raw mode; just throttle what goes through, as in
+    /// `cat file | slowpty 100 cat`
+    ///
+    /// This is the default when stdin isn't a terminal. The program's input isn't echoed, its
+    /// output is passed through untranslated, and the end of stdin is passed on as the end of
+    /// its input (though being line-buffered, input lines over 4095 bytes are cut short).
+    #[arg(long)]
+    no_raw: bool,
+}
+
+/// How to run the program.
+#[derive(clap::Args, Default)]
+struct Program {
+    /// Set an environment variable for the program (can be given more than once)
+    #[arg(long, value_name = "KEY=VAL", value_parser = parse_env)]
+    env: Vec<(OsString, OsString)>,
+
+    /// Start the program with an empty environment, apart from what --env sets
+    #[arg(long)]
+    env_clear: bool,
+
+    /// Run the program in this directory
+    #[arg(long, value_name = "DIR")]
+    chdir: Option<PathBuf>,
+
+    /// Leave the program's stderr where slowpty's goes, instead of on the pty, so what it writes
+    /// there isn't throttled or mixed in with its output
+    ///
+    /// Best with stderr redirected (as in `2>errors.log`); on the terminal, it's written while
+    /// that's in raw mode.
+    #[arg(long)]
+    separate_stderr: bool,
+}
+
+/// The ways of running slowpty other than on the console it's started from.
+#[derive(Subcommand)]
+enum Mode {
+    /// Listen for connections, and run the program for each one, with the rate limits between
+    /// it and the client
+    ///
+    /// The terminal slowpty is started from isn't used.
+    #[command(group(ArgGroup::new("listener").required(true).args(["telnet", "listen"])))]
+    Serve {
+        /// Listen for telnet connections on this TCP port
+        ///
+        /// The client is asked for binary mode in both directions, character-at-a-time input
+        /// echoed by the program's terminal, and its window size.
+        #[arg(long, value_name = "PORT", conflicts_with = "no_raw")]
+        telnet: Option<u16>,
+
+        /// Listen on a Unix domain socket at this path
+        ///
+        /// Each connection gets a session of its own, in its own process. The client is expected
+        /// to be a terminal in raw mode, as with `socat STDIO,raw,echo=0 UNIX-CONNECT:<PATH>`.
+        /// With --control, each session gets its own control socket, named after the given path
+        /// with a dot and the session's process ID added, so each one's rate can be changed by
+        /// itself.
+        #[arg(long, value_name = "PATH", conflicts_with = "no_raw")]
+        listen: Option<PathBuf>,
+
+        #[command(flatten)]
+        shared: Shared,
+
+        #[command(flatten)]
+        program: Program,
+
+        /// The rate (unless it's optional), then the program to run and its arguments
+        #[arg(value_name = "ARGS", required = true, trailing_var_arg = true)]
+        command: Vec<OsString>,
+    },
+
+    /// Connect to a TCP server and throttle what goes to and from it, as with a MUD or a BBS
+    /// reached over a modem, instead of running a program
+    ///
+    /// The connection is raw, like netcat; nothing is done about telnet commands. The session
+    /// ends when the server closes the connection, or slowpty gets SIGINT, SIGTERM or SIGHUP.
+    Connect {
+        /// The server to connect to
+        #[arg(value_name = "HOST:PORT")]
+        address: String,
+
+        /// The rate, unless it's optional
+        rate: Option<OsString>,
+
+        #[command(flatten)]
+        shared: Shared,
+    },
+
+    /// Play back a recording, instead of running a program: an asciicast (from --record or
+    /// asciinema), a ttyrec, a transcript from --transcript, or a typescript from script(1) or
+    /// --script-record along with its --timing file
+    ///
+    /// The output is shown with the timing it was recorded with, as well as the rate limits
+    /// allow. Anything else is shown as it is, as fast as the rate allows. Press q or Ctrl-C to
+    /// stop.
+    Replay {
+        /// The recording to play back
+        #[arg(value_name = "FILE", conflicts_with = "script")]
+        file: PathBuf,
+
+        /// What kind of recording it is
+        #[arg(long, value_enum, value_name = "FORMAT", default_value = "auto")]
+        format: ReplayFormat,
+
+        /// Play it back this many times as fast as it was recorded, as in 0.5 for half speed
+        #[arg(long, value_name = "FACTOR", value_parser = parse_fraction, default_value = "1")]
+        speed: f64,
+
+        /// The rate, unless it's optional
+        rate: Option<OsString>,
+
+        #[command(flatten)]
+        shared: Shared,
+    },
+
+    /// Open a serial device and throttle what goes to and from it, as though the line to it were
+    /// slower (or noisier) than it is, instead of running a program
+    ///
+    /// The device is set up to pass bytes through untouched, ignoring carrier, at its current
+    /// speed unless --speed is given. The session ends when the device goes away, or slowpty gets
+    /// SIGINT, SIGTERM or SIGHUP.
+    Tty {
+        /// The serial device, as in /dev/ttyS0
+        #[arg(value_name = "DEVICE")]
+        device: PathBuf,
+
+        /// The speed to set the device to
+        #[arg(long, value_name = "BAUD", value_parser = parse_serial_speed)]
+        speed: Option<u32>,
+
+        /// The rate, unless it's optional
+        rate: Option<OsString>,
+
+        #[command(flatten)]
+        shared: Shared,
+    },
+}
+
+const USAGE: &str = "\
+slowpty [OPTIONS] <RATE> <PROGRAM> [ARGS]...
+       slowpty --probe <DURATION> [OPTIONS] [RATE] <PROGRAM> [ARGS]...
+       slowpty --percent <P> [OPTIONS] [RATE] <PROGRAM> [ARGS]...
+       slowpty --baud <BAUD> [OPTIONS] <PROGRAM> [ARGS]...
+       slowpty --in-rate <RATE>|--out-rate <RATE> [OPTIONS] <PROGRAM> [ARGS]...
+       slowpty serve --telnet <PORT>|--listen <PATH> [OPTIONS] <RATE> <PROGRAM> [ARGS]...
+       slowpty connect [OPTIONS] <HOST:PORT> [RATE]
+       slowpty replay [OPTIONS] <FILE> [RATE]
+       slowpty tty [OPTIONS] <DEVICE> [RATE]
+       slowpty --cat [OPTIONS] <RATE>
+       slowpty --modem [OPTIONS] <RATE> [PROGRAM] [ARGS]...
+       slowpty --attach <NAME> [--prefix-key[=KEY]]";
+
+impl Options {
+    /// Parse the command line. Errors, and requests for help or the version, come back as clap
+    /// errors, which know how to print themselves and exit.
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self, clap::Error> {
+        let top = Args::try_parse_from(args)?;
+        // What sets the mode apart; the rest is the same in every mode.
+        let mut mode = Options {
+            modem: top.modem,
+            cat: top.cat,
+            session: top.session,
+            attach: top.attach,
+            ..Options::default()
+        };
+        let (args, program, command) = match top.mode {
+            None => (top.shared, top.program, top.command),
+            Some(Mode::Serve { telnet, listen, shared, program, command }) => {
+                (mode.telnet, mode.listen) = (telnet, listen);
+                (shared, program, command)
+            }
+            Some(Mode::Connect { address, rate, shared }) => {
+                mode.connect = Some(address);
+                (shared, Program::default(), Vec::from_iter(rate))
+            }
+            Some(Mode::Replay { file, format, speed, rate, shared }) => {
+                (mode.replay, mode.replay_format, mode.replay_speed) = (Some(file), format, speed);
+                (shared, Program::default(), Vec::from_iter(rate))
+            }
+            Some(Mode::Tty { device, speed, rate, shared }) => {
+                (mode.serial, mode.serial_speed) = (Some(device), speed);
+                (shared, Program::default(), Vec::from_iter(rate))
+            }
+        };
+        let invalid = |e| Args::command().error(ErrorKind::ValueValidation, e);
+        let schedule = match (&args.schedule, args.ramp) {
+            (Some(path), _) => read_schedule(path).map_err(invalid)?,
+            (None, Some((from, to, time))) => ramp(from, to, time),
+            (None, None) => vec![],
+        };
+        let script = args.script.as_deref().map(read_script).transpose().map_err(invalid)?;
+        if args.rate_presets.as_ref().is_some_and(|presets| presets.len() < 2) {
+            return Err(Args::command().error(ErrorKind::TooFewValues,
+                "--rate-presets needs at least two rates to cycle through"));
+        }
+        let mut command = command.into_iter().peekable();
+        let rate_arg = command.next();
+
+        let mut o = Options {
+            rate: None,
+            in_rate: args.in_rate,
+            out_rate: args.out_rate,
+            direction: args.direction,
+            in_latency: args.in_latency.or(args.latency).unwrap_or_default(),
+            out_latency: args.out_latency.or(args.latency).unwrap_or_default(),
+            shared_rate: args.shared_rate,
+            burst: args.burst,
+            chunk: args.chunk,
+            buffer_limit: args.buffer_limit,
+            overflow: args.overflow,
+            jitter: args.jitter.map_or(0., |pct| pct / 100.),
+            noise: args.noise.unwrap_or(0.),
+            noise_burst: args.noise_burst.unwrap_or(1),
+            data_bits: args.databits,
+            parity: args.parity,
+            fill_nuls: args.fill_nuls,
+            cr_delay: args.cr_delay.unwrap_or_default(),
+            utf8: args.utf8,
+            colors: if args.monochrome { Some(Colors::Monochrome) } else { args.colors },
+            charset: args.charset,
+            onlcr: args.onlcr,
+            ocrnl: args.ocrnl,
+            raw_nl: args.raw_nl,
+            schedule,
+            script: script.unwrap_or_default(),
+            humanize: args.humanize,
+            rate_presets: args.rate_presets.unwrap_or_default(),
+            indicate: args.indicate,
+            probe: args.probe,
+            probe_then: args.probe_then,
+            probe_adaptive: args.percent_adaptive,
+            wakeup: args.wakeup,
+            wakeup_idle: args.wakeup_idle,
+            show_command: args.show_command.map(|p| p.unwrap_or_else(|| "$".to_owned())),
+            connect_banner: args.connect_banner,
+            detach_after: args.detach_after,
+            on_output: vec![],
+            transfer_rate: args.transfer_rate.map(|rate| rate.unwrap_or(f64::INFINITY)),
+            kick_winch: args.kick_winch.map(|d| d.unwrap_or(DEFAULT_KICK_WINCH_DELAY)),
+            cols: args.cols,
+            rows: args.rows,
+            timeout: args.timeout,
+            idle_timeout: args.idle_timeout,
+            idle_signal: args.idle_signal,
+            exit_status: args.exit_status,
+            drain: args.drain,
+            intr: args.intr,
+            xon_xoff: args.xon_xoff,
+            prefix_key: args.prefix_key,
+            esc_timeout: args.esc_timeout,
+            rate_log: args.rate_log,
+            rate_log_interval: args.rate_log_interval,
+            json_log: args.json_log,
+            transcript: args.transcript,
+            transcript_format: args.transcript_format,
+            record: args.record,
+            record_format: args.record_format,
+            compress: args.compress,
+            snapshot: args.snapshot,
+            script_record: args.script_record,
+            timing: args.timing,
+            log_output: args.log,
+            log_input: args.log_input,
+            log_timestamps: args.log_timestamps,
+            control: args.control,
+            hooks: args.hooks,
+            events_fd: args.events_fd,
+            stats: args.stats,
+            force: args.force,
+            verbose: args.verbose,
+            quiet: args.quiet,
+            reset_sane: args.reset_sane,
+            no_raw: args.no_raw,
+            env: program.env,
+            env_clear: program.env_clear,
+            chdir: program.chdir,
+            separate_stderr: program.separate_stderr,
+            command: vec![],
+            ..mode
+        };
+
+        if let Some(percent) = args.percent {
+            o.probe_then = Some(percent / 100.);
+            o.probe.get_or_insert(DEFAULT_PERCENT_PROBE);
+        }
+
+        let rate_optional = o.probe.is_some() || o.in_rate.is_some() || o.out_rate.is_some()
+            || !o.schedule.is_empty();
+        // Only without a program to run can there be nothing here at all.
+        if let Some(rate_arg) = rate_arg {
+            match parse_rate(&rate_arg.to_string_lossy()) {
+                Ok(_) if args.baud.is_some() => {
+                    return Err(Args::command().error(ErrorKind::ArgumentConflict,
+                        "a rate can't be given along with --baud"));
+                }
+                // With --baud, this is the program.
+                Err(_) if args.baud.is_some() => o.command.push(rate_arg),
+                Ok(rate) => {
+                    o.rate = Some(rate);
+                    // clap only takes "--" to end the options before the first positional
+                    // argument, but it's just as natural to put it between the rate and the
+                    // program.
+                    command.next_if_eq("--");
+                }
+                // When probing, or when a direction has its own rate, the rate is optional,
+                // so this is the program instead.
+                Err(_) if rate_optional => o.command.push(rate_arg),
+                Err(e) => {
+                    return Err(Args::command().error(ErrorKind::ValueValidation,
+                        format!("invalid rate {rate_arg:?}: {e}")));
+                }
+            }
+        }
+
+        for pair in args.on_output.chunks(2) {
+            let [regex, action] = pair else { unreachable!() };
+            let regex = Regex::new(regex).map_err(|e| Args::command()
+                .error(ErrorKind::ValueValidation, format!("invalid --on-output pattern: {e}")))?;
+            if regex.is_match(b"") {
+                return Err(Args::command().error(ErrorKind::ValueValidation,
+                    format!("--on-output pattern {:?} matches nothing at all", regex.as_str())));
+            }
+            let action = parse_output_action(action).map_err(|e| Args::command()
+                .error(ErrorKind::ValueValidation, format!("invalid --on-output action: {e}")))?;
+            o.on_output.push((regex, action));
+        }
+
+        if o.compress.is_some() && o.record.is_none() && o.script_record.is_none()
+            && o.transcript.is_none()
+        {
+            return Err(Args::command().error(ErrorKind::MissingRequiredArgument,
+                "--compress needs something to compress: --record, --script-record or \
+                --transcript"));
+        }
+
+        if o.parity != Parity::None && o.data_bits != 7 {
+            return Err(Args::command().error(ErrorKind::ArgumentConflict,
+                "--parity only goes with --databits 7"));
+        }
+
+        if let Some(baud) = args.baud {
+            o.rate = Some(f64::from(baud) / args.framing.bits());
+        }
+
+        if o.timing.is_some() {
+            match (o.script_record.is_some(), o.replay.is_some(), o.replay_format) {
+                (true, false, _) => (),
+                (false, true, ReplayFormat::Auto) => o.replay_format = ReplayFormat::Script,
+                (false, true, ReplayFormat::Script) => (),
+                _ => {
+                    return Err(Args::command().error(ErrorKind::ArgumentConflict,
+                        "--timing goes with either --script-record or replay --format script"));
+                }
+            }
+        } else if o.replay_format == ReplayFormat::Script {
+            return Err(Args::command().error(ErrorKind::MissingRequiredArgument,
+                "replay --format script needs the --timing file too"));
+        }
+
+        o.command.extend(command);
+        let no_program = o.connect.is_some() || o.replay.is_some() || o.cat || o.serial.is_some()
+            || o.attach.is_some();
+        match (no_program, o.command.is_empty()) {
+            (true, false) => {
+                return Err(Args::command().error(ErrorKind::ArgumentConflict,
+                    "a program can't be given along with --cat, or to connect, replay or tty"));
+            }
+            (false, true) if !o.modem => {
+                return Err(Args::command().error(ErrorKind::MissingRequiredArgument,
+                    "no program to run was given"));
+            }
+            _ => (),
+        }
+
+        Ok(o)
+    }
+}
+
+/// A command line for the tests, split on spaces.
+#[cfg(test)]
+fn args(s: &str) -> Vec<OsString> {
+    s.split(' ').map(OsString::from).collect()
+}
+
+#[test]
+fn test_parse_percent() {
+    let Ok(o) = Options::parse(args("slowpty --percent 50 cat")) else { panic!() };
+    assert_eq!(o.rate, None);
+    assert_eq!(o.probe, Some(DEFAULT_PERCENT_PROBE));
+    assert_eq!(o.probe_then, Some(0.5));
+    assert_eq!(o.command, args("cat"));
+
+    let Ok(o) = Options::parse(args("slowpty --probe 5s --percent 200 300 cat")) else {
+        panic!()
+    };
+    assert_eq!(o.rate, Some(300.));
+    assert_eq!(o.probe, Some(Duration::from_secs(5)));
+    assert_eq!(o.probe_then, Some(2.));
+
+    assert!(Options::parse(args("slowpty --percent-adaptive 300 cat")).is_err());
+}
+
+/// The command line, quoted like a shell would need it to be.
+pub fn display_command(command: &[OsString]) -> String {
+    let quote = |arg: &OsString| {
+        let arg = arg.to_string_lossy();
+        let plain = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+        if !arg.is_empty() && arg.chars().all(plain) {
+            arg.into_owned()
+        } else {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        }
+    };
+    command.iter().map(quote).collect::<Vec<_>>().join(" ")
+}
+
+#[test]
+fn test_display_command() {
+    let command = ["ls", "-l", "my file", "", "it's"].map(OsString::from);
+    assert_eq!(display_command(&command), r"ls -l 'my file' '' 'it'\''s'");
+}
+
+#[test]
+fn test_parse_direction_rates() {
+    let Ok(o) = Options::parse(args("slowpty --out-rate 240 less file")) else { panic!() };
+    assert_eq!((o.rate, o.in_rate, o.out_rate), (None, None, Some(240.)));
+    assert_eq!(o.command, args("less file"));
+
+    let Ok(o) = Options::parse(args("slowpty --in-rate 10 300 cat")) else { panic!() };
+    assert_eq!((o.rate, o.in_rate, o.out_rate), (Some(300.), Some(10.), None));
+
+    assert!(Options::parse(args("slowpty cat")).is_err());
+
+    let Ok(o) = Options::parse(args("slowpty --direction out 300 cat")) else { panic!() };
+    assert_eq!((o.rate, o.direction), (Some(300.), Direction::Out));
+    assert!(Options::parse(args("slowpty --direction in --out-rate 5 300 cat")).is_err());
+    assert!(Options::parse(args("slowpty --direction out -s 300 cat")).is_err());
+}
+
+#[test]
+fn test_parse_exit_status() {
+    let Ok(o) = Options::parse(args("slowpty 300 cat")) else { panic!() };
+    assert_eq!(o.exit_status, ExitMode::Shell);
+    let Ok(o) = Options::parse(args("slowpty --exit-status 128+sig 300 cat")) else { panic!() };
+    assert_eq!(o.exit_status, ExitMode::Shell);
+    let Ok(o) = Options::parse(args("slowpty --exit-status always-zero 300 cat")) else {
+        panic!()
+    };
+    assert_eq!((o.exit_status, o.drain), (ExitMode::AlwaysZero, DrainMode::Rate));
+    let Ok(o) = Options::parse(args("slowpty --drain discard 300 cat")) else { panic!() };
+    assert_eq!(o.drain, DrainMode::Discard);
+}
+
+#[test]
+fn test_parse_utf8() {
+    let Ok(o) = Options::parse(args("slowpty --utf8 300 cat")) else { panic!() };
+    assert_eq!((o.utf8, o.rate), (Some(Utf8Charge::Bytes), Some(300.)));
+    let Ok(o) = Options::parse(args("slowpty --utf8=chars 300 cat")) else { panic!() };
+    assert_eq!(o.utf8, Some(Utf8Charge::Chars));
+}
+
+#[test]
+fn test_parse_buffer_limit() {
+    let Ok(o) = Options::parse(args("slowpty --buffer-limit 64 300 ls")) else { panic!() };
+    assert_eq!((o.buffer_limit, o.overflow), (Some(64), Overflow::Block));
+    let Ok(o) = Options::parse(args("slowpty --buffer-limit 64 --overflow drop-oldest 300 ls"))
+    else {
+        panic!()
+    };
+    assert_eq!(o.overflow, Overflow::DropOldest);
+    assert!(Options::parse(args("slowpty --overflow drop-newest 300 ls")).is_err());
+    assert!(Options::parse(args("slowpty --buffer-limit 0 300 ls")).is_err());
+}
+
+#[test]
+fn test_parse_colors() {
+    let Ok(o) = Options::parse(args("slowpty --colors 16 300 ls")) else { panic!() };
+    assert_eq!(o.colors, Some(Colors::Sixteen));
+    let Ok(o) = Options::parse(args("slowpty --monochrome 300 ls")) else { panic!() };
+    assert_eq!(o.colors, Some(Colors::Monochrome));
+    assert!(Options::parse(args("slowpty --colors 88 300 ls")).is_err());
+    assert!(Options::parse(args("slowpty --colors 8 --monochrome 300 ls")).is_err());
+
+    let Ok(o) = Options::parse(args("slowpty --charset cp437 300 ls")) else { panic!() };
+    assert_eq!(o.charset, Some(Charset::Cp437));
+    assert!(Options::parse(args("slowpty --charset ebcdic 300 ls")).is_err());
+}
+
+#[test]
+fn test_parse_newlines() {
+    let Ok(o) = Options::parse(args("slowpty tty --onlcr --ocrnl /dev/ttyS0 2400baud")) else {
+        panic!()
+    };
+    assert_eq!((o.onlcr, o.ocrnl, o.raw_nl), (true, true, false));
+    let Ok(o) = Options::parse(args("slowpty --raw-nl 300 ls")) else { panic!() };
+    assert!(o.raw_nl);
+    assert!(Options::parse(args("slowpty --raw-nl --onlcr 300 ls")).is_err());
+}
+
+#[test]
+fn test_parse_cat() {
+    let Ok(o) = Options::parse(args("slowpty --cat 30")) else { panic!() };
+    assert_eq!((o.cat, o.rate), (true, Some(30.)));
+    assert!(o.command.is_empty());
+    assert!(Options::parse(args("slowpty --cat 30 cat")).is_err());
+    assert!(Options::parse(args("slowpty --cat --in-rate 5 30")).is_err());
+}
+
+#[test]
+fn test_parse_verbosity() {
+    let Ok(o) = Options::parse(args("slowpty -vv 1M sh")) else { panic!() };
+    assert_eq!((o.verbose, o.quiet), (2, false));
+    let Ok(o) = Options::parse(args("slowpty --verbose 1M sh")) else { panic!() };
+    assert_eq!((o.verbose, o.quiet), (1, false));
+    let Ok(o) = Options::parse(args("slowpty -q 1M sh")) else { panic!() };
+    assert_eq!((o.verbose, o.quiet), (0, true));
+    assert!(Options::parse(args("slowpty -v -q 1M sh")).is_err());
+}
+
+#[test]
+fn test_parse_events_fd() {
+    let Ok(o) = Options::parse(args("slowpty --events-fd 5 1M sh")) else { panic!() };
+    assert_eq!(o.events_fd, Some(5));
+    let Ok(o) = Options::parse(args("slowpty 1M sh")) else { panic!() };
+    assert_eq!(o.events_fd, None);
+    // Not stdin, stdout or stderr.
+    assert!(Options::parse(args("slowpty --events-fd 1 1M sh")).is_err());
+}
+
+#[test]
+fn test_parse_record() {
+    let Ok(o) = Options::parse(args("slowpty --record a.cast 1M sh")) else { panic!() };
+    assert_eq!(o.record_format, RecordFormat::Cast);
+    let Ok(o) = Options::parse(args("slowpty --record a.tty --record-format ttyrec 1M sh"))
+        else { panic!() };
+    assert_eq!(o.record_format, RecordFormat::Ttyrec);
+    assert!(Options::parse(args("slowpty --record-format ttyrec 1M sh")).is_err());
+
+    let Ok(o) = Options::parse(args("slowpty --transcript t.gz --compress gzip 1M sh"))
+        else { panic!() };
+    assert_eq!(o.compress, Some(Compression::Gzip));
+    assert!(Options::parse(args("slowpty --compress zstd 1M sh")).is_err());
+    assert!(Options::parse(args("slowpty --record a.cast --compress lzma 1M sh")).is_err());
+}
+
+#[test]
+fn test_parse_replay() {
+    let Ok(o) = Options::parse(args("slowpty replay --timing tm --speed 0.5 ts 30")) else {
+        panic!()
+    };
+    assert_eq!(o.replay, Some(PathBuf::from("ts")));
+    assert_eq!((o.replay_format, o.replay_speed), (ReplayFormat::Script, 0.5));
+    let Ok(o) = Options::parse(args("slowpty replay a.cast 30")) else { panic!() };
+    assert_eq!((o.replay_format, o.replay_speed), (ReplayFormat::Auto, 1.));
+    assert!(Options::parse(args("slowpty replay --format script ts 30")).is_err());
+    assert!(Options::parse(args("slowpty replay --format cast --timing tm a.cast 30")).is_err());
+    assert!(Options::parse(args("slowpty --timing tm 30 cat")).is_err());
+    assert!(Options::parse(args("slowpty --speed 2 30 cat")).is_err());
+    assert!(Options::parse(args("slowpty replay --speed 0 a.cast 30")).is_err());
+    assert!(Options::parse(args("slowpty replay --script keys a.cast 30")).is_err());
+    assert!(Options::parse(args("slowpty replay a.cast 30 cat")).is_err());
+}
+
+#[test]
+fn test_parse_session() {
+    let Ok(o) = Options::parse(args("slowpty --session build 30 make")) else { panic!() };
+    assert_eq!((o.session.as_deref(), o.rate), (Some("build"), Some(30.)));
+    let Ok(o) = Options::parse(args("slowpty --attach build --prefix-key")) else { panic!() };
+    assert_eq!((o.attach.as_deref(), o.prefix_key), (Some("build"), Some(0x01)));
+    assert!(Options::parse(args("slowpty --attach build 30")).is_err());
+    assert!(Options::parse(args("slowpty --session ../x 30 make")).is_err());
+    assert!(Options::parse(args("slowpty --session a --cat 30")).is_err());
+}
+
+#[test]
+fn test_parse_modem() {
+    let Ok(o) = Options::parse(args("slowpty --modem 2400baud")) else { panic!() };
+    assert!(o.modem && o.command.is_empty());
+    assert_eq!(o.rate, Some(240.));
+    let Ok(o) = Options::parse(args("slowpty --modem 240 login")) else { panic!() };
+    assert_eq!(o.command, args("login"));
+    assert!(Options::parse(args("slowpty --modem --cat 240")).is_err());
+}
+
+#[test]
+fn test_parse_serial() {
+    let Ok(o) = Options::parse(args("slowpty tty --speed 9600 /dev/ttyS0 30")) else { panic!() };
+    assert_eq!(o.serial, Some(PathBuf::from("/dev/ttyS0")));
+    assert_eq!((o.serial_speed, o.rate), (Some(9600), Some(30.)));
+    let Ok(o) = Options::parse(args("slowpty tty /dev/ttyUSB0")) else { panic!() };
+    assert_eq!((o.serial_speed, o.rate), (None, None));
+    assert!(Options::parse(args("slowpty tty --speed 14400 /dev/ttyS0 30")).is_err());
+    assert!(Options::parse(args("slowpty tty /dev/ttyS0 30 cu")).is_err());
+    assert!(Options::parse(args("slowpty --serial /dev/ttyS0 30")).is_err());
+}
+
+#[test]
+fn test_parse_latency() {
+    let ms = Duration::from_millis;
+    let Ok(o) = Options::parse(args("slowpty --latency 300ms 1M cat")) else { panic!() };
+    assert_eq!((o.in_latency, o.out_latency), (ms(300), ms(300)));
+
+    let Ok(o) = Options::parse(args("slowpty --latency 300ms --in-latency 0 1M cat")) else {
+        panic!()
+    };
+    assert_eq!((o.in_latency, o.out_latency), (ms(0), ms(300)));
+}
+
+#[test]
+fn test_parse_command() {
+    for line in ["slowpty 300 ls -l --color", "slowpty --force -- 300 ls -l --color",
+        "slowpty -f 300 -- ls -l --color"]
+    {
+        let Ok(o) = Options::parse(args(line)) else { panic!("{line}") };
+        assert_eq!(o.rate, Some(300.));
+        assert_eq!(o.command, args("ls -l --color"), "{line}");
+    }
+
+    let Ok(o) = Options::parse(args("slowpty --out-rate 2k -- -weird-name --kick-winch")) else {
+        panic!()
+    };
+    assert_eq!(o.command, args("-weird-name --kick-winch"));
+    assert_eq!(o.kick_winch, None);
+
+    let Ok(o) = Options::parse(args("slowpty --kick-winch --show-command=% 300 vi")) else {
+        panic!()
+    };
+    assert_eq!(o.kick_winch, Some(DEFAULT_KICK_WINCH_DELAY));
+    assert_eq!(o.show_command.as_deref(), Some("%"));
+
+    let Ok(o) = Options::parse(args("slowpty --transfer-rate 300 sh")) else { panic!() };
+    assert_eq!(o.transfer_rate, Some(f64::INFINITY));
+    let Ok(o) = Options::parse(args("slowpty --transfer-rate=56kbps 300 sh")) else { panic!() };
+    assert_eq!(o.transfer_rate, Some(5600.));
+
+    assert!(Options::parse(args("slowpty 300")).is_err());
+    assert!(Options::parse(args("slowpty --bogus 300 cat")).is_err());
+
+    // After the options, a program named like a subcommand is just a program.
+    let Ok(o) = Options::parse(args("slowpty -f 300 serve")) else { panic!() };
+    assert_eq!(o.command, args("serve"));
+}
+
+#[test]
+fn test_parse_serve() {
+    let Ok(o) = Options::parse(args("slowpty serve --telnet 2323 -s 2400baud bash -l")) else {
+        panic!()
+    };
+    assert_eq!((o.telnet, o.listen), (Some(2323), None));
+    assert_eq!((o.rate, o.shared_rate), (Some(240.), true));
+    assert_eq!(o.command, args("bash -l"));
+
+    let Ok(o) = Options::parse(args("slowpty serve --listen /tmp/bbs --env-clear 300 sh")) else {
+        panic!()
+    };
+    assert_eq!((o.telnet, o.listen), (None, Some(PathBuf::from("/tmp/bbs"))));
+    assert!(o.env_clear);
+
+    assert!(Options::parse(args("slowpty serve 300 sh")).is_err());
+    assert!(Options::parse(args("slowpty serve --telnet 23 --listen /tmp/bbs 300 sh")).is_err());
+    assert!(Options::parse(args("slowpty serve --telnet 23 --no-raw 300 sh")).is_err());
+    assert!(Options::parse(args("slowpty serve --telnet 23 300")).is_err());
+    assert!(Options::parse(args("slowpty --telnet 23 300 sh")).is_err());
+}
+
+#[test]
+fn test_parse_connect() {
+    let Ok(o) = Options::parse(args("slowpty connect -s bbs.example.com:23 1200baud")) else {
+        panic!()
+    };
+    assert_eq!(o.connect.as_deref(), Some("bbs.example.com:23"));
+    assert_eq!((o.rate, o.shared_rate), (Some(120.), true));
+    assert!(o.command.is_empty());
+
+    let Ok(o) = Options::parse(args("slowpty connect --out-rate 300 localhost:4000")) else {
+        panic!()
+    };
+    assert_eq!((o.rate, o.out_rate), (None, Some(300.)));
+
+    assert!(Options::parse(args("slowpty connect localhost:4000 300 telnet")).is_err());
+    assert!(Options::parse(args("slowpty connect --env A=b localhost:4000 300")).is_err());
+    assert!(Options::parse(args("slowpty connect")).is_err());
+}
+
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    // Bits per second on an 8N1 line, which takes 10 bits to send a byte.
+    let (s, bits) = [("bps", 10.), ("baud", 10.), ("cps", 1.)]
+        .into_iter()
+        .find_map(|(unit, bits)| Some((s.strip_suffix(unit)?, bits)))
+        .unwrap_or((s, 1.));
+    let (number, scale) = match s.char_indices().last() {
+        Some((i, 'k')) => (&s[.. i], 1e3),
+        Some((i, 'M')) => (&s[.. i], 1e6),
+        Some((i, 'G')) => (&s[.. i], 1e9),
+        _ => (s, 1.),
+    };
+    let rate: f64 = number.parse()
+        .map_err(|e| format!("invalid number for the rate: {e}"))?;
+    if rate.is_nan() || rate <= 0. {
+        return Err("rate must be greater than zero.".to_owned());
+    }
+    Ok(rate * scale / bits)
+}
+
+#[test]
+fn test_parse_rate() {
+    assert_eq!(parse_rate("300"), Ok(300.));
+    assert_eq!(parse_rate("2.5k"), Ok(2500.));
+    assert_eq!(parse_rate("1M"), Ok(1e6));
+    assert_eq!(parse_rate("1G"), Ok(1e9));
+    assert!(parse_rate("0k").is_err());
+    assert!(parse_rate("k").is_err());
+    assert!(parse_rate("5 kB").is_err());
+    assert_eq!(parse_rate("9600bps"), Ok(960.));
+    assert_eq!(parse_rate("2400baud"), Ok(240.));
+    assert_eq!(parse_rate("56kbps"), Ok(5600.));
+    assert_eq!(parse_rate("1.2k"), Ok(1200.));
+    assert_eq!(parse_rate("300cps"), Ok(300.));
+    assert!(parse_rate("bps").is_err());
+}
+
+/// Parse a duration like "5s", "250ms", "2m", or a plain number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.trim().parse()
+        .map_err(|e| format!("invalid duration {s:?}: {e}"))?;
+    let scale = match unit {
+        "" | "s" => 1.,
+        "ms" => 1e-3,
+        "us" => 1e-6,
+        "m" => 60.,
+        "h" => 3600.,
+        _ => return Err(format!("invalid duration {s:?}: unknown unit {unit:?}")),
+    };
+    Duration::try_from_secs_f64(number * scale)
+        .map_err(|e| format!("invalid duration {s:?}: {e}"))
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("5").unwrap(), Duration::from_secs(5));
+    assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+    assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+    assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+    assert!(parse_duration("-1s").is_err());
+    assert!(parse_duration("5 parsecs").is_err());
+    assert!(parse_duration("ms").is_err());
+}
+
+fn parse_detach_trigger(s: &str) -> Result<DetachTrigger, String> {
+    if let Some(text) = s.strip_prefix("match:") {
+        if text.is_empty() {
+            return Err("nothing to match".to_owned());
+        }
+        return Ok(DetachTrigger::OutputMatch(text.as_bytes().to_vec()));
+    }
+    if let Some(bytes) = s.strip_suffix('B') {
+        return bytes.parse().map(DetachTrigger::OutputBytes)
+            .map_err(|e| format!("invalid byte count {s:?}: {e}"));
+    }
+    parse_duration(s).map(DetachTrigger::Elapsed)
+}
+
+#[test]
+fn test_parse_detach_trigger() {
+    assert_eq!(parse_detach_trigger("30s"), Ok(DetachTrigger::Elapsed(Duration::from_secs(30))));
+    assert_eq!(parse_detach_trigger("4096B"), Ok(DetachTrigger::OutputBytes(4096)));
+    assert_eq!(parse_detach_trigger("match:$ "), Ok(DetachTrigger::OutputMatch(b"$ ".to_vec())));
+    assert!(parse_detach_trigger("match:").is_err());
+    assert!(parse_detach_trigger("-5B").is_err());
+    assert!(parse_detach_trigger("soon").is_err());
+}
+
+fn parse_output_action(s: &str) -> Result<OutputAction, String> {
+    if let Some(rate) = s.strip_prefix("rate=") {
+        return parse_rate(rate).map(OutputAction::Rate);
+    }
+    if let Some(text) = s.strip_prefix("send:") {
+        return crate::replay::unescape_ascii(text).map(OutputAction::Send)
+            .ok_or_else(|| format!("invalid escape in {text:?}"));
+    }
+    match s {
+        "pause" => Ok(OutputAction::Pause),
+        "exit" => Ok(OutputAction::Exit),
+        _ => Err(format!("{s:?}: expected pause, rate=<RATE>, exit or send:<TEXT>")),
+    }
+}
+
+#[test]
+fn test_parse_on_output() {
+    let Ok(o) = Options::parse(args("slowpty --on-output ^login: rate=300 \
+            --on-output [Pp]assword: send:secret\\r 1M login")) else { panic!() };
+    let on_output: Vec<_> = o.on_output.iter()
+        .map(|(regex, action)| (regex.as_str(), action.clone()))
+        .collect();
+    assert_eq!(on_output, [("^login:", OutputAction::Rate(300.)),
+        ("[Pp]assword:", OutputAction::Send(b"secret\r".to_vec()))]);
+
+    assert_eq!(parse_output_action("pause"), Ok(OutputAction::Pause));
+    assert_eq!(parse_output_action("exit"), Ok(OutputAction::Exit));
+    assert_eq!(parse_output_action("send:y\\r"), Ok(OutputAction::Send(b"y\r".to_vec())));
+    assert!(parse_output_action("rate=fast").is_err());
+    assert!(parse_output_action("stop").is_err());
+    assert!(Options::parse(args("slowpty --on-output (unclosed pause 1M cat")).is_err());
+    assert!(Options::parse(args("slowpty --on-output x* exit 1M cat")).is_err());
+    assert!(Options::parse(args("slowpty --on-output done 1M cat")).is_err());
+}
+
+/// Parse a signal by its name, with or without the SIG, or its number.
+fn parse_signal(s: &str) -> Result<libc::c_int, String> {
+    match s.parse() {
+        Ok(n) if crate::known_signal_name(n).is_some() => Ok(n),
+        Ok(n) => Err(format!("no signal {n}")),
+        Err(_) => crate::signal_by_name(s).ok_or_else(|| format!("unknown signal {s:?}")),
+    }
+}
+
+#[test]
+fn test_parse_signal() {
+    assert_eq!(parse_signal("HUP"), Ok(libc::SIGHUP));
+    assert_eq!(parse_signal("SIGKILL"), Ok(libc::SIGKILL));
+    assert_eq!(parse_signal("term"), Ok(libc::SIGTERM));
+    assert_eq!(parse_signal("2"), Ok(libc::SIGINT));
+    assert_eq!(parse_signal("sigterm"), Ok(libc::SIGTERM));
+    assert_eq!(parse_signal("WINCH"), Ok(libc::SIGWINCH));
+    assert!(parse_signal("0").is_err());
+    assert!(parse_signal("999").is_err());
+    assert!(parse_signal("SIGWHATEVER").is_err());
+
+    let Ok(o) = Options::parse(args("slowpty --idle-timeout 5m 1M sh")) else { panic!() };
+    assert_eq!(o.idle_timeout, Some(Duration::from_secs(300)));
+    assert_eq!(o.idle_signal, libc::SIGTERM);
+    let Ok(o) = Options::parse(args("slowpty --idle-timeout 30 --idle-signal HUP 1M sh"))
+        else { panic!() };
+    assert_eq!(o.idle_signal, libc::SIGHUP);
+    assert!(Options::parse(args("slowpty --idle-signal HUP 1M sh")).is_err());
+}
+
+fn parse_baud(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(baud) if BAUD_RATES.contains(&baud) => Ok(baud),
+        _ => {
+            let rates = BAUD_RATES.map(|baud| baud.to_string()).join(", ");
+            Err(format!("not a standard baud rate (one of {rates})"))
+        }
+    }
+}
+
+fn parse_serial_speed(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(baud) if term::serial_speed(baud).is_some() => Ok(baud),
+        _ => Err("not a speed a serial device can be set to".to_owned()),
+    }
+}
+
+/// Parse framing like "8N1" or "7E2": data bits, parity, and stop bits.
+fn parse_framing(s: &str) -> Result<Framing, String> {
+    let invalid = || format!("invalid framing {s:?} (expected something like 8N1 or 7E1)");
+    let mut chars = s.chars();
+    let data = match chars.next() {
+        Some(c @ '5' ..= '8') => c as u8 - b'0',
+        _ => return Err(invalid()),
+    };
+    let parity = match chars.next().map(|c| c.to_ascii_uppercase()) {
+        Some('N') => false,
+        Some('E' | 'O' | 'M' | 'S') => true,
+        _ => return Err(invalid()),
+    };
+    let stop = match chars.as_str() {
+        "1" => 1.,
+        "1.5" => 1.5,
+        "2" => 2.,
+        _ => return Err(invalid()),
+    };
+    Ok(Framing { data, parity, stop })
+}
+
+#[test]
+fn test_baud() {
+    let Ok(o) = Options::parse(args("slowpty --baud 2400 cat")) else { panic!() };
+    assert_eq!(o.rate, Some(240.));
+    assert_eq!(o.command, args("cat"));
+
+    let Ok(o) = Options::parse(args("slowpty --baud 110 --framing 7E2 --out-rate 5 cat")) else {
+        panic!()
+    };
+    assert_eq!((o.rate, o.out_rate), (Some(10.), Some(5.)));
+
+    assert_eq!(parse_framing("8n1").map(|f| f.bits()), Ok(10.));
+    assert_eq!(parse_framing("5O1.5").map(|f| f.bits()), Ok(8.5));
+    assert!(parse_framing("9N1").is_err());
+    assert!(parse_framing("8X1").is_err());
+    assert!(parse_framing("8N3").is_err());
+    assert!(parse_baud("2401").is_err());
+    assert!(Options::parse(args("slowpty --baud 2400 300 cat")).is_err());
+    assert!(Options::parse(args("slowpty --framing 8N1 300 cat")).is_err());
+}
+
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    match parse_duration(s)? {
+        d if d.is_zero() => Err("interval must be greater than zero".into()),
+        d => Ok(d),
+    }
+}
+
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    let pct: f64 = s.trim_end_matches('%').parse()
+        .map_err(|e| format!("invalid percentage {s:?}: {e}"))?;
+    if !(0. ..= 100.).contains(&pct) {
+        return Err("must be from 0 to 100".to_owned());
+    }
+    Ok(pct)
+}
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    let p: f64 = s.parse().map_err(|e| format!("invalid number {s:?}: {e}"))?;
+    if !(0. ..= 1.).contains(&p) {
+        return Err("must be from 0 to 1".to_owned());
+    }
+    Ok(p)
+}
+
+/// Parse a ramp like "110baud..1M/20s" into the rates it goes from and to, and how long it takes.
+fn parse_ramp(s: &str) -> Result<(f64, f64, Duration), String> {
+    let invalid = || format!("invalid ramp {s:?} (expected something like 300..9600/10s)");
+    let (rates, time) = s.rsplit_once('/').ok_or_else(invalid)?;
+    let (from, to) = rates.split_once("..").ok_or_else(invalid)?;
+    Ok((parse_rate(from)?, parse_rate(to)?, parse_duration(time)?))
+}
+
+/// A schedule that gets from one rate to the other in even steps.
+fn ramp(from: f64, to: f64, time: Duration) -> Vec<(Duration, f64)> {
+    let steps = (time.as_secs_f64() / RAMP_STEP.as_secs_f64()).ceil().max(1.) as u32;
+    (0 ..= steps)
+        .map(|i| {
+            let progress = f64::from(i) / f64::from(steps);
+            (time.mul_f64(progress), from * (to / from).powf(progress))
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_ramp() {
+    let Ok((from, to, time)) = parse_ramp("100..10k/200ms") else { panic!() };
+    let schedule = ramp(from, to, time);
+    assert_eq!(schedule.len(), 3);
+    assert_eq!(schedule[0], (Duration::ZERO, 100.));
+    assert_eq!(schedule[1].0, Duration::from_millis(100));
+    assert!((schedule[1].1 - 1000.).abs() < 1e-6);
+    assert_eq!(schedule[2].0, Duration::from_millis(200));
+    assert!((schedule[2].1 - 10_000.).abs() < 1e-6);
+    assert_eq!(ramp(300., 300., Duration::ZERO).len(), 2);
+    assert!(parse_ramp("300..9600").is_err());
+    assert!(parse_ramp("300/10s").is_err());
+    assert!(parse_ramp("0..9600/10s").is_err());
+}
+
+fn read_schedule(path: &Path) -> Result<Vec<(Duration, f64)>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read --schedule file {path:?}: {e}"))?;
+    parse_schedule(&text).map_err(|e| format!("in --schedule file {path:?}: {e}"))
+}
+
+/// Parse a rate schedule: lines of a time and a rate, in order.
+fn parse_schedule(text: &str) -> Result<Vec<(Duration, f64)>, String> {
+    let mut schedule: Vec<(Duration, f64)> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let step = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [offset, rate] => (parse_duration(offset)?, parse_rate(rate)?),
+            _ => return Err(format!("line {}: expected a time and a rate", i + 1)),
+        };
+        if schedule.last().is_some_and(|&(last, _)| step.0 < last) {
+            return Err(format!("line {}: times must be in order", i + 1));
+        }
+        schedule.push(step);
+    }
+    if schedule.is_empty() {
+        return Err("the schedule is empty".to_owned());
+    }
+    Ok(schedule)
+}
+
+#[test]
+fn test_parse_schedule() {
+    let text = "# demo\n0 30\n\n10s 120   # 1200 baud\n1m 960\n";
+    assert_eq!(parse_schedule(text), Ok(vec![(Duration::ZERO, 30.),
+        (Duration::from_secs(10), 120.), (Duration::from_secs(60), 960.)]));
+    assert!(parse_schedule("10 300\n5 1200").is_err());
+    assert!(parse_schedule("10").is_err());
+    assert!(parse_schedule("10 300 1200").is_err());
+    assert!(parse_schedule("soon 300").is_err());
+    assert!(parse_schedule("# nothing\n").is_err());
+
+    let path = std::env::temp_dir().join(format!("slowpty-schedule-{}", std::process::id()));
+    std::fs::write(&path, text).unwrap();
+    let args = ["slowpty", "--schedule", path.to_str().unwrap(), "cat"].map(OsString::from);
+    let Ok(o) = Options::parse(args) else { panic!() };
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(o.schedule.len(), 3);
+    assert_eq!((o.rate, o.command), (None, vec![OsString::from("cat")]));
+}
+
+fn read_script(path: &Path) -> Result<Vec<(Duration, Vec<u8>)>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read --script file {path:?}: {e}"))?;
+    parse_script(&text).map_err(|e| format!("in --script file {path:?}: {e}"))
+}
+
+/// Parse a keystroke script: lines of a delay in milliseconds and the text to type.
+fn parse_script(text: &str) -> Result<Vec<(Duration, Vec<u8>)>, String> {
+    let mut script = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((delay, keys)) = line.split_once('\t') else {
+            return Err(format!("line {}: expected a delay, a tab, and some text", i + 1));
+        };
+        let delay = delay.trim().parse().map(Duration::from_millis)
+            .map_err(|e| format!("line {}: invalid delay {delay:?}: {e}", i + 1))?;
+        let keys = crate::replay::unescape_ascii(keys)
+            .ok_or_else(|| format!("line {}: invalid escape in {keys:?}", i + 1))?;
+        script.push((delay, keys));
+    }
+    if script.is_empty() {
+        return Err("the script is empty".to_owned());
+    }
+    Ok(script)
+}
+
+#[test]
+fn test_parse_script() {
+    let text = "# login\n500\troot\\r\n\n1000\t\\e[A\\x03\n0\t a\\\\b\n";
+    assert_eq!(parse_script(text), Ok(vec![(Duration::from_millis(500), b"root\r".to_vec()),
+        (Duration::from_secs(1), b"\x1b[A\x03".to_vec()), (Duration::ZERO, b" a\\b".to_vec())]));
+    assert!(parse_script("500 ls").is_err());
+    assert!(parse_script("soon\tls").is_err());
+    assert!(parse_script("0\tbad\\x4").is_err());
+    assert!(parse_script("# nothing\n").is_err());
+}
+
+fn parse_env(s: &str) -> Result<(OsString, OsString), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.into(), value.into())),
+        _ => Err(format!("expected KEY=VALUE, not {s:?}")),
+    }
+}
+
+#[test]
+fn test_parse_env() {
+    assert_eq!(parse_env("TERM=vt100"), Ok(("TERM".into(), "vt100".into())));
+    assert_eq!(parse_env("A=b=c"), Ok(("A".into(), "b=c".into())));
+    assert_eq!(parse_env("EMPTY="), Ok(("EMPTY".into(), "".into())));
+    assert!(parse_env("TERM").is_err());
+    assert!(parse_env("=x").is_err());
+
+    let args = "slowpty --env A=1 --env B=2 --chdir /tmp 10 env".split(' ').map(OsString::from);
+    let Ok(o) = Options::parse(args) else { panic!() };
+    assert_eq!(o.env, [("A".into(), "1".into()), ("B".into(), "2".into())]);
+    assert_eq!(o.chdir, Some(PathBuf::from("/tmp")));
+    assert_eq!(o.command, [OsString::from("env")]);
+}
+
+fn parse_session_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.starts_with('.') || s.contains('/') {
+        return Err(format!("invalid session name {s:?}"));
+    }
+    Ok(s.to_owned())
+}
+
+/// Parse a control key in caret notation, like "^A".
+fn parse_control_key(s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        [b'^', b'?'] => Ok(0x7f),
+        [b'^', c @ (b'@' ..= b'_' | b'a' ..= b'z')] => Ok(c.to_ascii_uppercase() & 0x1f),
+        _ => Err(format!("invalid key {s:?}: expected a control key like ^A")),
+    }
+}
+
+#[test]
+fn test_parse_control_key() {
+    assert_eq!(parse_control_key("^A"), Ok(0x01));
+    assert_eq!(parse_control_key("^b"), Ok(0x02));
+    assert_eq!(parse_control_key("^]"), Ok(0x1d));
+    assert_eq!(parse_control_key("^?"), Ok(0x7f));
+    assert!(parse_control_key("a").is_err());
+    assert!(parse_control_key("^1").is_err());
+    assert!(parse_control_key("^AB").is_err());
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction: f64 = s.parse().map_err(|e| format!("invalid number {s:?}: {e}"))?;
+    if fraction.is_nan() || fraction <= 0. {
+        return Err("must be greater than zero".to_owned());
+    }
+    Ok(fraction)
+}
+
+fn parse_preset(s: &str) -> Result<f64, String> {
+    parse_rate(s.trim()).map_err(|e| format!("bad rate preset {s:?}: {e}"))
+}
+
+#[test]
+fn test_parse_presets() {
+    let Ok(o) = Options::parse(args("slowpty --rate-presets 300,9600,115200 300 cat")) else {
+        panic!()
+    };
+    assert_eq!(o.rate_presets, vec![300., 9600., 115200.]);
+    assert!(Options::parse(args("slowpty --rate-presets 300 300 cat")).is_err());
+    assert!(Options::parse(args("slowpty --rate-presets 300,,9600 300 cat")).is_err());
+    assert!(Options::parse(args("slowpty --rate-presets 300,-5 300 cat")).is_err());
+    assert!(Options::parse(args("slowpty --rate-presets 300,0 300 cat")).is_err());
+}
@@ -0,0 +1,52 @@
+use crate::filter::Filter;
+use crate::options::{Direction, Parity};
+
+/// ASCII SUB, which serial equipment puts in place of a character that arrived garbled.
+const SUB: u8 = 0x1a;
+
+/// Pass data through a link with 7 data bits. The high bit of each byte is lost; with parity, a
+/// byte that has it set is taken to have sent it as the parity bit, and becomes SUB if that's
+/// wrong for the other seven.
+pub fn seven_bit(data: &mut [u8], parity: Parity) {
+    for b in data {
+        if *b & 0x80 == 0 {
+            continue;
+        }
+        // Counting the parity bit, which is set.
+        let odd = (*b).count_ones() % 2 == 1;
+        let ok = match parity {
+            Parity::None => true,
+            Parity::Even => !odd,
+            Parity::Odd => odd,
+        };
+        *b = if ok { *b & 0x7f } else { SUB };
+    }
+}
+
+/// With `--databits 7`, passes what's read in either direction through the 7-bit link.
+#[derive(Clone, Copy)]
+pub struct SevenBit(pub Parity);
+
+impl Filter for SevenBit {
+    fn transform(&mut self, _dir: Direction, input: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(input);
+        seven_bit(&mut out[start ..], self.0);
+    }
+}
+
+#[test]
+fn test_seven_bit() {
+    let mut data = *b"A\xc1\xc3\xa9";
+    seven_bit(&mut data, Parity::None);
+    assert_eq!(&data, b"AAC)");
+
+    // 'A' has two bits set and 'C' and ')' have three.
+    let mut data = *b"A\xc1\xc3\xa9";
+    seven_bit(&mut data, Parity::Even);
+    assert_eq!(&data, b"A\x1aC)");
+
+    let mut data = *b"A\xc1\xc3\xa9";
+    seven_bit(&mut data, Parity::Odd);
+    assert_eq!(&data, b"AA\x1a\x1a");
+}
@@ -1,33 +1,127 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
 use std::fs::File;
 use std::io;
-use std::os::unix::io::{AsRawFd, FromRawFd};
 
-use crate::checkerr;
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+    target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+pub use bsd::{login_tty, open_pty_pair};
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+    target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd")))]
+pub use posix::{login_tty, open_pty_pair};
+
+/// Whether the pty throws away what's buffered in it once nothing has the slave side open, as on
+/// the BSDs (macOS included), rather than keeping it to be read, as Linux does.
+pub const DISCARDS_ON_CLOSE: bool = cfg!(any(target_os = "macos", target_os = "ios",
+    target_os = "freebsd", target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"));
 
 pub struct PtyPair {
     pub master: File,
     pub slave: File,
 }
 
-pub fn open_pty_pair() -> Result<PtyPair> {
-    let master = unsafe {
-        File::from_raw_fd(checkerr(libc::posix_openpt(libc::O_RDWR), "posix_openpt")?)
-    };
+/// The path of the pty's slave side, like `/dev/pts/3`.
+pub fn name(slave: &File) -> Option<String> {
+    use std::ffi::CStr;
+    use std::os::unix::io::AsRawFd;
+
+    let mut buf = [0 as libc::c_char; 256];
+    let ret = unsafe { libc::ttyname_r(slave.as_raw_fd(), buf.as_mut_ptr(), buf.len()) };
+    if ret != 0 {
+        debug!("ttyname: {}", std::io::Error::from_raw_os_error(ret));
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned())
+}
+
+/// For the child's side, which can't use `checkerr`, as it allocates.
+fn os_result(result: libc::c_int) -> io::Result<()> {
+    if result == -1 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+/// The BSDs (macOS included) have had `openpty` and `login_tty` since long before the POSIX
+/// functions, and they're the better-trodden path there.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+    target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+mod bsd {
+    use super::*;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::ptr;
+
+    use crate::checkerr;
 
-    checkerr(unsafe { libc::grantpt(master.as_raw_fd()) }, "grantpt")?;
-    checkerr(unsafe { libc::unlockpt(master.as_raw_fd()) }, "unlockpt")?;
+    pub fn open_pty_pair() -> Result<PtyPair> {
+        let mut master = -1;
+        let mut slave = -1;
+        checkerr(unsafe {
+            libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null_mut(),
+                ptr::null_mut())
+        }, "openpty")?;
+        unsafe {
+            Ok(PtyPair {
+                master: File::from_raw_fd(master),
+                slave: File::from_raw_fd(slave),
+            })
+        }
+    }
 
-    let slavename: *const libc::c_char = unsafe { libc::ptsname(master.as_raw_fd()) };
-    if slavename.is_null() {
-        let e = io::Error::last_os_error();
-        eprintln!("ptsname: {e}");
-        return Err(e).context("ptsname");
+    /// In the child: start a new session with the slave as its controlling terminal, and make
+    /// it stdin, stdout, and stderr.
+    pub fn login_tty(slave: File) -> io::Result<()> {
+        os_result(unsafe { libc::login_tty(slave.into_raw_fd()) })
     }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+    target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd")))]
+mod posix {
+    use super::*;
+    use anyhow::Context;
+    use std::io;
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+    use crate::checkerr;
 
-    let slave = unsafe {
-        File::from_raw_fd(checkerr(libc::open(slavename, libc::O_RDWR), "open slave")?)
-    };
+    pub fn open_pty_pair() -> Result<PtyPair> {
+        let master = unsafe {
+            File::from_raw_fd(checkerr(libc::posix_openpt(libc::O_RDWR), "posix_openpt")?)
+        };
 
-    Ok(PtyPair { master, slave })
+        checkerr(unsafe { libc::grantpt(master.as_raw_fd()) }, "grantpt")?;
+        checkerr(unsafe { libc::unlockpt(master.as_raw_fd()) }, "unlockpt")?;
+
+        let slavename: *const libc::c_char = unsafe { libc::ptsname(master.as_raw_fd()) };
+        if slavename.is_null() {
+            let e = io::Error::last_os_error();
+            eprintln!("ptsname: {e}");
+            return Err(e).context("ptsname");
+        }
+
+        let slave = unsafe {
+            File::from_raw_fd(checkerr(libc::open(slavename, libc::O_RDWR), "open slave")?)
+        };
+
+        Ok(PtyPair { master, slave })
+    }
+
+    /// In the child: start a new session with the slave as its controlling terminal, and make
+    /// it stdin, stdout, and stderr. This is what `login_tty` does where it exists.
+    pub fn login_tty(slave: File) -> io::Result<()> {
+        let fd = slave.as_raw_fd();
+        for target in 0 ..= 2 {
+            os_result(unsafe { libc::dup2(fd, target) })?;
+        }
+        drop(slave);
+
+        set_session_leader()?;
+        set_controlling_tty(0)
+    }
+
+    fn set_controlling_tty(fd: RawFd) -> io::Result<()> {
+        #[allow(clippy::useless_conversion)] // it isn't identical on all platforms
+        os_result(unsafe { libc::ioctl(fd, libc::TIOCSCTTY.into(), 1) })
+    }
+
+    fn set_session_leader() -> io::Result<()> {
+        os_result(unsafe { libc::setsid() })
+    }
 }
@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::stats::Stats;
+
+/// Periodically writes the achieved transfer rates to a CSV file.
+pub struct RateLog {
+    file: File,
+    interval: Duration,
+    start: Instant,
+    next: Instant,
+    /// When the previous sample was taken, and the byte counts at that time.
+    last: (Instant, [u64; 2]),
+}
+
+impl RateLog {
+    pub fn create(path: &Path, interval: Duration, now: Instant) -> Result<Self> {
+        let mut file = File::create(path)
+            .with_context(|| format!("failed to create rate log {path:?}"))?;
+        file.write_all(b"timestamp,bytes_in,bytes_out,rate_in,rate_out\n")
+            .with_context(|| format!("failed to write to rate log {path:?}"))?;
+        Ok(RateLog {
+            file,
+            interval,
+            start: now,
+            next: now + interval,
+            last: (now, [0, 0]),
+        })
+    }
+
+    /// When the next sample is due.
+    pub fn next_sample(&self) -> Instant {
+        self.next
+    }
+
+    /// Write a row with the byte counts so far, and the rates since the last sample.
+    pub fn sample(&mut self, now: Instant, stats: &Stats) -> io::Result<()> {
+        let (last_time, last_bytes) = self.last;
+        let elapsed = now.saturating_duration_since(last_time).as_secs_f64();
+        let rate = |i: usize| if elapsed > 0. {
+            (stats.bytes[i] - last_bytes[i]) as f64 / elapsed
+        } else {
+            0.
+        };
+        let row = format!("{:.3},{},{},{:.1},{:.1}\n",
+            now.saturating_duration_since(self.start).as_secs_f64(),
+            stats.bytes[0], stats.bytes[1], rate(0), rate(1));
+
+        self.last = (now, stats.bytes);
+        while self.next <= now {
+            self.next += self.interval;
+        }
+
+        // One write per row, so the file is always up to date.
+        self.file.write_all(row.as_bytes())
+    }
+}
@@ -1,23 +1,58 @@
 use anyhow::{Context, Result};
-use mio::{Events, Poll, Interest, Token};
+use mio::{Events, Poll, Interest, Registry, Token};
 use mio::unix::SourceFd;
 use std::fs::File;
 use std::io;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use crate::delay::Timer;
+
+/// Index (and mio token) of the signal pipe, when one is registered. 0 and 1 are the endpoints.
+pub const SIGNALS: usize = 2;
+
+/// Token of the control socket and its connections, when there is one.
+pub const CONTROL: usize = 3;
+
+/// Token of the console's output, when it's separate from its input. It's only polled for
+/// writability, which counts as the console's.
+const CONSOLE_OUT: usize = 4;
+
+/// Token of the timer that times out `block`, when there is one.
+const TIMER: usize = 5;
 
 pub struct ReadableSet<'a> {
     mio_poll: Poll,
     console: &'a mut File,
+    /// Where the console's output goes, if not back through `console`.
+    console_out: Option<&'a mut File>,
     pty_master: &'a mut File,
+    /// On Linux, what `block` waits on for its timeout, as epoll only goes to the millisecond.
+    /// Elsewhere, kqueue takes the timeout as precisely as a sleep would.
+    timer: Option<Timer>,
     bits: u8,
+    /// Which endpoints can be written to, as far as we know: cleared when a write would block,
+    /// and set again by a poll event.
+    writable: u8,
+    /// File status flags of the console, pty, and console output before they were made
+    /// non-blocking.
+    original_flags: [libc::c_int; 3],
+    /// Whether the console's input can end without ending the session.
+    console_eof_ok: bool,
+    /// Whether the console's input is registered with the poll (which it can't be if it's a
+    /// regular file), and hasn't been closed.
+    console_polled: bool,
 }
 
 pub enum PollResult {
     /// You're good to go.
     Ok,
 
-    /// At least one of the endpoints is closed or permanently unreadable.
-    Closed,
+    /// Nothing happened before the timeout.
+    Nothing,
+
+    /// At least one of the endpoints (this one) is closed or permanently unreadable.
+    Closed(&'static str),
 }
 
 pub struct PollEndpoint<'a> {
@@ -26,7 +61,8 @@ pub struct PollEndpoint<'a> {
     pub dst: &'a mut File,
 }
 
-fn set_nonblocking(f: &File) -> Result<()> {
+/// Returns the previous file status flags.
+fn set_nonblocking(f: &File) -> Result<libc::c_int> {
     let fd = f.as_raw_fd();
     let previous = unsafe { libc::fcntl(fd, libc::F_GETFL) };
     if previous < 0 {
@@ -38,67 +74,199 @@ fn set_nonblocking(f: &File) -> Result<()> {
         return Err(io::Error::last_os_error())
             .context("fcntl(F_SETFL)");
     }
-    Ok(())
+    Ok(previous)
 }
 
 impl<'a> ReadableSet<'a> {
-    pub fn new(console: &'a mut File, pty_master: &'a mut File) -> Result<Self> {
+    pub fn new(console: &'a mut File, console_out: Option<&'a mut File>,
+        pty_master: &'a mut File) -> Result<Self>
+    {
         let mio_poll = Poll::new().context("mio poll instantiation")?;
+        let mut original_flags = [0; 3];
+        let mut bits = 0;
+        let mut console_polled = true;
         for (i, f) in [&console, &pty_master].iter_mut().enumerate() {
-            set_nonblocking(f)
+            original_flags[i] = set_nonblocking(f)
                 .with_context(|| format!("failed to set {} nonblocking", Self::name(i)))?;
+            match mio_poll.registry().register(
+                &mut SourceFd(&f.as_raw_fd()),
+                Token(i),
+                Interest::READABLE,
+            ) {
+                Ok(()) => (),
+                Err(ref e) if i == 0 && e.raw_os_error() == Some(libc::EPERM) => {
+                    // A regular file (or /dev/null) can't be polled, but it never blocks, so
+                    // it can be left marked readable until it runs out.
+                    debug!("console can't be polled; treating it as always readable");
+                    bits |= 1;
+                    console_polled = false;
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("mio poll registration for {}", Self::name(i)));
+                }
+            }
+        }
+        if let Some(ref out) = console_out {
+            original_flags[2] = set_nonblocking(out)
+                .context("failed to set console output nonblocking")?;
+            // Events are edge-triggered, so this only wakes the poll when a write that would
+            // have blocked can go ahead.
+            match mio_poll.registry().register(&mut SourceFd(&out.as_raw_fd()),
+                Token(CONSOLE_OUT), Interest::WRITABLE)
+            {
+                Ok(()) => (),
+                // As above: it's always writable.
+                Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => (),
+                Err(e) => return Err(e).context("mio poll registration for console output"),
+            }
+        }
+
+        let timer = Timer::new().context("failed to create timer")?;
+        if let Some(ref timer) = timer {
             mio_poll.registry()
-                .register(
-                    &mut SourceFd(&f.as_raw_fd()),
-                    Token(i),
-                    Interest::READABLE,
-                )
-                .with_context(|| format!("mio poll registration for {}", Self::name(i)))?;
+                .register(&mut SourceFd(&timer.as_raw_fd()), Token(TIMER), Interest::READABLE)
+                .context("mio poll registration for timer")?;
         }
-        
+
         Ok(Self {
             mio_poll,
             console,
+            console_out,
             pty_master,
-            bits: 0,
+            timer,
+            bits,
+            writable: 0b11,
+            original_flags,
+            console_eof_ok: false,
+            console_polled,
         })
     }
 
+    /// Let the console's input end without ending the session: when it does, it's reported as
+    /// readable, so the end of file is found by reading it.
+    pub fn allow_console_eof(&mut self) {
+        self.console_eof_ok = true;
+    }
+
+    /// Stop polling the console's input, once it has ended.
+    pub fn close_console(&mut self) -> Result<()> {
+        self.unset(0);
+        if !self.console_polled {
+            return Ok(());
+        }
+        self.console_polled = false;
+        self.mio_poll.registry()
+            .deregister(&mut SourceFd(&self.console.as_raw_fd()))
+            .context("mio poll deregistration for console")
+    }
+
     pub fn is_empty(&self) -> bool {
         self.bits == 0
     }
 
+    pub fn is_set(&self, index: usize) -> bool {
+        self.bits & (1 << index) as u8 != 0
+    }
+
     fn name(idx: usize) -> &'static str {
         match idx {
             0 => "console",
             1 => "pty",
+            SIGNALS => "signals",
+            CONTROL => "control",
+            CONSOLE_OUT => "console output",
+            TIMER => "timer",
             _ => panic!(),
         }
     }
 
-    pub fn block(&mut self) -> Result<PollResult> {
-        debug!("mio poll");
-        let mut events = Events::with_capacity(2);
-        self.mio_poll.poll(&mut events, None).context("mio poll")?;
+    /// Also wake up from `block` when the given file (a `SignalPipe`) is readable, setting the
+    /// `SIGNALS` bit.
+    pub fn register_signals(&mut self, fd: RawFd) -> Result<()> {
+        self.mio_poll.registry()
+            .register(&mut SourceFd(&fd), Token(SIGNALS), Interest::READABLE)
+            .context("mio poll registration for signal pipe")
+    }
+
+    /// A handle for registering more files with the poll, for the control socket to register
+    /// itself and its connections under the `CONTROL` token.
+    pub fn registry(&self) -> Result<Registry> {
+        self.mio_poll.registry().try_clone().context("failed to clone mio registry")
+    }
 
+    /// Wait for an endpoint to become readable (or writable again, after a write would have
+    /// blocked), or until the timeout (if any) expires.
+    pub fn block(&mut self, timeout: Option<Duration>) -> Result<PollResult> {
+        debug!("mio poll, timeout {:?}", timeout);
+        let mut events = Events::with_capacity(8);
+        let timer = self.timer.as_ref().zip(timeout.filter(|t| !t.is_zero()));
+        if let Some((timer, t)) = timer {
+            timer.set(t)?;
+        }
+        let result = self.mio_poll.poll(&mut events, if timer.is_some() { None } else { timeout });
+        if let Some((timer, _)) = timer {
+            timer.set(Duration::ZERO)?;
+        }
+        match result {
+            Ok(()) => (),
+            // A signal arrived; it will show up on the signal pipe next time around.
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return Ok(PollResult::Ok),
+            Err(e) => return Err(e).context("mio poll"),
+        }
+
+        let mut woken = false;
         for event in events.into_iter() {
             debug!("{:?}", event);
             let index = event.token().0;
+            woken |= index != TIMER;
+
+            if index == CONTROL {
+                // Whatever happened, the control socket sorts it out.
+                self.bits |= (1 << index) as u8;
+                continue;
+            }
+
+            if index == TIMER {
+                if let Some(ref timer) = self.timer {
+                    timer.clear();
+                }
+                continue;
+            }
+
+            if index == CONSOLE_OUT {
+                // Writable, or an error that the next write will find.
+                self.writable |= 1;
+                continue;
+            }
+
+            if index == 0 && self.console_eof_ok && event.is_read_closed() {
+                self.bits |= 1;
+                continue;
+            }
 
             if event.is_read_closed() && !event.is_readable() {
                 // Don't even try to read in this state. Even with O_NONBLOCK set, it may still
                 // block.
                 debug!("endpoint closed: {}", Self::name(index));
-                return Ok(PollResult::Closed);
+                return Ok(PollResult::Closed(Self::name(index)));
+            }
+
+            if event.is_writable() && index != SIGNALS && !self.is_writable(index) {
+                self.writable |= (1 << index) as u8;
+                self.interest(index, Interest::READABLE)?;
+                if !event.is_readable() {
+                    continue;
+                }
             }
 
             self.bits |= (1 << index) as u8;
         }
 
-        Ok(PollResult::Ok)
+        Ok(if woken { PollResult::Ok } else { PollResult::Nothing })
     }
 
-    pub fn endpoint(&mut self, idx: usize) -> Option<PollEndpoint> {
+    pub fn endpoint(&mut self, idx: usize) -> Option<PollEndpoint<'_>> {
         match idx {
             0 => Some(PollEndpoint {
                 name: "console",
@@ -108,14 +276,97 @@ impl<'a> ReadableSet<'a> {
             1 => Some(PollEndpoint {
                 name: "pty",
                 src: self.pty_master,
-                dst: self.console,
+                dst: self.console_out.as_deref_mut().unwrap_or(self.console),
             }),
             _ => None,
         }
     }
 
+    /// Where to write to the console.
+    pub fn console(&mut self) -> &mut File {
+        self.console_out.as_deref_mut().unwrap_or(self.console)
+    }
+
+    /// Where the console's input comes from, which is the terminal, if there is one. Things meant
+    /// for the terminal rather than the output (like the status) are written here.
+    pub fn console_input(&self) -> &File {
+        self.console
+    }
+
+    pub fn pty_master(&self) -> &File {
+        self.pty_master
+    }
+
+    pub fn is_writable(&self, index: usize) -> bool {
+        self.writable & (1 << index) as u8 != 0
+    }
+
+    /// A write to the endpoint would have blocked; stop writing to it until `block` says it's
+    /// writable again.
+    pub fn wait_writable(&mut self, index: usize) -> Result<()> {
+        self.writable &= !((1 << index) as u8);
+        self.interest(index, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    /// Block until the endpoint is writable, without regard to anything else.
+    pub fn poll_writable(&mut self, index: usize) -> Result<()> {
+        let file = if index == 0 {
+            self.console_out.as_deref().unwrap_or(self.console)
+        } else {
+            &*self.pty_master
+        };
+        let mut pollfd = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        while unsafe { libc::poll(&mut pollfd, 1, -1) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e).context("poll");
+            }
+        }
+        self.writable |= (1 << index) as u8;
+        self.interest(index, Interest::READABLE)
+    }
+
+    fn interest(&mut self, index: usize, interest: Interest) -> Result<()> {
+        if index == 0 && self.console_out.is_some() {
+            // The console's output is always polled for writability, and its input never is.
+            return Ok(());
+        }
+        let fd = if index == 0 { self.console.as_raw_fd() } else { self.pty_master.as_raw_fd() };
+        self.mio_poll.registry()
+            .reregister(&mut SourceFd(&fd), Token(index), interest)
+            .with_context(|| format!("mio poll reregistration for {}", Self::name(index)))
+    }
+
+    /// Mark an endpoint as readable, so that it gets read from even without a poll event.
+    pub fn set(&mut self, index: usize) {
+        self.bits |= (1 << index) as u8;
+    }
+
     pub fn unset(&mut self, index: usize) {
         let mask = (1 << index) as u8;
         self.bits &= !mask;
     }
 }
+
+impl Drop for ReadableSet<'_> {
+    fn drop(&mut self) {
+        // The console's file description is shared with whatever started us (usually a shell),
+        // so don't leave it non-blocking.
+        let files = [
+            (Some(&*self.console), 0),
+            (Some(&*self.pty_master), 1),
+            (self.console_out.as_deref(), CONSOLE_OUT),
+        ];
+        for (i, (f, token)) in files.into_iter().enumerate() {
+            let Some(f) = f else { continue };
+            if unsafe { libc::fcntl(f.as_raw_fd(), libc::F_SETFL, self.original_flags[i]) } < 0 {
+                warn!("failed to restore file status flags of {}: {}", Self::name(token),
+                    io::Error::last_os_error());
+            }
+        }
+    }
+}
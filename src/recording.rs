@@ -0,0 +1,19 @@
+use std::io;
+use std::time::Instant;
+
+/// A recording of what the console was shown, like the ones `--record` and `--script-record`
+/// make.
+pub trait Recording {
+    /// Add output shown on the console.
+    fn output(&mut self, now: Instant, data: &[u8]) -> io::Result<()>;
+
+    /// Note that the console changed size, if the format has a way to say so.
+    fn resize(&mut self, _now: Instant, _cols: u16, _rows: u16) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Write whatever goes at the end.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{Context, Result};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::CharIndices;
+use std::time::{Duration, Instant};
+
+use crate::compress;
+use crate::options::ReplayFormat;
+
+/// What a recording showed, and when, from its start.
+pub type Chunks = Vec<(Duration, Vec<u8>)>;
+
+/// Read a recording of the given format, with its times scaled to play back at `speed`. When the
+/// format is left to be worked out, it's an asciicast, a transcript from `--transcript`, a ttyrec,
+/// or failing those, anything at all, to be shown all at once.
+pub fn load(path: &Path, format: ReplayFormat, timing: Option<&Path>, speed: f64)
+    -> Result<Chunks>
+{
+    let data = compress::read(path).with_context(|| format!("failed to read {path:?}"))?;
+    let parsed = |chunks: Option<Chunks>, what: &str| {
+        chunks.ok_or_else(|| anyhow!("{path:?} isn't {what}"))
+    };
+    let chunks = match format {
+        ReplayFormat::Auto => parse_cast(&data)
+            .or_else(|| parse_transcript(&data))
+            .or_else(|| parse_ttyrec(&data))
+            .unwrap_or_else(|| vec![(Duration::ZERO, data)]),
+        ReplayFormat::Cast => parsed(parse_cast(&data), "an asciicast")?,
+        ReplayFormat::Transcript => parsed(parse_transcript(&data), "a transcript")?,
+        ReplayFormat::Ttyrec => parsed(parse_ttyrec(&data), "a ttyrec")?,
+        ReplayFormat::Script => {
+            let timing = timing.context("a typescript can't be played back without its timing")?;
+            let times = std::fs::read_to_string(timing)
+                .with_context(|| format!("failed to read {timing:?}"))?;
+            parse_typescript(&data, &times)
+                .map_err(|e| anyhow!("{timing:?} isn't timing for {path:?}: {e}"))?
+        }
+    };
+    debug!("replaying {} chunks from {:?}", chunks.len(), path);
+    Ok(chunks.into_iter().map(|(time, data)| (time.div_f64(speed), data)).collect())
+}
+
+fn seconds(s: &str) -> Option<Duration> {
+    Duration::try_from_secs_f64(s.trim().parse().ok()?).ok()
+}
+
+/// An asciicast (v2): a header line, then one JSON array per event, of which only the output
+/// matters.
+fn parse_cast(data: &[u8]) -> Option<Chunks> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut lines = text.lines();
+    if !lines.next()?.contains("\"version\"") {
+        return None;
+    }
+    let mut chunks = vec![];
+    for line in lines.filter(|l| !l.trim().is_empty()) {
+        let (time, rest) = line.trim().strip_prefix('[')?.split_once(',')?;
+        let (code, rest) = rest.trim_start().split_once(',')?;
+        if code.trim() == "\"o\"" {
+            let (s, _) = json_string(rest.trim_start())?;
+            chunks.push((seconds(time)?, s.into_bytes()));
+        }
+    }
+    Some(chunks)
+}
+
+/// Read a JSON string from the start of `s`, and return it and what's left after it.
+fn json_string(s: &str) -> Option<(String, &str)> {
+    fn hex4(chars: &mut CharIndices) -> Option<u32> {
+        let hex: String = chars.take(4).map(|(_, c)| c).collect();
+        u32::from_str_radix(&hex, 16).ok()
+    }
+
+    let body = s.strip_prefix('"')?;
+    let mut out = String::new();
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &body[i + 1 ..])),
+            '\\' => match chars.next()?.1 {
+                'u' => {
+                    let mut code = hex4(&mut chars)?;
+                    if (0xd800 .. 0xdc00).contains(&code) {
+                        // The first half of a surrogate pair, which the second has to follow.
+                        if chars.next()?.1 != '\\' || chars.next()?.1 != 'u' {
+                            return None;
+                        }
+                        let low = hex4(&mut chars)?.checked_sub(0xdc00)?;
+                        code = 0x10000 + ((code - 0xd800) << 10) + low;
+                    }
+                    out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                'b' => out.push('\x08'),
+                'f' => out.push('\x0c'),
+                other => out.push(other),
+            },
+            _ => out.push(c),
+        }
+    }
+    None
+}
+
+#[test]
+fn test_parse_cast() {
+    let cast = br#"{"version": 2, "width": 80, "height": 24}
+[0.5, "o", "a\"b\\\r\n"]
+[0.75, "i", "x"]
+[1.0, "o", "\u001b[0m\u2500\ud83d\ude00"]
+"#;
+    assert_eq!(parse_cast(cast), Some(vec![
+        (Duration::from_millis(500), b"a\"b\\\r\n".to_vec()),
+        (Duration::from_secs(1), "\x1b[0m\u{2500}\u{1f600}".as_bytes().to_vec()),
+    ]));
+    assert_eq!(parse_cast(b"hello\n"), None);
+}
+
+/// A transcript, as written by `--transcript`: the output chunks from the "chunks" format, or the
+/// output lines from the "text" format.
+fn parse_transcript(data: &[u8]) -> Option<Chunks> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut chunks = vec![];
+    for line in text.lines() {
+        let line = line.trim_start();
+        let (time, rest) = line.split_once(' ')?;
+        let time = seconds(time)?;
+        if let Some(escaped) = rest.strip_prefix("out \"").and_then(|r| r.strip_suffix('"')) {
+            chunks.push((time, unescape_ascii(escaped)?));
+        } else if let Some(text) = rest.strip_prefix("< ") {
+            chunks.push((time, format!("{text}\r\n").into_bytes()));
+        } else if !(rest.starts_with("in  \"") || rest.starts_with("> ") || rest == ">") {
+            return None;
+        }
+    }
+    (!chunks.is_empty()).then_some(chunks)
+}
+
+/// Undo `escape_ascii`, also taking `\e` for ESC.
+pub fn unescape_ascii(s: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        out.push(match bytes.next()? {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'e' => 0x1b,
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            other => other,
+        });
+    }
+    Some(out)
+}
+
+#[test]
+fn test_parse_transcript() {
+    let chunks = concat!("     0.000 out \"$ \"\n     1.250 in  \"l\"\n",
+        "     1.500 out \"l\\x1b[K\\r\\n\\\"\"\n");
+    assert_eq!(parse_transcript(chunks.as_bytes()), Some(vec![
+        (Duration::ZERO, b"$ ".to_vec()),
+        (Duration::from_millis(1500), b"l\x1b[K\r\n\"".to_vec()),
+    ]));
+    let text = "     0.000 < hello\n     2.000 > ls\n     2.500 < a b c\n";
+    assert_eq!(parse_transcript(text.as_bytes()), Some(vec![
+        (Duration::ZERO, b"hello\r\n".to_vec()),
+        (Duration::from_millis(2500), b"a b c\r\n".to_vec()),
+    ]));
+    assert_eq!(parse_transcript(b"just some text\n"), None);
+}
+
+/// A ttyrec: each record is a header of three little-endian 32-bit numbers (seconds and
+/// microseconds since the epoch, and the length), followed by that much output.
+fn parse_ttyrec(mut data: &[u8]) -> Option<Chunks> {
+    let mut chunks = vec![];
+    let mut start = None;
+    while !data.is_empty() {
+        let word = |i: usize| Some(u32::from_le_bytes(data.get(i .. i + 4)?.try_into().ok()?));
+        let time = Duration::new(u64::from(word(0)?), word(4)?.checked_mul(1000)?);
+        let len = word(8)? as usize;
+        let body = data.get(12 .. 12 + len)?;
+        let start = *start.get_or_insert(time);
+        chunks.push((time.checked_sub(start)?, body.to_vec()));
+        data = &data[12 + len ..];
+    }
+    (!chunks.is_empty()).then_some(chunks)
+}
+
+#[test]
+fn test_parse_ttyrec() {
+    let mut data = vec![];
+    for (sec, usec, body) in [(100u32, 0u32, &b"ab"[..]), (101, 500_000, b"c")] {
+        for n in [sec, usec, body.len() as u32] {
+            data.extend(n.to_le_bytes());
+        }
+        data.extend(body);
+    }
+    assert_eq!(parse_ttyrec(&data), Some(vec![
+        (Duration::ZERO, b"ab".to_vec()),
+        (Duration::from_millis(1500), b"c".to_vec()),
+    ]));
+    data.pop();
+    assert_eq!(parse_ttyrec(&data), None);
+}
+
+/// A typescript from script(1), and its timing: one line per output chunk, of the seconds since
+/// the last and how many bytes it was. The advanced timing format, which starts each line with
+/// the kind of entry, is read too, though only its output entries are played back.
+fn parse_typescript(data: &[u8], timing: &str) -> Result<Chunks, String> {
+    // The header line has no timing, and scriptreplay skips it.
+    let mut rest = match data.strip_prefix(b"Script started") {
+        Some(header) => header.splitn(2, |&b| b == b'\n').nth(1).unwrap_or_default(),
+        None => data,
+    };
+    let mut chunks = vec![];
+    let mut time = Duration::ZERO;
+    for (n, line) in timing.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let err = |e: &str| format!("line {}: {e}", n + 1);
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (kind, delay, len) = match fields[..] {
+            [delay, len] => ("O", delay, len),
+            [kind, delay, len, ..] => (kind, delay, len),
+            _ => return Err(err("expected the delay and the number of bytes")),
+        };
+        time += seconds(delay).ok_or_else(|| err("invalid delay"))?;
+        if kind != "O" {
+            continue;
+        }
+        let len = len.parse::<usize>().map_err(|e| err(&e.to_string()))?;
+        if len > rest.len() {
+            return Err(err("past the end of the typescript"));
+        }
+        let (chunk, after) = rest.split_at(len);
+        chunks.push((time, chunk.to_vec()));
+        rest = after;
+    }
+    if chunks.is_empty() {
+        return Err("there's no output in it".to_owned());
+    }
+    Ok(chunks)
+}
+
+#[test]
+fn test_parse_typescript() {
+    let data = b"Script started on 2026-10-16 11:43:19+0000 [COMMAND=\"ls\"]\na b\r\n$ \n";
+    assert_eq!(parse_typescript(data, "0.5 5\n1.25 2\n"), Ok(vec![
+        (Duration::from_millis(500), b"a b\r\n".to_vec()),
+        (Duration::from_millis(1750), b"$ ".to_vec()),
+    ]));
+    let advanced = "H 0.000000 START_TIME 2026-10-16\nO 0.5 5\nI 0.25 1\nO 0.25 2\n";
+    assert_eq!(parse_typescript(data, advanced), Ok(vec![
+        (Duration::from_millis(500), b"a b\r\n".to_vec()),
+        (Duration::from_secs(1), b"$ ".to_vec()),
+    ]));
+    assert!(parse_typescript(b"ab", "0.5 3\n").is_err());
+    assert!(parse_typescript(b"ab", "soon 1\n").is_err());
+    assert!(parse_typescript(b"ab", "").is_err());
+}
+
+/// Play the recording into the socket, each chunk at its time, to be read from the other end as
+/// though it were a program's output. Ctrl-C or q read from the socket stops it early; other
+/// input is ignored, as is the end of it. The socket is closed at the end, which ends the session.
+pub fn play(chunks: Chunks, mut conn: UnixStream) -> io::Result<()> {
+    let start = Instant::now();
+    let mut input = true;
+    for (time, data) in chunks {
+        loop {
+            let wait = (start + time).saturating_duration_since(Instant::now());
+            if wait.is_zero() {
+                break;
+            }
+            if !input {
+                std::thread::sleep(wait);
+                break;
+            }
+            let mut pollfd = libc::pollfd {
+                fd: conn.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ms = wait.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int;
+            if unsafe { libc::poll(&mut pollfd, 1, ms) } > 0 {
+                let mut buf = [0u8; 64];
+                match conn.read(&mut buf) {
+                    Ok(0) => input = false,
+                    Ok(n) if buf[.. n].iter().any(|&b| b == 0x03 || b == b'q') => {
+                        debug!("replay stopped");
+                        return Ok(());
+                    }
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        conn.write_all(&data)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::ManuallyDrop;
+use std::net::TcpListener;
+use std::os::fd::OwnedFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process::exit;
+
+use crate::checkerr;
+use crate::options::Options;
+use crate::session::SlowPty;
+use crate::telnet;
+
+enum Listener {
+    /// Telnet clients, with `--telnet`.
+    Telnet(TcpListener),
+    /// Anything that connects, with `--listen`.
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Wait for the next client, and return its connection and a description of it for logging.
+    fn accept(&self) -> io::Result<(OwnedFd, String)> {
+        match self {
+            Listener::Telnet(listener) => {
+                let (stream, addr) = listener.accept()?;
+                // Output is paced a byte at a time; don't let it be held back to be sent in
+                // bigger packets.
+                stream.set_nodelay(true)?;
+                Ok((stream.into(), addr.to_string()))
+            }
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept()?;
+                Ok((stream.into(), format!("{path:?}")))
+            }
+        }
+    }
+}
+
+/// Listen for connections, and run a session for each one in a process of its own, with the
+/// connection as its console. This only returns if listening fails.
+///
+/// Each session is separate, with the rates it started with until they're changed. With
+/// `--control`, each one gets its own control socket, at the given path with the process ID of
+/// the session added to it.
+pub fn serve(options: Options) -> Result<()> {
+    let listener = match (options.telnet, &options.listen) {
+        (Some(port), _) => {
+            let listener = TcpListener::bind(("::", port))
+                .or_else(|_| TcpListener::bind(("0.0.0.0", port)))
+                .with_context(|| format!("failed to listen on port {port}"))?;
+            info!("listening for telnet connections on port {}", port);
+            Listener::Telnet(listener)
+        }
+        (None, Some(path)) => {
+            let stale = UnixStream::connect(path)
+                .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused);
+            if stale {
+                // Left over from a server that's gone.
+                debug!("removing stale socket {:?}", path);
+                let _ = std::fs::remove_file(path);
+            }
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("failed to listen on {path:?}"))?;
+            info!("listening for connections on {:?}", path);
+            Listener::Unix(listener, path.clone())
+        }
+        (None, None) => panic!("nothing to listen on"),
+    };
+
+    // The sessions' processes aren't waited for; this leaves them for the kernel to reap.
+    unsafe { libc::signal(libc::SIGCHLD, libc::SIG_IGN) };
+
+    loop {
+        let (conn, peer) = match listener.accept() {
+            Ok(conn) => conn,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        info!("connection from {}", peer);
+        let pid = checkerr(unsafe { libc::fork() }, "fork")?;
+        if pid == 0 {
+            drop(listener);
+            if let Err(e) = session(conn, options) {
+                error!("connection from {}: {:#}", peer, e);
+                exit(1);
+            }
+            info!("connection from {} closed", peer);
+            exit(0);
+        }
+    }
+}
+
+/// Run a session for a client. The connection becomes stdin and stdout, which is where a session
+/// expects to find its console.
+fn session(conn: OwnedFd, mut options: Options) -> Result<()> {
+    unsafe { libc::signal(libc::SIGCHLD, libc::SIG_DFL) };
+    for fd in [0, 1] {
+        checkerr(unsafe { libc::dup2(conn.as_raw_fd(), fd) }, "dup2")?;
+    }
+    drop(conn);
+
+    if let Some(ref mut control) = options.control {
+        control.as_mut_os_string().push(format!(".{}", std::process::id()));
+    }
+
+    if options.telnet.is_some() {
+        let mut console = ManuallyDrop::new(unsafe { File::from_raw_fd(1) });
+        console.write_all(telnet::NEGOTIATION).context("failed to write to connection")?;
+    }
+
+    SlowPty::with_options(options).spawn()?.wait()?;
+    Ok(())
+}
@@ -0,0 +1,511 @@
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::{self, ManuallyDrop};
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::OwnedFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::checkerr;
+use crate::child::{self, Child, Exec};
+use crate::event_loop::{event_loop, Exit, Session};
+use crate::events::EventStream;
+use crate::modem;
+use crate::options::Options;
+use crate::pty;
+use crate::replay;
+use crate::signals::{SignalPipe, INFO_SIGNALS};
+use crate::stats::Stats;
+use crate::telnet;
+use crate::term::{self, TermGuard};
+
+/// How long a `modem` takes to make its handshake after dialing, unless `connect_banner` says.
+const MODEM_HANDSHAKE: Duration = Duration::from_secs(3);
+
+/// How long the program gets to exit after a SIGINT, SIGTERM or SIGHUP is passed on to it, before
+/// it's killed.
+const SIGNAL_GRACE: Duration = Duration::from_secs(1);
+
+/// Builds a rate-limited session: a program running on a new pty, connected to this process's
+/// terminal (stdin and stdout) through the rate limits.
+///
+/// ```no_run
+/// let outcome = slowpty::SlowPty::new(["vi", "notes.txt"]).rate(240.).spawn()?.wait()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct SlowPty {
+    options: Options,
+}
+
+impl SlowPty {
+    /// A session running the given program (followed by its arguments), with no rate limit.
+    pub fn new(command: impl IntoIterator<Item = impl Into<OsString>>) -> Self {
+        SlowPty {
+            options: Options {
+                command: command.into_iter().map(Into::into).collect(),
+                ..Options::default()
+            },
+        }
+    }
+
+    /// A session configured by the full set of options, as parsed from the command line.
+    pub fn with_options(options: Options) -> Self {
+        SlowPty { options }
+    }
+
+    /// Limit each direction (or both together, with `shared_rate`) to this many bytes per second.
+    pub fn rate(mut self, rate: f64) -> Self {
+        self.options.rate = Some(rate);
+        self
+    }
+
+    /// Limit input (console to program) to this many bytes per second, instead of `rate`.
+    pub fn in_rate(mut self, rate: f64) -> Self {
+        self.options.in_rate = Some(rate);
+        self
+    }
+
+    /// Limit output (program to console) to this many bytes per second, instead of `rate`.
+    pub fn out_rate(mut self, rate: f64) -> Self {
+        self.options.out_rate = Some(rate);
+        self
+    }
+
+    /// Limit the total of both directions to `rate`, instead of each one.
+    pub fn shared_rate(mut self, shared: bool) -> Self {
+        self.options.shared_rate = shared;
+        self
+    }
+
+    /// The options the session will run with, for anything there isn't a method for.
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    /// Start the program on a new pty, and put the terminal in raw mode. The terminal settings
+    /// are restored when this process exits, or by [`Running::wait`].
+    ///
+    /// If stdin isn't a terminal, or with `no_raw`, the terminal is left alone and the session
+    /// throttles a pipeline from stdin to stdout instead.
+    ///
+    /// With `connect`, there's no program or pty: the connection takes their place. With
+    /// `replay`, the recording does, with `serial`, the device, and with `cat`, a socket that
+    /// sends back whatever it gets.
+    ///
+    /// On Linux, the program gets SIGHUP when the thread that called this exits, so that it isn't
+    /// left behind if this process is killed; but that goes for any other thread too, so call
+    /// this from one that lasts as long as the session does, such as the main thread.
+    pub fn spawn(mut self) -> Result<Running> {
+        let console = if self.options.telnet.is_some() {
+            Console::Telnet
+        } else if self.options.listen.is_some() || self.options.session.is_some() {
+            Console::Socket
+        } else if self.options.no_raw || self.options.cat {
+            // With `cat`, it's always a pipeline, even from a terminal.
+            self.options.no_raw = true;
+            Console::Pipeline
+        } else if !term::is_tty(0) {
+            debug!("stdin isn't a terminal; not using raw mode");
+            self.options.no_raw = true;
+            Console::Pipeline
+        } else {
+            Console::Terminal
+        };
+
+        // Catch SIGCHLD before forking, so an early exit can't be missed. SIGWINCH is caught from
+        // the start too, so a resize while the program is starting up still gets passed on.
+        // SIGUSR1 and SIGUSR2 double and halve the rate. SIGTSTP suspends the whole session, with
+        // the terminal settings restored while it's stopped. SIGINT, SIGTERM and SIGHUP are passed
+        // on to the program before the session ends, so it isn't left behind on a dead pty with
+        // the terminal still raw. SIGQUIT (or SIGINFO) asks for a report.
+        let mut catch = vec![libc::SIGCHLD, libc::SIGWINCH, libc::SIGUSR1, libc::SIGUSR2,
+            libc::SIGTSTP, libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+        catch.extend_from_slice(INFO_SIGNALS);
+        let signals = SignalPipe::install(&catch).context("failed to set up signal handling")?;
+
+        let term = match console {
+            Console::Terminal => Some(raw_console(self.options.reset_sane)?),
+            _ => None,
+        };
+
+        // Output goes to stdout, unless that's the terminal the input comes from, in which case
+        // the console is written through stdin, as it's read. So a redirected stdout gets just
+        // the output, and nothing but writes is done to it.
+        let console_out = (!term::same_terminal(0, 1))
+            .then(|| ManuallyDrop::new(unsafe { File::from_raw_fd(1) }));
+        // The console is our stdin, which is not ours to close: the terminal settings are
+        // restored through it at exit.
+        let console_file = ManuallyDrop::new(unsafe { File::from_raw_fd(0) });
+
+        if self.options.modem {
+            let output: &File = console_out.as_deref().unwrap_or(&console_file);
+            let number = modem::dial(&mut &*console_file, &mut &*output)?;
+            if self.options.command.is_empty() {
+                let addr = if number.contains(':') { number } else { format!("{number}:23") };
+                self.options.connect = Some(addr);
+            }
+            self.options.connect_banner.get_or_insert(MODEM_HANDSHAKE);
+        }
+
+        let mut events = self.options.events_fd.map(EventStream::from_fd).transpose()?;
+
+        let mut pty_slave = None;
+        let (child, pty_master) = match (&self.options.connect, &self.options.replay,
+            &self.options.serial)
+        {
+            (Some(addr), _, _) => {
+                let stream = TcpStream::connect(addr.as_str());
+                if stream.is_err() && self.options.modem {
+                    let output: &File = console_out.as_deref().unwrap_or(&console_file);
+                    let _ = (&*output).write_all(&modem::result("NO CARRIER"));
+                }
+                let stream = stream.with_context(|| format!("failed to connect to {addr}"))?;
+                // Paced a byte at a time, which is how it should go out.
+                stream.set_nodelay(true).context("failed to set TCP_NODELAY")?;
+                (None, File::from(OwnedFd::from(stream)))
+            }
+            (None, Some(path), _) => {
+                let options = &self.options;
+                let chunks = replay::load(path, options.replay_format, options.timing.as_deref(),
+                    options.replay_speed)?;
+                let (ours, theirs) = UnixStream::pair().context("failed to create socket pair")?;
+                std::thread::spawn(move || {
+                    if let Err(e) = replay::play(chunks, theirs) {
+                        warn!("replay failed: {}", e);
+                    }
+                });
+                (None, File::from(OwnedFd::from(ours)))
+            }
+            (None, None, Some(path)) => {
+                let device = term::open_serial(path, self.options.serial_speed)?;
+                (None, device)
+            }
+            (None, None, None) if self.options.cat => {
+                let (ours, theirs) = UnixStream::pair().context("failed to create socket pair")?;
+                std::thread::spawn(move || {
+                    if let Err(e) = io::copy(&mut &theirs, &mut &theirs) {
+                        warn!("copying failed: {}", e);
+                    }
+                    let _ = theirs.shutdown(Shutdown::Write);
+                });
+                (None, File::from(OwnedFd::from(ours)))
+            }
+            (None, None, None) => {
+                let options = &self.options;
+                let exec = Exec::new(&options.command, &options.env, options.env_clear,
+                    options.chdir.as_deref())?;
+                let ForkResult { child_pid, pty_master, pty_slave: slave, pty_name,
+                    window_size } = setup(options, console, &exec)
+                        .context("failed to setup PTY")?;
+                pty_slave = slave;
+                info!("started {} as child process {}",
+                    crate::options::display_command(&self.options.command), child_pid);
+                if let Some(ref mut events) = events {
+                    events.session_start(Some(child_pid), pty_name.as_deref(),
+                        Some((window_size.cols(), window_size.rows())));
+                }
+                (Some(Child::new(child_pid)), pty_master)
+            }
+        };
+        if let (None, Some(events)) = (&child, &mut events) {
+            events.session_start(None, None, None);
+        }
+
+        Ok(Running {
+            options: self.options,
+            signals,
+            console: console_file,
+            console_out,
+            pty_master,
+            pty_slave,
+            child,
+            term,
+            events,
+        })
+    }
+}
+
+/// A session whose program has been started.
+pub struct Running {
+    options: Options,
+    signals: SignalPipe,
+    console: ManuallyDrop<File>,
+    console_out: Option<ManuallyDrop<File>>,
+    /// The pty master, or with `connect`, the connection, or with `serial`, the device, or with
+    /// `replay` or `cat`, the socket the recording is played into or the input is sent round.
+    pty_master: File,
+    /// Held open until the session is over, where the pty would otherwise throw away what the
+    /// program left in it.
+    pty_slave: Option<File>,
+    child: Option<Child>,
+    /// The terminal's settings from before it was put in raw mode, if it was.
+    term: Option<TermGuard>,
+    events: Option<EventStream>,
+}
+
+/// How a session ended.
+pub struct Outcome {
+    pub exit: Exit,
+    /// The program's wait status.
+    pub status: libc::c_int,
+    pub stats: Stats,
+}
+
+impl Running {
+    /// Run the session until it's over, then clean up: hang up on the program if it's still
+    /// running, reap it, and restore the terminal settings.
+    pub fn wait(self) -> Result<Outcome> {
+        let Running { options, mut signals, mut console, mut console_out, mut pty_master,
+            pty_slave, mut child, mut term, mut events } = self;
+
+        let mut stats = Stats::default();
+        let result = event_loop(
+            &options,
+            Session {
+                console: &mut console,
+                console_out: console_out.as_deref_mut(),
+                pty_master: &mut pty_master,
+                child: child.as_mut(),
+                signals: Some(&mut signals),
+                term: term.as_mut(),
+                events: events.as_mut(),
+            },
+            &mut stats);
+
+        // Tear down the session. The order matters:
+        //   1. Flush anything still destined for the console. (The event loop has already put
+        //      the console and pty back into blocking mode.)
+        //   2. Close the pty, which hangs up on the child if it's still running.
+        //   3. Reap the child.
+        //   4. Restore the terminal settings, so anything printed from here on looks normal.
+
+        debug!("flushing console");
+        if let Err(e) = console_out.as_deref_mut().unwrap_or(&mut console).flush() {
+            warn!("failed to flush console: {}", e);
+        }
+
+        debug!("dropping pty master");
+        mem::drop(pty_master);
+        mem::drop(pty_slave);
+
+        let Some(mut child) = child else {
+            if let Some(ref mut events) = events {
+                events.bytes_transferred(&stats);
+            }
+            mem::drop(term);
+            return Ok(Outcome { exit: result?, status: 0, stats });
+        };
+        match result {
+            Ok(Exit::Closed) => (),
+            Ok(Exit::Signaled(_) | Exit::TimedOut) => {
+                // The signal has already been passed on; give the child a moment to act on it.
+                if !matches!(child.wait_timeout(SIGNAL_GRACE), Ok(Some(_))) {
+                    debug!("child didn't exit after being signaled; killing it");
+                    child.signal_group(libc::SIGKILL);
+                }
+            }
+            _ => {
+                // The child is most likely still running, but the session is over.
+                debug!("hanging up on child");
+                child.signal(libc::SIGHUP);
+            }
+        }
+
+        debug!("waiting on child");
+        let wait_result = child.wait();
+        if let Some(ref mut events) = events {
+            if let Ok(status) = wait_result {
+                events.child_exit(status);
+            }
+            events.bytes_transferred(&stats);
+        }
+
+        debug!("resetting tty settings");
+        mem::drop(term);
+
+        Ok(Outcome {
+            exit: result?,
+            status: wait_result?,
+            stats,
+        })
+    }
+}
+
+/// Check, as well as can be done, whether what gets written to the console would come back when
+/// reading from it, which would make a runaway loop. The console is read and written through
+/// `input`; `output` is stdout. Returns a description of the problem, if there is one.
+pub fn loopback(input: RawFd, output: RawFd) -> Option<&'static str> {
+    let stat = |fd| {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        (unsafe { libc::fstat(fd, &mut st) } == 0).then_some(st)
+    };
+    let is_fifo = |st: &libc::stat| st.st_mode & libc::S_IFMT == libc::S_IFIFO;
+
+    let input_stat = stat(input).filter(is_fifo)?;
+    let flags = unsafe { libc::fcntl(input, libc::F_GETFL) };
+    if flags != -1 && flags & libc::O_ACCMODE == libc::O_RDWR {
+        return Some("stdin is a pipe open for both reading and writing");
+    }
+    if let Some(output_stat) = stat(output).filter(is_fifo) {
+        if (input_stat.st_dev, input_stat.st_ino) == (output_stat.st_dev, output_stat.st_ino) {
+            return Some("stdin and stdout are the same pipe");
+        }
+    }
+    None
+}
+
+#[test]
+fn test_loopback() {
+    let mut fds = [0; 2];
+    checkerr(unsafe { libc::pipe(fds.as_mut_ptr()) }, "pipe").unwrap();
+    let [read, write] = fds.map(|fd| unsafe { File::from_raw_fd(fd) });
+    assert!(loopback(read.as_raw_fd(), write.as_raw_fd()).is_some());
+
+    let mut other = [0; 2];
+    checkerr(unsafe { libc::pipe(other.as_mut_ptr()) }, "pipe").unwrap();
+    let [_other_read, other_write] = other.map(|fd| unsafe { File::from_raw_fd(fd) });
+    assert!(loopback(read.as_raw_fd(), other_write.as_raw_fd()).is_none());
+}
+
+/// What's at the other end of the session from the program.
+#[derive(Clone, Copy, PartialEq)]
+enum Console {
+    /// A terminal, which is put in raw mode.
+    Terminal,
+    /// A pipeline, which the pty is set up to pass through untouched.
+    Pipeline,
+    /// A telnet client, connected to stdin and stdout, whose terminal is at the far end.
+    Telnet,
+    /// Some other client connected to stdin and stdout (or with `session`, whoever is attached),
+    /// with a terminal at the far end.
+    Socket,
+}
+
+/// Put the terminal in raw mode, to be restored at exit.
+fn raw_console(reset_sane: bool) -> Result<TermGuard> {
+    let mut term = TermGuard::new(0)?;
+    if reset_sane {
+        term.reset_sane();
+    }
+    term.set_raw()?;
+    debug!("terminal is in raw mode");
+    Ok(term)
+}
+
+struct ForkResult {
+    child_pid: libc::pid_t,
+    pty_master: File,
+    /// Where the pty would throw away the output left in it when the child exits, the slave,
+    /// held open until the session is over.
+    pty_slave: Option<File>,
+    pty_name: Option<String>,
+    window_size: term::WindowSize,
+}
+
+fn setup(options: &Options, console: Console, exec: &Exec) -> Result<ForkResult> {
+    let window_size = match term::WindowSize::from_fd(0) {
+        Ok(ws) => {
+            debug!("terminal size: {}x{}", ws.cols(), ws.rows());
+            Some(ws)
+        }
+        Err(_) if console == Console::Telnet => telnet::peek_window_size(0),
+        Err(_) if matches!(console, Console::Pipeline | Console::Socket) => {
+            term::WindowSize::from_env()
+        }
+        Err(e) if term::is_tty(0) => {
+            // Some terminals (like serial consoles) don't know their size, but are otherwise
+            // perfectly usable.
+            info!("terminal doesn't report its size: {:#}", e);
+            let ws = term::WindowSize::from_env();
+            if let Some(ref ws) = ws {
+                info!("using $COLUMNS and $LINES for the terminal size: {}x{}", ws.cols(),
+                    ws.rows());
+            }
+            ws
+        }
+        Err(e) => {
+            debug!("no terminal size: {:#}", e);
+            term::WindowSize::from_env()
+        }
+    };
+    // Without any size at all, a lot of programs misbehave, so make one up if need be.
+    let window_size = window_size.unwrap_or_else(|| {
+        debug!("using the default terminal size");
+        term::WindowSize::default()
+    })
+        .overridden(options.cols, options.rows);
+
+    let pty::PtyPair { master, slave } = pty::open_pty_pair()?;
+    let pty_name = pty::name(&slave);
+    if console == Console::Pipeline {
+        // Before anything can be written to it, or it'd be echoed.
+        term::set_filter(slave.as_raw_fd())?;
+    } else if options.raw_nl {
+        term::set_untranslated_output(slave.as_raw_fd())?;
+    }
+
+    let parent = unsafe { libc::getpid() };
+    let pid = checkerr(unsafe { libc::fork() }, "fork")?;
+    if pid != 0 {
+        // parent
+
+        // On Linux, only the child holds the slave open, so once it exits, reads from the
+        // master fail with EIO once what was buffered has been read. Elsewhere the pty would
+        // throw that away as soon as the child closed the slave, so there it's kept open here,
+        // which leaves the event loop to find out about the exit via SIGCHLD and drain the
+        // master until it's empty.
+        let pty_slave = if pty::DISCARDS_ON_CLOSE {
+            // Not for anything started from here on, like filters, to inherit.
+            checkerr(unsafe { libc::fcntl(slave.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) },
+                "fcntl(F_SETFD)")?;
+            Some(slave)
+        } else {
+            debug!("dropping the pty slave");
+            mem::drop(slave);
+            None
+        };
+        Ok(ForkResult {
+            child_pid: pid,
+            pty_master: master,
+            pty_slave,
+            pty_name,
+            window_size,
+        })
+    } else {
+        start_program(options, master, slave, &window_size, exec, parent)
+    }
+}
+
+/// The child's side of the fork: make the pty its terminal, and run the program there.
+///
+/// It never returns, as the child has a copy of everything the parent had, like the console's
+/// `TermGuard` and what's saved for resetting the terminal at exit, and none of that may be
+/// acted on from here. As with `Exec`, nothing may allocate either, so anything that goes wrong
+/// ends it with `child::fail`.
+fn start_program(options: &Options, master: File, slave: File, window_size: &term::WindowSize,
+    exec: &Exec, parent: libc::pid_t) -> !
+{
+    mem::drop(master);
+    child::hangup_on_parent_death(parent);
+    // With --separate-stderr, keep hold of ours to put back once the pty has taken over.
+    let stderr = options.separate_stderr.then(|| unsafe { libc::dup(2) });
+    if stderr == Some(-1) {
+        child::fail(b"slowpty: dup(stderr)", child::errno());
+    }
+    if let Err(e) = pty::login_tty(slave) {
+        child::fail(b"slowpty: login_tty", e.raw_os_error().unwrap_or(0));
+    }
+    if let Some(fd) = stderr {
+        if unsafe { libc::dup2(fd, 2) } == -1 {
+            child::fail(b"slowpty: dup2 stderr -> 2", child::errno());
+        }
+        unsafe { libc::close(fd) };
+    }
+    if let Err(e) = window_size.apply_to_fd(0) {
+        child::fail(b"slowpty: ioctl(TIOCSWINSZ)", e.raw_os_error().unwrap_or(0));
+    }
+    exec.exec()
+}
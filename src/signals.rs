@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use crate::checkerr;
+
+/// Signals that ask for a report on how the session is going, like dd: SIGQUIT, and SIGINFO
+/// (Ctrl-T) where there is such a thing.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly",
+    target_os = "openbsd", target_os = "netbsd"))]
+pub const INFO_SIGNALS: &[libc::c_int] = &[libc::SIGQUIT, libc::SIGINFO];
+
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+    target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd")))]
+pub const INFO_SIGNALS: &[libc::c_int] = &[libc::SIGQUIT];
+
+/// Write end of the self-pipe, for the signal handler.
+static PIPE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__error()
+}
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno()
+}
+
+extern "C" fn handler(sig: libc::c_int) {
+    // note: only async-signal-safe things allowed in here
+    unsafe {
+        let saved_errno = *errno_location();
+        let fd = PIPE_WRITE_FD.load(Ordering::Relaxed);
+        if fd != -1 {
+            // If the pipe is full, there's already plenty of wakeups queued.
+            let byte = sig as u8;
+            libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        }
+        *errno_location() = saved_errno;
+    }
+}
+
+/// Turns signals into bytes on a pipe, so the event loop can poll for them alongside everything
+/// else (the "self-pipe trick").
+pub struct SignalPipe {
+    read: File,
+}
+
+impl SignalPipe {
+    /// Set up the pipe and start catching the given signals.
+    pub fn install(signals: &[libc::c_int]) -> Result<Self> {
+        let mut fds = [0; 2];
+        checkerr(unsafe { libc::pipe(fds.as_mut_ptr()) }, "pipe")?;
+        for fd in fds {
+            checkerr(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) },
+                "fcntl(F_SETFD)")?;
+            checkerr(unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) },
+                "fcntl(F_SETFL)")?;
+        }
+        let read = unsafe { File::from_raw_fd(fds[0]) };
+        PIPE_WRITE_FD.store(fds[1], Ordering::SeqCst);
+
+        let pipe = SignalPipe { read };
+        for &sig in signals {
+            pipe.catch(sig)?;
+        }
+        Ok(pipe)
+    }
+
+    /// Start catching another signal.
+    pub fn catch(&self, sig: libc::c_int) -> Result<()> {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        if sig == libc::SIGCHLD {
+            // Only interested in exits.
+            action.sa_flags |= libc::SA_NOCLDSTOP;
+        }
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+        checkerr(unsafe { libc::sigaction(sig, &action, std::ptr::null_mut()) }, "sigaction")
+            .with_context(|| format!("failed to install handler for signal {sig}"))?;
+        Ok(())
+    }
+
+    /// Let a caught signal have its default effect after all, then go back to catching it. For
+    /// SIGTSTP this stops the process, and returns once it's continued.
+    pub fn raise_default(&self, sig: libc::c_int) -> Result<()> {
+        if unsafe { libc::signal(sig, libc::SIG_DFL) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("failed to reset handler for signal {sig}"));
+        }
+        checkerr(unsafe { libc::raise(sig) }, "raise")?;
+        self.catch(sig)
+    }
+
+    /// Signals received since the last call, in order, without duplicates.
+    pub fn pending(&mut self) -> Vec<libc::c_int> {
+        let mut signals = vec![];
+        let mut buf = [0u8; 64];
+        loop {
+            match self.read.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    for &b in &buf[.. n] {
+                        let sig = libc::c_int::from(b);
+                        if !signals.contains(&sig) {
+                            signals.push(sig);
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("error reading signal pipe: {}", e);
+                    break;
+                }
+            }
+        }
+        signals
+    }
+}
+
+impl AsRawFd for SignalPipe {
+    fn as_raw_fd(&self) -> RawFd {
+        self.read.as_raw_fd()
+    }
+}
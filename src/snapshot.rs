@@ -0,0 +1,76 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::recording::Recording;
+
+/// With `--snapshot`, keeps track of what's on the screen, by running the output shown on the
+/// console through a terminal emulator, and writes it out as plain text at the end.
+pub struct Snapshot {
+    path: PathBuf,
+    /// Created up front, so a bad path is found before the session rather than after it.
+    file: Option<File>,
+    parser: vt100::Parser,
+}
+
+impl Snapshot {
+    pub fn create(path: &Path, (cols, rows): (u16, u16)) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create snapshot {path:?}"))?;
+        Ok(Snapshot {
+            path: path.to_owned(),
+            file: Some(file),
+            parser: vt100::Parser::new(rows, cols, 0),
+        })
+    }
+
+    /// What's on the screen: a line for each row, without the blanks at the end of it, and
+    /// without the blank rows at the bottom.
+    pub fn contents(&self) -> String {
+        let screen = self.parser.screen();
+        let mut rows: Vec<String> = screen.rows(0, screen.size().1)
+            .map(|row| row.trim_end().to_owned())
+            .collect();
+        while rows.last().is_some_and(String::is_empty) {
+            rows.pop();
+        }
+        rows.into_iter().map(|row| row + "\n").collect()
+    }
+}
+
+impl Recording for Snapshot {
+    fn output(&mut self, _now: Instant, data: &[u8]) -> io::Result<()> {
+        self.parser.process(data);
+        Ok(())
+    }
+
+    fn resize(&mut self, _now: Instant, cols: u16, rows: u16) -> io::Result<()> {
+        self.parser.set_size(rows, cols);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let Some(mut file) = self.file.take() else { return Ok(()) };
+        debug!("writing the final screen to {:?}", self.path);
+        file.write_all(self.contents().as_bytes())
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let path = std::env::temp_dir().join(format!("slowpty-snapshot-{}", std::process::id()));
+    let mut snapshot = Snapshot::create(&path, (20, 5)).unwrap();
+    let now = Instant::now();
+    snapshot.output(now, b"$ ls\r\nfoo  bar   \r\n$ progress 10%").unwrap();
+    snapshot.output(now, b"\r\x1b[K$ progress \x1b[1m100%\x1b[m\r\n$ ").unwrap();
+    assert_eq!(snapshot.contents(), "$ ls\nfoo  bar\n$ progress 100%\n$\n");
+
+    // Scrolled off the top, and wrapped at the edge.
+    snapshot.output(now, b"\r\n\r\n\r\n0123456789abcdefghijKLM").unwrap();
+    snapshot.finish().unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(text, "$\n\n\n0123456789abcdefghij\nKLM\n");
+}
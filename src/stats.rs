@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+/// Counters for the traffic passing through the event loop.
+#[derive(Default)]
+pub struct Stats {
+    /// Bytes delivered, indexed like the `ReadableSet` endpoints: 0 is console -> pty (input),
+    /// 1 is pty -> console (output).
+    pub bytes: [u64; 2],
+
+    /// Total time each delivered byte spent between being read and being written, in seconds.
+    pub latency_sum: [f64; 2],
+
+    /// How long the session lasted.
+    pub elapsed: Duration,
+
+    /// Times the link had to wake up after being idle (with `--wakeup`).
+    pub wakeups: u64,
+
+    /// Bytes thrown away in each direction when the buffer overflowed (with `--overflow`).
+    pub dropped: [u64; 2],
+}
+
+impl Stats {
+    pub fn output_bytes(&self) -> u64 {
+        self.bytes[1]
+    }
+
+    /// Account for some bytes being delivered, after spending the given time in transit.
+    pub fn delivered(&mut self, idx: usize, bytes: usize, latency: Duration) {
+        self.bytes[idx] += bytes as u64;
+        self.latency_sum[idx] += latency.as_secs_f64() * bytes as f64;
+    }
+
+    /// Average bytes per second delivered in one direction over the whole session.
+    pub fn throughput(&self, idx: usize) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0. => self.bytes[idx] as f64 / secs,
+            _ => 0.,
+        }
+    }
+
+    /// A summary of the session, a line for each direction.
+    pub fn summary(&self) -> String {
+        let mut summary = format!("session lasted {:.3}s\n", self.elapsed.as_secs_f64());
+        for (idx, direction) in ["input", "output"].into_iter().enumerate() {
+            summary += &format!("{direction}: {} bytes, {:.1} bytes/sec\n", self.bytes[idx],
+                self.throughput(idx));
+        }
+        summary
+    }
+
+    /// Average time a byte took to be delivered in one direction.
+    pub fn mean_latency(&self, idx: usize) -> Duration {
+        match self.bytes[idx] {
+            0 => Duration::ZERO,
+            n => Duration::from_secs_f64(self.latency_sum[idx] / n as f64),
+        }
+    }
+}
+
+#[test]
+fn test_stats() {
+    let mut stats = Stats::default();
+    stats.delivered(1, 10, Duration::from_millis(40));
+    stats.delivered(1, 30, Duration::from_millis(80));
+    stats.elapsed = Duration::from_secs(2);
+    assert_eq!(stats.throughput(1), 20.);
+    assert_eq!(stats.mean_latency(1), Duration::from_millis(70));
+    assert_eq!(stats.throughput(0), 0.);
+    assert_eq!(stats.mean_latency(0), Duration::ZERO);
+    assert_eq!(stats.summary(),
+        "session lasted 2.000s\ninput: 0 bytes, 0.0 bytes/sec\noutput: 40 bytes, 20.0 bytes/sec\n");
+}
@@ -0,0 +1,39 @@
+use std::io::{self, Write};
+
+/// Reports status changes to the user without disturbing the session, by putting them in the
+/// terminal's title.
+pub struct Status {
+    enabled: bool,
+    title_saved: bool,
+}
+
+impl Status {
+    pub fn new(enabled: bool) -> Self {
+        Status {
+            enabled,
+            title_saved: false,
+        }
+    }
+
+    pub fn show(&mut self, console: &mut impl Write, msg: &str) -> io::Result<()> {
+        info!("{}", msg);
+        if !self.enabled {
+            return Ok(());
+        }
+        if !self.title_saved {
+            // xterm: push the current title onto the title stack so it can be put back later.
+            console.write_all(b"\x1b[22;0t")?;
+            self.title_saved = true;
+        }
+        write!(console, "\x1b]2;slowpty: {msg}\x07")
+    }
+
+    pub fn clear(&mut self, console: &mut impl Write) -> io::Result<()> {
+        if self.title_saved {
+            // Pop the original title back off the stack.
+            console.write_all(b"\x1b[23;0t")?;
+            self.title_saved = false;
+        }
+        Ok(())
+    }
+}
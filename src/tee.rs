@@ -0,0 +1,35 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// Copies what's delivered in one direction to a file, as it's delivered.
+pub struct Tee {
+    file: File,
+    /// Write each chunk on its own line, timestamped and escaped, instead of as it is.
+    timestamps: bool,
+    start: Instant,
+}
+
+impl Tee {
+    pub fn create(path: &Path, timestamps: bool, now: Instant) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create log {path:?}"))?;
+        Ok(Tee {
+            file,
+            timestamps,
+            start: now,
+        })
+    }
+
+    pub fn write(&mut self, now: Instant, data: &[u8]) -> io::Result<()> {
+        if self.timestamps {
+            let timestamp = now.saturating_duration_since(self.start).as_secs_f64();
+            let line = format!("{timestamp:10.3} \"{}\"\n", data.escape_ascii());
+            self.file.write_all(line.as_bytes())
+        } else {
+            self.file.write_all(data)
+        }
+    }
+}
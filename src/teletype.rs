@@ -0,0 +1,105 @@
+use std::time::{Duration, Instant};
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+
+/// Finds where a teletype's carriage goes back to the start of the line: at a CR, or at a LF
+/// that doesn't come right after one (as in output that the pty hasn't translated). NULs, being
+/// padding, don't count either way.
+struct LineEnds {
+    prev: u8,
+}
+
+impl LineEnds {
+    fn new() -> Self {
+        LineEnds { prev: 0 }
+    }
+
+    /// The positions just past each line end in the next of the data.
+    fn find(&mut self, data: &[u8]) -> Vec<usize> {
+        let mut ends = vec![];
+        for (i, &b) in data.iter().enumerate() {
+            if b == CR || (b == LF && self.prev != CR) {
+                ends.push(i + 1);
+            }
+            if b != 0 {
+                self.prev = b;
+            }
+        }
+        ends
+    }
+}
+
+/// With `--fill-nuls` and `--cr-delay`, gives the carriage time to return after each line of
+/// output, as a hardcopy terminal needed: by sending NULs after it, or by pausing after it.
+pub struct Teletype {
+    nuls: usize,
+    delay: Duration,
+    read: LineEnds,
+    written: LineEnds,
+    /// The end of the pause after the last line end written.
+    until: Option<Instant>,
+}
+
+impl Teletype {
+    pub fn new(nuls: usize, delay: Duration) -> Self {
+        Teletype {
+            nuls,
+            delay,
+            read: LineEnds::new(),
+            written: LineEnds::new(),
+            until: None,
+        }
+    }
+
+    /// Split output as it's read into pieces that each end at a line end (and its padding), to
+    /// be queued separately, so that none of them gets written with a line end in the middle.
+    pub fn split(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let ends = self.read.find(data);
+        let mut pieces = Vec::with_capacity(ends.len() + 1);
+        let mut start = 0;
+        for end in ends {
+            let mut piece = data[start .. end].to_vec();
+            piece.resize(piece.len() + self.nuls, 0);
+            pieces.push(piece);
+            start = end;
+        }
+        if start < data.len() {
+            pieces.push(data[start ..].to_vec());
+        }
+        pieces
+    }
+
+    /// Take note of output having been written: after a line end, what comes next has to wait.
+    pub fn written(&mut self, now: Instant, data: &[u8]) {
+        if !self.delay.is_zero() && !self.written.find(data).is_empty() {
+            self.until = Some(now + self.delay);
+        }
+    }
+
+    /// How long until more output can be written, if it has to wait.
+    pub fn wait(&self, now: Instant) -> Option<Duration> {
+        self.until.filter(|&until| until > now).map(|until| until - now)
+    }
+}
+
+#[test]
+fn test_teletype() {
+    let mut tty = Teletype::new(2, Duration::ZERO);
+    assert_eq!(tty.split(b"ab\r\ncd\ne"), [&b"ab\r\0\0"[..], b"\ncd\n\0\0", b"e"]);
+    // The CR at the end of one read goes with the LF at the start of the next.
+    assert_eq!(tty.split(b"f\r"), [b"f\r\0\0"]);
+    assert_eq!(tty.split(b"\ng"), [b"\ng"]);
+
+    let start = Instant::now();
+    let ms = Duration::from_millis;
+    let mut tty = Teletype::new(0, ms(200));
+    tty.written(start, b"abc");
+    assert_eq!(tty.wait(start), None);
+    tty.written(start, b"\r");
+    assert_eq!(tty.wait(start + ms(50)), Some(ms(150)));
+    assert_eq!(tty.wait(start + ms(200)), None);
+    // Not again for the LF after the CR, even with padding in between.
+    tty.written(start + ms(200), b"\0\n");
+    assert_eq!(tty.wait(start + ms(200)), None);
+}
@@ -0,0 +1,227 @@
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use crate::filter::Filter;
+use crate::options::Direction;
+use crate::term::WindowSize;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const BINARY: u8 = 0;
+const ECHO: u8 = 1;
+const SGA: u8 = 3;
+const NAWS: u8 = 31;
+
+/// What the server asks for at the start of a connection: both directions 8-bit clean, character
+/// at a time with the pty doing the echoing, and the client's window size.
+pub const NEGOTIATION: &[u8] = &[
+    IAC, WILL, ECHO,
+    IAC, WILL, SGA, IAC, DO, SGA,
+    IAC, WILL, BINARY, IAC, DO, BINARY,
+    IAC, DO, NAWS,
+];
+
+/// How long to wait for the client to say what its window size is, before starting the program
+/// without one.
+const WINDOW_SIZE_WAIT: Duration = Duration::from_secs(1);
+
+/// Longest subnegotiation to keep; NAWS only needs 5 bytes, and nothing else is understood.
+const MAX_SUBNEGOTIATION: usize = 64;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Data,
+    /// After an IAC.
+    Iac,
+    /// After IAC and WILL, WONT, DO or DONT, waiting for the option.
+    Option(u8),
+    /// Inside IAC SB ... IAC SE.
+    Sub,
+    /// An IAC inside a subnegotiation.
+    SubIac,
+}
+
+/// The telnet protocol, as much of it as a server needs: takes the commands out of what the
+/// client sends, and turns down any options it asks for that aren't supported.
+pub struct Telnet {
+    state: State,
+    sub: Vec<u8>,
+    /// The last byte of data was a CR, so a NUL or LF following it is part of the newline.
+    cr: bool,
+    /// Replies to send to the client.
+    replies: Vec<u8>,
+    /// The window size the client last reported, if it hasn't been picked up yet.
+    window_size: Option<(u16, u16)>,
+}
+
+impl Telnet {
+    pub fn new() -> Self {
+        Telnet {
+            state: State::Data,
+            sub: vec![],
+            cr: false,
+            replies: vec![],
+            window_size: None,
+        }
+    }
+
+    /// Take what the client sent, and return the data in it.
+    pub fn input(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            self.state = match (self.state, b) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, 0 | b'\n') if self.cr => {
+                    // A newline is sent as CR NUL or CR LF; the pty only wants the CR.
+                    self.cr = false;
+                    State::Data
+                }
+                (State::Data, _) => {
+                    self.cr = b == b'\r';
+                    out.push(b);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    self.cr = false;
+                    out.push(IAC);
+                    State::Data
+                }
+                (State::Iac, WILL | WONT | DO | DONT) => State::Option(b),
+                (State::Iac, SB) => {
+                    self.sub.clear();
+                    State::Sub
+                }
+                // Anything else (NOP, GA, and the like) means nothing here.
+                (State::Iac, _) => State::Data,
+                (State::Option(verb), _) => {
+                    self.respond(verb, b);
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => {
+                    if self.sub.len() < MAX_SUBNEGOTIATION {
+                        self.sub.push(b);
+                    }
+                    State::Sub
+                }
+                (State::SubIac, IAC) => {
+                    if self.sub.len() < MAX_SUBNEGOTIATION {
+                        self.sub.push(IAC);
+                    }
+                    State::Sub
+                }
+                (State::SubIac, SE) => {
+                    self.subnegotiation();
+                    State::Data
+                }
+                // Not a proper end; give up on it.
+                (State::SubIac, _) => State::Data,
+            };
+        }
+        out
+    }
+
+    /// Turn down options that aren't supported. The ones that are were offered or asked for at
+    /// the start, so agreeing needs no reply.
+    fn respond(&mut self, verb: u8, option: u8) {
+        let reply = match verb {
+            WILL if ![BINARY, SGA, NAWS].contains(&option) => DONT,
+            DO if ![BINARY, SGA, ECHO].contains(&option) => WONT,
+            _ => return,
+        };
+        self.replies.extend_from_slice(&[IAC, reply, option]);
+    }
+
+    fn subnegotiation(&mut self) {
+        if let [NAWS, c1, c0, r1, r0] = self.sub[..] {
+            let size = (u16::from_be_bytes([c1, c0]), u16::from_be_bytes([r1, r0]));
+            debug!("telnet client window size: {}x{}", size.0, size.1);
+            self.window_size = Some(size);
+        }
+    }
+
+    /// Replies for the client that have piled up.
+    pub fn take_replies(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.replies)
+    }
+
+    /// The columns and rows of the client's window, if it's reported a new size.
+    pub fn take_window_size(&mut self) -> Option<(u16, u16)> {
+        self.window_size.take()
+    }
+}
+
+/// Prepares the output to send to the client, which just means doubling any IAC bytes in it.
+pub struct Escape;
+
+impl Filter for Escape {
+    fn transform(&mut self, _dir: Direction, input: &[u8], out: &mut Vec<u8>) {
+        for &b in input {
+            out.push(b);
+            if b == IAC {
+                out.push(IAC);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_telnet() {
+    let mut telnet = Telnet::new();
+    assert_eq!(telnet.input(b"ab\xff\xfb\x1f\xff\xfd\x18c"), b"abc");
+    // Turns down DO TERMINAL-TYPE.
+    assert_eq!(telnet.take_replies(), [IAC, WONT, 24]);
+
+    assert_eq!(telnet.input(b"\xff\xfa\x1f\x00\x50\x00"), b"");
+    assert_eq!(telnet.input(b"\x18\xff\xf0x\r\0y\r\nz\xff\xff"), b"x\ry\rz\xff");
+    assert_eq!(telnet.take_window_size(), Some((80, 24)));
+    assert_eq!(telnet.take_window_size(), None);
+    assert!(telnet.take_replies().is_empty());
+
+    let mut out = vec![];
+    Escape.transform(Direction::Out, b"a\xffb", &mut out);
+    assert_eq!(out, b"a\xff\xffb");
+}
+
+/// Wait a little for the client on this socket to report its window size, so the program can
+/// start out with it. What the client sent is only peeked at, and is read properly later.
+pub fn peek_window_size(fd: RawFd) -> Option<WindowSize> {
+    let deadline = Instant::now() + WINDOW_SIZE_WAIT;
+    let mut buf = [0u8; 512];
+    let mut seen = 0;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            debug!("telnet client didn't report its window size");
+            return None;
+        }
+        let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis() as libc::c_int) } <= 0 {
+            continue;
+        }
+        let n = unsafe {
+            libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), libc::MSG_PEEK)
+        };
+        if n <= 0 || n as usize == buf.len() {
+            // Closed, or too much to look through.
+            return None;
+        }
+        if n as usize == seen {
+            // Nothing new; polling again would return straight away.
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        seen = n as usize;
+        let mut telnet = Telnet::new();
+        telnet.input(&buf[.. seen]);
+        if let Some((cols, rows)) = telnet.take_window_size() {
+            return Some(WindowSize::new(cols, rows));
+        }
+    }
+}
@@ -1,65 +1,337 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::mem;
-use std::os::unix::io::RawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, Once, PoisonError};
 
 use crate::checkerr;
 
-static mut ORIGINAL_TERM_SETTINGS: Option<libc::termios> = None;
+/// The settings to put back at exit (or on a panic) for each terminal a `TermGuard` has taken
+/// over, and whether to make them sane first.
+static SAVED: Mutex<Vec<(RawFd, libc::termios, bool)>> = Mutex::new(vec![]);
 
+fn saved() -> MutexGuard<'static, Vec<(RawFd, libc::termios, bool)>> {
+    SAVED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Put back the settings of every terminal whose `TermGuard` hasn't done it yet, as a last
+/// resort at exit or on a panic.
 pub extern "C" fn reset_tty() {
-    unsafe {
-        // note: can't print anything here
-        if let Some(settings) = ORIGINAL_TERM_SETTINGS.take() {
-            let result = libc::tcsetattr(0, libc::TCSANOW, &settings);
-            let _e = io::Error::last_os_error();
-            if -1 == result {
-                libc::abort()
-            }
+    // note: can't print anything here
+    for (fd, mut settings, sane) in saved().drain(..) {
+        if sane {
+            make_sane(&mut settings);
         }
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &settings) };
     }
 }
 
-pub fn set_raw(fd: RawFd) -> Result<()> {
-    let mut t = unsafe { ORIGINAL_TERM_SETTINGS }
-        .ok_or_else(|| anyhow!("original terminal settings not set yet!"))?;
-    unsafe { libc::cfmakeraw(&mut t as *mut _) };
-    checkerr(unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &t as *const _) },
-        "tcsetattr(raw)")?;
-    Ok(())
+/// Keeps hold of a terminal's settings from before it's put in raw mode, and puts them back when
+/// dropped. In case it never is, they're put back at exit, and before a panic message is
+/// printed, too.
+pub struct TermGuard {
+    fd: RawFd,
+    original: libc::termios,
+    /// Restore a known-good set of settings (like `stty sane`) instead of the original ones.
+    sane: bool,
+}
+
+impl TermGuard {
+    /// Save the settings of the terminal on this file descriptor, which has to stay open for as
+    /// long as the guard is around.
+    pub fn new(fd: RawFd) -> Result<Self> {
+        static AT_EXIT: Once = Once::new();
+        let mut result = Ok(());
+        AT_EXIT.call_once(|| {
+            result = checkerr(unsafe { libc::atexit(reset_tty) }, "atexit").map(drop);
+            reset_tty_on_panic();
+        });
+        result?;
+        let mut guard = TermGuard { fd, original: unsafe { mem::zeroed() }, sane: false };
+        guard.save()?;
+        Ok(guard)
+    }
+
+    /// Restore sane settings instead of the original ones, for a terminal left in a mess.
+    pub fn reset_sane(&mut self) {
+        self.sane = true;
+        self.register();
+    }
+
+    /// Take the terminal's settings as they are now as the ones to put back, as after being
+    /// stopped and continued, when they may have been changed.
+    pub fn save(&mut self) -> Result<()> {
+        checkerr(unsafe { libc::tcgetattr(self.fd, &mut self.original) },
+            "tcgetattr(original settings)")?;
+        self.register();
+        Ok(())
+    }
+
+    fn register(&self) {
+        let mut saved = saved();
+        saved.retain(|&(fd, _, _)| fd != self.fd);
+        saved.push((self.fd, self.original, self.sane));
+    }
+
+    pub fn set_raw(&self) -> Result<()> {
+        let mut t = self.original;
+        unsafe { libc::cfmakeraw(&mut t) };
+        checkerr(unsafe { libc::tcsetattr(self.fd, libc::TCSAFLUSH, &t) }, "tcsetattr(raw)")?;
+        Ok(())
+    }
+
+    /// Put back the saved settings, while keeping hold of them, so raw mode can be entered again
+    /// later.
+    pub fn restore(&self) -> Result<()> {
+        checkerr(unsafe { libc::tcsetattr(self.fd, libc::TCSADRAIN, &self.original) },
+            "tcsetattr(original)")?;
+        Ok(())
+    }
+
+    /// One of the special control characters (like `libc::VINTR`) from the saved settings, if
+    /// it's enabled.
+    pub fn control_char(&self, index: usize) -> Option<u8> {
+        match self.original.c_cc[index] {
+            // _POSIX_VDISABLE is 0 on Linux and 0xff on the BSDs.
+            0 | 0xff => None,
+            c => Some(c),
+        }
+    }
+}
+
+impl Drop for TermGuard {
+    fn drop(&mut self) {
+        saved().retain(|&(fd, _, _)| fd != self.fd);
+        let mut settings = self.original;
+        if self.sane {
+            make_sane(&mut settings);
+        }
+        if unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &settings) } == -1 {
+            warn!("failed to restore terminal settings: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+#[test]
+fn test_term_guard() {
+    let crate::pty::PtyPair { master: _master, slave } = crate::pty::open_pty_pair().unwrap();
+    let fd = slave.as_raw_fd();
+    let lflag = || {
+        let mut t: libc::termios = unsafe { mem::zeroed() };
+        assert_eq!(unsafe { libc::tcgetattr(fd, &mut t) }, 0);
+        t.c_lflag
+    };
+    assert_ne!(lflag() & libc::ICANON, 0);
+    let guard = TermGuard::new(fd).unwrap();
+    guard.set_raw().unwrap();
+    assert_eq!(lflag() & libc::ICANON, 0);
+    assert!(saved().iter().any(|&(saved_fd, _, _)| saved_fd == fd));
+    drop(guard);
+    assert_ne!(lflag() & libc::ICANON, 0);
+    assert!(!saved().iter().any(|&(saved_fd, _, _)| saved_fd == fd));
 }
 
-pub fn save_term_settings(fd: RawFd) -> Result<()> {
-    let mut settings: libc::termios = unsafe { mem::zeroed() };
-    checkerr(unsafe { libc::tcgetattr(fd, &mut settings) },
-        "tcgetattr(original settings)")?;
+/// Output fill characters, which only Linux and macOS have; the other BSDs never did.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+const FILL_FLAGS: libc::tcflag_t = libc::OFILL | libc::OFDEL;
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos",
+    target_os = "ios")))]
+const FILL_FLAGS: libc::tcflag_t = 0;
+
+/// Modify the settings to be equivalent to what `stty sane` would do. Things it doesn't touch
+/// (like the baud rate and character size) are kept as they are.
+fn make_sane(t: &mut libc::termios) {
+    t.c_iflag &= !(libc::IGNBRK | libc::INLCR | libc::IGNCR | libc::IXOFF | libc::IXANY);
+    t.c_iflag |= libc::BRKINT | libc::ICRNL | libc::IXON | libc::IMAXBEL;
+
+    t.c_oflag &= !(libc::OCRNL | libc::ONOCR | libc::ONLRET | FILL_FLAGS);
+    t.c_oflag |= libc::OPOST | libc::ONLCR;
+
+    t.c_cflag |= libc::CREAD;
 
-    unsafe { ORIGINAL_TERM_SETTINGS = Some(settings); }
+    t.c_lflag &= !(libc::ECHONL | libc::NOFLSH | libc::TOSTOP | libc::ECHOPRT);
+    t.c_lflag |= libc::ISIG | libc::ICANON | libc::IEXTEN | libc::ECHO | libc::ECHOE
+        | libc::ECHOK | libc::ECHOCTL | libc::ECHOKE;
 
+    for (idx, c) in [
+        (libc::VINTR, 0x03), // ^C
+        (libc::VQUIT, 0x1c), // ^\
+        (libc::VERASE, 0x7f), // DEL
+        (libc::VKILL, 0x15), // ^U
+        (libc::VEOF, 0x04), // ^D
+        (libc::VSTART, 0x11), // ^Q
+        (libc::VSTOP, 0x13), // ^S
+        (libc::VSUSP, 0x1a), // ^Z
+        (libc::VREPRINT, 0x12), // ^R
+        (libc::VWERASE, 0x17), // ^W
+        (libc::VLNEXT, 0x16), // ^V
+        (libc::VDISCARD, 0x0f), // ^O
+        (libc::VMIN, 1),
+        (libc::VTIME, 0),
+    ] {
+        t.c_cc[idx] = c;
+    }
+}
+
+#[test]
+fn test_make_sane() {
+    let mut t: libc::termios = unsafe { mem::zeroed() };
+    unsafe { libc::cfmakeraw(&mut t) };
+    t.c_iflag |= libc::INLCR;
+    make_sane(&mut t);
+    assert_ne!(t.c_lflag & libc::ICANON, 0);
+    assert_ne!(t.c_lflag & libc::ECHO, 0);
+    assert_ne!(t.c_lflag & libc::ISIG, 0);
+    assert_ne!(t.c_oflag & libc::ONLCR, 0);
+    assert_ne!(t.c_iflag & libc::ICRNL, 0);
+    assert_eq!(t.c_iflag & libc::INLCR, 0);
+    assert_eq!(t.c_cc[libc::VINTR], 0x03);
+    assert_eq!(t.c_cc[libc::VEOF], 0x04);
+}
+
+/// Settings for the pty when it's carrying a pipeline rather than a terminal session: nothing is
+/// echoed and output is passed through untranslated, but input is still line-buffered, so the
+/// EOF character can end it.
+pub fn set_filter(fd: RawFd) -> Result<()> {
+    let mut t: libc::termios = unsafe { mem::zeroed() };
+    checkerr(unsafe { libc::tcgetattr(fd, &mut t) }, "tcgetattr")?;
+    t.c_lflag &= !(libc::ECHO | libc::ECHONL);
+    t.c_oflag &= !libc::OPOST;
+    checkerr(unsafe { libc::tcsetattr(fd, libc::TCSANOW, &t) }, "tcsetattr(filter)")?;
     Ok(())
 }
 
-pub fn restore_term_settings_at_exit() -> Result<()> {
-    checkerr(unsafe { libc::atexit(reset_tty) }, "atexit")?;
+/// With `--raw-nl`, the pty passes on what the program writes without turning LF into CR LF, or
+/// any of its other output processing.
+pub fn set_untranslated_output(fd: RawFd) -> Result<()> {
+    let mut t: libc::termios = unsafe { mem::zeroed() };
+    checkerr(unsafe { libc::tcgetattr(fd, &mut t) }, "tcgetattr")?;
+    t.c_oflag &= !libc::OPOST;
+    checkerr(unsafe { libc::tcsetattr(fd, libc::TCSANOW, &t) }, "tcsetattr(raw-nl)")?;
     Ok(())
 }
 
-pub fn set_controlling_tty(fd: RawFd) -> Result<()> {
-    #[allow(clippy::useless_conversion)] // it isn't identical on all platforms
-    checkerr(unsafe { libc::ioctl(fd, libc::TIOCSCTTY.into(), 1) }, "ioctl(TIOCSCTTY)")
-        .map(|_| ())
+/// Open a serial device and set it up to carry bytes untouched, at the given speed if there is
+/// one. It's opened without waiting for carrier, which is then ignored.
+pub fn open_serial(path: &Path, speed: Option<u32>) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(path)
+        .with_context(|| format!("failed to open {path:?}"))?;
+    let fd = file.as_raw_fd();
+    let mut t: libc::termios = unsafe { mem::zeroed() };
+    checkerr(unsafe { libc::tcgetattr(fd, &mut t) }, "tcgetattr(serial)")
+        .with_context(|| format!("{path:?} isn't a terminal"))?;
+    unsafe { libc::cfmakeraw(&mut t) };
+    t.c_cflag |= libc::CLOCAL | libc::CREAD;
+    t.c_cc[libc::VMIN] = 1;
+    t.c_cc[libc::VTIME] = 0;
+    if let Some(baud) = speed {
+        let speed = serial_speed(baud).ok_or_else(|| anyhow!("unsupported speed {baud}"))?;
+        checkerr(unsafe { libc::cfsetspeed(&mut t, speed) }, "cfsetspeed")?;
+    }
+    checkerr(unsafe { libc::tcsetattr(fd, libc::TCSANOW, &t) }, "tcsetattr(serial)")?;
+    debug!("opened serial device {:?}", path);
+    Ok(file)
 }
 
-pub fn set_session_leader() -> Result<()> {
-    checkerr(unsafe { libc::setsid() }, "setsid")?;
-    Ok(())
+/// The termios speed for a baud rate, if it's one that can be set.
+pub fn serial_speed(baud: u32) -> Option<libc::speed_t> {
+    Some(match baud {
+        50 => libc::B50,
+        75 => libc::B75,
+        110 => libc::B110,
+        134 => libc::B134,
+        150 => libc::B150,
+        200 => libc::B200,
+        300 => libc::B300,
+        600 => libc::B600,
+        1200 => libc::B1200,
+        1800 => libc::B1800,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        _ => return None,
+    })
+}
+
+/// Restore the terminal settings when the main thread panics, before the message is printed, so
+/// it's readable and the terminal doesn't need a `reset` afterwards. (The atexit handler would
+/// only get to it afterwards, and not at all if the panic aborts.) Other threads are only helping
+/// out, and their panics don't end the session.
+fn reset_tty_on_panic() {
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if std::thread::current().name() == Some("main") {
+            reset_tty();
+        }
+        hook(info);
+    }));
+}
+
+pub fn is_tty(fd: RawFd) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// Whether both file descriptors are the same terminal.
+pub fn same_terminal(a: RawFd, b: RawFd) -> bool {
+    let rdev = |fd| {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        (is_tty(fd) && unsafe { libc::fstat(fd, &mut st) } == 0).then_some(st.st_rdev)
+    };
+    matches!((rdev(a), rdev(b)), (Some(a), Some(b)) if a == b)
 }
 
 pub struct WindowSize {
     ws: libc::winsize,
 }
 
+/// The size assumed when there's no telling, as for a VT100.
+impl Default for WindowSize {
+    fn default() -> Self {
+        WindowSize::new(80, 24)
+    }
+}
+
 impl WindowSize {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        let mut ws: libc::winsize = unsafe { mem::zeroed() };
+        ws.ws_col = cols;
+        ws.ws_row = rows;
+        WindowSize { ws }
+    }
+
+    /// With `--cols` and `--rows`, the size the program gets instead of this one.
+    pub fn overridden(mut self, cols: Option<u16>, rows: Option<u16>) -> Self {
+        self.ws.ws_col = cols.unwrap_or(self.ws.ws_col);
+        self.ws.ws_row = rows.unwrap_or(self.ws.ws_row);
+        self
+    }
+
+    /// Use the size given by the COLUMNS and LINES environment variables, if they're both set.
+    pub fn from_env() -> Option<Self> {
+        let get = |name| std::env::var(name).ok()?.parse::<u16>().ok().filter(|&n| n > 0);
+        Some(Self::new(get("COLUMNS")?, get("LINES")?))
+    }
+
+    pub fn cols(&self) -> u16 {
+        self.ws.ws_col
+    }
+
+    pub fn rows(&self) -> u16 {
+        self.ws.ws_row
+    }
+
     pub fn from_fd(fd: RawFd) -> Result<Self> {
         let mut ws: libc::winsize = unsafe { mem::zeroed() };
         checkerr(unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws as *mut _) },
@@ -67,9 +339,19 @@ impl WindowSize {
         Ok(WindowSize { ws })
     }
 
-    pub fn apply_to_fd(&self, fd: RawFd) -> Result<()> {
-        checkerr(unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &self.ws) },
-            "ioctl(TIOCSWINSZ)")?;
+    /// This is done in the child too, so it doesn't allocate, even to fail.
+    pub fn apply_to_fd(&self, fd: RawFd) -> io::Result<()> {
+        if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &self.ws) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
         Ok(())
     }
 }
+
+#[test]
+fn test_window_size_overridden() {
+    let ws = WindowSize::default().overridden(Some(100), None);
+    assert_eq!((ws.cols(), ws.rows()), (100, 24));
+    let ws = WindowSize::new(132, 43).overridden(None, Some(10));
+    assert_eq!((ws.cols(), ws.rows()), (132, 10));
+}
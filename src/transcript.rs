@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::compress;
+use crate::options::{Compression, TranscriptFormat};
+
+/// Records both directions of the session, interleaved in the order they were forwarded.
+pub struct Transcript {
+    file: Box<dyn Write>,
+    format: TranscriptFormat,
+    start: Instant,
+    /// Direction of the line currently being written, if one is unfinished.
+    open_line: Option<usize>,
+    stripper: EscapeStripper,
+}
+
+impl Transcript {
+    pub fn create(
+        path: &Path,
+        compression: Option<Compression>,
+        format: TranscriptFormat,
+        now: Instant,
+    ) -> Result<Self> {
+        let file = compress::create(path, compression)
+            .with_context(|| format!("failed to create transcript {path:?}"))?;
+        Ok(Transcript {
+            file,
+            format,
+            start: now,
+            open_line: None,
+            stripper: EscapeStripper::default(),
+        })
+    }
+
+    /// Add data forwarded in the given direction (0 for input, 1 for output).
+    pub fn record(&mut self, now: Instant, idx: usize, data: &[u8]) -> io::Result<()> {
+        let timestamp = now.saturating_duration_since(self.start).as_secs_f64();
+        let mut out = vec![];
+        match self.format {
+            TranscriptFormat::Chunks => {
+                let dir = if idx == 0 { "in " } else { "out" };
+                writeln!(out, "{timestamp:10.3} {dir} \"{}\"", data.escape_ascii())?;
+            }
+            TranscriptFormat::Text => {
+                let text = if idx == 0 { show_input(data) } else { self.stripper.strip(data) };
+                for &b in &text {
+                    if self.open_line.is_some_and(|dir| dir != idx) {
+                        out.push(b'\n');
+                        self.open_line = None;
+                    }
+                    if self.open_line.is_none() {
+                        let prefix = if idx == 0 { '>' } else { '<' };
+                        write!(out, "{timestamp:10.3} {prefix} ")?;
+                        self.open_line = Some(idx);
+                    }
+                    out.push(b);
+                    if b == b'\n' {
+                        self.open_line = None;
+                    }
+                }
+            }
+        }
+        // One write per chunk, so the file is always up to date.
+        self.file.write_all(&out)
+    }
+
+    /// End the last line, if it's unfinished.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.open_line.take().is_some() {
+            self.file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Typed input, made visible: Enter ends the line, and other control characters are shown in
+/// caret notation.
+fn show_input(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    for &b in data {
+        match b {
+            b'\r' | b'\n' => out.push(b'\n'),
+            0 ..= 0x1f => out.extend([b'^', b + 0x40]),
+            0x7f => out.extend(b"^?"),
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+/// Removes terminal escape sequences and control characters from output, leaving the text. Keeps
+/// its state between calls, since sequences can be split across reads.
+#[derive(Default)]
+struct EscapeStripper {
+    state: StripState,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum StripState {
+    #[default]
+    Text,
+    /// After ESC.
+    Escape,
+    /// In a control sequence (ESC [), until its final byte.
+    Csi,
+    /// In an operating system command or other string (ESC ], ESC P, ...), until BEL or ST.
+    String,
+    /// After ESC inside a string, which is the start of ST (ESC \).
+    StringEscape,
+}
+
+impl EscapeStripper {
+    fn strip(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        for &b in data {
+            self.state = match (self.state, b) {
+                (StripState::Text, 0x1b) => StripState::Escape,
+                (StripState::Text, b'\n' | b'\t') => {
+                    out.push(b);
+                    StripState::Text
+                }
+                (StripState::Text, 0 ..= 0x1f | 0x7f) => StripState::Text,
+                (StripState::Text, _) => {
+                    out.push(b);
+                    StripState::Text
+                }
+                (StripState::Escape, b'[') => StripState::Csi,
+                (StripState::Escape, b']' | b'P' | b'X' | b'^' | b'_') => StripState::String,
+                // Intermediate bytes, as in ESC ( B; the sequence continues.
+                (StripState::Escape, 0x20 ..= 0x2f) => StripState::Escape,
+                (StripState::Escape, _) => StripState::Text,
+                (StripState::Csi, 0x40 ..= 0x7e) => StripState::Text,
+                (StripState::Csi, _) => StripState::Csi,
+                (StripState::String, 0x07) => StripState::Text,
+                (StripState::String, 0x1b) => StripState::StringEscape,
+                (StripState::String, _) => StripState::String,
+                (StripState::StringEscape, b'\\') => StripState::Text,
+                (StripState::StringEscape, _) => StripState::String,
+            };
+        }
+        out
+    }
+}
+
+#[test]
+fn test_escape_stripper() {
+    let mut stripper = EscapeStripper::default();
+    assert_eq!(stripper.strip(b"\x1b[1;31mred\x1b[0m\r\n"), b"red\n");
+    assert_eq!(stripper.strip(b"\x1b]2;title\x07a\x1b]0;x\x1b\\b"), b"ab");
+    assert_eq!(stripper.strip(b"\x1b(Bc\x08d"), b"cd");
+
+    // Sequences split across calls.
+    assert_eq!(stripper.strip(b"one\x1b["), b"one");
+    assert_eq!(stripper.strip(b"2Jtwo"), b"two");
+}
+
+#[test]
+fn test_show_input() {
+    assert_eq!(show_input(b"ls -l\r"), b"ls -l\n");
+    assert_eq!(show_input(b"\x03\x7f"), b"^C^?");
+}
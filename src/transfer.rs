@@ -0,0 +1,138 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long a transfer can go without anything passing either way before it's taken to be over.
+/// XMODEM and YMODEM don't say so in a way that can be told from the data, and anything can stop
+/// part way through.
+const IDLE: Duration = Duration::from_secs(3);
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const CAN: u8 = 0x18;
+
+/// The start of a ZMODEM hex header, which both ends send to begin with.
+const ZMODEM_HEADER: &[u8] = b"**\x18B";
+/// A ZFIN header, which both ends send at the end of the session; the sender follows it with
+/// "OO" (over and out).
+const ZMODEM_ZFIN: &[u8] = b"**\x18B08";
+/// Enough CANs in a row to cancel a transfer in any of them.
+const CANCEL: &[u8] = &[CAN; 5];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Zmodem,
+    /// XMODEM or YMODEM, which send the same blocks.
+    Xmodem,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Zmodem => "ZMODEM",
+            Protocol::Xmodem => "XMODEM/YMODEM",
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Started(Protocol),
+    Ended,
+}
+
+/// Watches both directions for file transfers starting and ending.
+pub struct Transfers {
+    /// The last few bytes each way, for sequences split across reads.
+    recent: [Vec<u8>; 2],
+    active: Option<Protocol>,
+    /// Whether a ZMODEM transfer has got as far as a ZFIN.
+    zfin: bool,
+    last_activity: Instant,
+}
+
+impl Transfers {
+    pub fn new(now: Instant) -> Self {
+        Transfers { recent: [vec![], vec![]], active: None, zfin: false, last_activity: now }
+    }
+
+    /// When the transfer in progress, if there is one, will be taken to be over if nothing
+    /// more happens.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.active.map(|_| self.last_activity + IDLE)
+    }
+
+    /// End the transfer in progress if it's gone idle. Returns whether it did.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        if self.deadline().is_some_and(|t| now >= t) {
+            self.active = None;
+            return true;
+        }
+        false
+    }
+
+    /// Look at what was just read in one direction (indexed like the `ReadableSet` endpoints),
+    /// and return whether a transfer started or ended with it.
+    pub fn data(&mut self, idx: usize, now: Instant, data: &[u8]) -> Option<Change> {
+        let before = self.active;
+        self.last_activity = now;
+        for &b in data {
+            let recent = &mut self.recent[idx];
+            if recent.len() == ZMODEM_ZFIN.len() {
+                recent.remove(0);
+            }
+            recent.push(b);
+            match self.active {
+                None if recent.ends_with(ZMODEM_HEADER) => {
+                    self.active = Some(Protocol::Zmodem);
+                    self.zfin = false;
+                }
+                // A block header: SOH (or STX, for 1K blocks), the block number, and its
+                // complement.
+                None if matches!(recent[..], [.., SOH | STX, n, c] if c == !n) => {
+                    self.active = Some(Protocol::Xmodem);
+                }
+                None => (),
+                Some(_) if recent.ends_with(CANCEL) => self.active = None,
+                Some(Protocol::Zmodem) if recent.ends_with(ZMODEM_ZFIN) => self.zfin = true,
+                Some(Protocol::Zmodem) if self.zfin && recent.ends_with(b"OO") => {
+                    self.active = None;
+                }
+                Some(_) => (),
+            }
+        }
+        match (before, self.active) {
+            (None, Some(protocol)) => Some(Change::Started(protocol)),
+            (Some(_), None) => Some(Change::Ended),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_transfers() {
+    let start = Instant::now();
+    let mut transfers = Transfers::new(start);
+    assert_eq!(transfers.data(1, start, b"$ sz file\r\nrz\r**\x18"), None);
+    assert_eq!(transfers.data(1, start, b"B00000000000000\r\x8a"),
+        Some(Change::Started(Protocol::Zmodem)));
+    assert_eq!(transfers.data(0, start, b"**\x18B0100000023be50\r\x8a"), None);
+    assert_eq!(transfers.data(1, start, b"OO"), None);
+    assert_eq!(transfers.data(1, start, b"**\x18B0800000000022d\r\x8a"), None);
+    assert_eq!(transfers.data(0, start, b"**\x18B0800000000022d\r\x8a"), None);
+    assert_eq!(transfers.data(1, start, b"OO"), Some(Change::Ended));
+    assert_eq!(transfers.deadline(), None);
+
+    // XMODEM (or YMODEM) starts with a block, and ends when it's gone quiet.
+    assert_eq!(transfers.data(0, start, b"C"), None);
+    assert_eq!(transfers.data(1, start, b"\x01\x00\xfffile\0"),
+        Some(Change::Started(Protocol::Xmodem)));
+    let later = start + Duration::from_secs(1);
+    assert_eq!(transfers.data(0, later, b"\x06"), None);
+    assert!(!transfers.expire(later + Duration::from_secs(1)));
+    assert!(transfers.expire(later + IDLE));
+    assert_eq!(transfers.deadline(), None);
+
+    // Or when it's cancelled.
+    assert_eq!(transfers.data(1, later, b"\x02\x01\xfe"), Some(Change::Started(Protocol::Xmodem)));
+    assert_eq!(transfers.data(0, later, b"\x18\x18\x18\x18\x18\x08\x08"), Some(Change::Ended));
+}
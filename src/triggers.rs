@@ -0,0 +1,58 @@
+use regex::bytes::Regex;
+
+use crate::options::OutputAction;
+
+/// The most output kept for the patterns to match in, when they haven't so far.
+const MAX_TAIL: usize = 4096;
+
+/// With `--on-output`, watches the output as it's shown for the patterns, and says what to do
+/// when one of them turns up.
+pub struct Triggers {
+    triggers: Vec<(Regex, OutputAction)>,
+    /// The output since the last match, in case one is split across writes.
+    tail: Vec<u8>,
+}
+
+impl Triggers {
+    pub fn new(triggers: Vec<(Regex, OutputAction)>) -> Self {
+        Triggers { triggers, tail: vec![] }
+    }
+
+    /// Look at some output that was just shown, and return what to do about it, in the order
+    /// the matches came in. Text that matched once doesn't match again.
+    pub fn output(&mut self, data: &[u8]) -> Vec<OutputAction> {
+        self.tail.extend_from_slice(data);
+        let mut actions = vec![];
+        loop {
+            let first = self.triggers.iter()
+                .filter_map(|(regex, action)| regex.find(&self.tail).map(|m| (m, action)))
+                .min_by_key(|(m, _)| m.start());
+            let Some((m, action)) = first else { break };
+            debug!("output matched {:?}", String::from_utf8_lossy(m.as_bytes()));
+            actions.push(action.clone());
+            let end = m.end();
+            self.tail.drain(.. end);
+        }
+        if self.tail.len() > MAX_TAIL {
+            self.tail.drain(.. self.tail.len() - MAX_TAIL);
+        }
+        actions
+    }
+}
+
+#[test]
+fn test_triggers() {
+    let mut triggers = Triggers::new(vec![
+        (Regex::new("[Pp]assword: ").unwrap(), OutputAction::Send(b"hunter2\r".to_vec())),
+        (Regex::new(r"Welcome to \w+").unwrap(), OutputAction::Rate(30.)),
+        (Regex::new(r"logout\r?\n").unwrap(), OutputAction::Exit),
+    ]);
+    assert_eq!(triggers.output(b"login: guest\r\nPass"), []);
+    assert_eq!(triggers.output(b"word: "), [OutputAction::Send(b"hunter2\r".to_vec())]);
+    // Already matched, so not again.
+    assert_eq!(triggers.output(b"\r\n"), []);
+    assert_eq!(triggers.output(b"Welcome to demo!\r\n$ logout\r\n"),
+        [OutputAction::Rate(30.), OutputAction::Exit]);
+    assert_eq!(triggers.output(b"Password: Password: "),
+        [OutputAction::Send(b"hunter2\r".to_vec()), OutputAction::Send(b"hunter2\r".to_vec())]);
+}
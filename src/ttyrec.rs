@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::compress;
+use crate::options::Compression;
+use crate::recording::Recording;
+
+/// Records what the console was shown as a ttyrec, which ttyplay and IPBT can play back with
+/// the timing it had: each chunk of output has a header of three little-endian 32-bit numbers,
+/// the seconds and microseconds since the epoch, and the length.
+pub struct Ttyrec {
+    file: Box<dyn Write>,
+    /// When the recording started, by the wall clock and by the event loop's.
+    start: (SystemTime, Instant),
+}
+
+impl Ttyrec {
+    pub fn create(path: &Path, compression: Option<Compression>, now: Instant) -> Result<Self> {
+        let file = compress::create(path, compression)
+            .with_context(|| format!("failed to create recording {path:?}"))?;
+        Ok(Ttyrec { file, start: (SystemTime::now(), now) })
+    }
+}
+
+impl Recording for Ttyrec {
+    fn output(&mut self, now: Instant, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let time = (self.start.0 + now.saturating_duration_since(self.start.1))
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        // A chunk can't be longer than the length field goes.
+        for data in data.chunks(u32::MAX as usize) {
+            let mut record = Vec::with_capacity(12 + data.len());
+            for n in [time.as_secs() as u32, time.subsec_micros(), data.len() as u32] {
+                record.extend(n.to_le_bytes());
+            }
+            record.extend(data);
+            // One write per record, so the file is always up to date.
+            self.file.write_all(&record)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_ttyrec() {
+    let path = std::env::temp_dir().join(format!("slowpty-ttyrec-{}", std::process::id()));
+    let start = Instant::now();
+    let mut ttyrec = Ttyrec::create(&path, None, start).unwrap();
+    ttyrec.output(start + Duration::from_millis(250), b"hi\r\n").unwrap();
+    ttyrec.output(start + Duration::from_millis(500), b"").unwrap();
+    ttyrec.output(start + Duration::from_millis(1750), b"$ ").unwrap();
+    drop(ttyrec);
+    let data = std::fs::read(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    let word = |i: usize| u32::from_le_bytes(data[i .. i + 4].try_into().unwrap());
+    let time = |i: usize| Duration::new(word(i).into(), word(i + 4) * 1000);
+    assert_eq!(word(8), 4);
+    assert_eq!(&data[12 .. 16], b"hi\r\n");
+    assert_eq!(word(24), 2);
+    assert_eq!(&data[28 ..], b"$ ");
+    assert_eq!(time(16) - time(0), Duration::from_millis(1500));
+}
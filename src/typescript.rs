@@ -0,0 +1,107 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::compress;
+use crate::options::Compression;
+use crate::recording::Recording;
+
+/// Records what the console was shown as a typescript like script(1) writes, with an optional
+/// timing file alongside, so scriptreplay(1) can play it back at the speed it ran at.
+pub struct Typescript {
+    file: Box<dyn Write>,
+    /// The timing file, and when the last output was.
+    timing: Option<(File, Instant)>,
+}
+
+impl Typescript {
+    pub fn create(
+        path: &Path,
+        compression: Option<Compression>,
+        timing: Option<&Path>,
+        command: &str,
+        now: Instant,
+    ) -> Result<Self> {
+        let mut file = compress::create(path, compression)
+            .with_context(|| format!("failed to create typescript {path:?}"))?;
+        // scriptreplay skips this line, so it doesn't need to be exactly what script(1) writes.
+        writeln!(file, "Script started on {} [COMMAND={}]", date(), shell_quote(command))
+            .with_context(|| format!("failed to write to typescript {path:?}"))?;
+        let timing = match timing {
+            Some(path) => {
+                let file = File::create(path)
+                    .with_context(|| format!("failed to create timing file {path:?}"))?;
+                Some((file, now))
+            }
+            None => None,
+        };
+        Ok(Typescript { file, timing })
+    }
+}
+
+impl Recording for Typescript {
+    fn output(&mut self, now: Instant, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(data)?;
+        if let Some((ref mut timing, ref mut last)) = self.timing {
+            // The classic timing format: seconds since the last output, and how many bytes.
+            let delay = now.saturating_duration_since(*last).as_secs_f64();
+            writeln!(timing, "{delay:.6} {}", data.len())?;
+            *last = now;
+        }
+        Ok(())
+    }
+
+    /// Write the closing line, which scriptreplay leaves alone as it has no timing.
+    fn finish(&mut self) -> io::Result<()> {
+        writeln!(self.file, "\nScript done on {}", date())
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The local date and time, the way script(1) writes it.
+fn date() -> String {
+    let mut buf = [0u8; 64];
+    let len = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return String::new();
+        }
+        libc::strftime(buf.as_mut_ptr().cast(), buf.len(), c"%Y-%m-%d %H:%M:%S%z".as_ptr(),
+            &tm)
+    };
+    String::from_utf8_lossy(&buf[.. len]).into_owned()
+}
+
+#[test]
+fn test_typescript() {
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("slowpty-typescript-{}", std::process::id()));
+    let timing = dir.join(format!("slowpty-timing-{}", std::process::id()));
+    let start = Instant::now();
+    let ms = std::time::Duration::from_millis;
+    let mut typescript = Typescript::create(&path, None, Some(&timing), "echo \"hi\"", start)
+        .unwrap();
+    typescript.output(start + ms(250), b"hi\r\n").unwrap();
+    typescript.output(start + ms(500), b"").unwrap();
+    typescript.output(start + ms(1000), b"$ ").unwrap();
+    typescript.finish().unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+    let timings = std::fs::read_to_string(&timing).unwrap();
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(timing).unwrap();
+
+    let (header, rest) = text.split_once('\n').unwrap();
+    assert!(header.starts_with("Script started on "), "{header}");
+    assert!(header.ends_with(r#" [COMMAND="echo \"hi\""]"#), "{header}");
+    assert!(rest.starts_with("hi\r\n$ \nScript done on "), "{rest}");
+    assert_eq!(timings, "0.250000 4\n0.750000 2\n");
+}
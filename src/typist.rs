@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+use crate::rng::Rng;
+
+/// With `--humanize`, paces input like someone typing it: a keystroke at a time, each after a
+/// gap that varies around the usual one, and that's longer after spaces, punctuation, and the
+/// ends of lines, where people stop to think.
+pub struct Typist {
+    gap: Duration,
+    rng: Rng,
+    /// When the next keystroke can go.
+    until: Option<Instant>,
+}
+
+impl Typist {
+    pub fn new(gap: Duration, seed: u64) -> Self {
+        Typist {
+            gap,
+            rng: Rng::new(seed),
+            until: None,
+        }
+    }
+
+    /// Take note of a keystroke having been typed, and pick how long until the next.
+    pub fn typed(&mut self, now: Instant, data: &[u8]) {
+        let Some(&last) = data.last() else { return };
+        let pause = match last {
+            b'\r' | b'\n' => 4.,
+            b'.' | b',' | b';' | b':' | b'!' | b'?' => 3.,
+            b' ' | b'\t' => 1.8,
+            _ => 1.,
+        };
+        // Anywhere from half as long to half as long again.
+        let vary = 0.5 + self.rng.next_f64();
+        self.until = Some(now + self.gap.mul_f64(pause * vary));
+    }
+
+    /// How long until the next keystroke, if it has to wait.
+    pub fn wait(&self, now: Instant) -> Option<Duration> {
+        self.until.filter(|&until| until > now).map(|until| until - now)
+    }
+}
+
+#[test]
+fn test_typist() {
+    let start = Instant::now();
+    let ms = Duration::from_millis;
+    let mut typist = Typist::new(ms(100), 1);
+    assert_eq!(typist.wait(start), None);
+
+    typist.typed(start, b"a");
+    let wait = typist.wait(start).unwrap();
+    assert!((ms(50) ..= ms(150)).contains(&wait), "{wait:?}");
+    assert_eq!(typist.wait(start + ms(150)), None);
+
+    typist.typed(start, b".");
+    let wait = typist.wait(start).unwrap();
+    assert!((ms(150) ..= ms(450)).contains(&wait), "{wait:?}");
+}
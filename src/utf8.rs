@@ -0,0 +1,108 @@
+/// With `--utf8`, keeps each UTF-8 character in the output together, so a terminal never gets
+/// half of one and shows a replacement character until the rest comes.
+pub struct Utf8 {
+    /// Charge a character as one byte, however many it takes.
+    per_char: bool,
+    /// The start of a character that was cut off at the end of the last read.
+    held: Vec<u8>,
+}
+
+fn is_continuation(b: u8) -> bool {
+    b & 0xc0 == 0x80
+}
+
+/// How long a sequence this byte starts. Anything that isn't the start of one counts as a byte
+/// by itself, so output that isn't UTF-8 goes through as usual.
+fn sequence_len(b: u8) -> usize {
+    match b {
+        0xc2 ..= 0xdf => 2,
+        0xe0 ..= 0xef => 3,
+        0xf0 ..= 0xf4 => 4,
+        _ => 1,
+    }
+}
+
+/// The length of the character at the start of the data, or 1 if it isn't a whole one.
+fn char_len(data: &[u8]) -> usize {
+    let len = sequence_len(data[0]);
+    match data.get(1 .. len) {
+        Some(rest) if rest.iter().all(|&b| is_continuation(b)) => len,
+        _ => 1,
+    }
+}
+
+/// How many bytes at the end of the data are a character that isn't all there yet.
+fn incomplete_tail(data: &[u8]) -> usize {
+    for back in 1 ..= data.len().min(3) {
+        let b = data[data.len() - back];
+        if !is_continuation(b) {
+            return if sequence_len(b) > back { back } else { 0 };
+        }
+    }
+    0
+}
+
+impl Utf8 {
+    pub fn new(per_char: bool) -> Self {
+        Utf8 { per_char, held: vec![] }
+    }
+
+    /// Take output as it's read, and return it with any character cut off at the end held back
+    /// until the next read brings the rest.
+    pub fn join(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = std::mem::take(&mut self.held);
+        out.extend_from_slice(data);
+        self.held = out.split_off(out.len() - incomplete_tail(&out));
+        out
+    }
+
+    /// How much of the data can be written with the tokens there are, without splitting a
+    /// character. The first character can always go, on credit if need be.
+    pub fn fit(&self, data: &[u8], tokens: usize) -> usize {
+        let (mut end, mut cost) = (0, 0);
+        while end < data.len() {
+            let len = char_len(&data[end ..]);
+            let price = if self.per_char { 1 } else { len };
+            if end > 0 && cost + price > tokens {
+                break;
+            }
+            end += len;
+            cost += price;
+        }
+        end
+    }
+
+    /// What writing the data costs.
+    pub fn cost(&self, data: &[u8]) -> usize {
+        if !self.per_char {
+            return data.len();
+        }
+        let (mut end, mut chars) = (0, 0);
+        while end < data.len() {
+            end += char_len(&data[end ..]);
+            chars += 1;
+        }
+        chars
+    }
+}
+
+#[test]
+fn test_utf8() {
+    let mut utf8 = Utf8::new(false);
+    // "é" is 2 bytes, "€" is 3.
+    assert_eq!(utf8.join(b"a\xc3\xa9\xe2\x82"), b"a\xc3\xa9");
+    assert_eq!(utf8.join(b"\xacb"), b"\xe2\x82\xacb");
+    // Not UTF-8 at all.
+    assert_eq!(utf8.join(b"\xff\x80"), b"\xff\x80");
+
+    let data = "a€é".as_bytes();
+    assert_eq!(utf8.fit(data, 1), 1);
+    assert_eq!(utf8.fit(data, 3), 1);
+    assert_eq!(utf8.fit(data, 4), 4);
+    assert_eq!(utf8.fit(&data[1 ..], 1), 3);
+    assert_eq!(utf8.cost(data), 6);
+
+    let utf8 = Utf8::new(true);
+    assert_eq!(utf8.fit(data, 2), 4);
+    assert_eq!(utf8.cost(data), 3);
+}